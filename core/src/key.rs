@@ -0,0 +1,145 @@
+use bitcoin::key::{Secp256k1, TweakedPublicKey, XOnlyPublicKey};
+use bitcoin::taproot::TapNodeHash;
+use bitcoin::{Address, KnownHrp, ScriptBuf};
+use musig2::KeyAggContext;
+use musig2::secp::{Point, Scalar};
+use rand::Rng;
+
+use crate::{Error, Result};
+
+/**
+The order of the key shares for the aggregation. Both traders must use the same order, otherwise they get
+different aggregated keys. We use the smaller pubkey first, so it doesn't matter who is buyer or seller.
+The secret keys must be in the same order as their pubkeys, so the values to sort are passed with their points.
+*/
+pub fn sort_key_shares<T>(my_point: Point, mine: T, other_point: Point, other: T) -> [T; 2] {
+    if my_point < other_point {
+        [mine, other]
+    } else {
+        [other, mine]
+    }
+}
+
+/**
+the context for the aggregated taproot key. Without a merkle root the key is tweaked with the unspendable
+tweak (BIP-86), which is the same as a P2TR address without script tree.
+*/
+pub fn key_agg_context(my_point: Point, other_point: Point, merkle_root: Option<TapNodeHash>) -> Result<KeyAggContext> {
+    let ctx = KeyAggContext::new(sort_key_shares(my_point, my_point, other_point, other_point))?;
+    Ok(match merkle_root {
+        None => ctx.with_unspendable_taproot_tweak()?,
+        Some(merkle_root) => ctx.with_taproot_tweak(merkle_root.as_ref())?,
+    })
+}
+
+/**
+the P2TR output script of the aggregated key. The context must already be tweaked, see `key_agg_context`.
+*/
+pub fn agg_script_pubkey(ctx: &KeyAggContext) -> Result<ScriptBuf> {
+    let output_key = XOnlyPublicKey::from_slice(&ctx.aggregated_pubkey::<Point>().serialize_xonly())?;
+    Ok(ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key)))
+}
+
+/**
+the secret key of the aggregated (and tweaked) key, once we know the secret key share of the other trader.
+*/
+pub fn aggregated_seckey(ctx: &KeyAggContext, my_key: (Point, Scalar), other_key: (Point, Scalar)) -> Result<Scalar> {
+    let seckeys = sort_key_shares(my_key.0, my_key.1, other_key.0, other_key.1);
+    // lib checks that the aggregated secret key actually belongs to the aggregated pubkey
+    Ok(ctx.aggregated_seckey(seckeys)?)
+}
+
+/**
+MuSig2 interaction, it represents the Key not only our side of the equation
+*/
+#[derive(PartialEq, Clone)]
+#[derive(Debug)]
+pub struct AggKey {
+    pub sec: Scalar,
+    pub other_sec: Option<Scalar>,
+    pub agg_sec: Option<Scalar>,
+    pub pub_point: Point,
+    pub other_point: Option<Point>,
+    pub agg_point: Option<Point>,
+    pub key_agg_context: Option<KeyAggContext>,
+    pub merkle_root: Option<TapNodeHash>, // None means key spend only
+}
+
+impl AggKey {
+    pub fn new() -> Result<AggKey> {
+        //TODO is this random sufficient?
+        let mut seed = [0u8; 32];
+        rand::rng().fill(&mut seed);
+
+        let sec: Scalar = Scalar::from_slice(&seed)?;
+        let point = sec.base_point_mul();
+        Ok(AggKey { sec, other_sec: None, agg_sec: None, pub_point: point, other_point: None, agg_point: None, key_agg_context: None, merkle_root: None })
+    }
+
+    pub fn aggregate_key(&mut self, point_from_bob: Point) -> Result<Point> {
+        assert_ne!(point_from_bob, self.pub_point, "Bob is sending my point back.");
+        let ctx = key_agg_context(self.pub_point, point_from_bob, None)?;
+        let result = ctx.aggregated_pubkey();
+        self.key_agg_context = Some(ctx);
+        self.agg_point = Some(result);
+        self.other_point = Some(point_from_bob);
+        Ok(result)
+    }
+
+    /**
+    the same aggregated key, but tweaked with a taproot tree instead of the unspendable tweak.
+    */
+    pub fn with_merkle_root(&self, merkle_root: TapNodeHash) -> Result<AggKey> {
+        let other_point = self.other_point.ok_or(Error::MissingAggKey)?;
+        let ctx = key_agg_context(self.pub_point, other_point, Some(merkle_root))?;
+        let mut key = self.clone();
+        key.agg_point = Some(ctx.aggregated_pubkey());
+        key.key_agg_context = Some(ctx);
+        key.merkle_root = Some(merkle_root);
+        Ok(key)
+    }
+
+    /**
+    sets the secret key share of the other trader and calculates the aggregated secret key.
+    */
+    pub fn aggregate_other_sec(&mut self, other_sec: Scalar) -> Result<Scalar> {
+        let (Some(ctx), Some(other_point)) = (self.key_agg_context.as_ref(), self.other_point) else {
+            return Err(Error::MissingAggKey);
+        };
+        let agg_sec = aggregated_seckey(ctx, (self.pub_point, self.sec), (other_point, other_sec))?;
+        self.other_sec = Some(other_sec);
+        self.agg_sec = Some(agg_sec);
+        Ok(agg_sec)
+    }
+
+    fn internal_key(&self) -> Result<XOnlyPublicKey> {
+        let ctx = self.key_agg_context.as_ref().ok_or(Error::MissingAggKey)?;
+        Ok(XOnlyPublicKey::from_slice(&ctx.aggregated_pubkey_untweaked::<Point>().serialize_xonly())?)
+    }
+
+    // check https://bitcoin.stackexchange.com/questions/116384/what-are-the-steps-to-convert-a-private-key-to-a-taproot-address
+    pub fn get_agg_adr(&self, hrp: impl Into<KnownHrp>) -> Result<Address> {
+        Ok(Address::p2tr(&Secp256k1::new(), self.internal_key()?, self.merkle_root, hrp))
+    }
+
+    pub fn get_agg_script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2tr(&Secp256k1::new(), self.internal_key()?, self.merkle_root))
+    }
+}
+
+#[test]
+fn test_key_order() -> Result<()> {
+    let mut alice = AggKey::new()?;
+    let mut bob = AggKey::new()?;
+    // both sides must get the same key, no matter in which order they aggregate
+    assert_eq!(alice.aggregate_key(bob.pub_point)?, bob.aggregate_key(alice.pub_point)?);
+    assert_eq!(alice.get_agg_script_pubkey()?, bob.get_agg_script_pubkey()?);
+    assert_eq!(alice.get_agg_script_pubkey()?, agg_script_pubkey(alice.key_agg_context.as_ref().unwrap())?);
+
+    let agg_sec = alice.aggregate_other_sec(bob.sec)?;
+    assert_eq!(agg_sec, bob.aggregate_other_sec(alice.sec)?);
+    assert_eq!(agg_sec.base_point_mul(), alice.agg_point.unwrap());
+    // a wrong secret key share is refused
+    assert!(alice.aggregate_other_sec(AggKey::new()?.sec).is_err());
+    Ok(())
+}
@@ -0,0 +1,50 @@
+/*!
+The parts of the bisq MuSig trade protocol, which both the `protocol` crate and the `rpc` daemon need:
+key aggregation, (adaptive) MuSig2 signing and the construction of the prepared transactions.
+Both traders must arrive at the very same transactions and keys, so there must be only one implementation.
+*/
+pub mod key;
+pub mod musig;
+pub mod tx;
+
+use musig2::LiftedSignature;
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum Error {
+    #[error("missing aggregated key")]
+    MissingAggKey,
+    #[error("missing adaptor signature")]
+    MissingAdaptorSig,
+    #[error("missing nonce")]
+    MissingNonce,
+    #[error("invalid input index {0}")]
+    InvalidInputIndex(usize),
+    #[error("input is not a P2TR key path spend")]
+    NotKeySpend,
+    #[error("mismatched adaptor and final signature")]
+    MismatchedSigs,
+    #[error("prevout {0} not found")]
+    MissingPrevout(bitcoin::OutPoint),
+    #[error("fee of the {0} exceeds its funds")]
+    FeeExceedsFunds(&'static str),
+    #[error("fee overflows")]
+    FeeOverflow,
+    #[error("amount overflows")]
+    AmountOverflow,
+    #[error("the RedirectTx has no receivers")]
+    MissingReceivers,
+    KeyAgg(#[from] musig2::errors::KeyAggError),
+    Tweak(#[from] musig2::errors::TweakError),
+    Signing(#[from] musig2::errors::SigningError),
+    Verify(#[from] musig2::errors::VerifyError),
+    InvalidSecretKeys(#[from] musig2::errors::InvalidSecretKeysError),
+    InvalidScalar(#[from] musig2::secp::errors::InvalidScalarBytes),
+    DecodeSig(#[from] musig2::errors::DecodeError<LiftedSignature>),
+    Secp(#[from] bitcoin::secp256k1::Error),
+    TaprootSig(#[from] bitcoin::taproot::SigFromSliceError),
+    Sighash(#[from] bitcoin::sighash::TaprootError),
+}
@@ -0,0 +1,224 @@
+use bitcoin::hashes::sha256t::Hash;
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::taproot::Signature;
+use bitcoin::{TapSighashTag, TapSighashType, Transaction, TxIn, TxOut, Witness};
+use musig2::secp::{MaybePoint, MaybeScalar, Scalar};
+use musig2::{AdaptorSignature, AggNonce, LiftedSignature, PartialSignature, PubNonce, SecNonce, SecNonceBuilder};
+use rand::Rng;
+
+use crate::key::AggKey;
+use crate::{Error, Result};
+
+/**
+adaptive MuSig2, constructing a signature
+
+round n: new(agg_key) -> pub_nonce
+round n+1: generate partial adapted sig -> part-sig
+round n+2: aggregate sig (and publish)
+*/
+pub struct TMuSig2 {
+    pub agg_key: AggKey,
+    sec_nonce: SecNonce,
+    pub pub_nonce: PubNonce,
+    agg_nonce: Option<AggNonce>,
+    other_nonce: Option<PubNonce>,
+    pub adaptor_sig: Option<Adaptor>,
+}
+
+#[derive(Debug)]
+pub struct Adaptor {
+    pub partial_sig: PartialSignature,
+    pub input_index: usize, // which input in our transaction is going to use this signature?
+    pub pub_adaptor: MaybePoint, // this is the image for which the other party must provide the pre-image in order to use this sig.
+    pub msg: Hash<TapSighashTag>, // message to be signed
+    pub adaptor_signature: Option<AdaptorSignature>,
+}
+
+impl TMuSig2 {
+    pub fn new(agg_key: AggKey) -> TMuSig2 {
+        // there must be the aggregated key at this point
+        assert!(agg_key.agg_point.is_some());
+        let mut seed = [0u8; 32];
+        rand::rng().fill(&mut seed);
+        let mut seed2 = [0u8; 32];
+        rand::rng().fill(&mut seed2);
+        let sec_nonce = SecNonceBuilder::new(seed)
+            .with_aggregated_pubkey(agg_key.agg_point.unwrap())
+            .with_extra_input(&seed2) //TODO does this help? Or do we need more random?
+            // TODO check  BIP327 for nonce generation.
+            .build();
+        let pub_nonce = sec_nonce.public_nonce();
+        TMuSig2 { agg_key, sec_nonce, pub_nonce, agg_nonce: None, other_nonce: None, adaptor_sig: None }
+    }
+
+    pub fn generate_partial_sig(&mut self,
+                                input_index: usize, // which input in our transaction is going to use this signature?
+                                other_nonce: &PubNonce, // the public nonce from the other side to calc the aggregated nonce
+                                prevouts: &[TxOut], // the TxOuts from the previous transaction is part of the sig-alg in taproot
+                                tx: &Transaction) // the current transaction which needs the signature
+                                -> Result<PartialSignature> { // the partial transaction with adaptor to be sent to the other party.
+        // sign_partial()
+        self.generate_adapted_partial_sig(input_index, MaybePoint::Infinity, other_nonce, prevouts, tx)
+    }
+
+    pub fn generate_adapted_partial_sig(&mut self,
+                                        input_index: usize, // which input in our transaction is going to use this signature?
+                                        pub_adaptor: MaybePoint, // this is the image for which the other party must provide the pre-image in order to use this sig.
+                                        other_nonce: &PubNonce, // the public nonce from the other side to calc the aggregated nonce
+                                        prevouts: &[TxOut], // the TxOuts from the previous transaction is part of the sig-alg in taproot
+                                        tx: &Transaction) // the current transaction which needs the signature
+                                        -> Result<PartialSignature> { // the partial transaction with adaptor to be sent to the other party.
+        // calculate aggregated nonce first.
+        let total_nonce = [self.pub_nonce.clone(), other_nonce.clone()];
+        let agg_nonce = AggNonce::sum(total_nonce);
+        self.agg_nonce = Some(agg_nonce.clone());
+        self.other_nonce = Some(other_nonce.clone());
+
+        let msg = Self::extract_message_from_tx(input_index, prevouts, tx)?;
+
+        // see also trader:wallet::create_keyspend_payout_signature
+        // BIP-341: "the message commits to the scriptPubKeys of all outputs spent by the transaction."
+        let partial_signature = musig2::adaptor::sign_partial(
+            self.agg_key.key_agg_context.as_ref().ok_or(Error::MissingAggKey)?,
+            self.agg_key.sec,
+            self.sec_nonce.clone(),
+            &agg_nonce,
+            pub_adaptor,
+            msg)?;
+
+        self.adaptor_sig = Some(Adaptor {
+            partial_sig: partial_signature,
+            input_index,
+            pub_adaptor,
+            msg,
+            adaptor_signature: None,
+        });
+
+        // secure nonce is used, delete it to protect against reuse
+        self.sec_nonce = SecNonce::new(Scalar::one(), Scalar::one());
+
+        Ok(partial_signature)
+    }
+
+    pub fn get_part_sig(&self) -> PartialSignature {
+        self.adaptor_sig.as_ref().unwrap().partial_sig
+    }
+
+    /**
+    this is probably only called by Alice, the seller as the swapTx is only contructed by her.
+    the aggregated sig is still not valid, needs to be adapted.
+    */
+    pub fn aggregate_sigs(&mut self, other_sig: PartialSignature) -> Result<()> {
+        let my_adaptor = self.adaptor_sig.as_mut().ok_or(Error::MissingAdaptorSig)?;
+        let key_agg_ctx = self.agg_key.key_agg_context.as_ref().ok_or(Error::MissingAggKey)?;
+        let (Some(agg_point), Some(other_point)) = (self.agg_key.agg_point, self.agg_key.other_point) else {
+            return Err(Error::MissingAggKey);
+        };
+        let (Some(agg_nonce), Some(other_nonce)) = (self.agg_nonce.as_ref(), self.other_nonce.as_ref()) else {
+            return Err(Error::MissingNonce);
+        };
+        // verify other_sig is strictly not necessary but fail fast is always good
+        musig2::adaptor::verify_partial(
+            key_agg_ctx,
+            other_sig,
+            agg_nonce,
+            my_adaptor.pub_adaptor,
+            other_point,
+            other_nonce,
+            my_adaptor.msg,
+        )?;
+
+        let my_sig = my_adaptor.partial_sig;
+
+        let agg_signature = musig2::adaptor::aggregate_partial_signatures(
+            key_agg_ctx,
+            agg_nonce,
+            my_adaptor.pub_adaptor,
+            [my_sig, other_sig],
+            my_adaptor.msg,
+        )?;
+        my_adaptor.adaptor_signature = Some(agg_signature);
+
+        // Verify the adaptor signature is valid for the given adaptor point and pubkey.
+        musig2::adaptor::verify_single(
+            agg_point,
+            &agg_signature,
+            my_adaptor.msg,
+            my_adaptor.pub_adaptor,
+        )?;
+        Ok(())
+    }
+
+    pub fn sign(&mut self, sec_adaptor: MaybeScalar, tx: Transaction) -> Result<Transaction> {
+        let my_adaptor = self.adaptor_sig.as_mut().ok_or(Error::MissingAdaptorSig)?;
+        // Decrypt the signature with the adaptor secret.
+        let valid_signature: LiftedSignature = my_adaptor.adaptor_signature.ok_or(Error::MissingAdaptorSig)?
+            .adapt(sec_adaptor)
+            .ok_or(Error::MismatchedSigs)?;
+
+        // this check shall be authoritative
+        musig2::verify_single(
+            self.agg_key.agg_point.unwrap(),
+            valid_signature,
+            my_adaptor.msg,
+        )?;
+
+        // valid_signature must be made into Taprrot signature, means we need to tweak with merkle_root (even if we don't have a merkle root)
+        // stuff the valid signature into the transaction
+        let ts = Signature::from_slice(valid_signature.serialize().as_ref())?;
+
+        let mut sighasher = SighashCache::new(tx);
+        *sighasher.witness_mut(my_adaptor.input_index).ok_or(Error::InvalidInputIndex(my_adaptor.input_index))? =
+            Witness::p2tr_key_spend(&ts);
+        Ok(sighasher.into_transaction())
+    }
+
+    /**
+    Now let say Alice has posted the SwapTx, then Bob wants to reveal the secret for the public adaptor from the Transaction.
+    */
+    pub fn reveal(&self, final_sig: &Signature) -> Result<Scalar> {
+        let sig = self.adaptor_sig.as_ref().and_then(|a| a.adaptor_signature).ok_or(Error::MissingAdaptorSig)?;
+        let lifted_sig = &LiftedSignature::from_bytes(final_sig.serialize().as_ref())?;
+        let revealed: MaybeScalar = sig.reveal_secret(lifted_sig).ok_or(Error::MismatchedSigs)?;
+        revealed.not_zero().map_err(|_| Error::MismatchedSigs)
+    }
+
+    pub fn reveal2other(&self, final_sig: &Signature, tik: &mut AggKey) -> Result<()> {
+        let sec_adaptor = self.reveal(final_sig)?;
+        // calculate combined key as well, lib has checked that the aggregated generated key actually works
+        tik.aggregate_other_sec(sec_adaptor)?;
+        Ok(())
+    }
+
+    pub fn extract_p2tr_key_path_signature(tx: &Transaction, input_index: usize) -> Result<Signature> {
+        // Ensure the input index is valid
+        let input: &TxIn = tx.input.get(input_index).ok_or(Error::InvalidInputIndex(input_index))?;
+        let witness = &input.witness;
+
+        // For key path spending, the witness should contain exactly one element
+        if witness.len() != 1 {
+            return Err(Error::NotKeySpend);
+        }
+
+        // The first (and only) element in the witness should be the Schnorr signature
+        let raw_signature = &witness[0];
+
+        // Ensure the signature is 64 bytes long (we use only SigHash::Default)
+        if raw_signature.len() != 64 {
+            return Err(Error::NotKeySpend);
+        }
+
+        // Parse the Schnorr signature
+        Ok(Signature::from_slice(raw_signature)?)
+    }
+
+    pub fn extract_message_from_tx(input_index: usize, prevouts: &[TxOut], unsigned_tx: &Transaction) -> Result<Hash<TapSighashTag>> {
+        let sighash_type = TapSighashType::Default; // we are using in Musig only Default which is effectively equiv. to SIGHASH_ALL
+        let prevouts = Prevouts::All(prevouts);
+
+        let mut sighasher = SighashCache::new(unsigned_tx);
+        let sighash = sighasher
+            .taproot_key_spend_signature_hash(input_index, &prevouts, sighash_type)?;
+        Ok(sighash.to_raw_hash())
+    }
+}
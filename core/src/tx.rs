@@ -0,0 +1,204 @@
+/*!
+The unsigned transactions of the trade. Both traders build them independently and must get the same txid,
+so all amounts, fees and the order of inputs and outputs are defined here.
+*/
+use bitcoin::absolute::LockTime;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::opcodes::all::{OP_CHECKSIGVERIFY, OP_CSV};
+use bitcoin::script::Builder;
+use bitcoin::taproot::{LeafVersion, TapNodeHash};
+use bitcoin::transaction::Version;
+use bitcoin::{Amount, FeeRate, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+use musig2::secp::Point;
+use std::ops::RangeInclusive;
+
+use crate::{Error, Result};
+
+pub const ANCHOR_AMOUNT: Amount = Amount::from_sat(330); // 330 is std amount for anchors
+pub const CLAIM_DELAY: u16 = 2; // t2 in blocks, TODO find a good value
+pub const REDIRECT_DELAY: u16 = 1; // t1 in blocks, TODO find a good value
+// fee rates outside of these bounds are refused, no matter which trader picked them (1 to 500 sat/vB)
+pub const DEPOSIT_TX_FEE_RATE_BOUNDS: RangeInclusive<FeeRate> =
+    FeeRate::from_sat_per_kwu(250)..=FeeRate::from_sat_per_kwu(125_000);
+pub const PREPARED_TX_FEE_RATE_BOUNDS: RangeInclusive<FeeRate> =
+    FeeRate::from_sat_per_kwu(250)..=FeeRate::from_sat_per_kwu(125_000);
+
+/**
+the fee for a transaction with P2TR key spends only, the witnesses may still be empty.
+*/
+pub fn key_spend_fee(tx: &Transaction, fee_rate: FeeRate) -> Result<Amount> {
+    let mut tx = tx.clone();
+    for input in &mut tx.input {
+        input.witness = Witness::from_slice(&[[0u8; 64]]); // schnorr signature with default sighash
+    }
+    fee_rate.fee_wu(tx.weight()).ok_or(Error::FeeOverflow)
+}
+
+/**
+For Taproot signing, we need for all inputs of the spending transaction to look into the outpoint of TxIn and find
+the referenced transaction output (TxOut). All inputs must spend outputs of `tx`.
+*/
+pub fn prevouts(tx: &Transaction, inputs: &[TxIn]) -> Result<Vec<TxOut>> {
+    let txid = tx.compute_txid();
+    inputs.iter()
+        .map(|input| input.previous_output)
+        .map(|outpoint| match tx.output.get(outpoint.vout as usize) {
+            Some(output) if outpoint.txid == txid => Ok(output.clone()),
+            _ => Err(Error::MissingPrevout(outpoint)),
+        })
+        .collect()
+}
+
+fn input(previous_output: OutPoint, sequence: Sequence) -> TxIn {
+    TxIn {
+        previous_output,
+        script_sig: ScriptBuf::default(), // empty for p2tr
+        sequence,
+        witness: Witness::default(), // will be changed when signing.
+    }
+}
+
+fn deduct_fee(mut tx: Transaction, output_index: usize, fee_rate: FeeRate, name: &'static str) -> Result<Transaction> {
+    let fee = key_spend_fee(&tx, fee_rate)?;
+    let output = &mut tx.output[output_index];
+    output.value = output.value.checked_sub(fee).ok_or(Error::FeeExceedsFunds(name))?;
+    Ok(tx)
+}
+
+/**
+SwapTx, spends the seller's deposit output (the buyer's funds) of the DepositTx to the seller.
+No anchor output necessary, because the seller can spend the output for CPFP.
+*/
+pub fn swap_tx(deposit_out: OutPoint, deposit_amount: Amount, swap_spend: ScriptBuf, fee_rate: FeeRate) -> Result<Transaction> {
+    let tx = Transaction {
+        version: Version::TWO,       // Post BIP-68.
+        lock_time: LockTime::ZERO,   // Ignore the locktime.
+        input: vec![input(deposit_out, Sequence::MAX)],
+        output: vec![TxOut { value: deposit_amount, script_pubkey: swap_spend }],
+    };
+    deduct_fee(tx, 0, fee_rate, "SwapTx")
+}
+
+/**
+WarningTx, spends both outputs of the DepositTx (P' first, then Q') to the key spend of the warning party,
+plus an anchor output for fee bumping.
+*/
+pub fn warning_tx(deposit_outs: [OutPoint; 2], deposit_amount: Amount, key_spend: ScriptBuf, anchor_spend: ScriptBuf,
+                  fee_rate: FeeRate) -> Result<Transaction> {
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: deposit_outs.map(|out| input(out, Sequence::MAX)).into(),
+        output: vec![
+            TxOut { value: deposit_amount.checked_sub(ANCHOR_AMOUNT).ok_or(Error::FeeExceedsFunds("WarningTx"))?, script_pubkey: key_spend },
+            TxOut { value: ANCHOR_AMOUNT, script_pubkey: anchor_spend },
+        ],
+    };
+    deduct_fee(tx, 0, fee_rate, "WarningTx")
+}
+
+/**
+the only script path of a WarningTx output, with which the warning party alone can claim it after t2:
+`<claim key> OP_CHECKSIGVERIFY <t2> OP_CHECKSEQUENCEVERIFY`, which is the miniscript `and_v(v:pk(K),older(t2))`.
+*/
+pub fn claim_leaf(claim_key: Point) -> Result<ScriptBuf> {
+    let claim_key = XOnlyPublicKey::from_slice(&claim_key.serialize_xonly())?;
+    Ok(Builder::new()
+        .push_x_only_key(&claim_key)
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .push_int(CLAIM_DELAY.into())
+        .push_opcode(OP_CSV)
+        .into_script())
+}
+
+/**
+the merkle root to tweak the key of a WarningTx output with, the claim leaf being the only leaf of the tree.
+*/
+pub fn claim_leaf_merkle_root(claim_leaf: &Script) -> TapNodeHash {
+    TapNodeHash::from_script(claim_leaf, LeafVersion::TapScript)
+}
+
+/**
+ClaimTx, the warning party takes the output of its WarningTx after t2, if the other party doesn't react.
+*/
+pub fn claim_tx(warning_out: OutPoint, warning_amount: Amount, claim_spend: ScriptBuf, fee_rate: FeeRate) -> Result<Transaction> {
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![input(warning_out, Sequence::from_height(CLAIM_DELAY))],
+        output: vec![TxOut { value: warning_amount, script_pubkey: claim_spend }],
+    };
+    deduct_fee(tx, 0, fee_rate, "ClaimTx")
+}
+
+/**
+RedirectTx, the other party sends the output of the WarningTx to the receivers after t1. The amount left after
+anchor and fee is split in proportion to the amounts of the receivers, which need not add up to it, so that both
+traders get the same outputs without floating point. The anchor output is last.
+*/
+pub fn redirect_tx(warning_out: OutPoint, warning_amount: Amount, receivers: &[(ScriptBuf, Amount)], anchor_spend: ScriptBuf,
+                   fee_rate: FeeRate) -> Result<Transaction> {
+    let total = receivers.iter()
+        .try_fold(Amount::ZERO, |sum, (_, share)| sum.checked_add(*share))
+        .ok_or(Error::AmountOverflow)?;
+    if total == Amount::ZERO {
+        return Err(Error::MissingReceivers);
+    }
+    // share <= total, so the result fits into the amount
+    let split = |amount: Amount, share: Amount| Amount::from_sat(
+        (u128::from(amount.to_sat()) * u128::from(share.to_sat()) / u128::from(total.to_sat())) as u64);
+    let amount = warning_amount.checked_sub(ANCHOR_AMOUNT).ok_or(Error::FeeExceedsFunds("RedirectTx"))?;
+    let mut output: Vec<TxOut> = receivers.iter()
+        .map(|(script_pubkey, share)| TxOut { value: split(amount, *share), script_pubkey: script_pubkey.clone() })
+        .collect();
+    output.push(TxOut { value: ANCHOR_AMOUNT, script_pubkey: anchor_spend });
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![input(warning_out, Sequence::from_height(REDIRECT_DELAY))],
+        output,
+    };
+    // the fee is shared by all receivers
+    let amount = amount.checked_sub(key_spend_fee(&tx, fee_rate)?).ok_or(Error::FeeExceedsFunds("RedirectTx"))?;
+    for (output, (_, share)) in tx.output.iter_mut().zip(receivers) {
+        output.value = split(amount, *share);
+    }
+    Ok(tx)
+}
+
+#[test]
+fn test_prepared_txs() -> Result<()> {
+    let fee_rate = FeeRate::from_sat_per_kwu(2500); // 10 sat/vB
+    let script = ScriptBuf::new_p2tr_tweaked(bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(
+        "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0".parse().unwrap()));
+    let deposit = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![],
+        output: vec![TxOut { value: Amount::from_sat(100_000), script_pubkey: script.clone() }; 2],
+    };
+    let deposit_outs = [0, 1].map(|vout| OutPoint::new(deposit.compute_txid(), vout));
+
+    let swap = swap_tx(deposit_outs[1], Amount::from_sat(100_000), script.clone(), fee_rate)?;
+    assert_eq!(swap.output[0].value, Amount::from_sat(100_000 - 1110)); // 111 vB
+    assert_eq!(prevouts(&deposit, &swap.input)?, vec![deposit.output[1].clone()]);
+    assert!(prevouts(&swap, &swap.input).is_err());
+
+    let warning = warning_tx(deposit_outs, Amount::from_sat(200_000), script.clone(), script.clone(), fee_rate)?;
+    assert_eq!(warning.output[1].value, ANCHOR_AMOUNT);
+    let warning_out = OutPoint::new(warning.compute_txid(), 0);
+    let receivers = [(script.clone(), Amount::from_sat(3)), (script.clone(), Amount::from_sat(1))];
+    let redirect = redirect_tx(warning_out, warning.output[0].value, &receivers, script.clone(), fee_rate)?;
+    assert_eq!(redirect.output.len(), 3);
+    assert_eq!(redirect.output[0].value / 3, redirect.output[1].value); // rounded down
+    assert!(redirect_tx(warning_out, warning.output[0].value, &[], script.clone(), fee_rate).is_err());
+    assert_eq!(redirect.input[0].sequence, Sequence::from_height(REDIRECT_DELAY));
+
+    assert!(claim_tx(warning_out, Amount::from_sat(1000), script, fee_rate).is_err());
+
+    let claim_key: Point = "0250929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0".parse().unwrap();
+    let leaf = claim_leaf(claim_key)?;
+    assert_eq!(leaf.to_asm_string(), format!("OP_PUSHBYTES_32 {} OP_CHECKSIGVERIFY OP_PUSHNUM_{CLAIM_DELAY} OP_CSV",
+        "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0"));
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use bdk_wallet::bitcoin::FeeRate;
+use musig_core::tx::{DEPOSIT_TX_FEE_RATE_BOUNDS, PREPARED_TX_FEE_RATE_BOUNDS};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/**
+A range of fee rates, both ends included.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FeeRange {
+    pub min: FeeRate,
+    pub max: FeeRate,
+}
+
+impl FeeRange {
+    pub fn new(min: FeeRate, max: FeeRate) -> anyhow::Result<FeeRange> {
+        if min > max {
+            anyhow::bail!("Fee range is empty, {} is above {}.", min.to_sat_per_vb_ceil(), max.to_sat_per_vb_ceil())
+        }
+        Ok(FeeRange { min, max })
+    }
+
+    pub fn contains(&self, fee_rate: FeeRate) -> bool {
+        self.min <= fee_rate && fee_rate <= self.max
+    }
+
+    pub fn intersect(&self, other: &FeeRange) -> Option<FeeRange> {
+        FeeRange::new(self.min.max(other.min), self.max.min(other.max)).ok()
+    }
+
+    pub fn clamp(&self, fee_rate: FeeRate) -> FeeRate {
+        fee_rate.clamp(self.min, self.max)
+    }
+}
+
+impl From<RangeInclusive<FeeRate>> for FeeRange {
+    fn from(range: RangeInclusive<FeeRate>) -> Self {
+        FeeRange { min: *range.start(), max: *range.end() }
+    }
+}
+
+impl fmt::Display for FeeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..={} sat/vB", self.min.to_sat_per_vb_floor(), self.max.to_sat_per_vb_ceil())
+    }
+}
+
+/**
+Where our fee rates come from, e.g. a price node or the mempool of our own node.
+*/
+pub trait FeeEstimator {
+    // the DepositTx is broadcast right after the setup and should confirm soon
+    fn deposit_fee_rate(&self) -> anyhow::Result<FeeRate>;
+    // the presigned transactions are broadcast much later, if ever. They can be bumped through their anchors.
+    fn prepared_fee_rate(&self) -> anyhow::Result<FeeRate>;
+}
+
+pub struct FixedFeeEstimator {
+    pub deposit: FeeRate,
+    pub prepared: FeeRate,
+}
+
+impl FeeEstimator for FixedFeeEstimator {
+    fn deposit_fee_rate(&self) -> anyhow::Result<FeeRate> {
+        Ok(self.deposit)
+    }
+
+    fn prepared_fee_rate(&self) -> anyhow::Result<FeeRate> {
+        Ok(self.prepared)
+    }
+}
+
+/**
+The fee rates both traders have agreed on during the setup.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FeeRates {
+    pub deposit: FeeRate,
+    pub prepared: FeeRate,
+}
+
+/**
+The fee settings of a trader. The taker proposes its bounds as acceptable ranges (message A), the maker picks
+the rates from its estimator within both the proposed ranges and its own bounds (message B), and the taker
+checks that the picked rates are within its bounds. Either side refuses the trade otherwise.
+*/
+pub struct FeePolicy {
+    pub deposit_bounds: FeeRange,
+    pub prepared_bounds: FeeRange,
+    pub estimator: Arc<dyn FeeEstimator + Send + Sync>,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        FeePolicy {
+            deposit_bounds: DEPOSIT_TX_FEE_RATE_BOUNDS.into(),
+            prepared_bounds: PREPARED_TX_FEE_RATE_BOUNDS.into(),
+            estimator: Arc::new(FixedFeeEstimator {
+                // Only placeholders: a trader plugs in a real estimator, like the bitcoind or pricenode
+                // sources of the daemon (see rpc/src/fees.rs).
+                deposit: FeeRate::from_sat_per_vb_unchecked(20),
+                prepared: FeeRate::from_sat_per_vb_unchecked(10),
+            }),
+        }
+    }
+}
+
+impl FeePolicy {
+    /**
+    our rates without any negotiation. In the five-round flow the seller proposes them, and the buyer checks them.
+    */
+    pub fn estimate(&self) -> anyhow::Result<FeeRates> {
+        Ok(FeeRates {
+            deposit: self.deposit_bounds.clamp(self.estimator.deposit_fee_rate()?),
+            prepared: self.prepared_bounds.clamp(self.estimator.prepared_fee_rate()?),
+        })
+    }
+
+    /**
+    Maker: picks the rates of the trade within the ranges proposed by the taker.
+    */
+    pub fn pick(&self, deposit_range: FeeRange, prepared_range: FeeRange) -> anyhow::Result<FeeRates> {
+        let pick = |name, estimate: FeeRate, proposed: FeeRange, bounds: FeeRange| {
+            let Some(range) = proposed.intersect(&bounds) else {
+                anyhow::bail!("{} fee range {} of the taker is outside of our bounds {}.", name, proposed, bounds)
+            };
+            Ok(range.clamp(estimate))
+        };
+        Ok(FeeRates {
+            deposit: pick("DepositTx", self.estimator.deposit_fee_rate()?, deposit_range, self.deposit_bounds)?,
+            prepared: pick("Prepared tx", self.estimator.prepared_fee_rate()?, prepared_range, self.prepared_bounds)?,
+        })
+    }
+
+    /**
+    Taker: checks the rates the maker has picked, or the buyer those of the seller in the five-round flow.
+    */
+    pub fn check(&self, rates: FeeRates) -> anyhow::Result<()> {
+        for (name, fee_rate, bounds) in [("DepositTx", rates.deposit, self.deposit_bounds), ("Prepared tx", rates.prepared, self.prepared_bounds)] {
+            if !bounds.contains(fee_rate) {
+                anyhow::bail!("{} fee rate {} sat/vB of the peer is outside of our bounds {}.", name, fee_rate.to_sat_per_vb_ceil(), bounds)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fee_negotiation() -> anyhow::Result<()> {
+    let sat_vb = FeeRate::from_sat_per_vb_unchecked;
+    let maker = FeePolicy {
+        deposit_bounds: FeeRange::new(sat_vb(5), sat_vb(100))?,
+        estimator: Arc::new(FixedFeeEstimator { deposit: sat_vb(30), prepared: sat_vb(2) }),
+        ..FeePolicy::default()
+    };
+    let taker = FeePolicy {
+        deposit_bounds: FeeRange::new(sat_vb(1), sat_vb(25))?,
+        prepared_bounds: FeeRange::new(sat_vb(4), sat_vb(50))?,
+        ..FeePolicy::default()
+    };
+    // the maker's estimates are clamped into the ranges of the taker
+    let rates = maker.pick(taker.deposit_bounds, taker.prepared_bounds)?;
+    assert_eq!(rates, FeeRates { deposit: sat_vb(25), prepared: sat_vb(4) });
+    taker.check(rates)?;
+
+    assert!(taker.check(FeeRates { deposit: sat_vb(26), prepared: sat_vb(4) }).is_err());
+    assert!(maker.pick(FeeRange::new(sat_vb(101), sat_vb(200))?, taker.prepared_bounds).is_err());
+    assert!(FeeRange::new(sat_vb(2), sat_vb(1)).is_err());
+    Ok(())
+}
@@ -7,13 +7,31 @@ use bdk_wallet::bitcoin::key::TapTweak;
 use musig2::secp::{Point, Scalar};
 use musig2::KeyAggContext;
 mod protocol_musig_adaptor;
+mod fees;
 mod nigiri;
+mod shared_wallet;
+mod silent_payment;
+mod trade_state;
 
 #[cfg(test)]
 mod tests {
+    use crate::fees::{FeePolicy, FeeRates, FixedFeeEstimator};
     use crate::nigiri;
-    use crate::protocol_musig_adaptor::{BMPContext, BMPProtocol, ProtocolRole};
-    use bdk_electrum::bdk_core::bitcoin::Amount;
+    use crate::protocol_musig_adaptor::{BMPContext, BMPProtocol, MediationProposal, MediationState, OfferRole, ProtocolRole, WarningOutputMode};
+    use bdk_wallet::bitcoin::taproot::{LeafVersion, TapNodeHash};
+    use bdk_wallet::bitcoin::ScriptBuf;
+    use bdk_wallet::KeychainKind;
+    use crate::shared_wallet::SharedWallet;
+    use crate::silent_payment::RedirectPaymentProof;
+    use crate::trade_state::{TradeEvent, TradeState};
+    use bdk_electrum::bdk_core::bitcoin::{Amount, FeeRate, Network};
+    use musig_core::key::AggKey;
+    use musig_core::tx::{self, CLAIM_DELAY};
+    use bdk_wallet::bitcoin::key::XOnlyPublicKey;
+    use bdk_wallet::miniscript::{Miniscript, Tap};
+    use bdk_wallet::SignOptions;
+    use std::str::FromStr;
+    use std::sync::Arc;
 
     #[test]
     fn test_musig() -> anyhow::Result<()> {
@@ -21,6 +39,9 @@ mod tests {
         Ok(())
     }
     pub fn initial_tx_creation() -> anyhow::Result<(BMPProtocol, BMPProtocol)> {
+        initial_tx_creation_with(WarningOutputMode::KeySpend)
+    }
+    pub fn initial_tx_creation_with(warning_mode: WarningOutputMode) -> anyhow::Result<(BMPProtocol, BMPProtocol)> {
         println!("running...");
         nigiri::check_start();
         let mut alice_funds = nigiri::funded_wallet();
@@ -33,10 +54,12 @@ mod tests {
         let buyer_amount = &Amount::from_btc(0.2)?;
 
         // init p3 --------------------------
-        let alice_context = BMPContext::new(alice_funds, ProtocolRole::Seller, seller_amount.clone(), buyer_amount.clone())?;
+        let alice_context = BMPContext::new(alice_funds.into(), ProtocolRole::Seller, seller_amount.clone(), buyer_amount.clone())?
+            .with_warning_mode(warning_mode);
 
         let mut alice = BMPProtocol::new(alice_context)?;
-        let bob_context = BMPContext::new(bob_funds, ProtocolRole::Buyer, seller_amount.clone(), buyer_amount.clone())?;
+        let bob_context = BMPContext::new(bob_funds.into(), ProtocolRole::Buyer, seller_amount.clone(), buyer_amount.clone())?
+            .with_warning_mode(warning_mode);
         let mut bob = BMPProtocol::new(bob_context)?;
         nigiri::tiktok();
 
@@ -48,8 +71,8 @@ mod tests {
         let alice_r2 = alice.round2(bob_response)?;
         let bob_r2 = bob.round2(alice_response)?;
 
-        println!("P2TR P' {}", alice.p_tik.get_agg_adr()?.to_string());
-        println!("P2TR Q' {}", alice.q_tik.get_agg_adr()?.to_string());
+        println!("P2TR P' {}", alice.p_tik.get_agg_adr(Network::Regtest)?.to_string());
+        println!("P2TR Q' {}", alice.q_tik.get_agg_adr(Network::Regtest)?.to_string());
 
         assert!(alice.get_p_tik_agg() == bob.get_p_tik_agg());
         assert!(alice.q_tik.agg_point == bob.q_tik.agg_point);
@@ -71,12 +94,218 @@ mod tests {
         // Round 5 --------------------------
         alice.round5(bob_r4)?;
         bob.round5(alice_r4)?;
+        assert_eq!(alice.state(), TradeState::Open);
 
         // done -----------------------------
         crate::nigiri::tiktok();
         Ok((alice, bob))
     }
 
+    #[test]
+    fn test_external_deposit_signer() -> anyhow::Result<()> {
+        nigiri::check_start();
+        let alice_funds = nigiri::funded_wallet();
+        let bob_funds = nigiri::funded_wallet();
+        let seller_amount = Amount::from_btc(1.4)?;
+        let buyer_amount = Amount::from_btc(0.2)?;
+
+        let mut alice = BMPProtocol::new(BMPContext::new(alice_funds.into(), ProtocolRole::Seller, seller_amount, buyer_amount)?)?;
+        let mut bob = BMPProtocol::new(BMPContext::new(bob_funds.into(), ProtocolRole::Buyer, seller_amount, buyer_amount)?)?;
+
+        let alice_response = alice.round1()?;
+        let bob_response = bob.round1()?;
+
+        // Alice's deposit funds are in a cold wallet, she gets the PSBT for signing
+        let unsigned_psbt = alice.round2_external(bob_response)?;
+        let bob_r2 = bob.round2(alice_response)?;
+
+        // a signer which changes anything else but our signatures is rejected
+        let mut tampered = unsigned_psbt.clone();
+        tampered.unsigned_tx.output[0].value -= Amount::from_sat(1000);
+        assert!(alice.round2_external_signed(tampered).is_err());
+        // as well as one, which did not sign at all
+        assert!(alice.round2_external_signed(unsigned_psbt.clone()).is_err());
+
+        // here the cold wallet would sign, simulate this with the wallet
+        let mut signed_psbt = unsigned_psbt;
+        alice.ctx.funds.lock().wallet.sign(&mut signed_psbt, SignOptions::default())?;
+        let alice_r2 = alice.round2_external_signed(signed_psbt)?;
+
+        let alice_r3 = alice.round3(bob_r2)?;
+        let bob_r3 = bob.round3(alice_r2)?;
+        assert_eq!(alice_r3.deposit_txid, bob_r3.deposit_txid);
+        nigiri::tiktok();
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_wallet_reservation() -> anyhow::Result<()> {
+        nigiri::check_start();
+        // a market maker runs two trades concurrently from the same wallet
+        let mut maker_funds = nigiri::funded_wallet();
+        nigiri::fund_wallet(&mut maker_funds);
+        let maker_funds: SharedWallet = maker_funds.into();
+        let seller_amount = Amount::from_btc(0.4)?;
+        let buyer_amount = Amount::from_btc(0.1)?;
+
+        let mut maker1 = BMPProtocol::new(BMPContext::new(maker_funds.clone(), ProtocolRole::Seller, seller_amount, buyer_amount)?)?;
+        let mut maker2 = BMPProtocol::new(BMPContext::new(maker_funds.clone(), ProtocolRole::Seller, seller_amount, buyer_amount)?)?;
+        let mut taker1 = BMPProtocol::new(BMPContext::new(nigiri::funded_wallet().into(), ProtocolRole::Buyer, seller_amount, buyer_amount)?)?;
+        let mut taker2 = BMPProtocol::new(BMPContext::new(nigiri::funded_wallet().into(), ProtocolRole::Buyer, seller_amount, buyer_amount)?)?;
+
+        let maker1_r1 = maker1.round1()?;
+        let maker2_r1 = maker2.round1()?;
+        let taker1_r1 = taker1.round1()?;
+        let taker2_r1 = taker2.round1()?;
+
+        let leased1 = maker_funds.leased(maker1.ctx.lease);
+        let leased2 = maker_funds.leased(maker2.ctx.lease);
+        assert!(!leased1.is_empty() && !leased2.is_empty());
+        assert!(leased1.iter().all(|op| !leased2.contains(op)), "both trades selected the same UTXO");
+
+        let maker1_r2 = maker1.round2(taker1_r1)?;
+        let maker2_r2 = maker2.round2(taker2_r1)?;
+        let taker1_r2 = taker1.round2(maker1_r1)?;
+        let taker2_r2 = taker2.round2(maker2_r1)?;
+
+        // both DepositTx can be published side by side
+        maker1.round3(taker1_r2)?;
+        maker2.round3(taker2_r2)?;
+        taker1.round3(maker1_r2)?;
+        taker2.round3(maker2_r2)?;
+        nigiri::tiktok();
+
+        // once confirmed, the reservations are not needed anymore
+        maker_funds.sync()?;
+        assert!(maker_funds.reserved().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_abort() -> anyhow::Result<()> {
+        nigiri::check_start();
+        let alice_funds: SharedWallet = nigiri::funded_wallet().into();
+        let bob_funds = nigiri::funded_wallet();
+        let seller_amount = Amount::from_btc(0.4)?;
+        let buyer_amount = Amount::from_btc(0.1)?;
+
+        let mut alice = BMPProtocol::new(BMPContext::new(alice_funds.clone(), ProtocolRole::Seller, seller_amount, buyer_amount)?)?;
+        let mut bob = BMPProtocol::new(BMPContext::new(bob_funds.into(), ProtocolRole::Buyer, seller_amount, buyer_amount)?)?;
+
+        let alice_response = alice.round1()?;
+        let bob_response = bob.round1()?;
+        let alice_r2 = alice.round2(bob_response)?;
+        let _bob_r2 = bob.round2(alice_response)?;
+
+        // Bob has Alice's signed inputs now, so Alice spends them elsewhere with a higher fee rate.
+        let abort = alice.abort(Some(FeeRate::from_sat_per_vb(50).unwrap()))?;
+        assert!(abort.double_spend_txid.is_some());
+        assert!(alice_funds.reserved().is_empty());
+        assert!(alice.abort(None).is_err());
+        assert_eq!(alice.state(), TradeState::Aborted);
+
+        // Bob can't complete the DepositTx anymore
+        assert!(bob.round3(alice_r2).is_err());
+        // the failed broadcast did not move Bob on, so he can still abort
+        assert!(bob.abort(None).is_ok());
+        nigiri::tiktok();
+
+        // after round 1 nothing signed has been shared, so both sides just release their UTXOs
+        let mut alice = BMPProtocol::new(BMPContext::new(alice_funds.clone(), ProtocolRole::Seller, seller_amount, buyer_amount)?)?;
+        let mut bob = BMPProtocol::new(BMPContext::new(nigiri::funded_wallet().into(), ProtocolRole::Buyer, seller_amount, buyer_amount)?)?;
+        alice.round1()?;
+        bob.round1()?;
+        let abort = bob.abort(None)?;
+        assert!(abort.double_spend_txid.is_none());
+        assert!(alice.peer_aborted(abort, None)?.is_none());
+        assert_eq!(alice.state(), TradeState::Aborted);
+        assert!(alice_funds.reserved().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mediation() -> anyhow::Result<()> {
+        // create all transaction and Broadcast DepositTx already
+        let (mut alice, mut bob) = initial_tx_creation()?;
+        let deposit_total = Amount::from_btc(1.4)? + Amount::from_btc(0.2)?; // seller and buyer output
+        let fee = Amount::from_sat(2000);
+
+        // a split which doesn't add up is refused
+        let wrong = MediationProposal { seller_payout: Amount::from_btc(1.0)?, buyer_payout: Amount::from_btc(1.0)?, fee };
+        assert!(alice.mediation_proposed(wrong).is_err());
+        // as is a mining fee above the ceiling, which would go to the miners instead of the traders
+        let greedy = Amount::from_btc(0.1)?;
+        let wrong = MediationProposal { seller_payout: Amount::from_btc(1.0)?, buyer_payout: deposit_total - Amount::from_btc(1.0)? - greedy, fee: greedy };
+        assert!(alice.mediation_proposed(wrong).is_err());
+
+        // first proposal is rejected by Bob
+        let seller_payout = Amount::from_btc(1.0)?;
+        let proposal = MediationProposal { seller_payout, buyer_payout: deposit_total - seller_payout - fee, fee };
+        alice.mediation_proposed(proposal.clone())?;
+        bob.mediation_proposed(proposal)?;
+        alice.mediation_accept()?;
+        bob.mediation_reject()?;
+        alice.mediation_reject()?; // Bob told Alice
+        assert_eq!(alice.mediated_payout.state, Some(MediationState::Rejected));
+
+        // second proposal is accepted by both
+        let seller_payout = Amount::from_btc(1.3)?;
+        let proposal = MediationProposal { seller_payout, buyer_payout: deposit_total - seller_payout - fee, fee };
+        alice.mediation_proposed(proposal.clone())?;
+        bob.mediation_proposed(proposal)?;
+        let alice_nonces = alice.mediation_accept()?;
+        let bob_nonces = bob.mediation_accept()?;
+        let alice_sigs = alice.mediation_sign(bob_nonces)?;
+        let bob_sigs = bob.mediation_sign(alice_nonces)?;
+        let alice_tx = alice.mediation_finalize(bob_sigs)?;
+        let bob_tx = bob.mediation_finalize(alice_sigs)?;
+        assert_eq!(alice_tx.compute_txid(), bob_tx.compute_txid());
+
+        assert_eq!(alice.state(), TradeState::Open, "signing alone doesn't end the trade");
+        assert_eq!(bob.mediation_broadcast()?, bob_tx.compute_txid());
+        assert_eq!(bob.state(), TradeState::MediatedPayout);
+        alice.transition(TradeEvent::MediatedPayoutPublished)?;
+        nigiri::tiktok();
+        Ok(())
+    }
+
+    #[test]
+    fn test_maker_taker() -> anyhow::Result<()> {
+        nigiri::check_start();
+        let seller_amount = Amount::from_btc(0.4)?;
+        let buyer_amount = Amount::from_btc(0.1)?;
+        let sat_vb = FeeRate::from_sat_per_vb_unchecked;
+        let maker_fees = FeePolicy { estimator: Arc::new(FixedFeeEstimator { deposit: sat_vb(30), prepared: sat_vb(3) }), ..FeePolicy::default() };
+        let mut maker = BMPProtocol::new(BMPContext::new(nigiri::funded_wallet().into(), ProtocolRole::Buyer, seller_amount, buyer_amount)?
+            .with_offer_role(OfferRole::Maker)
+            .with_fee_policy(maker_fees))?;
+        let mut taker = BMPProtocol::new(BMPContext::new(nigiri::funded_wallet().into(), ProtocolRole::Seller, seller_amount, buyer_amount)?
+            .with_offer_role(OfferRole::Taker))?;
+
+        let a = taker.message_a()?;
+        let b = maker.message_b(a)?;
+        let c = taker.message_c(b)?;
+        assert!(c.swap_part_sig.is_some()); // the taker is the seller
+        assert_eq!(taker.ctx.fee_rates, Some(FeeRates { deposit: sat_vb(30), prepared: sat_vb(3) })); // the maker's estimates
+        let d = maker.message_d(c)?;
+        assert!(d.swap_part_sig.is_none());
+        taker.publish_deposit(d)?;
+        let deposit_tx = taker.deposit_tx.tx.clone().unwrap();
+        maker.deposit_published(&deposit_tx)?;
+        assert_eq!(maker.state(), TradeState::Open);
+        assert!(taker.claim_tx_me.tx.is_none()); // claimed late through the CSV leaf, nothing is presigned
+        nigiri::tiktok();
+
+        // the buyer has paid, the seller can take the SwapTx
+        let e = maker.message_e()?;
+        let swap_tx = taker.sign_swap_tx(e)?;
+        taker.swap_tx.broadcast(taker.ctx);
+        maker.swap_tx.reveal(&swap_tx, &mut maker.p_tik)?;
+        assert!(maker.p_tik.agg_sec.is_some());
+        nigiri::tiktok();
+        Ok(())
+    }
+
     #[test]
     fn test_swap() -> anyhow::Result<()> {
         // create all transaction and Broadcast DepositTx already
@@ -127,6 +356,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_claim_late() -> anyhow::Result<()> {
+        // WarningTx output has a CSV claim leaf for Alice
+        let (alice, _bob) = initial_tx_creation_with(WarningOutputMode::CsvClaimLeaf)?;
+        alice.warning_tx_me.broadcast(&alice.ctx);
+        nigiri::tiktok();
+
+        // Alice decides only now where the funds go to
+        let claim_spend = alice.ctx.funds.lock().wallet.reveal_next_address(KeychainKind::External).script_pubkey();
+        let claim_tx = alice.claim_late(claim_spend, FeeRate::from_sat_per_vb(5).unwrap())?;
+        assert!(alice.ctx.funds.lock().transaction_broadcast(&claim_tx).is_err(), "t2 has not passed yet");
+        nigiri::tiktok();
+        assert_eq!(alice.ctx.funds.lock().transaction_broadcast(&claim_tx)?, claim_tx.compute_txid());
+        nigiri::tiktok();
+        Ok(())
+    }
+
+    #[test]
+    fn test_claim_leaf_tweak() -> anyhow::Result<()> {
+        let mut alice = AggKey::new()?;
+        let bob = AggKey::new()?;
+        alice.aggregate_key(bob.pub_point)?;
+        let root = TapNodeHash::from_script(&ScriptBuf::new_op_return([1u8; 4]), LeafVersion::TapScript);
+        let tweaked = alice.with_merkle_root(root)?;
+        // the MuSig2 key must be the output key of the address, otherwise the key spend signatures are invalid.
+        let script = tweaked.get_agg_script_pubkey()?;
+        assert_eq!(script.as_bytes()[2..], tweaked.agg_point.unwrap().serialize_xonly());
+        assert_ne!(script, alice.get_agg_script_pubkey()?);
+
+        // musig-core builds the claim leaf by hand, it must be the miniscript which the ClaimTx satisfies
+        let claim_key = XOnlyPublicKey::from_slice(&bob.pub_point.serialize_xonly())?;
+        let miniscript = Miniscript::<XOnlyPublicKey, Tap>::from_str(&format!("and_v(v:pk({}),older({}))", claim_key, CLAIM_DELAY))?;
+        assert_eq!(tx::claim_leaf(bob.pub_point)?, miniscript.encode());
+        Ok(())
+    }
+
     #[test]
     fn test_claim_too_early() -> anyhow::Result<()> {
         // create all transaction and Broadcast DepositTx already
@@ -161,6 +426,11 @@ mod tests {
         nigiri::tiktok();
         dbg!(bob_warn_id);
 
+        // the silent payment receiver of the DAO gets a proof of payment from Alice
+        let proof = alice.redirect_tx_me.payment_proof(&alice.p_tik, &alice.warning_tx_peer, 2)?;
+        let proof = RedirectPaymentProof::from_bytes(&proof.to_bytes())?;
+        proof.verify(alice.redirect_tx_me.tx.as_ref().unwrap(), &bob.warning_tx_me.funds_as_output())?;
+
         let tx = alice.redirect_tx_me.broadcast(alice.ctx);
         dbg!(tx);
         nigiri::tiktok();
@@ -248,7 +518,7 @@ fn test_q_tik() -> anyhow::Result<()> {
     // Get the signed transaction.
     let tx = sighasher.into_transaction();
 
-    let txid = alice.ctx.funds.client.transaction_broadcast(&tx)?;
+    let txid = alice.ctx.funds.lock().client.transaction_broadcast(&tx)?;
     dbg!(txid);
     nigiri::tiktok();
     Ok(())
@@ -1,24 +1,33 @@
 use bdk_electrum::{electrum_client, BdkElectrumClient};
 use bdk_wallet::bitcoin::absolute::LockTime;
 use bdk_wallet::bitcoin::bip32::Xpriv;
-use bdk_wallet::bitcoin::hashes::sha256t::Hash;
-use bdk_wallet::bitcoin::key::Secp256k1;
+use bdk_wallet::bitcoin::key::{Keypair, Secp256k1, XOnlyPublicKey};
+use bdk_wallet::bitcoin::secp256k1::Message;
 use bdk_wallet::bitcoin::sighash::{Prevouts, SighashCache};
-use bdk_wallet::bitcoin::taproot::Signature;
+use bdk_wallet::bitcoin::taproot::{LeafVersion, Signature, TapLeafHash, TaprootBuilder};
 use bdk_wallet::bitcoin::transaction::Version;
-use bdk_wallet::bitcoin::{absolute, transaction, Address, Amount, FeeRate, KnownHrp, Network, OutPoint, Psbt, PublicKey, ScriptBuf, Sequence, TapSighashTag, TapSighashType, Transaction, TxIn, TxOut, Txid, Weight, Witness};
+use bdk_wallet::bitcoin::{absolute, transaction, Address, Amount, FeeRate, KnownHrp, Network, OutPoint, Psbt, PublicKey, ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut, Txid, Weight, Witness};
 use bdk_wallet::coin_selection::BranchAndBoundCoinSelection;
 use bdk_wallet::miniscript::ToPublicKey;
 use bdk_wallet::template::{Bip86, DescriptorTemplate};
-use bdk_wallet::{bitcoin, AddressInfo, KeychainKind, SignOptions, TxBuilder, Wallet};
+use bdk_wallet::{AddressInfo, KeychainKind, SignOptions, TxBuilder, Wallet};
 use musig2::secp::MaybePoint::Valid;
-use musig2::secp::{MaybePoint, MaybeScalar, Point, Scalar};
+use musig2::secp::{MaybeScalar, Point, Scalar};
 // use musig2::secp256k1::Scalar;
-use musig2::{AdaptorSignature, AggNonce, KeyAggContext, LiftedSignature, PartialSignature, PubNonce, SecNonce, SecNonceBuilder};
+use musig2::{PartialSignature, PubNonce};
 use rand::{Rng, RngCore};
 use std::io::Write;
-use std::ops::{Add, Sub};
+use std::ops::Add;
 use std::str::FromStr;
+use crate::fees::{FeePolicy, FeeRange, FeeRates};
+use musig_core::key::AggKey;
+use musig_core::musig::TMuSig2;
+use musig_core::tx;
+use musig_core::tx::{ANCHOR_AMOUNT, CLAIM_DELAY};
+use crate::shared_wallet::{LeaseId, SharedWallet};
+use crate::silent_payment;
+use crate::silent_payment::{EcdhShare, RedirectPaymentProof, SilentPaymentAddress};
+use crate::trade_state::{SetupMessage, TradeAction, TradeEvent, TradeState};
 
 pub struct MemWallet {
     pub wallet: Wallet,
@@ -46,6 +55,15 @@ pub enum ProtocolRole {
     Buyer,
 }
 
+/**
+Who made the offer. The maker sends its DepositTx inputs first (message B), the taker publishes the DepositTx.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OfferRole {
+    Maker,
+    Taker,
+}
+
 impl ProtocolRole {
     fn other(&self) -> ProtocolRole {
         match self {
@@ -120,6 +138,30 @@ impl MemWallet {
         self.wallet.next_unused_address(KeychainKind::External)
     }
 
+    /**
+    spends the given UTXOs back to a fresh address of ours, so they cannot be used anymore
+    in a transaction we signed before.
+    */
+    pub(crate) fn spend_to_self(&mut self, outpoints: &[OutPoint], fee_rate: FeeRate) -> anyhow::Result<Txid> {
+        let drain_script = self.wallet.reveal_next_address(KeychainKind::Internal).script_pubkey();
+        let mut tx_builder = self.wallet.build_tx();
+        tx_builder.add_utxos(outpoints)?
+            .manually_selected_only()
+            .drain_to(drain_script)
+            .fee_rate(fee_rate);
+
+        let mut psbt = tx_builder.finish()?;
+        if !self.wallet.sign(&mut psbt, SignOptions::default())? {
+            anyhow::bail!("Could not sign all inputs of the double-spend.")
+        }
+
+        let tx = psbt.extract_tx()?;
+        let txid = self.transaction_broadcast(&tx)?;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+        self.wallet.apply_unconfirmed_txs([(tx, now)]);
+        Ok(txid)
+    }
+
     fn _transfer_to_address(
         &mut self,
         address: AddressInfo,
@@ -150,7 +192,11 @@ pub struct Round1Parameter {
     pub warn_anchor_spend: ScriptBuf,
     pub claim_spend: ScriptBuf,
     pub redirect_anchor_spend: ScriptBuf,
+    pub warning_mode: WarningOutputMode,
+    pub claim_leaf_point: Option<Point>, // only in WarningOutputMode::CsvClaimLeaf
+    pub(crate) fee_rates: FeeRates, // proposed, the ones of the seller are used by both traders
 }
+#[derive(Clone)]
 pub(crate) struct Round2Parameter {
     // DepositTx --------
     pub(crate) p_agg: Point,
@@ -167,6 +213,8 @@ pub(crate) struct Round2Parameter {
     claim_bob_nonce: PubNonce,
     redirect_alice_nonce: PubNonce,
     redirect_bob_nonce: PubNonce,
+    redirect_alice_shares: Vec<EcdhShare>,
+    redirect_bob_shares: Vec<EcdhShare>,
 }
 pub(crate) struct Round3Parameter {
     // DepositTx --------
@@ -184,21 +232,118 @@ pub(crate) struct Round4Parameter {
     pub(crate) swap_onchain: Option<Transaction>,
 }
 /**
+Message A, taker to maker.
+*/
+pub(crate) struct MessageA {
+    pub(crate) p_a: Point,
+    pub(crate) q_a: Point,
+    pub(crate) warning_mode: WarningOutputMode,
+    pub(crate) claim_leaf_point: Option<Point>, // only in WarningOutputMode::CsvClaimLeaf
+    pub(crate) deposit_fee_range: FeeRange, // acceptable for the taker
+    pub(crate) prepared_fee_range: FeeRange,
+}
+/**
+Message B, maker to taker.
+*/
+pub(crate) struct MessageB {
+    pub(crate) p_a: Point,
+    pub(crate) q_a: Point,
+    pub(crate) warning_mode: WarningOutputMode,
+    pub(crate) claim_leaf_point: Option<Point>,
+    pub(crate) fee_rates: FeeRates, // picked by the maker within the taker's ranges
+    pub(crate) dep_part_psbt: Psbt, // maker inputs and change
+    pub(crate) addresses: SetupAddresses,
+    pub(crate) nonces: SetupNonces,
+}
+/**
+Message C, taker to maker.
+*/
+pub(crate) struct MessageC {
+    pub(crate) dep_part_psbt: Psbt, // taker inputs and change
+    pub(crate) addresses: SetupAddresses,
+    pub(crate) nonces: SetupNonces,
+    pub(crate) peer_sigs: PeerTxSigs, // on the maker's transactions
+    pub(crate) swap_part_sig: Option<PartialSignature>, // adaptor signature, only if the taker is the seller
+}
+/**
+Message D, maker to taker.
+*/
+pub(crate) struct MessageD {
+    pub(crate) peer_sigs: PeerTxSigs, // on the taker's transactions
+    pub(crate) swap_part_sig: Option<PartialSignature>, // adaptor signature, only if the maker is the seller
+    pub(crate) deposit_tx_signed: Psbt, // signatures on the maker inputs
+}
+/**
+Message E, buyer to seller after the payment has been started.
+*/
+pub(crate) struct MessageE {
+    pub(crate) swap_part_sig: PartialSignature,
+}
+pub(crate) struct SetupAddresses {
+    pub(crate) swap_script: Option<ScriptBuf>, // only set from Seller
+    pub(crate) warn_anchor_spend: ScriptBuf,
+    pub(crate) redirect_anchor_spend: ScriptBuf,
+}
+/**
+nonces for all presigned transactions, 'mine' and 'peer' as seen from the sender.
+*/
+pub(crate) struct SetupNonces {
+    swap: PubNonce,
+    warn_mine_p: PubNonce,
+    warn_mine_q: PubNonce,
+    warn_peer_p: PubNonce,
+    warn_peer_q: PubNonce,
+    redirect_mine: PubNonce,
+    redirect_peer: PubNonce,
+    redirect_mine_shares: Vec<EcdhShare>,
+    redirect_peer_shares: Vec<EcdhShare>,
+}
+pub(crate) struct PeerTxSigs {
+    warn_p: PartialSignature,
+    warn_q: PartialSignature,
+    redirect: PartialSignature,
+}
+/**
+tells the peer that we have left the trade. Can be sent in any round before the DepositTx is broadcast.
+*/
+pub(crate) struct AbortParameter {
+    pub(crate) state: TradeState, // the state of the sender when it aborted
+    pub(crate) double_spend_txid: Option<Txid>, // if set, our deposit inputs are already spent elsewhere
+}
+/**
 this context is for the whole process and need to be persisted by the caller
 */
 pub struct BMPContext {
     // first of all, everything which is general to the protcol itself
-    pub funds: MemWallet,
+    pub funds: SharedWallet,
+    pub lease: LeaseId, // our reservation of UTXOs in the shared wallet
     pub role: ProtocolRole,
     pub seller_amount: Amount,
     pub buyer_amount: Amount,
+    pub warning_mode: WarningOutputMode, // both traders must use the same mode
+    pub offer_role: Option<OfferRole>, // only for the maker / taker setup (messages A to D)
+    pub fee_policy: FeePolicy,
+    pub fee_rates: Option<FeeRates>, // agreed with the peer during setup
+}
+/**
+How the funds in the WarningTx output can be claimed by the party which broadcast the WarningTx.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum WarningOutputMode {
+    // key spend only, the ClaimTx is presigned at trade start and enforces t2 by its nSequence.
+    KeySpend,
+    // additionally a script leaf `and_v(v:pk(K),older(t2))` for the warning party, so the claim
+    // can be built late, to any address and with a current fee rate. See ClaimTx::build_late().
+    CsvClaimLeaf,
 }
 pub struct BMPProtocol {
     pub(crate) ctx: BMPContext,
     pub(crate) p_tik: AggKey, // Point securing Seller deposit and trade amount
     pub(crate) q_tik: AggKey, // Point securing Buyer deposit
     pub(crate) deposit_tx: DepositTx,
-    round: u8, // which round are we in.
+    state: TradeState,
+    claim_leaf_sec: Option<Scalar>, // our key in the claim leaf of our WarningTx
+    pending_round2: Option<Round2Parameter>, // round 2 waiting for the external signer
     pub(crate) swap_tx: SwapTx,
     pub(crate) warning_tx_me: WarningTx,
     pub(crate) warning_tx_peer: WarningTx,
@@ -206,17 +351,46 @@ pub struct BMPProtocol {
     pub claim_tx_peer: ClaimTx,
     pub redirect_tx_me: RedirectTx,
     pub redirect_tx_peer: RedirectTx,
+    pub mediated_payout: MediatedPayoutTx,
 }
 
 impl BMPContext {
-    pub(crate) fn new(funds: MemWallet, role: ProtocolRole, seller_amount: Amount, buyer_amount: Amount) -> anyhow::Result<BMPContext> {
+    pub(crate) fn new(funds: SharedWallet, role: ProtocolRole, seller_amount: Amount, buyer_amount: Amount) -> anyhow::Result<BMPContext> {
         Ok(BMPContext {
+            lease: funds.new_lease(),
             funds,
             role,
             seller_amount,
             buyer_amount,
+            warning_mode: WarningOutputMode::KeySpend,
+            offer_role: None,
+            fee_policy: FeePolicy::default(),
+            fee_rates: None,
         })
     }
+
+    pub(crate) fn with_warning_mode(mut self, warning_mode: WarningOutputMode) -> BMPContext {
+        self.warning_mode = warning_mode;
+        self
+    }
+
+    /**
+    the maker / taker setup has no presigned ClaimTx, the warning party claims through the CSV leaf instead.
+    */
+    pub(crate) fn with_offer_role(mut self, offer_role: OfferRole) -> BMPContext {
+        self.offer_role = Some(offer_role);
+        self.warning_mode = WarningOutputMode::CsvClaimLeaf;
+        self
+    }
+
+    pub(crate) fn with_fee_policy(mut self, fee_policy: FeePolicy) -> BMPContext {
+        self.fee_policy = fee_policy;
+        self
+    }
+
+    pub(crate) fn agreed_fee_rates(&self) -> anyhow::Result<FeeRates> {
+        self.fee_rates.ok_or_else(|| anyhow::anyhow!("Fee rates have not been agreed yet."))
+    }
 }
 impl BMPProtocol {
     pub(crate) fn new(ctx: BMPContext) -> anyhow::Result<BMPProtocol> {
@@ -226,7 +400,9 @@ impl BMPProtocol {
             p_tik: AggKey::new()?,
             q_tik: AggKey::new()?,
             deposit_tx: DepositTx::new(),
-            round: 0,
+            state: TradeState::Init,
+            claim_leaf_sec: None,
+            pending_round2: None,
             swap_tx: SwapTx::new(role),
             warning_tx_me: WarningTx::new(role),
             warning_tx_peer: WarningTx::new(role.other()),
@@ -234,51 +410,120 @@ impl BMPProtocol {
             claim_tx_peer: ClaimTx::new(),
             redirect_tx_me: RedirectTx::new(),
             redirect_tx_peer: RedirectTx::new(),
+            mediated_payout: MediatedPayoutTx::new(),
         })
     }
 
     pub(crate) fn round1(&mut self) -> anyhow::Result<Round1Parameter> {
-        self.check_round(1);
+        let next_state = self.state.next(TradeEvent::Round(1))?;
+        // our half of the DepositTx pays our estimate, the prepared txs use the rates of the seller (see round 2).
+        let fee_rates = self.ctx.fee_policy.estimate()?;
+        self.ctx.fee_rates = Some(fee_rates);
 
         let dep_part_psbt = self.deposit_tx.generate_part_tx(&mut self.ctx, &self.p_tik.pub_point, &self.q_tik.pub_point)?;
+        let addresses = self.init_addresses();
+        let claim_leaf_point = self.init_claim_leaf()?;
+
+        self.state = next_state;
+        Ok(Round1Parameter {
+            p_a: self.p_tik.pub_point,
+            q_a: self.q_tik.pub_point,
+            dep_part_psbt,
+            swap_script: addresses.swap_script,
+            warn_anchor_spend: addresses.warn_anchor_spend,
+            claim_spend: self.init_claim_spend(),
+            redirect_anchor_spend: addresses.redirect_anchor_spend,
+            warning_mode: self.ctx.warning_mode,
+            claim_leaf_point,
+            fee_rates,
+        })
+    }
+
+    /**
+    our addresses for the anchors and the SwapTx (seller only).
+    */
+    fn init_addresses(&mut self) -> SetupAddresses {
         let swap_script = self.swap_tx.spend_condition(&mut self.ctx);
-        let warn_anchor_spend = self.ctx.funds.wallet.next_unused_address(KeychainKind::External).script_pubkey();
+        // the wallet may be shared with other trades, so never hand out the same address twice
+        let warn_anchor_spend = self.ctx.funds.lock().wallet.reveal_next_address(KeychainKind::External).script_pubkey();
         self.warning_tx_me.anchor_spend = Some(warn_anchor_spend.clone());
 
-        // ClaimTx
-        let claim_spend = self.ctx.funds.wallet.next_unused_address(KeychainKind::External).script_pubkey();
-        self.claim_tx_me.claim_spend = Some(claim_spend.clone());
-
         // RedirectTx
-        let redirect_anchor_spend = self.ctx.funds.wallet.next_unused_address(KeychainKind::External).script_pubkey();
+        let redirect_anchor_spend = self.ctx.funds.lock().wallet.reveal_next_address(KeychainKind::External).script_pubkey();
         self.redirect_tx_me.anchor_spend = Some(redirect_anchor_spend.clone());
 
-        Ok(Round1Parameter {
-            p_a: self.p_tik.pub_point,
-            q_a: self.q_tik.pub_point,
-            dep_part_psbt,
+        SetupAddresses {
             swap_script,
             warn_anchor_spend,
-            claim_spend,
             redirect_anchor_spend,
-        })
+        }
+    }
+
+    /**
+    our address for the presigned ClaimTx, only used by the five-round flow.
+    */
+    fn init_claim_spend(&mut self) -> ScriptBuf {
+        let claim_spend = self.ctx.funds.lock().wallet.reveal_next_address(KeychainKind::External).script_pubkey();
+        self.claim_tx_me.claim_spend = Some(claim_spend.clone());
+        claim_spend
+    }
+
+    fn init_claim_leaf(&mut self) -> anyhow::Result<Option<Point>> {
+        let claim_leaf_point = match self.ctx.warning_mode {
+            WarningOutputMode::KeySpend => None,
+            WarningOutputMode::CsvClaimLeaf => {
+                let claim_leaf = AggKey::new()?; // only a fresh single key is needed
+                self.claim_leaf_sec = Some(claim_leaf.sec);
+                Some(claim_leaf.pub_point)
+            }
+        };
+        self.warning_tx_me.claim_leaf_point = claim_leaf_point;
+        Ok(claim_leaf_point)
     }
 
     pub(crate) fn round2(&mut self, bob: Round1Parameter) -> anyhow::Result<Round2Parameter> {
-        self.check_round(2);
-        assert_ne!(bob.p_a, bob.q_a, "Bob is sending the same point for P' and Q'.");
-        println!("The {:?} sellers secret for P_Tik is {:?}.", self.ctx.role, self.p_tik.sec);
+        let mut round2 = self.prepare_round2(bob)?;
+        round2.deposit_tx_signed = self.deposit_tx.sign_merged_psbt(&mut self.ctx)?;
+        Ok(round2)
+    }
 
-        // key Aggregation -----
-        self.p_tik.other_point = Some(bob.p_a);
-        self.q_tik.other_point = Some(bob.q_a);
-        self.p_tik.aggregate_key(bob.p_a)?;
-        self.q_tik.aggregate_key(bob.q_a)?;
+    /**
+    Same as `round2()`, but the DepositTx is not signed with our hot wallet. Instead, the merged
+    PSBT is returned for signing by an external (cold or watch-only) wallet. Continue with
+    `round2_external_signed()` once the signed PSBT is back.
+    */
+    pub(crate) fn round2_external(&mut self, bob: Round1Parameter) -> anyhow::Result<Psbt> {
+        let round2 = self.prepare_round2(bob)?;
+        let unsigned_psbt = round2.deposit_tx_signed.clone();
+        self.pending_round2 = Some(round2);
+        Ok(unsigned_psbt)
+    }
+
+    pub(crate) fn round2_external_signed(&mut self, signed_psbt: Psbt) -> anyhow::Result<Round2Parameter> {
+        let Some(mut round2) = self.pending_round2.take() else {
+            anyhow::bail!("round2_external() must be called first.")
+        };
+        round2.deposit_tx_signed = self.deposit_tx.accept_external_signature(&mut self.ctx, signed_psbt)
+            .inspect_err(|_| self.pending_round2 = Some(round2.clone()))?;
+        Ok(round2)
+    }
+
+    fn prepare_round2(&mut self, bob: Round1Parameter) -> anyhow::Result<Round2Parameter> {
+        let next_state = self.state.next(TradeEvent::Round(2))?;
+        self.aggregate_keys(bob.p_a, bob.q_a, bob.warning_mode, bob.claim_leaf_point)?;
+        // both traders must build the same txs, so the buyer takes the rates of the seller, if within its bounds.
+        self.ctx.fee_policy.check(bob.fee_rates)?;
+        if self.ctx.role == ProtocolRole::Buyer {
+            self.ctx.fee_rates = Some(bob.fee_rates);
+        }
         // now we have the aggregated key
         // so we can contruct the Deposit Tx
-        let deposit_tx_signed = self.deposit_tx.build_and_merge_tx(&mut self.ctx, &bob.dep_part_psbt, &self.p_tik, &self.q_tik)?;
+        let deposit_tx_unsigned = self.deposit_tx.build_merged_psbt(&mut self.ctx, &bob.dep_part_psbt, &self.p_tik, &self.q_tik)?;
+        self.warning_tx_me.prepare(&self.p_tik, &self.q_tik)?;
         self.warning_tx_me.build(&mut self.ctx, &self.p_tik, &self.q_tik, &self.deposit_tx)?;
         self.warning_tx_peer.anchor_spend = Some(bob.warn_anchor_spend);
+        self.warning_tx_peer.claim_leaf_point = bob.claim_leaf_point;
+        self.warning_tx_peer.prepare(&self.p_tik, &self.q_tik)?;
         self.warning_tx_peer.build(&mut self.ctx, &self.p_tik, &self.q_tik, &self.deposit_tx)?;
         let warn_alice_p_nonce = self.warning_tx_me.sig_p.as_ref().unwrap().pub_nonce.clone();
         let warn_alice_q_nonce = self.warning_tx_me.sig_q.as_ref().unwrap().pub_nonce.clone();
@@ -286,32 +531,34 @@ impl BMPProtocol {
         let warn_bob_q_nonce = self.warning_tx_peer.sig_q.as_ref().unwrap().pub_nonce.clone();
 
         // given the depositTx, we can create SwapTx for Alice.
-        self.swap_tx.build(self.q_tik.clone(), &deposit_tx_signed.unsigned_tx, bob.swap_script)?;
+        self.swap_tx.prepare(self.q_tik.clone());
+        self.swap_tx.build(&deposit_tx_unsigned.unsigned_tx, bob.swap_script, self.ctx.agreed_fee_rates()?.prepared)?;
         // let start the signing process for swaptx already.
         let swap_pub_nonce = self.swap_tx.get_pub_nonce(); // could be one round earlier, if we solve secure nonce generation
 
-        //ClaimTx
-        let (tik, other_tik) = match self.ctx.role {
-            ProtocolRole::Seller => (&self.q_tik, &self.p_tik),
-            ProtocolRole::Buyer => (&self.p_tik, &self.q_tik)
-        };
-        self.claim_tx_me.build(&mut self.ctx, tik, &self.warning_tx_me)?;
+        //ClaimTx, spends from the WarningTx output, its key may be tweaked with the claim leaf.
+        let tik = &self.warning_tx_me.key_spend.clone().unwrap();
+        let other_tik = &self.warning_tx_peer.key_spend.clone().unwrap();
+        self.claim_tx_me.prepare(tik);
+        self.claim_tx_me.build(&mut self.ctx, &self.warning_tx_me)?;
         let claim_alice_nonce = self.claim_tx_me.sig.as_ref().unwrap().pub_nonce.clone();
         self.claim_tx_peer.claim_spend = Some(bob.claim_spend);
-        self.claim_tx_peer.build(&mut self.ctx, other_tik, &self.warning_tx_peer)?;
+        self.claim_tx_peer.prepare(other_tik);
+        self.claim_tx_peer.build(&mut self.ctx, &self.warning_tx_peer)?;
         let claim_bob_nonce = self.claim_tx_peer.sig.as_ref().unwrap().pub_nonce.clone();
 
         // RedirectTX
-        self.redirect_tx_me.build(&mut self.ctx, other_tik, &self.warning_tx_peer)?; // redirect TX is overcross alice reference Bob warningTx
+        let redirect_alice_shares = self.redirect_tx_me.build(&mut self.ctx, other_tik, &self.warning_tx_peer)?; // redirect TX is overcross alice reference Bob warningTx
         let redirect_alice_nonce = self.redirect_tx_me.sig.as_ref().unwrap().pub_nonce.clone();
         self.redirect_tx_peer.anchor_spend = Some(bob.redirect_anchor_spend);
-        self.redirect_tx_peer.build(&mut self.ctx, tik, &self.warning_tx_me)?;
+        let redirect_bob_shares = self.redirect_tx_peer.build(&mut self.ctx, tik, &self.warning_tx_me)?;
         let redirect_bob_nonce = self.redirect_tx_peer.sig.as_ref().unwrap().pub_nonce.clone();
 
+        self.state = next_state;
         Ok(Round2Parameter {
             p_agg: self.p_tik.agg_point.unwrap(),
             q_agg: self.q_tik.agg_point.unwrap(),
-            deposit_tx_signed,
+            deposit_tx_signed: deposit_tx_unsigned, // replaced by the signed one, before leaving round 2
             swap_pub_nonce,
             warn_alice_p_nonce,
             warn_alice_q_nonce,
@@ -321,16 +568,19 @@ impl BMPProtocol {
             claim_bob_nonce,
             redirect_alice_nonce,
             redirect_bob_nonce,
+            redirect_alice_shares,
+            redirect_bob_shares,
         })
     }
     pub(crate) fn round3(&mut self, bob: Round2Parameter) -> anyhow::Result<Round3Parameter> {
-        self.check_round(3);
+        let next_state = self.state.next(TradeEvent::Round(3))?;
         // actually this next test is not necessary, but double-checking and fast fail is always good
         // TODO since we are sending this only to validate, we could use a hash of it as well, optimization
         assert_eq!(bob.p_agg, self.p_tik.agg_point.unwrap(), "Bob is sending the wrong P' for his aggregated key.");
         assert_eq!(bob.q_agg, self.q_tik.agg_point.unwrap(), "Bob is sending the wrong Q' for his aggregated key.");
 
         let txid = self.deposit_tx.transfer_sig_and_broadcast(&mut self.ctx, bob.deposit_tx_signed)?;
+        self.state = next_state; // the DepositTx is out, whatever fails below
         let adaptor_point = match self.ctx.role { // the seller's key for payout of seller deposit and trade amount is in question
             ProtocolRole::Seller => self.p_tik.pub_point,
             ProtocolRole::Buyer => self.p_tik.other_point.unwrap(),
@@ -345,7 +595,12 @@ impl BMPProtocol {
         self.claim_tx_me.build_partial_sig(&self.ctx, &bob.claim_bob_nonce, &self.warning_tx_me)?; // no nneed to send my partial sig to peer
         let claim_part_sig = self.claim_tx_peer.build_partial_sig(&self.ctx, &bob.claim_alice_nonce, &self.warning_tx_peer)?; // sign bobs transaction that I constructed
 
-        //RedirectTx
+        //RedirectTx, now that we have the ECDH shares of the peer, the silent payment outputs are known.
+        let tik = &self.warning_tx_me.key_spend.clone().unwrap();
+        let other_tik = &self.warning_tx_peer.key_spend.clone().unwrap();
+        let fee_rate = self.ctx.agreed_fee_rates()?.prepared;
+        self.redirect_tx_me.complete(other_tik, &self.warning_tx_peer, &bob.redirect_bob_shares, fee_rate)?;
+        self.redirect_tx_peer.complete(tik, &self.warning_tx_me, &bob.redirect_alice_shares, fee_rate)?;
         self.redirect_tx_me.build_partial_sig(&self.ctx, &bob.redirect_bob_nonce, &self.warning_tx_peer)?;
         let redirect_part_sig = self.redirect_tx_peer.build_partial_sig(&self.ctx, &bob.redirect_alice_nonce, &self.warning_tx_me)?; // sign bobs transaction that I constructed
 
@@ -360,47 +615,617 @@ impl BMPProtocol {
         })
     }
     pub(crate) fn round4(&mut self, bob: Round3Parameter) -> anyhow::Result<Round4Parameter> {
-        self.check_round(4);
+        let next_state = self.state.next(TradeEvent::Round(4))?;
         self.swap_tx.aggregate_sigs(bob.swap_part_sig)?;
         self.warning_tx_me.aggregate_sigs(bob.p_part_peer, bob.q_part_peer)?;
         self.claim_tx_me.aggregate_sigs(bob.claim_part_sig)?;
         self.redirect_tx_me.aggregate_sigs(bob.redirect_part_sig)?;
 
-        if self.ctx.role == ProtocolRole::Seller {
+        let round4 = if self.ctx.role == ProtocolRole::Seller {
             // only the seller can sign and use SwapTx
             let tx = self.swap_tx.sign(&self.p_tik)?;
-            Ok(Round4Parameter {
+            Round4Parameter {
                 swap_onchain: Some(tx)
-            })          // debug normally Bob would see the SwapTx on the chain or mempool and reveal, let do it here
+            }          // debug normally Bob would see the SwapTx on the chain or mempool and reveal, let do it here
         } else {
-            Ok(Round4Parameter {
+            Round4Parameter {
                 swap_onchain: None,
-            })
-        }
+            }
+        };
+        self.state = next_state;
+        Ok(round4)
     }
 
     pub(crate) fn round5(&mut self, bob: Round4Parameter) -> anyhow::Result<()> {
-        self.check_round(5);
+        let next_state = self.state.next(TradeEvent::Round(5))?;
         if self.ctx.role == ProtocolRole::Buyer {
-            let tx = bob.swap_onchain.as_ref().unwrap();
+            let Some(tx) = bob.swap_onchain.as_ref() else {
+                anyhow::bail!("Seller did not send the SwapTx.")
+            };
             self.swap_tx.reveal(tx, &mut self.p_tik)?;
             // dbg!("Revealed p_tik aggregated secret key:");
             // dbg!(&self.p_tik);
         }
+        self.state = next_state;
+        Ok(())
+    }
+
+    fn aggregate_keys(&mut self, p_a: Point, q_a: Point, warning_mode: WarningOutputMode, claim_leaf_point: Option<Point>) -> anyhow::Result<()> {
+        assert_ne!(p_a, q_a, "Bob is sending the same point for P' and Q'.");
+        if warning_mode != self.ctx.warning_mode || claim_leaf_point.is_some() != (warning_mode == WarningOutputMode::CsvClaimLeaf) {
+            anyhow::bail!("Bob is using WarningOutputMode {:?}, but we use {:?}.", warning_mode, self.ctx.warning_mode)
+        }
+
+        // key Aggregation -----
+        self.p_tik.other_point = Some(p_a);
+        self.q_tik.other_point = Some(q_a);
+        self.p_tik.aggregate_key(p_a)?;
+        self.q_tik.aggregate_key(q_a)?;
+        self.warning_tx_peer.claim_leaf_point = claim_leaf_point;
+        Ok(())
+    }
+
+    // ------- maker / taker setup, see rpc/musig-trade-protocol-messages.txt --------
+
+    /**
+    Taker: starts the trade with our key shares. Our DepositTx inputs follow only in message C,
+    after the maker has committed to its inputs.
+    */
+    pub(crate) fn message_a(&mut self) -> anyhow::Result<MessageA> {
+        self.expect_offer_role(OfferRole::Taker)?;
+        let next_state = self.state.next(TradeEvent::Sent(SetupMessage::A))?;
+        let claim_leaf_point = self.init_claim_leaf()?;
+        self.state = next_state;
+        Ok(MessageA {
+            p_a: self.p_tik.pub_point,
+            q_a: self.q_tik.pub_point,
+            warning_mode: self.ctx.warning_mode,
+            claim_leaf_point,
+            deposit_fee_range: self.ctx.fee_policy.deposit_bounds,
+            prepared_fee_range: self.ctx.fee_policy.prepared_bounds,
+        })
+    }
+
+    /**
+    Maker: answers A with our key shares, our DepositTx inputs and the nonces for all presigned transactions.
+    The transactions themselves are built once the taker's inputs are known.
+    */
+    pub(crate) fn message_b(&mut self, taker: MessageA) -> anyhow::Result<MessageB> {
+        self.expect_offer_role(OfferRole::Maker)?;
+        let next_state = self.state.next(TradeEvent::Sent(SetupMessage::B))?;
+        let claim_leaf_point = self.init_claim_leaf()?;
+        self.aggregate_keys(taker.p_a, taker.q_a, taker.warning_mode, taker.claim_leaf_point)?;
+        let fee_rates = self.ctx.fee_policy.pick(taker.deposit_fee_range, taker.prepared_fee_range)?;
+        self.ctx.fee_rates = Some(fee_rates);
+        let dep_part_psbt = self.deposit_tx.generate_part_tx(&mut self.ctx, &self.p_tik.pub_point, &self.q_tik.pub_point)?;
+        let addresses = self.init_addresses();
+        let nonces = self.init_nonces()?;
+        self.state = next_state;
+        Ok(MessageB {
+            p_a: self.p_tik.pub_point,
+            q_a: self.q_tik.pub_point,
+            warning_mode: self.ctx.warning_mode,
+            claim_leaf_point,
+            fee_rates,
+            dep_part_psbt,
+            addresses,
+            nonces,
+        })
+    }
+
+    /**
+    Taker: adds our DepositTx inputs, builds all transactions and signs the maker's ones.
+    If we are the seller, the adaptor signature for the SwapTx goes along.
+    */
+    pub(crate) fn message_c(&mut self, maker: MessageB) -> anyhow::Result<MessageC> {
+        self.expect_offer_role(OfferRole::Taker)?;
+        let next_state = self.state.next(TradeEvent::Sent(SetupMessage::C))?;
+        self.aggregate_keys(maker.p_a, maker.q_a, maker.warning_mode, maker.claim_leaf_point)?;
+        self.ctx.fee_policy.check(maker.fee_rates)?;
+        self.ctx.fee_rates = Some(maker.fee_rates);
+        let dep_part_psbt = self.deposit_tx.generate_part_tx(&mut self.ctx, &self.p_tik.pub_point, &self.q_tik.pub_point)?;
+        let addresses = self.init_addresses();
+        let nonces = self.init_nonces()?;
+
+        self.deposit_tx.build_merged_psbt(&mut self.ctx, &maker.dep_part_psbt, &self.p_tik, &self.q_tik)?;
+        self.build_txs(maker.addresses, &maker.nonces)?;
+        let (peer_sigs, swap_part_sig) = self.sign_txs(&maker.nonces)?;
+        self.state = next_state;
+        Ok(MessageC {
+            dep_part_psbt,
+            addresses,
+            nonces,
+            peer_sigs,
+            swap_part_sig: self.seller_only(swap_part_sig),
+        })
+    }
+
+    /**
+    Maker: signs the taker's transactions and, now that all of our transactions are signed, our DepositTx inputs.
+    The taker publishes the DepositTx, watch for it and call `deposit_published()`.
+    */
+    pub(crate) fn message_d(&mut self, taker: MessageC) -> anyhow::Result<MessageD> {
+        self.expect_offer_role(OfferRole::Maker)?;
+        let next_state = self.state.next(TradeEvent::Sent(SetupMessage::D))?;
+        self.deposit_tx.build_merged_psbt(&mut self.ctx, &taker.dep_part_psbt, &self.p_tik, &self.q_tik)?;
+        self.build_txs(taker.addresses, &taker.nonces)?;
+        let (peer_sigs, swap_part_sig) = self.sign_txs(&taker.nonces)?;
+        self.aggregate_txs(taker.peer_sigs, taker.swap_part_sig)?;
+
+        let deposit_tx_signed = self.deposit_tx.sign_merged_psbt(&mut self.ctx)?;
+        self.state = next_state;
+        Ok(MessageD {
+            peer_sigs,
+            swap_part_sig: self.seller_only(swap_part_sig),
+            deposit_tx_signed,
+        })
+    }
+
+    /**
+    Taker: completes our transactions, signs our DepositTx inputs and publishes the DepositTx.
+    */
+    pub(crate) fn publish_deposit(&mut self, maker: MessageD) -> anyhow::Result<Txid> {
+        self.expect_offer_role(OfferRole::Taker)?;
+        if self.state != TradeState::DepositSigned {
+            anyhow::bail!("DepositTx can't be published in state {:?}.", self.state)
+        }
+        self.aggregate_txs(maker.peer_sigs, maker.swap_part_sig)?;
+        self.deposit_tx.sign_merged_psbt(&mut self.ctx)?;
+        let txid = self.deposit_tx.transfer_sig_and_broadcast(&mut self.ctx, maker.deposit_tx_signed)?;
+        self.transition(TradeEvent::DepositPublished)?;
+        Ok(txid)
+    }
+
+    /**
+    Maker: the DepositTx published by the taker has shown up on the chain (or in the mempool).
+    */
+    pub(crate) fn deposit_published(&mut self, deposit_tx: &Transaction) -> anyhow::Result<()> {
+        self.expect_offer_role(OfferRole::Maker)?;
+        if Some(deposit_tx.compute_txid()) != self.deposit_tx.tx.as_ref().map(Transaction::compute_txid) {
+            anyhow::bail!("Transaction {} is not our DepositTx.", deposit_tx.compute_txid())
+        }
+        self.deposit_tx.tx = Some(deposit_tx.clone());
+        self.transition(TradeEvent::DepositPublished)?;
+        Ok(())
+    }
+
+    /**
+    Buyer: confirms the payment, the seller can now complete and broadcast the SwapTx.
+    */
+    pub(crate) fn message_e(&mut self) -> anyhow::Result<MessageE> {
+        if self.ctx.role != ProtocolRole::Buyer || self.state != TradeState::Open {
+            anyhow::bail!("Only the buyer of an open trade sends message E.")
+        }
+        Ok(MessageE {
+            swap_part_sig: self.swap_tx.fund_sig.as_ref().unwrap().get_part_sig(),
+        })
+    }
+
+    /**
+    Seller: returns the SwapTx, signed and ready to broadcast.
+    */
+    pub(crate) fn sign_swap_tx(&mut self, buyer: MessageE) -> anyhow::Result<Transaction> {
+        self.expect_action(TradeAction::BroadcastSwap)?;
+        self.swap_tx.aggregate_sigs(buyer.swap_part_sig)?;
+        self.swap_tx.sign(&self.p_tik)
+    }
+
+    fn expect_action(&self, action: TradeAction) -> anyhow::Result<()> {
+        if !self.allowed_actions().contains(&action) {
+            anyhow::bail!("{:?} is not possible for the {:?} in state {:?}.", action, self.ctx.role, self.state)
+        }
+        Ok(())
+    }
+
+    fn expect_offer_role(&self, offer_role: OfferRole) -> anyhow::Result<()> {
+        if self.ctx.offer_role != Some(offer_role) {
+            anyhow::bail!("This step is for the {:?}, but we are {:?}.", offer_role, self.ctx.offer_role)
+        }
+        Ok(())
+    }
+
+    /**
+    creates the nonces (and ECDH shares) for all presigned transactions, without knowing the DepositTx.
+    */
+    fn init_nonces(&mut self) -> anyhow::Result<SetupNonces> {
+        self.warning_tx_me.prepare(&self.p_tik, &self.q_tik)?;
+        self.warning_tx_peer.prepare(&self.p_tik, &self.q_tik)?;
+        self.swap_tx.prepare(self.q_tik.clone());
+        let tik = &self.warning_tx_me.key_spend.clone().unwrap();
+        let other_tik = &self.warning_tx_peer.key_spend.clone().unwrap();
+        let redirect_mine_shares = self.redirect_tx_me.build(&mut self.ctx, other_tik, &self.warning_tx_peer)?;
+        let redirect_peer_shares = self.redirect_tx_peer.build(&mut self.ctx, tik, &self.warning_tx_me)?;
+        let nonce = |sig: &Option<TMuSig2>| sig.as_ref().unwrap().pub_nonce.clone();
+        Ok(SetupNonces {
+            swap: self.swap_tx.get_pub_nonce(),
+            warn_mine_p: nonce(&self.warning_tx_me.sig_p),
+            warn_mine_q: nonce(&self.warning_tx_me.sig_q),
+            warn_peer_p: nonce(&self.warning_tx_peer.sig_p),
+            warn_peer_q: nonce(&self.warning_tx_peer.sig_q),
+            redirect_mine: nonce(&self.redirect_tx_me.sig),
+            redirect_peer: nonce(&self.redirect_tx_peer.sig),
+            redirect_mine_shares,
+            redirect_peer_shares,
+        })
+    }
+
+    /**
+    builds all presigned transactions, once the merged DepositTx is known.
+    */
+    fn build_txs(&mut self, peer: SetupAddresses, peer_nonces: &SetupNonces) -> anyhow::Result<()> {
+        self.warning_tx_me.build(&mut self.ctx, &self.p_tik, &self.q_tik, &self.deposit_tx)?;
+        self.warning_tx_peer.anchor_spend = Some(peer.warn_anchor_spend);
+        self.warning_tx_peer.build(&mut self.ctx, &self.p_tik, &self.q_tik, &self.deposit_tx)?;
+        let fee_rate = self.ctx.agreed_fee_rates()?.prepared;
+        self.swap_tx.build(self.deposit_tx.tx.as_ref().unwrap(), peer.swap_script, fee_rate)?;
+
+        let tik = &self.warning_tx_me.key_spend.clone().unwrap();
+        let other_tik = &self.warning_tx_peer.key_spend.clone().unwrap();
+        self.redirect_tx_me.complete(other_tik, &self.warning_tx_peer, &peer_nonces.redirect_peer_shares, fee_rate)?;
+        self.redirect_tx_peer.anchor_spend = Some(peer.redirect_anchor_spend);
+        self.redirect_tx_peer.complete(tik, &self.warning_tx_me, &peer_nonces.redirect_mine_shares, fee_rate)?;
+        Ok(())
+    }
+
+    /**
+    creates our partial signatures for all transactions. The ones for the peer's transactions are returned,
+    the ones for our transactions are kept for `aggregate_txs()`. Same for the SwapTx, which only the seller
+    hands out during setup.
+    */
+    fn sign_txs(&mut self, peer_nonces: &SetupNonces) -> anyhow::Result<(PeerTxSigs, PartialSignature)> {
+        let adaptor_point = match self.ctx.role { // the seller's key for payout of seller deposit and trade amount is in question
+            ProtocolRole::Seller => self.p_tik.pub_point,
+            ProtocolRole::Buyer => self.p_tik.other_point.unwrap(),
+        };
+        let swap_part_sig = self.swap_tx.build_partial_sig(&self.ctx, peer_nonces.swap.clone(), adaptor_point, &self.deposit_tx)?;
+
+        self.warning_tx_me.build_partial_sig(&self.ctx, &peer_nonces.warn_peer_p, &peer_nonces.warn_peer_q, &self.deposit_tx)?;
+        let (warn_p, warn_q) = self.warning_tx_peer.build_partial_sig(&self.ctx, &peer_nonces.warn_mine_p, &peer_nonces.warn_mine_q, &self.deposit_tx)?;
+        self.redirect_tx_me.build_partial_sig(&self.ctx, &peer_nonces.redirect_peer, &self.warning_tx_peer)?;
+        let redirect = self.redirect_tx_peer.build_partial_sig(&self.ctx, &peer_nonces.redirect_mine, &self.warning_tx_me)?;
+        Ok((PeerTxSigs { warn_p, warn_q, redirect }, swap_part_sig))
+    }
+
+    /**
+    completes our transactions with the peer's partial signatures. The buyer gets the seller's
+    adaptor signature for the SwapTx, so it can reveal the seller's key once the SwapTx is published.
+    */
+    fn aggregate_txs(&mut self, peer_sigs: PeerTxSigs, swap_part_sig: Option<PartialSignature>) -> anyhow::Result<()> {
+        self.warning_tx_me.aggregate_sigs(peer_sigs.warn_p, peer_sigs.warn_q)?;
+        self.redirect_tx_me.aggregate_sigs(peer_sigs.redirect)?;
+        if self.ctx.role == ProtocolRole::Buyer {
+            let Some(swap_part_sig) = swap_part_sig else {
+                anyhow::bail!("Seller did not send the adaptor signature for the SwapTx.")
+            };
+            self.swap_tx.aggregate_sigs(swap_part_sig)?;
+        }
         Ok(())
     }
 
-    fn check_round(&mut self, round: u8) {
-        if self.round != round - 1 {
-            panic!("round already done");
+    fn seller_only(&self, swap_part_sig: PartialSignature) -> Option<PartialSignature> {
+        match self.ctx.role {
+            ProtocolRole::Seller => Some(swap_part_sig),
+            ProtocolRole::Buyer => None, // sent with message E, after the payment
         }
-        self.round = round;
+    }
+
+    /**
+    Aborts the trade, this is only possible as long as the DepositTx has not been broadcast (i.e. before round 3).
+    Our reserved UTXOs are given back to the wallet and all nonces and key material are dropped.
+    Once we have sent our signed DepositTx inputs to the peer (round 2), the peer could still complete and
+    broadcast the DepositTx. To prevent that, pass a `double_spend` fee rate and our inputs are spent back to us.
+    The returned message must be sent to the peer, which calls `peer_aborted()`.
+    */
+    pub(crate) fn abort(&mut self, double_spend: Option<FeeRate>) -> anyhow::Result<AbortParameter> {
+        let state = self.state;
+        let double_spend_txid = self.clean_up(TradeEvent::Abort, double_spend)?;
+        Ok(AbortParameter {
+            state,
+            double_spend_txid,
+        })
+    }
+
+    /**
+    The peer has aborted the trade. Same as `abort()`, but nothing needs to be sent back.
+    */
+    pub(crate) fn peer_aborted(&mut self, peer: AbortParameter, double_spend: Option<FeeRate>) -> anyhow::Result<Option<Txid>> {
+        println!("The {:?} peer aborted the trade in state {:?}.", self.ctx.role.other(), peer.state);
+        if let Some(txid) = peer.double_spend_txid {
+            println!("The peer double-spent its deposit inputs with {}.", txid);
+        }
+        self.clean_up(TradeEvent::PeerAborted, double_spend)
+    }
+
+    fn clean_up(&mut self, event: TradeEvent, double_spend: Option<FeeRate>) -> anyhow::Result<Option<Txid>> {
+        self.expect_action(TradeAction::Abort)?;
+        let aborted = self.state.next(event)?;
+        let leased = self.ctx.funds.leased(self.ctx.lease);
+        let double_spend_txid = match double_spend {
+            Some(fee_rate) if !leased.is_empty() => Some(self.ctx.funds.lock().spend_to_self(&leased, fee_rate)?),
+            _ => None,
+        };
+        self.ctx.funds.release(self.ctx.lease);
+
+        // drop all secrets and nonces, by replacing them with fresh ones that are never used.
+        let role = self.ctx.role;
+        self.p_tik = AggKey::new()?;
+        self.q_tik = AggKey::new()?;
+        self.deposit_tx = DepositTx::new();
+        self.pending_round2 = None;
+        self.swap_tx = SwapTx::new(role);
+        self.warning_tx_me = WarningTx::new(role);
+        self.warning_tx_peer = WarningTx::new(role.other());
+        self.claim_tx_me = ClaimTx::new();
+        self.claim_tx_peer = ClaimTx::new();
+        self.redirect_tx_me = RedirectTx::new();
+        self.redirect_tx_peer = RedirectTx::new();
+        self.mediated_payout = MediatedPayoutTx::new();
+        self.state = aborted;
+        Ok(double_spend_txid)
+    }
+
+    /**
+    The mediator has proposed how to split the deposit. Both traders call this with the same proposal,
+    then either `mediation_accept()` or `mediation_reject()`. A rejection must be passed on to the peer,
+    which calls `mediation_reject()` as well.
+    */
+    pub(crate) fn mediation_proposed(&mut self, proposal: MediationProposal) -> anyhow::Result<()> {
+        self.expect_action(TradeAction::Mediation)?;
+        // the MediatedPayoutTx has no anchor to bump it, so the DepositTx bounds apply
+        let Some(max_fee) = self.ctx.fee_policy.deposit_bounds.max.fee_vb(MEDIATED_PAYOUT_MAX_VSIZE) else {
+            anyhow::bail!("Mediation fee ceiling overflows.")
+        };
+        self.mediated_payout.propose(proposal, max_fee, &self.deposit_tx, &self.p_tik, &self.q_tik)
+    }
+
+    pub(crate) fn mediation_accept(&mut self) -> anyhow::Result<MediationNonces> {
+        self.mediated_payout.accept(&mut self.ctx, &self.p_tik, &self.q_tik)
+    }
+
+    pub(crate) fn mediation_reject(&mut self) -> anyhow::Result<()> {
+        self.mediated_payout.reject()
+    }
+
+    pub(crate) fn mediation_sign(&mut self, peer: MediationNonces) -> anyhow::Result<MediationPartialSigs> {
+        self.mediated_payout.build_partial_sig(&self.ctx, peer, &self.p_tik, &self.q_tik, &self.deposit_tx)
+    }
+
+    /**
+    returns the signed MediatedPayoutTx, ready to broadcast with `mediation_broadcast()`.
+    */
+    pub(crate) fn mediation_finalize(&mut self, peer: MediationPartialSigs) -> anyhow::Result<Transaction> {
+        self.mediated_payout.aggregate_sigs(peer)
+    }
+
+    /**
+    broadcasts the signed MediatedPayoutTx. The peer applies `TradeEvent::MediatedPayoutPublished`
+    once it shows up on the chain.
+    */
+    pub(crate) fn mediation_broadcast(&mut self) -> anyhow::Result<Txid> {
+        let next_state = self.state.next(TradeEvent::MediatedPayoutPublished)?;
+        let txid = self.mediated_payout.broadcast(&self.ctx)?;
+        self.state = next_state;
+        Ok(txid)
+    }
+
+    pub(crate) fn state(&self) -> TradeState {
+        self.state
+    }
+
+    pub(crate) fn allowed_actions(&self) -> Vec<TradeAction> {
+        self.state.allowed_actions(self.ctx.role)
+    }
+
+    /**
+    applies a peer message or chain event observed by the caller, e.g. the WarningTx showing up in the mempool.
+    Rounds, abort and mediation change the state on their own.
+    */
+    pub(crate) fn transition(&mut self, event: TradeEvent) -> anyhow::Result<TradeState> {
+        self.state = self.state.next(event)?;
+        Ok(self.state)
+    }
+
+    /**
+    claims the funds of our broadcast WarningTx through the script path, after t2 has passed.
+    Only in WarningOutputMode::CsvClaimLeaf.
+    */
+    pub(crate) fn claim_late(&self, claim_spend: ScriptBuf, fee_rate: FeeRate) -> anyhow::Result<Transaction> {
+        let Some(claim_leaf_sec) = self.claim_leaf_sec else {
+            anyhow::bail!("Late claim needs WarningOutputMode::CsvClaimLeaf.")
+        };
+        ClaimTx::build_late(&self.warning_tx_me, claim_leaf_sec, claim_spend, fee_rate)
     }
 
     // ------- Debug --------
     pub(crate) fn get_p_tik_agg(&self) -> Address {
         let r = &(*self).p_tik;
-        r.get_agg_adr().unwrap()
+        r.get_agg_adr(Network::Regtest).unwrap()
+    }
+}
+
+const MEDIATED_PAYOUT_MAX_VSIZE: u64 = 220; // two key spend inputs and two P2TR outputs, with some margin
+
+/**
+The mediator's proposal how to split the deposit, in case the traders cannot agree themselves.
+The payouts for seller and buyer together with the mining fee must add up to the total of both DepositTx outputs.
+The mining fee is capped at our highest DepositTx fee rate, so the split can't hand the deposit to the miners.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediationProposal {
+    pub seller_payout: Amount,
+    pub buyer_payout: Amount,
+    pub fee: Amount,
+}
+
+impl MediationProposal {
+    pub fn validate(&self, deposit_total: Amount, max_fee: Amount) -> anyhow::Result<()> {
+        let Some(total) = self.seller_payout.checked_add(self.buyer_payout).and_then(|a| a.checked_add(self.fee)) else {
+            anyhow::bail!("Mediation proposal overflows.")
+        };
+        if total != deposit_total {
+            anyhow::bail!("Mediation proposal pays out {} in total, but the deposit holds {}.", total, deposit_total)
+        }
+        if self.fee == Amount::ZERO {
+            anyhow::bail!("Mediation proposal does not pay any mining fee.")
+        }
+        if self.fee > max_fee {
+            anyhow::bail!("Mediation proposal pays a mining fee of {}, but at most {} is allowed.", self.fee, max_fee)
+        }
+        for payout in [self.seller_payout, self.buyer_payout] {
+            if payout != Amount::ZERO && payout < ANCHOR_AMOUNT { // dust limit of P2TR
+                anyhow::bail!("Mediation payout {} is below the dust limit.", payout)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MediationState {
+    Proposed,
+    Accepted,
+    Rejected,
+    Signed,
+}
+
+pub(crate) struct MediationNonces {
+    pub(crate) payout_spend: ScriptBuf, // where the sender wants its payout
+    p_nonce: PubNonce,
+    q_nonce: PubNonce,
+}
+pub(crate) struct MediationPartialSigs {
+    p_part: PartialSignature,
+    q_part: PartialSignature,
+}
+
+/**
+MediatedPayoutTx -- spends both DepositTx outputs according to the split proposed by the mediator.
+Both traders must accept the proposal, then they exchange nonces and partial signatures for
+both inputs, like for the WarningTx. Either trader can reject the proposal, which drops the nonces;
+the mediator may come up with a new proposal afterwards.
+*/
+pub struct MediatedPayoutTx {
+    pub proposal: Option<MediationProposal>,
+    pub state: Option<MediationState>,
+    pub payout_spend: Option<ScriptBuf>, // our payout address
+    pub sig_p: Option<TMuSig2>,
+    pub sig_q: Option<TMuSig2>,
+    pub tx: Option<Transaction>,
+}
+
+impl MediatedPayoutTx {
+    pub fn new() -> MediatedPayoutTx {
+        MediatedPayoutTx {
+            proposal: None,
+            state: None,
+            payout_spend: None,
+            sig_p: None,
+            sig_q: None,
+            tx: None,
+        }
+    }
+
+    fn propose(&mut self, proposal: MediationProposal, max_fee: Amount, deposit_tx: &DepositTx, p_tik: &AggKey, q_tik: &AggKey) -> anyhow::Result<()> {
+        if let Some(state) = self.state.filter(|s| *s != MediationState::Rejected) {
+            anyhow::bail!("There is already a mediation proposal in state {:?}.", state)
+        }
+        let dep_tx = deposit_tx.tx.as_ref().unwrap();
+        let deposit_total = dep_tx.output[dep_tx.output_index(p_tik) as usize].value
+            .add(dep_tx.output[dep_tx.output_index(q_tik) as usize].value);
+        proposal.validate(deposit_total, max_fee)?;
+        *self = MediatedPayoutTx::new();
+        self.proposal = Some(proposal);
+        self.state = Some(MediationState::Proposed);
+        Ok(())
+    }
+
+    fn accept(&mut self, ctx: &mut BMPContext, p_tik: &AggKey, q_tik: &AggKey) -> anyhow::Result<MediationNonces> {
+        if self.state != Some(MediationState::Proposed) {
+            anyhow::bail!("Cannot accept mediation in state {:?}.", self.state)
+        }
+        let payout_spend = ctx.funds.lock().wallet.reveal_next_address(KeychainKind::External).script_pubkey();
+        let sig_p = TMuSig2::new(p_tik.clone());
+        let sig_q = TMuSig2::new(q_tik.clone());
+        let nonces = MediationNonces {
+            payout_spend: payout_spend.clone(),
+            p_nonce: sig_p.pub_nonce.clone(),
+            q_nonce: sig_q.pub_nonce.clone(),
+        };
+        self.payout_spend = Some(payout_spend);
+        self.sig_p = Some(sig_p);
+        self.sig_q = Some(sig_q);
+        self.state = Some(MediationState::Accepted);
+        Ok(nonces)
+    }
+
+    fn reject(&mut self) -> anyhow::Result<()> {
+        match self.state {
+            Some(MediationState::Proposed) | Some(MediationState::Accepted) => {
+                // never sign this proposal with those nonces
+                self.sig_p = None;
+                self.sig_q = None;
+                self.state = Some(MediationState::Rejected);
+                Ok(())
+            }
+            _ => anyhow::bail!("Cannot reject mediation in state {:?}.", self.state)
+        }
+    }
+
+    fn build(&mut self, ctx: &BMPContext, peer_spend: ScriptBuf, p_tik: &AggKey, q_tik: &AggKey, deposit_tx: &DepositTx) -> anyhow::Result<Transaction> {
+        let proposal = self.proposal.as_ref().unwrap();
+        let my_spend = self.payout_spend.clone().unwrap();
+        let (seller_spend, buyer_spend) = match ctx.role {
+            ProtocolRole::Seller => (my_spend, peer_spend),
+            ProtocolRole::Buyer => (peer_spend, my_spend),
+        };
+        // output order is seller first, so both traders build the same transaction
+        let output = [(proposal.seller_payout, seller_spend), (proposal.buyer_payout, buyer_spend)].into_iter()
+            .filter(|(value, _)| *value != Amount::ZERO)
+            .map(|(value, script_pubkey)| TxOut { value, script_pubkey })
+            .collect();
+        let dep_tx = deposit_tx.tx.as_ref().unwrap();
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![dep_tx.get_txin_for(p_tik)?, dep_tx.get_txin_for(q_tik)?],
+            output,
+        };
+        self.tx = Some(tx.clone());
+        Ok(tx)
+    }
+
+    fn build_partial_sig(&mut self, ctx: &BMPContext, peer: MediationNonces, p_tik: &AggKey, q_tik: &AggKey, deposit_tx: &DepositTx) -> anyhow::Result<MediationPartialSigs> {
+        if self.state != Some(MediationState::Accepted) {
+            anyhow::bail!("Cannot sign mediation in state {:?}.", self.state)
+        }
+        let tx = self.build(ctx, peer.payout_spend, p_tik, q_tik, deposit_tx)?;
+        let prevouts = deposit_tx.tx.as_ref().unwrap().calc_prevouts(&tx.input)?;
+        let p_part = self.sig_p.as_mut().unwrap().generate_partial_sig(0, &peer.p_nonce, &prevouts, &tx)?;
+        let q_part = self.sig_q.as_mut().unwrap().generate_partial_sig(1, &peer.q_nonce, &prevouts, &tx)?;
+        Ok(MediationPartialSigs { p_part, q_part })
+    }
+
+    fn aggregate_sigs(&mut self, peer: MediationPartialSigs) -> anyhow::Result<Transaction> {
+        if self.state != Some(MediationState::Accepted) || self.tx.is_none() {
+            anyhow::bail!("Cannot finalize mediation in state {:?}.", self.state)
+        }
+        let sig_p = self.sig_p.as_mut().unwrap();
+        sig_p.aggregate_sigs(peer.p_part)?;
+        let sig_q = self.sig_q.as_mut().unwrap();
+        sig_q.aggregate_sigs(peer.q_part)?;
+        let mut tx = self.tx.clone().unwrap();
+        tx = sig_p.sign(MaybeScalar::Zero, tx)?;
+        tx = sig_q.sign(MaybeScalar::Zero, tx)?;
+        self.tx = Some(tx.clone());
+        self.state = Some(MediationState::Signed);
+        Ok(tx)
+    }
+
+    pub(crate) fn broadcast(&self, me: &BMPContext) -> anyhow::Result<Txid> {
+        if self.state != Some(MediationState::Signed) {
+            anyhow::bail!("MediatedPayoutTx is not signed yet.")
+        }
+        me.funds.lock().transaction_broadcast(self.tx.as_ref().unwrap())
     }
 }
 /**
@@ -419,6 +1244,18 @@ pub struct RedirectTx {
     pub sig: Option<TMuSig2>,
     pub tx: Option<Transaction>,
     pub anchor_spend: Option<ScriptBuf>,
+    pub ecdh_shares: Option<Vec<EcdhShare>>, // our part of the ECDH for the silent payment receivers
+    pub peer_ecdh_shares: Option<Vec<EcdhShare>>, // verified with their DLEQ proofs
+}
+
+/**
+a receiver of redirected funds, either a plain address or a silent payment address (BIP-352).
+For silent payments the output is derived from the ECDH shares of both traders, so redirect payouts cannot be linked.
+*/
+#[derive(Clone, Debug)]
+pub enum RedirectReceiver {
+    Address(Address),
+    SilentPayment(SilentPaymentAddress),
 }
 
 impl RedirectTx {
@@ -427,42 +1264,52 @@ impl RedirectTx {
             sig: None,
             tx: None,
             anchor_spend: None,
+            ecdh_shares: None,
+            peer_ecdh_shares: None,
         }
     }
-    fn build(&mut self, _ctx: &mut BMPContext, tik: &AggKey, warn_tx: &WarningTx) -> anyhow::Result<Transaction> {
+    /**
+    prepares signing and returns our ECDH shares, which the peer needs to derive the silent payment outputs.
+    The transaction itself is only built in `complete()`, once we have the ECDH shares of the peer.
+    */
+    fn build(&mut self, _ctx: &mut BMPContext, tik: &AggKey, _warn_tx: &WarningTx) -> anyhow::Result<Vec<EcdhShare>> {
         self.sig = Some(TMuSig2::new(tik.clone()));
 
-        let warn_funds = &warn_tx.funds_as_output();
-        let amount = warn_funds.value.sub(Amount::from_sat(1000)); //TODO calc fee rate.sub(); subtract ANCHOR_AMOUNT
+        let mut scan_keys: Vec<Point> = Vec::new();
+        for (receiver, _) in Self::get_dao_bm() {
+            if let RedirectReceiver::SilentPayment(sp) = receiver && !scan_keys.contains(&sp.scan) {
+                scan_keys.push(sp.scan);
+            }
+        }
+        let shares = silent_payment::ecdh_shares(tik, &scan_keys)?;
+        self.ecdh_shares = Some(shares.clone());
+        Ok(shares)
+    }
 
+    fn complete(&mut self, tik: &AggKey, warn_tx: &WarningTx, peer_shares: &[EcdhShare], fee_rate: FeeRate) -> anyhow::Result<Transaction> {
         let dao_bm = Self::get_dao_bm();
-        let mut outputs: Vec<TxOut> = dao_bm
+        let silent_payments = Self::silent_payments(&dao_bm);
+        // refuses shares without valid DLEQ proof
+        let mut sp_scripts = silent_payment::output_scripts(
+            &silent_payments,
+            &warn_tx.funds_as_outpoint(),
+            &tik.agg_point.unwrap(),
+            self.ecdh_shares.as_ref().unwrap(),
+            peer_shares,
+        )?.into_iter();
+        self.peer_ecdh_shares = Some(peer_shares.to_vec());
+
+        let warning_amount = warn_tx.funds_as_output().value;
+        // the RedirectTx splits what is left after anchor and fee in proportion to the weights, in integers
+        let receivers: Vec<(ScriptBuf, Amount)> = dao_bm
             .iter()
-            .map(|(address, ratio)| TxOut {
-                value: Amount::from_sat((amount.to_sat() as f32 * *ratio) as u64), // Calculate proportional value
-                script_pubkey: address.script_pubkey(),         // Convert DAO address to script_pubkey
-            })
+            .map(|(receiver, weight)| (match receiver {
+                RedirectReceiver::Address(address) => address.script_pubkey(), // Convert DAO address to script_pubkey
+                RedirectReceiver::SilentPayment(_) => sp_scripts.next().unwrap(), // same order as receivers
+            }, Amount::from_sat(u64::from(*weight))))
             .collect();
-
-        let anchor_output = TxOut {
-            value: ANCHOR_AMOUNT,
-            script_pubkey: self.anchor_spend.clone().unwrap(),
-        };
-        outputs.push(anchor_output);
-        let t1 = Sequence::from_height(1); // TODO define as const and find a good value
-        // let t1 = Sequence::from_seconds_ceil(1)?; // TODO define as const and find a good value
-        let input0 = TxIn {
-            previous_output: warn_tx.funds_as_outpoint(),
-            script_sig: ScriptBuf::default(),
-            sequence: t1,
-            witness: Witness::default(), // will be changed when signing.
-        };
-        let tx = Transaction {
-            version: Version::TWO,
-            input: vec![input0],
-            output: outputs,
-            lock_time: LockTime::ZERO,
-        };
+        let tx = tx::redirect_tx(warn_tx.funds_as_outpoint(), warning_amount, &receivers,
+                                 self.anchor_spend.clone().unwrap(), fee_rate)?;
         self.tx = Some(tx.clone());
 
         Ok(tx)
@@ -490,17 +1337,55 @@ impl RedirectTx {
         Ok(())
     }
     pub(crate) fn broadcast(&self, me: BMPContext) -> Txid {
-        me.funds.client.transaction_broadcast(self.tx.as_ref().unwrap()).unwrap()
+        me.funds.lock().client.transaction_broadcast(self.tx.as_ref().unwrap()).unwrap()
     }
 
     /**
-    sum of all f32 must be 1
+    the silent payment receivers of the DAO list, in list order.
     */
-    fn get_dao_bm() -> Vec<(Address, f32)> {
-        // TODO this needs a real implementation, and check that sum of ratios is 1
+    fn silent_payments(dao_bm: &[(RedirectReceiver, u32)]) -> Vec<SilentPaymentAddress> {
+        dao_bm.iter()
+            .filter_map(|(receiver, _)| match receiver {
+                RedirectReceiver::SilentPayment(sp) => Some(*sp),
+                RedirectReceiver::Address(_) => None,
+            })
+            .collect()
+    }
+
+    /**
+    proof of payment for the silent payment receiver at `index` of the DAO list, which can be
+    handed to the receiver or an arbitrator. Only available after `complete()`.
+    */
+    pub fn payment_proof(&self, tik: &AggKey, warn_tx: &WarningTx, index: usize) -> anyhow::Result<RedirectPaymentProof> {
+        let dao_bm = Self::get_dao_bm();
+        let Some((RedirectReceiver::SilentPayment(receiver), _)) = dao_bm.get(index) else {
+            anyhow::bail!("DAO receiver {} is not a silent payment address.", index)
+        };
+        let (Some(mine), Some(peer)) = (self.ecdh_shares.as_ref(), self.peer_ecdh_shares.as_ref()) else {
+            anyhow::bail!("RedirectTx is not complete yet.")
+        };
+        let find = |shares: &[EcdhShare]| shares.iter().find(|s| s.scan == receiver.scan).cloned()
+            .ok_or_else(|| anyhow::anyhow!("ECDH share for scan key {} is missing.", receiver.scan));
+        let silent_payments = Self::silent_payments(&dao_bm);
+        let sp_index = dao_bm[..index].iter().filter(|(r, _)| matches!(r, RedirectReceiver::SilentPayment(_))).count();
+        Ok(RedirectPaymentProof {
+            outpoint: warn_tx.funds_as_outpoint(),
+            input_key: tik.agg_point.unwrap(),
+            receiver: *receiver,
+            k: silent_payment::counters(&silent_payments)[sp_index],
+            shares: [find(mine)?, find(peer)?],
+        })
+    }
+
+    /**
+    the receivers with their weights, which need not add up to anything, as the funds are split in proportion to them
+    */
+    fn get_dao_bm() -> Vec<(RedirectReceiver, u32)> {
+        // TODO this needs a real implementation
         vec![
-            (Address::from_str("bcrt1p88h9s6lq8jw3ehdlljp7sa85kwpp9lvyrl077twvjnackk4lxt0sffnlrk").unwrap().assume_checked(), 0.6),
-            (Address::from_str("bcrt1phhl8d90r9haqwtvw2cv4ryjl8tlnqrv48nhpy7yyks5du6mr66xq5nlwhz").unwrap().assume_checked(), 0.4),
+            (RedirectReceiver::Address(Address::from_str("bcrt1p88h9s6lq8jw3ehdlljp7sa85kwpp9lvyrl077twvjnackk4lxt0sffnlrk").unwrap().assume_checked()), 60),
+            (RedirectReceiver::Address(Address::from_str("bcrt1phhl8d90r9haqwtvw2cv4ryjl8tlnqrv48nhpy7yyks5du6mr66xq5nlwhz").unwrap().assume_checked()), 20),
+            (RedirectReceiver::SilentPayment(SilentPaymentAddress::from_str(DAO_SP_RECEIVER).unwrap()), 20),
         ]
     }
 }
@@ -522,31 +1407,13 @@ impl ClaimTx {
             claim_spend: None,
         }
     }
-    fn build(&mut self, _ctx: &mut BMPContext, tik: &AggKey, warn_tx: &WarningTx) -> anyhow::Result<Transaction> {
+    fn prepare(&mut self, tik: &AggKey) {
         self.sig = Some(TMuSig2::new(tik.clone()));
+    }
 
-        let warn_funds = &warn_tx.funds_as_output();
-        let amount = warn_funds.value.sub(Amount::from_sat(1000)); //TODO calc fee rate.sub();
-        // let amount = w[0].value.sub(Amount::from_sat(1000)); //TODO calc fee rate.sub();
-
-        let output0 = TxOut {
-            value: amount,
-            script_pubkey: self.claim_spend.clone().unwrap(),
-        };
-        let t2 = Sequence::from_height(2); // TODO define as const and find a good value
-        // let t2 = Sequence::from_seconds_ceil(1)?;
-        let input0 = TxIn {
-            previous_output: warn_tx.funds_as_outpoint(),
-            script_sig: ScriptBuf::default(),
-            sequence: t2,
-            witness: Witness::default(), // will be changed when signing.
-        };
-        let tx = Transaction {
-            version: Version::TWO,
-            input: vec![input0],
-            output: vec![output0],
-            lock_time: LockTime::ZERO,
-        };
+    fn build(&mut self, ctx: &mut BMPContext, warn_tx: &WarningTx) -> anyhow::Result<Transaction> {
+        let tx = tx::claim_tx(warn_tx.funds_as_outpoint(), warn_tx.funds_as_output().value,
+                              self.claim_spend.clone().unwrap(), ctx.agreed_fee_rates()?.prepared)?;
         self.tx = Some(tx.clone());
 
         Ok(tx)
@@ -576,7 +1443,62 @@ impl ClaimTx {
     }
 
     pub(crate) fn broadcast(&self, me: BMPContext) -> anyhow::Result<Txid> {
-        Ok(me.funds.client.transaction_broadcast(self.tx.as_ref().unwrap())?)
+        Ok(me.funds.lock().client.transaction_broadcast(self.tx.as_ref().unwrap())?)
+    }
+
+    /**
+    ClaimTx through the claim leaf of the WarningTx output, nothing of this is presigned.
+    The witness is [signature, leaf script, control block].
+    */
+    pub fn build_late(warn_tx: &WarningTx, claim_leaf_sec: Scalar, claim_spend: ScriptBuf, fee_rate: FeeRate) -> anyhow::Result<Transaction> {
+        let (Some(script), Some(key_spend)) = (warn_tx.claim_leaf.clone(), warn_tx.key_spend.as_ref()) else {
+            anyhow::bail!("WarningTx has no claim leaf.")
+        };
+        let secp = Secp256k1::new();
+        let internal_key = XOnlyPublicKey::from_slice(&key_spend.key_agg_context.as_ref().unwrap().aggregated_pubkey_untweaked::<Point>().serialize_xonly())?;
+        let spend_info = TaprootBuilder::new().add_leaf(0, script.clone())?
+            .finalize(&secp, internal_key)
+            .map_err(|_| anyhow::anyhow!("cannot build the taproot tree"))?;
+        let control_block = spend_info.control_block(&(script.clone(), LeafVersion::TapScript)).unwrap();
+
+        let prevout = warn_tx.funds_as_output();
+        let mut tx = Transaction {
+            version: Version::TWO,
+            input: vec![TxIn {
+                previous_output: warn_tx.funds_as_outpoint(),
+                script_sig: ScriptBuf::default(),
+                sequence: Sequence::from_height(CLAIM_DELAY), // OP_CSV checks this
+                witness: Witness::default(),
+            }],
+            output: vec![TxOut { value: prevout.value, script_pubkey: claim_spend }],
+            lock_time: LockTime::ZERO,
+        };
+        // the signature has always the same size, so calculate the fee with a dummy one
+        let mut witness = Witness::new();
+        witness.push([0u8; 64]);
+        witness.push(script.as_bytes());
+        witness.push(control_block.serialize());
+        tx.input[0].witness = witness;
+        let Some(fee) = fee_rate.fee_vb(tx.vsize() as u64) else {
+            anyhow::bail!("fee overflow")
+        };
+        tx.output[0].value = prevout.value.checked_sub(fee)
+            .ok_or_else(|| anyhow::anyhow!("WarningTx output does not cover the fee."))?;
+
+        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(0, &Prevouts::All(&[prevout]), leaf_hash, TapSighashType::Default)?;
+        let keypair = Keypair::from_seckey_slice(&secp, &claim_leaf_sec.serialize())?;
+        let mut aux_rand = [0u8; 32];
+        rand::rng().fill(&mut aux_rand);
+        let sig = secp.sign_schnorr_with_aux_rand(&Message::from(sighash), &keypair, &aux_rand);
+
+        let mut witness = Witness::new();
+        witness.push(sig.as_ref());
+        witness.push(script.as_bytes());
+        witness.push(control_block.serialize());
+        tx.input[0].witness = witness;
+        Ok(tx)
     }
 }
 
@@ -590,10 +1512,13 @@ pub struct WarningTx {
     pub sig_p: Option<TMuSig2>,
     pub sig_q: Option<TMuSig2>,
     pub key_spend: Option<AggKey>,
+    pub claim_leaf_point: Option<Point>, // key of the warning party in the claim leaf, if any
+    pub claim_leaf: Option<ScriptBuf>,
     pub tx: Option<Transaction>,
 }
 
-const ANCHOR_AMOUNT: Amount = Amount::from_sat(330); // 330 is std amount for anchors
+// TODO the burningmen shall publish their silent payment addresses
+const DAO_SP_RECEIVER: &str = "sprt1qqd8n2k7uklxq4aegau7vawtptkgxsja4kt99lpv6krctwpq8tpc65qjxd4lu4etruh9sngx3su9mtqp5fqzxz7re59y5nnez9p03ht3lyult5ra5";
 
 impl WarningTx {
     pub fn get_tx(&self) -> &Transaction { self.tx.as_ref().unwrap() }
@@ -610,37 +1535,43 @@ impl WarningTx {
             sig_p: None,
             sig_q: None,
             key_spend: None,
+            claim_leaf_point: None,
+            claim_leaf: None,
             tx: None,
         }
     }
 
-    fn build(&mut self, ctx: &mut BMPContext, p_tik: &AggKey, q_tik: &AggKey, deposit_tx: &DepositTx) -> anyhow::Result<Transaction> {
+    /**
+    creates our nonces and the key of the WarningTx output (with the claim leaf, if any).
+    Nothing of this depends on the DepositTx, so the nonces can be sent before the transaction is known.
+    */
+    fn prepare(&mut self, p_tik: &AggKey, q_tik: &AggKey) -> anyhow::Result<()> {
         self.sig_p = Some(TMuSig2::new(p_tik.clone()));
         self.sig_q = Some(TMuSig2::new(q_tik.clone()));
 
-        //--------------------
-        let key_spend = match self.role {
+        let mut key_spend = match self.role {
             ProtocolRole::Seller => q_tik,
             ProtocolRole::Buyer => p_tik
-        };
-        self.key_spend = Some(key_spend.clone());
+        }.clone();
+        if let Some(claim_leaf_point) = self.claim_leaf_point {
+            let script = tx::claim_leaf(claim_leaf_point)?;
+            key_spend = key_spend.with_merkle_root(tx::claim_leaf_merkle_root(&script))?;
+            self.claim_leaf = Some(script);
+        }
+        self.key_spend = Some(key_spend);
+        Ok(())
+    }
 
-        let all_amount = ctx.buyer_amount.add(ctx.seller_amount).sub(ANCHOR_AMOUNT).sub(Amount::from_sat(1000)); //TODO calc fee rate.sub();
-        let output0 = TxOut {
-            value: all_amount,
-            script_pubkey: key_spend.get_agg_script_pubkey()?,
-        };
-        let output1 = TxOut {
-            value: ANCHOR_AMOUNT,
-            script_pubkey: self.anchor_spend.clone().unwrap(),
-        };
+    fn build(&mut self, ctx: &mut BMPContext, p_tik: &AggKey, q_tik: &AggKey, deposit_tx: &DepositTx) -> anyhow::Result<Transaction> {
+        let key_spend = self.key_spend.as_ref().unwrap();
         let deposit_tx = deposit_tx.tx.as_ref().unwrap();
-        let tx = Transaction {
-            output: vec![output0.clone(), output1],
-            input: vec![deposit_tx.get_txin_for(p_tik)?, deposit_tx.get_txin_for(q_tik)?],
-            lock_time: absolute::LockTime::ZERO,
-            version: transaction::Version::TWO,
-        };
+        let tx = tx::warning_tx(
+            [deposit_tx.get_outpoint_for(p_tik)?, deposit_tx.get_outpoint_for(q_tik)?],
+            ctx.buyer_amount.add(ctx.seller_amount),
+            key_spend.get_agg_script_pubkey()?,
+            self.anchor_spend.clone().unwrap(),
+            ctx.agreed_fee_rates()?.prepared,
+        )?;
         self.tx = Some(tx.clone());
         dbg!(ctx.role, self.role,  tx.clone().compute_txid()); //output0.script_pubkey); //
         Ok(tx)
@@ -680,7 +1611,7 @@ impl WarningTx {
     }
 
     pub(crate) fn broadcast(&self, me: &BMPContext) -> Txid {
-        me.funds.client.transaction_broadcast(self.tx.as_ref().unwrap()).unwrap()
+        me.funds.lock().client.transaction_broadcast(self.tx.as_ref().unwrap()).unwrap()
     }
 }
 /**
@@ -697,7 +1628,7 @@ pub struct SwapTx {
 impl SwapTx {
     pub(crate) fn spend_condition(&mut self, ctx: &mut BMPContext) -> Option<ScriptBuf> {
         self.swap_spend = match self.role {
-            ProtocolRole::Seller => Some(ctx.funds.wallet.next_unused_address(KeychainKind::External).script_pubkey()),
+            ProtocolRole::Seller => Some(ctx.funds.lock().wallet.reveal_next_address(KeychainKind::External).script_pubkey()),
             ProtocolRole::Buyer => None,
         };
         self.swap_spend.clone()
@@ -719,10 +1650,12 @@ impl SwapTx {
         self.fund_sig.as_ref().unwrap().pub_nonce.clone()
     }
 
-    // round 1
-    pub fn build(&mut self, q_tik: AggKey, deposit_tx: &Transaction, swap_spend_opt: Option<ScriptBuf>) -> anyhow::Result<Transaction> {
-        let dep_index = deposit_tx.output_index(&q_tik);
+    pub fn prepare(&mut self, q_tik: AggKey) {
         self.fund_sig = Some(TMuSig2::new(q_tik));
+    }
+
+    pub fn build(&mut self, deposit_tx: &Transaction, swap_spend_opt: Option<ScriptBuf>, fee_rate: FeeRate) -> anyhow::Result<Transaction> {
+        let dep_index = deposit_tx.output_index(&self.fund_sig.as_ref().unwrap().agg_key);
         let Some(use_spend) = (match self.role {
             ProtocolRole::Seller => self.swap_spend.clone(),
             ProtocolRole::Buyer => swap_spend_opt,
@@ -730,26 +1663,7 @@ impl SwapTx {
 
         let buyer_deposit_out = OutPoint::new(deposit_tx.compute_txid(), dep_index);
         let buyer_deposit_amount = deposit_tx.output.get(dep_index as usize).unwrap().value;
-
-        let input = TxIn {
-            previous_output: buyer_deposit_out,
-            script_sig: ScriptBuf::default(), // empty for p2tr
-            sequence: Sequence::MAX,
-            witness: Witness::default(), // will change after signing
-        };
-        // TODO do real calculation of fees
-        let payout_amount = buyer_deposit_amount.sub(Amount::from_sat(1000)); // TODO fee calculation?
-        // anchor output not neccessary, because Seller can spend the output for CPFP
-        let output = TxOut {
-            value: payout_amount,
-            script_pubkey: use_spend,
-        };
-        let unsigned_tx = Transaction {
-            version: transaction::Version::TWO,  // Post BIP-68.
-            lock_time: absolute::LockTime::ZERO, // Ignore the locktime.
-            input: vec![input],                  // Input goes into index 0.
-            output: vec![output],         // Outputs, order does not matter.
-        };
+        let unsigned_tx = tx::swap_tx(buyer_deposit_out, buyer_deposit_amount, use_spend, fee_rate)?;
         self.tx = Some(unsigned_tx.clone());
         Ok(unsigned_tx)
     }
@@ -809,7 +1723,6 @@ impl SwapTx {
         // in swapTx reveal2Other makes only sense, when Seller gives to Buyer the secret key for p_tik
         if self.role == ProtocolRole::Buyer {
             fund_sig.reveal2other(&signature, p_tik)?;
-            println!("revealed p_tik aggregated secret key: {:?}", p_tik.agg_sec);
             // p_tik shall have the other sec key and the aggregated secret key.
             // TODO Bob can import now the aggregated key into his wallet. there is no risc that Alice may
             // publish any transaction messing with it.
@@ -818,12 +1731,13 @@ impl SwapTx {
     }
 
     pub(crate) fn broadcast(&self, me: BMPContext) -> Txid {
-        me.funds.client.transaction_broadcast(self.tx.as_ref().unwrap()).unwrap()
+        me.funds.lock().client.transaction_broadcast(self.tx.as_ref().unwrap()).unwrap()
     }
 }
 
 pub struct DepositTx {
     pub part_psbt: Option<Psbt>,
+    pub unsigned_psbt: Option<Psbt>, // merged, waiting for an external signer
     pub signed_psbt: Option<Psbt>,
     pub tx: Option<Transaction>,
 }
@@ -833,6 +1747,7 @@ impl DepositTx {
     fn new() -> DepositTx {
         DepositTx {
             part_psbt: None,
+            unsigned_psbt: None,
             signed_psbt: None,
             tx: None,
         }
@@ -845,23 +1760,45 @@ impl DepositTx {
             ProtocolRole::Buyer => (q_a, ctx.buyer_amount),
         };
         // create and fund a (virtual) transaction which funds Alice part of the Deposit Tx
-        let mut builder = ctx.funds.wallet.build_tx();
+        // keep the wallet locked until the selected UTXOs are reserved, other trades may share the wallet.
+        let mut funds = ctx.funds.lock();
+        let mut builder = funds.wallet.build_tx();
         builder.add_recipient(
             funded_by_me.key_spend_no_merkle_address()?.script_pubkey(), amount,
         );
-        builder.fee_rate(FeeRate::from_sat_per_vb(20).unwrap()); // TODO feerates shall come from pricenodes
+        builder.unspendable(ctx.funds.reserved());
+        builder.fee_rate(ctx.agreed_fee_rates()?.deposit);
         let pbst = builder.finish()?;
+        let inputs: Vec<OutPoint> = pbst.unsigned_tx.input.iter().map(|input| input.previous_output).collect();
+        ctx.funds.reserve(ctx.lease, &inputs)?;
+        drop(funds);
         self.part_psbt = Some(pbst.clone());
         // dbg!(&pbst.unsigned_tx.output);
         Ok(pbst)
     }
 
-    pub fn build_and_merge_tx(&mut self, ctx: &mut BMPContext, other_psbt: &Psbt, p_tik: &AggKey, q_tik: &AggKey) -> anyhow::Result<Psbt> {
+    /**
+    Signs the PSBT from `build_merged_psbt()` with our hot wallet.
+    */
+    pub fn sign_merged_psbt(&mut self, ctx: &mut BMPContext) -> anyhow::Result<Psbt> {
+        let mut merged_psbt = self.unsigned_psbt.take().unwrap();
+        ctx.funds.lock().wallet.sign(&mut merged_psbt, SignOptions::default())?;
+        self.signed_psbt = Some(merged_psbt.clone());
+        Ok(merged_psbt)
+    }
+
+    /**
+    Builds the merged DepositTx, but doesn't sign it. The returned PSBT can be handed out to an external
+    signer (cold or watch-only wallet), which must only add signatures for our inputs.
+    The signed PSBT goes back in with `accept_external_signature()`.
+    */
+    pub fn build_merged_psbt(&mut self, ctx: &mut BMPContext, other_psbt: &Psbt, p_tik: &AggKey, q_tik: &AggKey) -> anyhow::Result<Psbt> {
         let my_psbt = self.part_psbt.as_ref().unwrap();
+        let mut funds = ctx.funds.lock();
         //sanity check that Bob doesn't send UTXOs owned by alice.
         for pbst_input in other_psbt.inputs.iter() {
             let scriptbuf = pbst_input.witness_utxo.clone().unwrap().script_pubkey;
-            if ctx.funds.wallet.is_mine(scriptbuf.clone()) {
+            if funds.wallet.is_mine(scriptbuf.clone()) {
                 // bob is trying to trick me.
                 panic!("Fraud detected. Bob send me my own scriptbuf {:?}", scriptbuf)
             }
@@ -869,7 +1806,7 @@ impl DepositTx {
         // TODO sanity check if bobs transaction actually calculate the fee correctly, otherwise he could save on fess at the expense of alice
 
         // recreate combined ty from scratch
-        let mut builder = ctx.funds.wallet.build_tx();
+        let mut builder = funds.wallet.build_tx();
         builder.manually_selected_only(); // only use inputs we have already identified.
         builder.set_exact_sequence(Sequence::MAX); // no RBF, RBF disabled for foreign utxos anyway.
         // keep track of the fees, as total fees is not equal to sum of fess from alice and bob psbts.
@@ -907,14 +1844,83 @@ impl DepositTx {
         // TODO use builder.ordering() with custom alg.
         sort(&mut merged_psbt);
 
-        // sign my psbt
-        ctx.funds.wallet.sign(&mut merged_psbt, SignOptions::default())?;
-        self.signed_psbt = Some(merged_psbt.clone());
+        self.unsigned_psbt = Some(merged_psbt.clone());
         self.part_psbt = None; // make sure not reused.
         self.tx = Some(merged_psbt.clone().extract_tx()?);
         Ok(merged_psbt)
     }
 
+    /**
+    Takes back the PSBT from `build_merged_psbt()` after it has been signed externally.
+    The external signer must not have touched anything else than the signatures of our inputs,
+    so we compare it against our own copy and check each of our signatures.
+    */
+    pub fn accept_external_signature(&mut self, ctx: &mut BMPContext, mut signed_psbt: Psbt) -> anyhow::Result<Psbt> {
+        let Some(unsigned_psbt) = self.unsigned_psbt.as_ref() else {
+            anyhow::bail!("No DepositTx waiting for an external signature.")
+        };
+        let Some(deposit_tx) = self.tx.as_ref() else {
+            anyhow::bail!("No DepositTx has been built.")
+        };
+        if signed_psbt.unsigned_tx != unsigned_psbt.unsigned_tx {
+            anyhow::bail!("External signer modified the DepositTx.")
+        }
+        if signed_psbt.inputs.len() != unsigned_psbt.inputs.len() || signed_psbt.outputs.len() != unsigned_psbt.outputs.len() {
+            anyhow::bail!("External signer added or removed PSBT inputs or outputs.")
+        }
+        // the signed tx must spend exactly our inputs and pay exactly our outputs, in the same order.
+        let outpoints = |tx: &Transaction| tx.input.iter().map(|input| input.previous_output).collect::<Vec<_>>();
+        if outpoints(&signed_psbt.unsigned_tx) != outpoints(deposit_tx) {
+            anyhow::bail!("External signer changed the inputs of the DepositTx.")
+        }
+        if signed_psbt.unsigned_tx.output != deposit_tx.output {
+            anyhow::bail!("External signer changed the outputs of the DepositTx.")
+        }
+        // signers usually only add tap_key_sig, so finalize what is ours.
+        let funds = ctx.funds.lock();
+        funds.wallet.finalize_psbt(&mut signed_psbt, SignOptions::default())?;
+
+        let prevouts = unsigned_psbt.inputs.iter()
+            .map(|input| input.witness_utxo.clone().ok_or_else(|| anyhow::anyhow!("witness_utxo missing in psbt")))
+            .collect::<anyhow::Result<Vec<TxOut>>>()?;
+        let mut sighasher = SighashCache::new(&unsigned_psbt.unsigned_tx);
+        let secp = Secp256k1::verification_only();
+
+        for (index, (signed_input, unsigned_input)) in signed_psbt.inputs.iter().zip(unsigned_psbt.inputs.iter()).enumerate() {
+            if signed_input.witness_utxo.as_ref() != Some(&prevouts[index]) {
+                anyhow::bail!("External signer changed the funding output of input {}.", index)
+            }
+            let script = &prevouts[index].script_pubkey;
+            if !funds.wallet.is_mine(script.clone()) {
+                // peer input, must come back exactly as we handed it out.
+                if signed_input != unsigned_input {
+                    anyhow::bail!("External signer touched input {} which is not ours.", index)
+                }
+                continue;
+            }
+            // our input, must carry a valid key spend signature.
+            let Some(witness) = signed_input.final_script_witness.as_ref() else {
+                anyhow::bail!("External signer did not sign our input {}.", index)
+            };
+            if witness.len() != 1 || !script.is_p2tr() {
+                anyhow::bail!("Expected a P2TR key spend for our input {}.", index)
+            }
+            let signature = Signature::from_slice(&witness[0])?;
+            // anything but SIGHASH_ALL would leave parts of the DepositTx open to change after signing.
+            if !matches!(signature.sighash_type, TapSighashType::Default | TapSighashType::All) {
+                anyhow::bail!("External signer used sighash type {} for our input {}, only ALL is accepted.", signature.sighash_type, index)
+            }
+            let sighash = sighasher.taproot_key_spend_signature_hash(index, &Prevouts::All(&prevouts), signature.sighash_type)?;
+            let output_key = XOnlyPublicKey::from_slice(&script.as_bytes()[2..34])?;
+            secp.verify_schnorr(&signature.signature, &Message::from(sighash), &output_key)
+                .map_err(|e| anyhow::anyhow!("Invalid signature for our input {}: {}", index, e))?;
+        }
+
+        self.signed_psbt = Some(signed_psbt.clone());
+        self.unsigned_psbt = None;
+        Ok(signed_psbt)
+    }
+
     fn transfer_sig_and_broadcast(&mut self, ctx: &mut BMPContext,
                                   psbt_bob: Psbt,   // bobs psbt should be same as mine but have bob's sig
     ) -> anyhow::Result<Txid> {
@@ -936,7 +1942,7 @@ impl DepositTx {
         self.tx = Some(tx.clone());
         self.signed_psbt = None; // remove used data
         // TODO alice and bob will broadcast, is that a bug or a feature?
-        let depo_txid = ctx.funds.transaction_broadcast(&tx)?;
+        let depo_txid = ctx.funds.lock().transaction_broadcast(&tx)?;
         dbg!("DepositTx txid: {:?}", &depo_txid);
         Ok(depo_txid)
     }
@@ -957,290 +1963,6 @@ impl DepositTx {
     }
 }
 
-/**
-MuSig2 interaction, it represents the Key not only our side of the equation
-*/
-
-#[derive(PartialEq, Clone)]
-#[derive(Debug)]
-pub struct AggKey {
-    pub sec: Scalar,
-    pub other_sec: Option<Scalar>,
-    pub agg_sec: Option<Scalar>,
-    pub pub_point: Point,
-    pub other_point: Option<Point>,
-    pub(crate) agg_point: Option<Point>,
-    pub(crate) key_agg_context: Option<KeyAggContext>,
-}
-
-impl AggKey {
-    pub fn new() -> anyhow::Result<AggKey> {
-        //TODO is this random sufficient?
-        let mut seed = [0u8; 32];
-        rand::rng().fill(&mut seed);
-
-        let sec: Scalar = Scalar::from_slice(&seed)?;
-        let point = sec.base_point_mul();
-        Ok(AggKey { sec, other_sec: None, agg_sec: None, pub_point: point, other_point: None, agg_point: None, key_agg_context: None })
-    }
-
-    pub fn aggregate_key(&mut self, point_from_bob: Point) -> anyhow::Result<Point> {
-        assert_ne!(point_from_bob, self.pub_point, "Bob is sending my point back.");
-        // order of pubkeys must be the same as order of secret keys.
-        // we use the smaller pubkey-value first. see reveal_other for secret keys.
-        let pubkeys = if self.pub_point < point_from_bob {
-            [self.pub_point, point_from_bob]
-        } else {
-            [point_from_bob, self.pub_point]
-        };
-        // dbg!(&pubkeys);
-        let ctx1 = KeyAggContext::new(pubkeys)?;
-        let ctx = ctx1.with_unspendable_taproot_tweak()?;
-        let result = ctx.aggregated_pubkey();
-        self.key_agg_context = Some(ctx);
-        self.agg_point = Some(result);
-        self.other_point = Some(point_from_bob);
-        Ok(result)
-    }
-    // check https://bitcoin.stackexchange.com/questions/116384/what-are-the-steps-to-convert-a-private-key-to-a-taproot-address
-    pub(crate) fn get_agg_adr(&self) -> anyhow::Result<Address> {
-        self.key_agg_context.as_ref().unwrap().aggregated_pubkey_untweaked::<Point>().key_spend_no_merkle_address()
-    }
-    pub(crate) fn get_agg_script_pubkey(&self) -> anyhow::Result<ScriptBuf> {
-        Ok(self.get_agg_adr()?.script_pubkey())
-    }
-}
-/**
- MuSig2 (non-adaptive), constructing a signature
-
-round n: new(agg_key) -> pub_nonce
-round n+1: generate partial adapted sig -> part-sig
-round n+2: aggregate sig (and publish)
-*//**
-adaptive MuSig2, constructing a signature
-
-round n: new(agg_key) -> pub_nonce
-round n+1: generate partial adapted sig -> part-sig
-round n+2: aggregate sig (and publish)
-*/
-pub struct TMuSig2 {
-    pub agg_key: AggKey,
-    sec_nonce: SecNonce,
-    pub_nonce: PubNonce,
-    agg_nonce: Option<AggNonce>,
-    other_nonce: Option<PubNonce>,
-    pub adaptor_sig: Option<Adaptor>,
-}
-
-#[derive(Debug)]
-pub struct Adaptor {
-    pub partial_sig: PartialSignature,
-    pub input_index: usize, // which input in our transaction is going to use this signature?
-    pub pub_adaptor: MaybePoint, // this is the image for which the other party must provide the pre-image in order to use this sig.
-    pub msg: Hash<TapSighashTag>, // message to be signed
-    pub adaptor_signature: Option<AdaptorSignature>,
-}
-
-impl TMuSig2 {
-    pub fn new(agg_key: AggKey) -> TMuSig2 {
-        // there must be the aggregated key at this point
-        assert!(agg_key.agg_point.is_some());
-        let mut seed = [0u8; 32];
-        rand::rng().fill(&mut seed);
-        let mut seed2 = [0u8; 32];
-        rand::rng().fill(&mut seed2);
-        let sec_nonce = SecNonceBuilder::new(seed)
-            .with_aggregated_pubkey(agg_key.agg_point.unwrap())
-            .with_extra_input(&seed2) //TODO does this help? Or do we need more random?
-            // TODO check  BIP327 for nonce generation.
-            .build();
-        let pub_nonce = sec_nonce.public_nonce();
-        TMuSig2 { agg_key, sec_nonce, pub_nonce, agg_nonce: None, other_nonce: None, adaptor_sig: None }
-    }
-
-    pub fn generate_partial_sig(&mut self,
-                                input_index: usize, // which input in our transaction is going to use this signature?
-                                other_nonce: &PubNonce, // the public nonce from the other side to calc the aggregated nonce
-                                prevouts: &Vec<TxOut>, // the TxOuts from the previous transaction is part of the sig-alg in taproot
-                                tx: &Transaction) // the current transaction which needs the signature
-                                -> anyhow::Result<PartialSignature> { // the partial transaction with adaptor to be sent to the other party.
-        // sign_partial()
-        self.generate_adapted_partial_sig(input_index, MaybePoint::Infinity, other_nonce, prevouts, tx)
-    }
-
-    pub fn generate_adapted_partial_sig(&mut self,
-                                        input_index: usize, // which input in our transaction is going to use this signature?
-                                        pub_adaptor: MaybePoint, // this is the image for which the other party must provide the pre-image in order to use this sig.
-                                        other_nonce: &PubNonce, // the public nonce from the other side to calc the aggregated nonce
-                                        prevouts: &Vec<TxOut>, // the TxOuts from the previous transaction is part of the sig-alg in taproot
-                                        tx: &Transaction) // the current transaction which needs the signature
-                                        -> anyhow::Result<PartialSignature> { // the partial transaction with adaptor to be sent to the other party.
-        // calculate aggregated nonce first.
-        let total_nonce = [self.pub_nonce.clone(), other_nonce.clone()];
-        let agg_nonce = AggNonce::sum(total_nonce);
-        self.agg_nonce = Some(agg_nonce.clone());
-        self.other_nonce = Some(other_nonce.clone());
-
-        let msg = Self::extract_message_from_tx(input_index, prevouts, tx);
-
-        // see also trader:wallet::create_keyspend_payout_signature
-        // BIP-341: "the message commits to the scriptPubKeys of all outputs spent by the transaction."
-        let partial_signature = musig2::adaptor::sign_partial(
-            self.agg_key.key_agg_context.as_ref().unwrap(),
-            self.agg_key.sec,
-            self.sec_nonce.clone(),
-            &agg_nonce,
-            pub_adaptor,
-            msg)?;
-
-        self.adaptor_sig = Some(Adaptor {
-            partial_sig: partial_signature,
-            input_index,
-            pub_adaptor,
-            msg,
-            adaptor_signature: None,
-        });
-
-        // secure nonce is used, delete it to protect against reuse
-        self.sec_nonce = SecNonce::new(Scalar::one(), Scalar::one());
-
-        Ok(partial_signature)
-    }
-
-    fn _get_part_sig(&self) -> PartialSignature {
-        self.adaptor_sig.as_ref().unwrap().partial_sig.clone()
-    }
-    /**
-    this is probably only called by Alice, the seller as the swapTx is only contructed by her.
-    the aggregated sig is still not valid, needs to be adapted.
-    */
-    pub fn aggregate_sigs(&mut self, other_sig: PartialSignature) -> anyhow::Result<()> {
-        let my_adaptor = self.adaptor_sig.as_mut().unwrap();
-        // verify other_sig is strictly not necessary but fail fast is always good
-        musig2::adaptor::verify_partial(
-            self.agg_key.key_agg_context.as_ref().unwrap(),
-            other_sig,
-            self.agg_nonce.as_ref().unwrap(),
-            my_adaptor.pub_adaptor,
-            self.agg_key.other_point.unwrap(),
-            self.other_nonce.as_ref().unwrap(),
-            my_adaptor.msg,
-        )
-            .expect("invalid partial signature");
-
-        let my_sig = my_adaptor.partial_sig.clone();
-
-        let agg_signature = musig2::adaptor::aggregate_partial_signatures(
-            self.agg_key.key_agg_context.as_ref().unwrap(),
-            self.agg_nonce.as_ref().unwrap(),
-            my_adaptor.pub_adaptor,
-            [my_sig, other_sig],
-            my_adaptor.msg,
-        )?;
-        my_adaptor.adaptor_signature = Some(agg_signature);
-
-        // Verify the adaptor signature is valid for the given adaptor point and pubkey.
-        musig2::adaptor::verify_single(
-            *self.agg_key.agg_point.as_ref().unwrap(),
-            &agg_signature,
-            my_adaptor.msg,
-            my_adaptor.pub_adaptor,
-        )
-            .expect("invalid aggregated adaptor signature");
-        Ok(())
-    }
-    pub fn sign(&mut self, sec_adaptor: MaybeScalar, tx: Transaction) -> anyhow::Result<Transaction> {
-        let my_adaptor = self.adaptor_sig.as_mut().unwrap();
-        // Decrypt the signature with the adaptor secret.
-        let valid_signature: LiftedSignature = my_adaptor.adaptor_signature.unwrap()
-            .adapt(sec_adaptor)
-            .unwrap();
-
-        // this check shall be authoritative
-        musig2::verify_single(
-            self.agg_key.agg_point.unwrap(),
-            valid_signature,
-            my_adaptor.msg,
-        )
-            .expect("invalid decrypted adaptor signature");
-
-        // valid_signature must be made into Taprrot signature, means we need to tweak with merkle_root (even if we don't have a merkle root)
-        // stuff the valid signature into the transaction
-        let ts = bitcoin::taproot::Signature::from_slice(valid_signature.serialize().as_ref())?;
-
-        let mut sighasher = SighashCache::new(tx);
-        *sighasher.witness_mut(my_adaptor.input_index).unwrap() = Witness::p2tr_key_spend(&ts);
-        let tx = sighasher.into_transaction();
-        // dbg!(&tx);
-        Ok(tx)
-    }
-    /**
-    Now let say Alice has posted the SwapTx, then Bob wants to reveal the secret for the public adaptor from the Transaction.
-    */
-    pub fn reveal(&self, final_sig: &Signature) -> anyhow::Result<Scalar> {
-        // LiftedSignature::from_bytes(Sign)
-        let sig = self.adaptor_sig.as_ref().unwrap().adaptor_signature.unwrap();
-        let lifted_sig = &LiftedSignature::from_bytes(final_sig.serialize().as_ref())?;
-        let revealed: MaybeScalar = sig.reveal_secret(lifted_sig).unwrap();
-        let sec_adaptor = revealed.unwrap();
-        Ok(sec_adaptor)
-    }
-
-    pub fn reveal2other(&self, final_sig: &Signature, tik: &mut AggKey) -> anyhow::Result<()> {
-        let sec_adaptor = self.reveal(final_sig)?;
-        tik.other_sec = Some(sec_adaptor);
-        // calculate combined key as well.
-        // array of seckeys must have same order as pubkeys. sort by pubkey
-        let seckeys = if tik.pub_point < tik.other_point.unwrap() {
-            [tik.sec, sec_adaptor]
-        } else {
-            [sec_adaptor, tik.sec]
-        };
-        let agg_sec = tik.key_agg_context.as_mut().unwrap().aggregated_seckey(seckeys)?;
-        // lib has checked that the aggregated generated key actually works
-        tik.agg_sec = Some(agg_sec);
-        Ok(())
-    }
-    pub fn extract_p2tr_key_path_signature(tx: &Transaction, input_index: usize) -> anyhow::Result<Signature> {
-        // Ensure the input index is valid
-        if input_index >= tx.input.len() {
-            anyhow::bail!("Invalid input index: {}", input_index);
-        }
-
-        let input: &TxIn = &tx.input[input_index];
-        let witness = &input.witness;
-
-        // For key path spending, the witness should contain exactly one element
-        if witness.len() != 1 {
-            anyhow::bail!("The witness does not contain the correct structure for P2TR key path spending");
-        }
-
-        // The first (and only) element in the witness should be the Schnorr signature
-        let raw_signature = &witness[0];
-
-        // Ensure the signature is 64 bytes long (we use only SigHash::Default)
-        if raw_signature.len() != 64 {
-            anyhow::bail!("Invalid Schnorr signature size (expected 64 bytes)");
-        }
-
-        // Parse the Schnorr signature
-        let schnorr_sig = Signature::from_slice(raw_signature)?;
-
-        Ok(schnorr_sig)
-    }
-
-    pub fn extract_message_from_tx(input_index: usize, prevouts: &Vec<TxOut>, unsigned_tx: &Transaction) -> Hash<TapSighashTag> {
-        let sighash_type = TapSighashType::Default; // we are using in Musig only Default which is effectively equiv. to SIGHASH_ALL
-        let prevouts = Prevouts::All(&prevouts);
-
-        let mut sighasher = SighashCache::new(unsigned_tx);
-        let sighash = sighasher
-            // TODO report missing validation to rust-bitcoin if index is not correct.
-            .taproot_key_spend_signature_hash(input_index, &prevouts, sighash_type)
-            .expect("failed to construct sighash");
-        sighash.to_raw_hash()
-    }
-}
 trait PointExt {
     fn key_spend_no_merkle_address(&self) -> anyhow::Result<Address>;
 }
@@ -1288,23 +2010,8 @@ impl TransactionExt for Transaction {
     For Taproot signing, we need for all inputs of this transactions to look into the outpoint of TxIn and find the referenced transaction output (TxOut).
     this must be supplied for signing.
      */
-    fn calc_prevouts(&self, inputs: &Vec<TxIn>) -> anyhow::Result<Vec<TxOut>> { // TODO use TransactionExt::calc_prevouts instead
-        // TODO this is subject to performance optimization
-        let mut prevouts = Vec::new();
-
-        for input in inputs {
-            let outpoint = input.previous_output;
-            if self.compute_txid() == outpoint.txid {
-                if let Some(output) = self.output.get(outpoint.vout as usize) {
-                    prevouts.push(output.clone());
-                } else {
-                    anyhow::bail!("Output index {} not found in transaction for OutPoint: {:?}", outpoint.vout, outpoint);
-                }
-            } else {
-                anyhow::bail!("Transaction not found for OutPoint: {:?}", outpoint);
-            }
-        }
-        Ok(prevouts)
+    fn calc_prevouts(&self, inputs: &Vec<TxIn>) -> anyhow::Result<Vec<TxOut>> {
+        Ok(tx::prevouts(self, inputs)?)
     }
 }
 /*
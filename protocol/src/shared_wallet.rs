@@ -0,0 +1,103 @@
+use crate::protocol_musig_adaptor::MemWallet;
+use bdk_wallet::bitcoin::OutPoint;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/**
+Identifies the trade, which has reserved some UTXOs of the wallet.
+*/
+pub type LeaseId = u64;
+
+/**
+A handle to a wallet which is shared by many concurrent trades (e.g. market makers).
+Every trade takes a lease and reserves the UTXOs it puts into its half of the DepositTx,
+so no other trade can select them. The reservation holds from round 1 until the
+DepositTx is confirmed (see `sync()`) or the trade is aborted (see `release()`).
+*/
+#[derive(Clone)]
+pub struct SharedWallet {
+    // NOTE: lock order is 'wallet' first, then 'reservations'.
+    wallet: Arc<Mutex<MemWallet>>,
+    reservations: Arc<Mutex<HashMap<OutPoint, LeaseId>>>,
+    next_lease: Arc<AtomicU64>,
+}
+
+impl SharedWallet {
+    pub fn new(wallet: MemWallet) -> SharedWallet {
+        SharedWallet {
+            wallet: Arc::new(Mutex::new(wallet)),
+            reservations: Arc::new(Mutex::new(HashMap::new())),
+            next_lease: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, MemWallet> {
+        self.wallet.lock().unwrap()
+    }
+
+    pub fn new_lease(&self) -> LeaseId {
+        self.next_lease.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /**
+    all outpoints which are reserved by any trade. Those must not be used for coin selection.
+    */
+    pub fn reserved(&self) -> Vec<OutPoint> {
+        self.reservations.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn leased(&self, lease: LeaseId) -> Vec<OutPoint> {
+        self.reservations.lock().unwrap().iter()
+            .filter(|(_, l)| **l == lease)
+            .map(|(op, _)| *op)
+            .collect()
+    }
+
+    /**
+    reserves all outpoints for the lease, or none of them if one is already reserved by another trade.
+    */
+    pub fn reserve(&self, lease: LeaseId, outpoints: &[OutPoint]) -> anyhow::Result<()> {
+        let mut reservations = self.reservations.lock().unwrap();
+        if let Some(op) = outpoints.iter().find(|op| reservations.get(op).is_some_and(|l| *l != lease)) {
+            anyhow::bail!("UTXO {} is already reserved by another trade.", op)
+        }
+        for op in outpoints {
+            reservations.insert(*op, lease);
+        }
+        Ok(())
+    }
+
+    /**
+    gives the UTXOs of an aborted trade back to the wallet.
+    */
+    pub fn release(&self, lease: LeaseId) {
+        self.reservations.lock().unwrap().retain(|_, l| *l != lease);
+    }
+
+    /**
+    syncs the wallet and drops all reservations of UTXOs, which have been spent in a confirmed transaction.
+    */
+    pub fn sync(&self) -> anyhow::Result<()> {
+        let mut wallet = self.lock();
+        wallet.sync()?;
+        self.release_confirmed(&wallet);
+        Ok(())
+    }
+
+    fn release_confirmed(&self, mem_wallet: &MemWallet) {
+        let wallet = &mem_wallet.wallet;
+        self.reservations.lock().unwrap().retain(|op, _| {
+            let spent_confirmed = wallet.tx_graph().outspends(*op).iter()
+                .filter_map(|txid| wallet.get_tx(*txid))
+                .any(|tx| tx.chain_position.is_confirmed());
+            !spent_confirmed
+        });
+    }
+}
+
+impl From<MemWallet> for SharedWallet {
+    fn from(wallet: MemWallet) -> Self {
+        SharedWallet::new(wallet)
+    }
+}
@@ -0,0 +1,432 @@
+/*!
+BIP-352 silent payments for outputs of transactions, which are spent from a MuSig2 key.
+No trader knows the secret key `a` of the input, so the ECDH `a·B_scan` is done in parts:
+each trader calculates `d_i·B_scan` with its share `d_i` of the (tweaked) aggregated secret key,
+such that `a = d_1 + d_2`. The shares are exchanged and summed up, then both traders derive
+the same output keys as a single-key sender would.
+See concept/SilentPayment.md
+*/
+
+use bdk_wallet::bitcoin::bech32::primitives::decode::CheckedHrpstring;
+use bdk_wallet::bitcoin::bech32::{Bech32m, ByteIterExt, Fe32, Fe32IterExt, Hrp};
+use bdk_wallet::bitcoin::consensus::encode::{deserialize, serialize};
+use bdk_wallet::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bdk_wallet::bitcoin::key::TweakedPublicKey;
+use bdk_wallet::bitcoin::{OutPoint, ScriptBuf, Transaction, TxOut, XOnlyPublicKey};
+use musig2::secp::MaybePoint::Valid;
+use musig2::secp::{MaybePoint, MaybeScalar, Point, Scalar, G};
+use musig_core::key::AggKey;
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+
+const HRP_REGTEST: &str = "sprt"; // TODO parameterize Network, "sp" for mainnet and "tsp" for testnet
+
+/**
+static silent payment address of a receiver, version 0 only.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SilentPaymentAddress {
+    pub scan: Point,
+    pub spend: Point,
+}
+
+impl FromStr for SilentPaymentAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut checked = CheckedHrpstring::new::<Bech32m>(s)?;
+        if !["sp", "tsp", HRP_REGTEST].contains(&checked.hrp().as_str()) {
+            anyhow::bail!("{} is not a silent payment address.", s)
+        }
+        if checked.remove_witness_version() != Some(Fe32::Q) {
+            anyhow::bail!("Only silent payment addresses of version 0 are supported.")
+        }
+        let data: Vec<u8> = checked.byte_iter().collect();
+        if data.len() != 66 {
+            anyhow::bail!("Silent payment address must contain 66 bytes of keys, got {}.", data.len())
+        }
+        Ok(SilentPaymentAddress {
+            scan: Point::from_slice(&data[..33])?,
+            spend: Point::from_slice(&data[33..])?,
+        })
+    }
+}
+
+impl fmt::Display for SilentPaymentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hrp = Hrp::parse(HRP_REGTEST).expect("valid hrp");
+        let chars = self.scan.serialize().into_iter()
+            .chain(self.spend.serialize())
+            .bytes_to_fes()
+            .with_checksum::<Bech32m>(&hrp)
+            .with_witness_version(Fe32::Q)
+            .chars();
+        for c in chars {
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+the part of the ECDH a trader contributes for one scan key: `C_i = d_i·B_scan`.
+The DLEQ proof (BIP-374) shows that `C_i` and `A_i = d_i·G` have the same discrete logarithm,
+so the peer can check the share without learning `d_i`. As `A_1 + A_2` must be the input key,
+nobody can redirect the payment by sending a wrong share.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct EcdhShare {
+    pub scan: Point,
+    pub input_share: Point, // A_i
+    pub share: Point, // C_i
+    pub proof: DleqProof,
+}
+
+impl EcdhShare {
+    pub fn verify(&self) -> anyhow::Result<()> {
+        if !dleq_verify(self.input_share, self.scan, self.share, &self.proof) {
+            anyhow::bail!("DLEQ proof of ECDH share for scan key {} is invalid.", self.scan)
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DleqProof(pub [u8; 64]);
+
+/**
+BIP-374 proof that `a·G` and `a·b` have the same secret `a`, no message is used.
+*/
+fn dleq_generate(a: Scalar, b: Point, aux_rand: &[u8; 32]) -> anyhow::Result<DleqProof> {
+    let g: Point = Scalar::one() * G;
+    let a_pub = a * G;
+    let c = a * b;
+    let aux_hash = tagged_hash("BIP0374/aux", &[aux_rand]);
+    let mut t = a.serialize();
+    for (t_byte, aux_byte) in t.iter_mut().zip(aux_hash) {
+        *t_byte ^= aux_byte;
+    }
+    let rand = tagged_hash("BIP0374/nonce", &[&t, &a_pub.serialize(), &c.serialize()]);
+    let k = MaybeScalar::reduce_from(&rand).not_zero()?;
+    let r1 = k * G;
+    let r2 = k * b;
+    let e = dleq_challenge(a_pub, b, c, g, r1, r2)?;
+    let s = k + e * a;
+    let mut proof = [0u8; 64];
+    proof[..32].copy_from_slice(&e.serialize());
+    proof[32..].copy_from_slice(&s.serialize());
+    let proof = DleqProof(proof);
+    if !dleq_verify(a_pub, b, c, &proof) {
+        anyhow::bail!("generated DLEQ proof does not verify.")
+    }
+    Ok(proof)
+}
+
+fn dleq_verify(a_pub: Point, b: Point, c: Point, proof: &DleqProof) -> bool {
+    let g: Point = Scalar::one() * G;
+    let (Ok(e), Ok(s)) = (Scalar::from_slice(&proof.0[..32]), MaybeScalar::from_slice(&proof.0[32..])) else {
+        return false;
+    };
+    let (Valid(r1), Valid(r2)) = (s * G - e * a_pub, s * b - e * c) else {
+        return false;
+    };
+    dleq_challenge(a_pub, b, c, g, r1, r2).is_ok_and(|e2| e2 == e)
+}
+
+fn dleq_challenge(a_pub: Point, b: Point, c: Point, g: Point, r1: Point, r2: Point) -> anyhow::Result<Scalar> {
+    let hash = tagged_hash("BIP0374/challenge", &[
+        &a_pub.serialize(), &b.serialize(), &c.serialize(), &g.serialize(), &r1.serialize(), &r2.serialize(),
+    ]);
+    Ok(MaybeScalar::reduce_from(&hash).not_zero()?)
+}
+
+/**
+our share `d_i` of the secret key which BIP-352 uses for the taproot output of `key`.
+This is our secret key multiplied with its key aggregation coefficient, the taproot tweak is
+added by the first signer only and everything is negated, if the output key has an odd Y.
+*/
+fn secret_share(key: &AggKey) -> anyhow::Result<MaybeScalar> {
+    let ctx = key.key_agg_context.as_ref().unwrap();
+    let output_key: Point = ctx.aggregated_pubkey();
+    let tweak: MaybeScalar = ctx.tweak_sum().unwrap_or(MaybeScalar::Zero);
+    let Some(coefficient) = ctx.key_coefficient(key.pub_point) else {
+        anyhow::bail!("our key is not part of the aggregated key.")
+    };
+    // MuSig2 may have negated the sum of the keys before tweaking, find out.
+    let key_sum: MaybePoint = ctx.pubkeys().iter()
+        .map(|pubkey| ctx.key_coefficient(*pubkey).unwrap() * *pubkey)
+        .sum();
+    let untweaked = output_key - tweak * G;
+    let mut share = coefficient * key.sec;
+    if untweaked == -key_sum {
+        share = -share;
+    } else if untweaked != key_sum {
+        anyhow::bail!("cannot reconstruct the aggregated key.")
+    }
+    if ctx.pubkey_index(key.pub_point) == Some(0) {
+        share += tweak;
+    }
+    // BIP-352 uses the x-only output key, so the secret key of the even Y point.
+    if !output_key.has_even_y() {
+        share = -share;
+    }
+    Ok(share)
+}
+
+/**
+calculates our part of the ECDH for every scan key, each with its DLEQ proof.
+*/
+pub(crate) fn ecdh_shares(key: &AggKey, scan_keys: &[Point]) -> anyhow::Result<Vec<EcdhShare>> {
+    let d = secret_share(key)?.not_zero()?;
+    scan_keys.iter()
+        .map(|scan| {
+            let mut aux_rand = [0u8; 32];
+            rand::rng().fill(&mut aux_rand);
+            Ok(EcdhShare {
+                scan: *scan,
+                input_share: d * G,
+                share: d * *scan,
+                proof: dleq_generate(d, *scan, &aux_rand)?,
+            })
+        })
+        .collect()
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag_hash.as_ref());
+    engine.input(tag_hash.as_ref());
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/**
+`input_hash` of BIP-352, our transactions have only one input, which is a taproot key spend of `input_key`.
+*/
+fn input_hash(outpoint: &OutPoint, input_key: &Point) -> anyhow::Result<Scalar> {
+    let hash = tagged_hash("BIP0352/Inputs", &[&serialize(outpoint), &input_key.to_even_y().serialize()]);
+    Ok(Scalar::from_slice(&hash)?)
+}
+
+/**
+the ECDH `input_hash·a·B_scan` from the shares of both traders. Shares without a valid DLEQ proof,
+or which don't add up to the input key, are refused.
+*/
+fn aggregate_shares(outpoint: &OutPoint, input_key: &Point, mine: &EcdhShare, peer: &EcdhShare) -> anyhow::Result<Point> {
+    mine.verify()?;
+    peer.verify()?;
+    if mine.scan != peer.scan {
+        anyhow::bail!("ECDH shares are for different scan keys.")
+    }
+    if mine.input_share + peer.input_share != Valid(input_key.to_even_y()) {
+        anyhow::bail!("ECDH shares do not belong to the input key {}.", input_key)
+    }
+    Ok((input_hash(outpoint, input_key)? * (mine.share + peer.share)).not_inf()?)
+}
+
+fn output_script(receiver: &SilentPaymentAddress, ecdh: Point, k: u32) -> anyhow::Result<ScriptBuf> {
+    let t_k = Scalar::from_slice(&tagged_hash("BIP0352/SharedSecret", &[&ecdh.serialize(), &k.to_be_bytes()]))?;
+    let output_key = (receiver.spend + t_k * G).not_inf()?;
+    let x_only = XOnlyPublicKey::from_slice(&output_key.serialize_xonly())?;
+    Ok(ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(x_only)))
+}
+
+fn find_share<'a>(shares: &'a [EcdhShare], scan: &Point) -> anyhow::Result<&'a EcdhShare> {
+    shares.iter().find(|s| s.scan == *scan)
+        .ok_or_else(|| anyhow::anyhow!("ECDH share for scan key {} is missing.", scan))
+}
+
+/**
+the counter k of BIP-352 for each receiver, it is incremented per scan key.
+*/
+pub(crate) fn counters(receivers: &[SilentPaymentAddress]) -> Vec<u32> {
+    receivers.iter().enumerate()
+        .map(|(i, receiver)| receivers[..i].iter().filter(|r| r.scan == receiver.scan).count() as u32)
+        .collect()
+}
+
+/**
+Derives the output scripts for all silent payment receivers from the ECDH shares of both traders.
+The result is in the order of `receivers`.
+*/
+pub(crate) fn output_scripts(receivers: &[SilentPaymentAddress], outpoint: &OutPoint, input_key: &Point,
+                             my_shares: &[EcdhShare], peer_shares: &[EcdhShare]) -> anyhow::Result<Vec<ScriptBuf>> {
+    receivers.iter().zip(counters(receivers))
+        .map(|(receiver, k)| {
+            let ecdh = aggregate_shares(outpoint, input_key, find_share(my_shares, &receiver.scan)?, find_share(peer_shares, &receiver.scan)?)?;
+            output_script(receiver, ecdh, k)
+        })
+        .collect()
+}
+
+/**
+Standalone proof that an output of a RedirectTx is paid to a silent payment receiver.
+A burningman or arbitrator can check it with the RedirectTx and the output it spends, no secrets are needed.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedirectPaymentProof {
+    pub outpoint: OutPoint, // the only input of the RedirectTx
+    pub input_key: Point, // taproot output key of that input
+    pub receiver: SilentPaymentAddress,
+    pub k: u32,
+    pub shares: [EcdhShare; 2],
+}
+
+impl RedirectPaymentProof {
+    /**
+    returns the index of the output in `tx` which has been paid to the receiver.
+    */
+    pub fn verify(&self, tx: &Transaction, prevout: &TxOut) -> anyhow::Result<usize> {
+        if tx.input.len() != 1 || tx.input[0].previous_output != self.outpoint {
+            anyhow::bail!("transaction does not spend {}.", self.outpoint)
+        }
+        let x_only = XOnlyPublicKey::from_slice(&self.input_key.serialize_xonly())?;
+        if prevout.script_pubkey != ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(x_only)) {
+            anyhow::bail!("spent output does not belong to input key {}.", self.input_key)
+        }
+        if self.shares.iter().any(|s| s.scan != self.receiver.scan) {
+            anyhow::bail!("ECDH shares are not for the receiver's scan key.")
+        }
+        let ecdh = aggregate_shares(&self.outpoint, &self.input_key, &self.shares[0], &self.shares[1])?;
+        let script = output_script(&self.receiver, ecdh, self.k)?;
+        tx.output.iter().position(|output| output.script_pubkey == script)
+            .ok_or_else(|| anyhow::anyhow!("no output has been paid to {}.", self.receiver))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = serialize(&self.outpoint);
+        bytes.extend(self.input_key.serialize());
+        bytes.extend(self.receiver.scan.serialize());
+        bytes.extend(self.receiver.spend.serialize());
+        bytes.extend(self.k.to_be_bytes());
+        for share in &self.shares {
+            bytes.extend(share.input_share.serialize());
+            bytes.extend(share.share.serialize());
+            bytes.extend(share.proof.0);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<RedirectPaymentProof> {
+        const SHARE_LEN: usize = 33 + 33 + 64;
+        if bytes.len() != 36 + 33 * 3 + 4 + 2 * SHARE_LEN {
+            anyhow::bail!("invalid length of redirect payment proof.")
+        }
+        let point = |from: usize| Point::from_slice(&bytes[from..from + 33]);
+        let receiver = SilentPaymentAddress { scan: point(69)?, spend: point(102)? };
+        let share = |from: usize| -> anyhow::Result<EcdhShare> {
+            Ok(EcdhShare {
+                scan: receiver.scan,
+                input_share: point(from)?,
+                share: point(from + 33)?,
+                proof: DleqProof(bytes[from + 66..from + SHARE_LEN].try_into()?),
+            })
+        };
+        Ok(RedirectPaymentProof {
+            outpoint: deserialize(&bytes[..36])?,
+            input_key: point(36)?,
+            receiver,
+            k: u32::from_be_bytes(bytes[135..139].try_into()?),
+            shares: [share(139)?, share(139 + SHARE_LEN)?],
+        })
+    }
+}
+
+#[test]
+fn test_partial_ecdh() -> anyhow::Result<()> {
+    let mut alice = AggKey::new()?;
+    let mut bob = AggKey::new()?;
+    alice.aggregate_key(bob.pub_point)?;
+    bob.aggregate_key(alice.pub_point)?;
+    let input_key = alice.agg_point.unwrap();
+
+    // the receiver's keys
+    let b_scan = Scalar::from_slice(&[0x11; 32])?;
+    let b_spend = Scalar::from_slice(&[0x22; 32])?;
+    let receiver = SilentPaymentAddress { scan: b_scan * G, spend: b_spend * G };
+    println!("receiver: {}", receiver);
+    assert_eq!(SilentPaymentAddress::from_str(&receiver.to_string())?, receiver);
+
+    let outpoint = OutPoint::from_str("8a6e1e6ea8e5b5ff30a0e2afcb9a1ff6d3c24a1c55b8ff1c3e1f3d6a8f3e7b12:0")?;
+    let alice_shares = ecdh_shares(&alice, &[receiver.scan])?;
+    let bob_shares = ecdh_shares(&bob, &[receiver.scan])?;
+    let receivers = [receiver, receiver];
+    let alice_scripts = output_scripts(&receivers, &outpoint, &input_key, &alice_shares, &bob_shares)?;
+    let bob_scripts = output_scripts(&receivers, &outpoint, &input_key, &bob_shares, &alice_shares)?;
+    assert_eq!(alice_scripts, bob_scripts);
+    assert_ne!(alice_scripts[0], alice_scripts[1]);
+
+    // the receiver finds the outputs with b_scan and the public input key only
+    let ecdh = input_hash(&outpoint, &input_key)? * b_scan * input_key.to_even_y();
+    for (k, script) in alice_scripts.iter().enumerate() {
+        let t_k = Scalar::from_slice(&tagged_hash("BIP0352/SharedSecret", &[&ecdh.serialize(), &(k as u32).to_be_bytes()]))?;
+        let p_k = (b_spend + t_k).not_zero()? * G;
+        assert_eq!(script.as_bytes()[2..], p_k.serialize_xonly());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_dleq_proof_of_payment() -> anyhow::Result<()> {
+    use bdk_wallet::bitcoin::{absolute, transaction, Amount, TxIn};
+    let mut alice = AggKey::new()?;
+    let mut bob = AggKey::new()?;
+    alice.aggregate_key(bob.pub_point)?;
+    bob.aggregate_key(alice.pub_point)?;
+    let input_key = alice.agg_point.unwrap();
+    let receiver = SilentPaymentAddress { scan: AggKey::new()?.pub_point, spend: AggKey::new()?.pub_point };
+    let outpoint = OutPoint::from_str("8a6e1e6ea8e5b5ff30a0e2afcb9a1ff6d3c24a1c55b8ff1c3e1f3d6a8f3e7b12:1")?;
+
+    let alice_shares = ecdh_shares(&alice, &[receiver.scan])?;
+    let bob_shares = ecdh_shares(&bob, &[receiver.scan])?;
+
+    // a share which is not proven is refused, even if the proof is valid for another share
+    let mut forged = bob_shares.clone();
+    forged[0].share = AggKey::new()?.pub_point;
+    assert!(output_scripts(&[receiver], &outpoint, &input_key, &alice_shares, &forged).is_err());
+    let mut forged = bob_shares.clone();
+    forged[0].proof = alice_shares[0].proof;
+    assert!(output_scripts(&[receiver], &outpoint, &input_key, &alice_shares, &forged).is_err());
+    // a correct proof for a key which doesn't belong to the input is refused as well
+    let mut mallory = AggKey::new()?;
+    mallory.aggregate_key(alice.pub_point)?;
+    let mallory = ecdh_shares(&mallory, &[receiver.scan])?;
+    mallory[0].verify()?;
+    assert!(output_scripts(&[receiver], &outpoint, &input_key, &alice_shares, &mallory).is_err());
+
+    let scripts = output_scripts(&[receiver], &outpoint, &input_key, &alice_shares, &bob_shares)?;
+    let tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn { previous_output: outpoint, ..Default::default() }],
+        output: vec![
+            TxOut { value: Amount::from_sat(5000), script_pubkey: ScriptBuf::new_op_return([1u8; 4]) },
+            TxOut { value: Amount::from_sat(10000), script_pubkey: scripts[0].clone() },
+        ],
+    };
+    let x_only = XOnlyPublicKey::from_slice(&input_key.serialize_xonly())?;
+    let prevout = TxOut {
+        value: Amount::from_sat(20000),
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(x_only)),
+    };
+
+    let proof = RedirectPaymentProof {
+        outpoint,
+        input_key,
+        receiver,
+        k: 0,
+        shares: [alice_shares[0].clone(), bob_shares[0].clone()],
+    };
+    // the bundle is exported and checked by the receiver
+    let exported = RedirectPaymentProof::from_bytes(&proof.to_bytes())?;
+    assert_eq!(exported, proof);
+    assert_eq!(exported.verify(&tx, &prevout)?, 1);
+
+    let other = RedirectPaymentProof { k: 1, ..proof };
+    assert!(other.verify(&tx, &prevout).is_err());
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use crate::protocol_musig_adaptor::ProtocolRole;
+
+/**
+Which trader did something, seen from our side of the trade.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Party {
+    Me,
+    Peer,
+}
+
+/**
+A broadcast WarningTx, who did it and whether the secret keys for P' and Q' had been exchanged before.
+Only then can the other party sweep the WarningTx output and punish the warning party.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Warning {
+    pub by: Party,
+    pub keys_exchanged: bool,
+}
+
+/**
+The maker / taker setup messages, see rpc/musig-trade-protocol-messages.txt.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SetupMessage {
+    A, // taker
+    B, // maker
+    C, // taker
+    D, // maker
+}
+
+/**
+The states of a trade, see concept/StateMachine.drawio.
+Rounds 1 and 2 are the signing of the DepositTx, nothing is on the chain yet and the trade can be aborted.
+From round 3 on the DepositTx is broadcast and the trade ends either on the happy path (SwapTx and
+exchange of the secret keys for P' and Q') or on the alternative path starting with a WarningTx.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TradeState {
+    Init,
+    NoncesExchanged,            // round 1
+    DepositSigned,              // round 2 or message C / D, our DepositTx inputs are handed to the peer
+    DepositPublished,           // round 3
+    TxsSigned,                  // round 4, SwapTx, WarningTx, ClaimTx and RedirectTx are signed
+    Open,                       // round 5, waiting for the buyer's payment
+    SwapPublished,              // the seller has broadcast the SwapTx
+    PKeyExchanged,              // secret key for P' exchanged
+    QKeyExchanged,              // secret keys for P' and Q' exchanged
+    WarningPublished(Warning),
+    T1Expired(Warning),         // the other party can broadcast the RedirectTx
+    T2Expired(Warning),         // the warning party can broadcast the ClaimTx
+    // --- final states
+    Closed,                     // happy path, both traders own their payout
+    Redirected,                 // the warning party has been inactive, funds went to the DAO
+    Claimed(Party),             // the other party has been inactive, funds went to the warning party
+    Punished(Party),            // the warning party had already handed out its keys and lost the funds
+    MediatedPayout,             // the MediatedPayoutTx has been broadcast
+    Aborted,
+}
+
+/**
+What moves the trade from one state to the next. Local actions are our own calls to BMPProtocol,
+peer messages are received from the other trader and chain events come from watching the blockchain.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TradeEvent {
+    // local actions and the peer messages they consume
+    Round(u8),
+    Sent(SetupMessage),
+    Abort,
+    PeerAborted,
+    // peer messages
+    PeerKeyP,                   // received the secret key for P'
+    PeerKeyQ,                   // received the secret key for Q'
+    // chain events (or our own broadcast)
+    DepositPublished,           // maker / taker setup only, the taker broadcasts after message D
+    SwapPublished,
+    SwapConfirmed,
+    WarningPublished(Party),
+    T1Expired,
+    T2Expired,
+    RedirectPublished,
+    ClaimPublished,
+    SweepPublished,             // the non-warning party spent the WarningTx output with the exchanged keys
+    MediatedPayoutPublished,
+}
+
+/**
+What the UI can offer to the user (or the daemon can do on its own) in a given state.
+*/
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TradeAction {
+    NextRound(u8),
+    Abort,
+    BroadcastSwap,
+    SendKeyP,
+    SendKeyQ,
+    BroadcastWarning,
+    BroadcastRedirect,
+    BroadcastClaim,
+    Sweep,
+    Mediation,
+}
+
+impl Warning {
+    /**
+    we can take the funds of the peer's WarningTx, as it had already handed out its keys.
+    */
+    pub fn can_sweep(&self) -> bool {
+        self.by == Party::Peer && self.keys_exchanged
+    }
+}
+
+impl TradeState {
+    /**
+    returns the state after the event, or an error if the event is not allowed in this state.
+    */
+    pub fn next(self, event: TradeEvent) -> anyhow::Result<TradeState> {
+        use TradeEvent as E;
+        use TradeState as S;
+        let next = match (self, event) {
+            (S::Init, E::Round(1)) => S::NoncesExchanged,
+            (S::NoncesExchanged, E::Round(2)) => S::DepositSigned,
+            (S::DepositSigned, E::Round(3)) => S::DepositPublished,
+            (S::DepositPublished, E::Round(4)) => S::TxsSigned,
+            (S::TxsSigned, E::Round(5)) => S::Open,
+            (S::Init, E::Sent(SetupMessage::A | SetupMessage::B)) => S::NoncesExchanged,
+            (S::NoncesExchanged, E::Sent(SetupMessage::C | SetupMessage::D)) => S::DepositSigned,
+            (S::DepositSigned, E::DepositPublished) => S::Open,
+            (S::Init | S::NoncesExchanged | S::DepositSigned, E::Abort | E::PeerAborted) => S::Aborted,
+            // happy path
+            (S::Open | S::PKeyExchanged | S::QKeyExchanged, E::SwapPublished) => S::SwapPublished,
+            (S::SwapPublished, E::SwapConfirmed) => S::Closed,
+            (S::Open, E::PeerKeyP) => S::PKeyExchanged,
+            (S::PKeyExchanged, E::PeerKeyQ) => S::QKeyExchanged,
+            // alternative path
+            (S::DepositPublished, E::WarningPublished(Party::Peer)) => S::WarningPublished(Warning { by: Party::Peer, keys_exchanged: false }),
+            (S::TxsSigned | S::Open | S::PKeyExchanged, E::WarningPublished(by)) => S::WarningPublished(Warning { by, keys_exchanged: false }),
+            (S::QKeyExchanged, E::WarningPublished(by)) => S::WarningPublished(Warning { by, keys_exchanged: true }),
+            (S::WarningPublished(warning), E::T1Expired) => S::T1Expired(warning),
+            (S::T1Expired(warning), E::T2Expired) => S::T2Expired(warning),
+            (S::T1Expired(_) | S::T2Expired(_), E::RedirectPublished) => S::Redirected,
+            (S::T2Expired(warning), E::ClaimPublished) => S::Claimed(warning.by),
+            (S::WarningPublished(warning) | S::T1Expired(warning) | S::T2Expired(warning), E::SweepPublished)
+                if warning.keys_exchanged => S::Punished(warning.by),
+            (state, E::MediatedPayoutPublished) if state.deposit_published() && !state.is_final() => S::MediatedPayout,
+            (state, event) => anyhow::bail!("{:?} is not allowed in state {:?}.", event, state),
+        };
+        Ok(next)
+    }
+
+    /**
+    the actions which are allowed in this state for the given role, meant for the UI.
+    */
+    pub fn allowed_actions(&self, role: ProtocolRole) -> Vec<TradeAction> {
+        use TradeAction as A;
+        use TradeState as S;
+        let mut actions = match self {
+            S::Init => vec![A::NextRound(1), A::Abort],
+            S::NoncesExchanged => vec![A::NextRound(2), A::Abort],
+            S::DepositSigned => vec![A::NextRound(3), A::Abort],
+            // our WarningTx is only signed in round 4
+            S::DepositPublished => vec![A::NextRound(4)],
+            S::TxsSigned => vec![A::NextRound(5), A::BroadcastWarning],
+            S::Open => vec![A::BroadcastSwap, A::SendKeyP, A::BroadcastWarning],
+            S::PKeyExchanged => vec![A::BroadcastSwap, A::SendKeyQ, A::BroadcastWarning],
+            S::QKeyExchanged => vec![A::BroadcastSwap, A::BroadcastWarning],
+            S::WarningPublished(warning) if warning.can_sweep() => vec![A::Sweep],
+            S::T1Expired(warning) | S::T2Expired(warning) if warning.can_sweep() => vec![A::BroadcastRedirect, A::Sweep],
+            S::T1Expired(warning) | S::T2Expired(warning) if warning.by == Party::Peer => vec![A::BroadcastRedirect],
+            S::T2Expired(_) => vec![A::BroadcastClaim],
+            _ => vec![],
+        };
+        if role != ProtocolRole::Seller {
+            actions.retain(|a| *a != A::BroadcastSwap); // only the seller can sign the SwapTx
+        }
+        if self.deposit_published() && !self.is_final() {
+            actions.push(A::Mediation);
+        }
+        actions
+    }
+
+    pub fn deposit_published(&self) -> bool {
+        !matches!(self, TradeState::Init | TradeState::NoncesExchanged | TradeState::DepositSigned | TradeState::Aborted)
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, TradeState::Closed | TradeState::Redirected | TradeState::Claimed(_) | TradeState::Punished(_)
+            | TradeState::MediatedPayout | TradeState::Aborted)
+    }
+}
+
+#[test]
+fn test_trade_state() -> anyhow::Result<()> {
+    use TradeEvent as E;
+    // happy path
+    let mut state = TradeState::Init;
+    for round in 1..=5 {
+        state = state.next(E::Round(round))?;
+    }
+    assert_eq!(state, TradeState::Open);
+    assert!(state.allowed_actions(ProtocolRole::Seller).contains(&TradeAction::BroadcastSwap));
+    assert!(!state.allowed_actions(ProtocolRole::Buyer).contains(&TradeAction::BroadcastSwap));
+    assert_eq!(state.next(E::SwapPublished)?.next(E::SwapConfirmed)?, TradeState::Closed);
+    assert!(TradeState::Closed.allowed_actions(ProtocolRole::Buyer).is_empty());
+
+    // maker / taker setup, the taker publishes the DepositTx after message D
+    let maker = TradeState::Init.next(E::Sent(SetupMessage::B))?.next(E::Sent(SetupMessage::D))?;
+    assert_eq!(maker.next(E::DepositPublished)?, TradeState::Open);
+    assert!(TradeState::NoncesExchanged.next(E::DepositPublished).is_err());
+
+    // rounds cannot be skipped or repeated
+    assert!(TradeState::Init.next(E::Round(2)).is_err());
+    assert!(TradeState::NoncesExchanged.next(E::Round(1)).is_err());
+
+    // abort only before the DepositTx is broadcast
+    assert_eq!(TradeState::DepositSigned.next(E::Abort)?, TradeState::Aborted);
+    assert!(TradeState::DepositPublished.next(E::Abort).is_err());
+    assert!(TradeState::Aborted.next(E::Round(1)).is_err());
+
+    // WarningTx after the key exchange gets the warning party punished
+    let state = TradeState::Open.next(E::PeerKeyP)?.next(E::PeerKeyQ)?;
+    let state = state.next(E::WarningPublished(Party::Peer))?;
+    assert!(state.allowed_actions(ProtocolRole::Buyer).contains(&TradeAction::Sweep));
+    assert_eq!(state.next(E::SweepPublished)?, TradeState::Punished(Party::Peer));
+    // but not before, then the peer's funds are still its own
+    let state = TradeState::Open.next(E::PeerKeyP)?.next(E::WarningPublished(Party::Peer))?;
+    assert!(!state.allowed_actions(ProtocolRole::Buyer).contains(&TradeAction::Sweep));
+    assert!(state.next(E::SweepPublished).is_err());
+    assert_eq!(state.next(E::T1Expired)?.allowed_actions(ProtocolRole::Buyer), vec![TradeAction::BroadcastRedirect, TradeAction::Mediation]);
+
+    // the WarningTx can only be broadcast once signed in round 4, the peer may have signed its own already
+    assert_eq!(TradeState::DepositPublished.allowed_actions(ProtocolRole::Buyer), vec![TradeAction::NextRound(4), TradeAction::Mediation]);
+    assert!(TradeState::DepositPublished.next(E::WarningPublished(Party::Me)).is_err());
+    assert!(TradeState::DepositPublished.next(E::WarningPublished(Party::Peer)).is_ok());
+    assert!(TradeState::TxsSigned.allowed_actions(ProtocolRole::Buyer).contains(&TradeAction::BroadcastWarning));
+
+    // RedirectTx only after t1, ClaimTx only after t2
+    let state = TradeState::TxsSigned.next(E::WarningPublished(Party::Me))?;
+    assert!(state.next(E::RedirectPublished).is_err());
+    let state = state.next(E::T1Expired)?;
+    assert!(state.next(E::ClaimPublished).is_err());
+    let state = state.next(E::T2Expired)?;
+    assert_eq!(state.allowed_actions(ProtocolRole::Seller), vec![TradeAction::BroadcastClaim, TradeAction::Mediation]);
+    assert_eq!(state.next(E::ClaimPublished)?, TradeState::Claimed(Party::Me));
+
+    // mediation needs the DepositTx, but no final state
+    assert!(TradeState::DepositSigned.next(E::MediatedPayoutPublished).is_err());
+    assert_eq!(TradeState::DepositPublished.next(E::MediatedPayoutPublished)?, TradeState::MediatedPayout);
+    assert!(TradeState::Redirected.next(E::MediatedPayoutPublished).is_err());
+    Ok(())
+}
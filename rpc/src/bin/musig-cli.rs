@@ -1,9 +1,14 @@
+use bdk_wallet::bitcoin::{FeeRate, OutPoint, Psbt, Txid};
 use bdk_wallet::bitcoin::hashes::{Hash as _, sha256d};
 use clap::{Parser, Subcommand};
 use futures::StreamExt as _;
-use rpc::pb::walletrpc::{ConfRequest, ListUnspentRequest, NewAddressRequest, WalletBalanceRequest};
+use rpc::pb::walletrpc::{self, BlockRequest, BroadcastResponse, ConfRequest, CreateFundedPsbtRequest,
+    CreateWalletRequest, EstimateFeeRequest, FinalizeAndBroadcastRequest, GetWalletInfoRequest, ListTransactionsRequest,
+    ListUnspentRequest, NewAddressRequest,
+    Recipient, RestoreWalletRequest, SendToAddressRequest, SignPsbtRequest, SpendRequest, SweepAllRequest, WalletBalanceRequest};
 use rpc::pb::walletrpc::wallet_client::WalletClient;
-use tonic::Request;
+use std::fmt::Debug;
+use tonic::{Request, Status, Streaming};
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
@@ -19,14 +24,87 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Create a wallet from a freshly generated BIP-39 mnemonic, which is displayed for backup
+    CreateWallet {
+        /// An optional BIP-39 passphrase, which must be backed up along with the mnemonic
+        #[arg(long, default_value = "")]
+        passphrase: String,
+    },
+    /// Restore a wallet from a BIP-39 mnemonic, rescanning the chain from the given birthday height
+    RestoreWallet {
+        /// The mnemonic, quoted as a single argument
+        mnemonic: String,
+        #[arg(long, default_value = "")]
+        passphrase: String,
+        #[arg(long)]
+        birthday_height: u32,
+    },
+    /// Display the wallet's network, key source, birthday and public descriptors
+    WalletInfo,
     /// Compute and display the wallet's current balance
     WalletBalance,
     /// Generate a new address
     NewAddress,
     /// List utxos available for spending
     ListUnspent,
+    /// List the wallet's txs, newest first
+    ListTransactions {
+        /// The number of txs to skip
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+        /// The maximum number of txs to list, or zero for all
+        #[arg(long, default_value_t = 0)]
+        limit: u32,
+        /// List only txs with this confidence: unconfirmed or confirmed
+        #[arg(long, value_parser = parse_confidence_type)]
+        confidence: Option<i32>,
+    },
+    /// Create a PSBT paying the given recipients from the wallet, with change back to it, and display it in base64
+    CreateFundedPsbt {
+        /// A recipient address and amount in sats, which may be repeated
+        #[arg(long = "recipient", value_name = "ADDRESS:AMOUNT", required = true, value_parser = parse_recipient)]
+        recipients: Vec<Recipient>,
+        /// The fee rate in sats per vbyte
+        #[arg(long, value_parser = parse_fee_rate)]
+        fee_rate: FeeRate,
+        /// A UTXO to spend, which may be repeated, in which case only the given UTXOs are spent
+        #[arg(long = "utxo", value_name = "TXID:VOUT")]
+        utxos: Vec<OutPoint>,
+    },
+    /// Sign the wallet's inputs of the given base64 PSBT, without finalizing it
+    SignPsbt { psbt: Psbt },
+    /// Finalize the given fully signed base64 PSBT and broadcast the tx
+    FinalizeAndBroadcast { psbt: Psbt },
+    /// Send the given amount in sats to the given address
+    SendToAddress {
+        address: String,
+        amount: u64,
+        /// The fee rate in sats per vbyte
+        #[arg(long, value_parser = parse_fee_rate)]
+        fee_rate: FeeRate,
+    },
+    /// Send all the wallet's funds to the given address
+    SweepAll {
+        address: String,
+        /// The fee rate in sats per vbyte
+        #[arg(long, value_parser = parse_fee_rate)]
+        fee_rate: FeeRate,
+    },
+    /// Estimate the fee rates in sats per kwu for txs to confirm within the given numbers of blocks
+    EstimateFee {
+        /// A confirmation target in blocks, which may be repeated
+        #[arg(long = "conf-target", default_values_t = [2, 144])]
+        conf_targets: Vec<u32>,
+    },
     /// Receive a stream of confidence events for the given txid
     NotifyConfidence { tx_id: String },
+    /// Receive a stream of spend events for the given outpoint, which needn't belong to the wallet
+    NotifySpend {
+        #[arg(value_name = "TXID:VOUT")]
+        outpoint: OutPoint,
+    },
+    /// Receive a stream of connected and disconnected blocks, starting with the current chain tip
+    NotifyBlocks,
 }
 
 #[tokio::main]
@@ -36,6 +114,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client = WalletClient::connect(format!("http://127.0.0.1:{}", cli.port)).await?;
 
     match cli.commands {
+        Commands::CreateWallet { passphrase } => {
+            let response = client.create_wallet(Request::new(CreateWalletRequest { passphrase })).await?;
+            drop(client);
+            println!("{response:#?}");
+        }
+        Commands::RestoreWallet { mnemonic, passphrase, birthday_height } => {
+            let request = RestoreWalletRequest { mnemonic, passphrase, birthday_height };
+            let response = client.restore_wallet(Request::new(request)).await?;
+            drop(client);
+            println!("{response:#?}");
+        }
+        Commands::WalletInfo => {
+            let response = client.get_wallet_info(Request::new(GetWalletInfoRequest {})).await?;
+            drop(client);
+            println!("{response:#?}");
+        }
         Commands::WalletBalance => {
             let response = client.wallet_balance(Request::new(WalletBalanceRequest {})).await?;
             drop(client);
@@ -51,15 +145,97 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             drop(client);
             println!("{response:?}");
         }
+        Commands::ListTransactions { offset, limit, confidence } => {
+            let request = ListTransactionsRequest { offset, limit, confidence_type: confidence };
+            let response = client.list_transactions(Request::new(request)).await?;
+            drop(client);
+            println!("{response:#?}");
+        }
+        Commands::CreateFundedPsbt { recipients, fee_rate, utxos } => {
+            let utxos = utxos.into_iter()
+                .map(|o| walletrpc::OutPoint { tx_id: o.txid.as_byte_array().to_vec(), vout: o.vout })
+                .collect();
+            let request = CreateFundedPsbtRequest { recipients, fee_rate: fee_rate.to_sat_per_kwu(), utxos };
+            let response = client.create_funded_psbt(Request::new(request)).await?;
+            drop(client);
+            println!("{}", Psbt::deserialize(&response.into_inner().psbt)?);
+        }
+        Commands::SignPsbt { psbt } => {
+            let response = client.sign_psbt(Request::new(SignPsbtRequest { psbt: psbt.serialize() })).await?;
+            drop(client);
+            println!("{}", Psbt::deserialize(&response.into_inner().psbt)?);
+        }
+        Commands::FinalizeAndBroadcast { psbt } => {
+            let request = FinalizeAndBroadcastRequest { psbt: psbt.serialize() };
+            let response = client.finalize_and_broadcast(Request::new(request)).await?;
+            drop(client);
+            print_txid(response.get_ref())?;
+        }
+        Commands::SendToAddress { address, amount, fee_rate } => {
+            let request = SendToAddressRequest { address, amount, fee_rate: fee_rate.to_sat_per_kwu() };
+            let response = client.send_to_address(Request::new(request)).await?;
+            drop(client);
+            print_txid(response.get_ref())?;
+        }
+        Commands::SweepAll { address, fee_rate } => {
+            let request = SweepAllRequest { address, fee_rate: fee_rate.to_sat_per_kwu() };
+            let response = client.sweep_all(Request::new(request)).await?;
+            drop(client);
+            print_txid(response.get_ref())?;
+        }
+        Commands::EstimateFee { conf_targets } => {
+            let response = client.estimate_fee(Request::new(EstimateFeeRequest { conf_targets })).await?;
+            drop(client);
+            println!("{response:#?}");
+        }
         Commands::NotifyConfidence { tx_id } => {
             let tx_id = tx_id.parse::<sha256d::Hash>()?.as_byte_array().to_vec();
             let response = client.register_confidence_ntfn(Request::new(ConfRequest { tx_id })).await?;
             drop(client);
-            let mut stream = response.into_inner();
-            while let Some(event_result) = stream.next().await {
-                println!("{:?}", event_result?);
-            }
+            print_events(response.into_inner()).await?;
+        }
+        Commands::NotifySpend { outpoint } => {
+            let request = SpendRequest { tx_id: outpoint.txid.as_byte_array().to_vec(), vout: outpoint.vout };
+            let response = client.register_spend_ntfn(Request::new(request)).await?;
+            drop(client);
+            print_events(response.into_inner()).await?;
+        }
+        Commands::NotifyBlocks => {
+            let response = client.register_block_ntfn(Request::new(BlockRequest {})).await?;
+            drop(client);
+            print_events(response.into_inner()).await?;
         }
     }
     Ok(())
 }
+
+fn parse_recipient(s: &str) -> Result<Recipient, String> {
+    let (address, amount) = s.rsplit_once(':').ok_or("expected ADDRESS:AMOUNT")?;
+    let amount = amount.parse().map_err(|e| format!("invalid amount: {e}"))?;
+    Ok(Recipient { address: address.to_owned(), amount })
+}
+
+fn parse_confidence_type(s: &str) -> Result<i32, String> {
+    walletrpc::ConfidenceType::from_str_name(&s.to_uppercase())
+        .map(Into::into)
+        .ok_or_else(|| format!("unknown confidence type: {s}"))
+}
+
+fn parse_fee_rate(s: &str) -> Result<FeeRate, String> {
+    let sat_per_vb: u64 = s.parse().map_err(|e| format!("invalid fee rate: {e}"))?;
+    FeeRate::from_sat_per_vb(sat_per_vb)
+        .filter(|fee_rate| *fee_rate > FeeRate::ZERO)
+        .ok_or_else(|| format!("fee rate out of range: {s}"))
+}
+
+async fn print_events<T: Debug>(mut stream: Streaming<T>) -> Result<(), Status> {
+    while let Some(event_result) = stream.next().await {
+        println!("{:?}", event_result?);
+    }
+    Ok(())
+}
+
+fn print_txid(response: &BroadcastResponse) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", Txid::from_slice(&response.tx_id)?);
+    Ok(())
+}
@@ -1,19 +1,30 @@
-use rpc::wallet::WalletServiceImpl;
+use clap::Parser as _;
+use rpc::config::{Config, Settings};
+use rpc::fees::FallbackFeeEstimator;
+use rpc::wallet::{WalletService as _, WalletServiceImpl};
 use rpc::server::{MusigImpl, MusigServer, WalletImpl, WalletServer};
+use rpc::store::FileTradeModelStore;
 use std::sync::Arc;
 use tonic::transport::Server;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:50051".parse()?;
-    let musig = MusigImpl::default();
-    let wallet = WalletImpl { wallet_service: Arc::new(WalletServiceImpl::new()) };
-    wallet.wallet_service.clone().spawn_connection();
+    let config = Config::load(Settings::parse())?;
+    let trade_model_store = FileTradeModelStore::open(config.data_dir.join("trades.jsonl"))?;
+    let fee_estimator = Arc::new(FallbackFeeEstimator::new(config.fee_sources,
+        &config.wallet.rpc_url, &config.wallet.rpc_auth));
+    let wallet_service = Arc::new(WalletServiceImpl::with_config(config.wallet)?);
+    wallet_service.clone().spawn_connection();
+    let trade_model_store = Arc::new(trade_model_store);
+    let musig = MusigImpl::new(trade_model_store.clone(), wallet_service.clone(), fee_estimator.clone(),
+        config.deposit_tx_confirmation_depth, config.network);
+    musig.spawn_open_trade_watchers();
+    let wallet = WalletImpl { wallet_service, trade_model_store, fee_estimator };
 
     Server::builder()
         .add_service(MusigServer::new(musig))
         .add_service(WalletServer::new(wallet))
-        .serve(addr)
+        .serve(config.listen_address)
         .await?;
 
     Ok(())
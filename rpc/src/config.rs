@@ -0,0 +1,308 @@
+use bdk_bitcoind_rpc::bitcoincore_rpc::Auth;
+use bdk_wallet::bitcoin::{FeeRate, Network};
+use clap::Parser;
+use serde::Deserialize;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::fees::FeeSource;
+use crate::keys::Descriptors;
+use crate::wallet::WalletConfig;
+
+pub const DEFAULT_DATA_DIR: &str = ".localnet/musigd";
+pub const DEFAULT_BITCOIND_DATA_DIR: &str = ".localnet/bitcoind";
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:50051";
+pub const DEFAULT_DEPOSIT_TX_CONFIRMATION_DEPTH: u32 = 6;
+/// The static fee rate in sats per vbyte to fall back to when bitcoind has no estimate, except on
+/// mainnet.
+pub const DEFAULT_FALLBACK_FEE_RATE: u64 = 2;
+const CONFIG_FILE_NAME: &str = "musigd.json";
+const PASSWORD_ENV_VAR: &str = "MUSIGD_BITCOIND_RPC_PASSWORD";
+const EXTERNAL_DESCRIPTOR_ENV_VAR: &str = "MUSIGD_EXTERNAL_DESCRIPTOR";
+const INTERNAL_DESCRIPTOR_ENV_VAR: &str = "MUSIGD_INTERNAL_DESCRIPTOR";
+
+/// The settings of the MuSig daemon, which may be given as command-line flags or in a JSON config
+/// file (with the same field names, in snake case). Flags take precedence over the config file, and
+/// settings given in neither take per-network defaults.
+///
+/// The secrets, namely the bitcoind RPC password and the wallet descriptors, are not accepted as
+/// flags, which would leak them to the process list and shell history. They may be given in the
+/// config file or as environment variables instead, the latter taking precedence.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(version, about, long_about = None)]
+#[serde(default, deny_unknown_fields)]
+#[expect(clippy::doc_markdown, reason = "doc comments are used verbatim by Clap and not intended to be markdown")]
+pub struct Settings {
+    /// Path of the JSON config file [default: <DATA_DIR>/musigd.json, if present]
+    #[arg(long)]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// The network: bitcoin, testnet, testnet4, signet or regtest [default: regtest]
+    #[arg(long)]
+    pub network: Option<Network>,
+    /// The data directory, with a subdirectory per network (except mainnet) as for bitcoind [default: .localnet/musigd]
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// The address for the gRPC server to listen on [default: 127.0.0.1:50051]
+    #[arg(long)]
+    pub listen_address: Option<SocketAddr>,
+    /// The URL of the bitcoind JSON-RPC server [default: http://127.0.0.1:<network RPC port>]
+    #[arg(long)]
+    pub bitcoind_rpc_url: Option<String>,
+    /// The bitcoind RPC cookie file [default: .localnet/bitcoind/<network dir>/.cookie]
+    #[arg(long, conflicts_with = "bitcoind_rpc_user")]
+    pub bitcoind_rpc_cookie_file: Option<PathBuf>,
+    /// The bitcoind RPC username, to authenticate with a password (from the config file or the environment variable MUSIGD_BITCOIND_RPC_PASSWORD) instead of the cookie file
+    #[arg(long)]
+    pub bitcoind_rpc_user: Option<String>,
+    /// The bitcoind RPC password [env: MUSIGD_BITCOIND_RPC_PASSWORD]
+    #[arg(skip)]
+    pub bitcoind_rpc_password: Option<String>,
+    /// The wallet's external (receive) descriptor, with private keys [env: MUSIGD_EXTERNAL_DESCRIPTOR, default: the wallet created or restored via gRPC, else test keys, except on mainnet]
+    #[arg(skip)]
+    pub external_descriptor: Option<String>,
+    /// The wallet's internal (change) descriptor, with private keys [env: MUSIGD_INTERNAL_DESCRIPTOR]
+    #[arg(skip)]
+    pub internal_descriptor: Option<String>,
+    /// The number of confirmations after which the deposit tx confirmation stream ends [default: 6]
+    #[arg(long)]
+    pub deposit_tx_confirmation_depth: Option<u32>,
+    /// A fee estimation source, tried in the order given: bitcoind, pricenode=<URL> or static=<sats per vbyte> [default: bitcoind, then static=2 except on mainnet]
+    #[arg(long = "fee-source")]
+    pub fee_sources: Option<Vec<FeeSource>>,
+}
+
+/// The resolved configuration of the daemon.
+#[derive(Debug)]
+pub struct Config {
+    pub network: Network,
+    /// The network-specific data directory.
+    pub data_dir: PathBuf,
+    pub listen_address: SocketAddr,
+    pub deposit_tx_confirmation_depth: u32,
+    pub fee_sources: Vec<FeeSource>,
+    pub wallet: WalletConfig,
+}
+
+impl Settings {
+    /// Fill in the settings missing from `self` with those of `other`. The bitcoind RPC credentials
+    /// and the wallet descriptors are each taken as a whole from one or the other.
+    fn or(self, other: Self) -> Self {
+        let has_auth = self.bitcoind_rpc_cookie_file.is_some() || self.bitcoind_rpc_user.is_some();
+        let has_descriptors = self.external_descriptor.is_some() || self.internal_descriptor.is_some();
+        let (auth, other_auth) = ((self.bitcoind_rpc_cookie_file, self.bitcoind_rpc_user, self.bitcoind_rpc_password),
+            (other.bitcoind_rpc_cookie_file, other.bitcoind_rpc_user, other.bitcoind_rpc_password));
+        let (descriptors, other_descriptors) = ((self.external_descriptor, self.internal_descriptor),
+            (other.external_descriptor, other.internal_descriptor));
+        let (bitcoind_rpc_cookie_file, bitcoind_rpc_user, bitcoind_rpc_password) =
+            if has_auth { auth } else { other_auth };
+        let (external_descriptor, internal_descriptor) =
+            if has_descriptors { descriptors } else { other_descriptors };
+        Self {
+            config: self.config.or(other.config),
+            network: self.network.or(other.network),
+            data_dir: self.data_dir.or(other.data_dir),
+            listen_address: self.listen_address.or(other.listen_address),
+            bitcoind_rpc_url: self.bitcoind_rpc_url.or(other.bitcoind_rpc_url),
+            bitcoind_rpc_cookie_file,
+            bitcoind_rpc_user,
+            bitcoind_rpc_password,
+            external_descriptor,
+            internal_descriptor,
+            deposit_tx_confirmation_depth: self.deposit_tx_confirmation_depth.or(other.deposit_tx_confirmation_depth),
+            fee_sources: self.fee_sources.or(other.fee_sources),
+        }
+    }
+
+    /// Take the secrets from the environment, where given.
+    fn with_env_secrets(self, var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            bitcoind_rpc_password: var(PASSWORD_ENV_VAR).or(self.bitcoind_rpc_password),
+            external_descriptor: var(EXTERNAL_DESCRIPTOR_ENV_VAR).or(self.external_descriptor),
+            internal_descriptor: var(INTERNAL_DESCRIPTOR_ENV_VAR).or(self.internal_descriptor),
+            ..self
+        }
+    }
+
+    fn read_config_file(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(|e| ConfigErrorKind::ConfigFile(path.to_owned(), e.into()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| ConfigErrorKind::ConfigFile(path.to_owned(), e.into()))
+    }
+}
+
+impl Config {
+    /// Resolve the configuration from the given command-line settings, together with the config
+    /// file, which is only required to exist if given explicitly.
+    ///
+    /// # Errors
+    /// Will return `Err` if the config file cannot be read or the configuration is invalid or unsafe
+    pub fn load(settings: Settings) -> Result<Self> {
+        let file_settings = if let Some(path) = &settings.config {
+            Settings::read_config_file(path)?
+        } else {
+            let data_dir = settings.data_dir.as_deref().unwrap_or_else(|| Path::new(DEFAULT_DATA_DIR));
+            let path = data_dir.join(CONFIG_FILE_NAME);
+            if path.exists() { Settings::read_config_file(&path)? } else { Settings::default() }
+        };
+        let settings = settings.with_env_secrets(|name| env::var(name).ok());
+        Self::from_settings(settings.or(file_settings))
+    }
+
+    fn from_settings(settings: Settings) -> Result<Self> {
+        let network = settings.network.unwrap_or(Network::Regtest);
+        let data_dir = settings.data_dir.unwrap_or_else(|| DEFAULT_DATA_DIR.into()).join(network_dir(network));
+        let rpc_auth = match (settings.bitcoind_rpc_user, settings.bitcoind_rpc_password) {
+            (Some(user), Some(password)) => Auth::UserPass(user, password),
+            (None, None) => Auth::CookieFile(settings.bitcoind_rpc_cookie_file.unwrap_or_else(||
+                Path::new(DEFAULT_BITCOIND_DATA_DIR).join(network_dir(network)).join(".cookie"))),
+            _ => return Err(ConfigErrorKind::IncompleteRpcCredentials),
+        };
+        let descriptors = match (settings.external_descriptor, settings.internal_descriptor) {
+            (Some(external), Some(internal)) => Some(Descriptors { external, internal }),
+            (None, None) => None,
+            _ => return Err(ConfigErrorKind::IncompleteDescriptors),
+        };
+        if network == Network::Bitcoin && descriptors.as_ref().is_some_and(Descriptors::is_test) {
+            return Err(ConfigErrorKind::TestKeysOnMainnet);
+        }
+        let wallet_data_dir = data_dir.clone();
+        Ok(Self {
+            network,
+            data_dir,
+            listen_address: settings.listen_address
+                .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.parse().expect("hardcoded address should be valid")),
+            deposit_tx_confirmation_depth: settings.deposit_tx_confirmation_depth
+                .unwrap_or(DEFAULT_DEPOSIT_TX_CONFIRMATION_DEPTH),
+            fee_sources: settings.fee_sources.unwrap_or_else(|| default_fee_sources(network)),
+            wallet: WalletConfig {
+                network,
+                descriptors,
+                use_test_keys: network != Network::Bitcoin,
+                data_dir: Some(wallet_data_dir),
+                rpc_url: settings.bitcoind_rpc_url
+                    .unwrap_or_else(|| format!("http://127.0.0.1:{}", default_rpc_port(network))),
+                rpc_auth,
+            },
+        })
+    }
+}
+
+/// The per-network subdirectory of the data directory, named as for bitcoind.
+fn network_dir(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "",
+        Network::Testnet => "testnet3",
+        _ => network.to_core_arg(),
+    }
+}
+
+fn default_fee_sources(network: Network) -> Vec<FeeSource> {
+    if network == Network::Bitcoin {
+        vec![FeeSource::Bitcoind]
+    } else {
+        let fallback_fee_rate = FeeRate::from_sat_per_vb_unchecked(DEFAULT_FALLBACK_FEE_RATE);
+        vec![FeeSource::Bitcoind, FeeSource::Static(fallback_fee_rate)]
+    }
+}
+
+const fn default_rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8332,
+        Network::Testnet => 18332,
+        Network::Testnet4 => 48332,
+        Network::Signet => 38332,
+        _ => 18443,
+    }
+}
+
+type Result<T, E = ConfigErrorKind> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum ConfigErrorKind {
+    #[error("could not read config file {0}: {1}")]
+    ConfigFile(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+    #[error("bitcoind RPC user and password must be given together")]
+    IncompleteRpcCredentials,
+    #[error("external and internal wallet descriptors must be given together")]
+    IncompleteDescriptors,
+    #[error("refusing to run on mainnet with the built-in test keys")]
+    TestKeysOnMainnet,
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk_wallet::bitcoin::NetworkKind;
+    use bdk_wallet::bitcoin::bip32::Xpriv;
+    use super::*;
+    use crate::keys::{TEST_EXTERNAL_DESCRIPTOR, TEST_INTERNAL_DESCRIPTOR};
+
+    #[test]
+    fn test_config_defaults_and_precedence() {
+        let config = Config::from_settings(Settings::default()).unwrap();
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.data_dir, Path::new(".localnet/musigd/regtest"));
+        assert_eq!(config.wallet.rpc_url, "http://127.0.0.1:18443");
+        assert_eq!(config.wallet.rpc_auth, Auth::CookieFile(".localnet/bitcoind/regtest/.cookie".into()));
+        assert_eq!(config.fee_sources, [FeeSource::Bitcoind, FeeSource::Static(FeeRate::from_sat_per_vb_unchecked(2))]);
+
+        let file_settings: Settings = serde_json::from_str(r#"{
+            "network": "signet",
+            "listen_address": "0.0.0.0:50051",
+            "bitcoind_rpc_user": "user",
+            "bitcoind_rpc_password": "password",
+            "fee_sources": ["pricenode=http://127.0.0.1:8080", "bitcoind"]
+        }"#).unwrap();
+        let cli_settings = Settings::parse_from(["musigd", "--listen-address", "127.0.0.1:50052",
+            "--bitcoind-rpc-cookie-file", "/tmp/.cookie"]);
+        assert!(Settings::try_parse_from(["musigd", "--bitcoind-rpc-password", "password"]).is_err(),
+            "secrets should not be accepted on the command line");
+        let config = Config::from_settings(cli_settings.or(file_settings)).unwrap();
+        assert_eq!(config.network, Network::Signet);
+        assert_eq!(config.data_dir, Path::new(".localnet/musigd/signet"));
+        assert_eq!(config.listen_address, "127.0.0.1:50052".parse().unwrap());
+        assert_eq!(config.wallet.rpc_url, "http://127.0.0.1:38332");
+        assert_eq!(config.wallet.rpc_auth, Auth::CookieFile("/tmp/.cookie".into()),
+            "credentials from the command line should replace those in the config file as a whole");
+        assert_eq!(config.fee_sources, [FeeSource::Pricenode("http://127.0.0.1:8080".to_owned()), FeeSource::Bitcoind]);
+
+        // The password may come from the environment, to go with the username from the command line.
+        let cli_settings = Settings::parse_from(["musigd", "--bitcoind-rpc-user", "alice"])
+            .with_env_secrets(|name| (name == PASSWORD_ENV_VAR).then(|| "secret".to_owned()));
+        let config = Config::from_settings(cli_settings.or(Settings::default())).unwrap();
+        assert_eq!(config.wallet.rpc_auth, Auth::UserPass("alice".to_owned(), "secret".to_owned()));
+    }
+
+    #[test]
+    fn test_config_refuses_mainnet_test_keys() {
+        let settings = Settings { network: Some(Network::Bitcoin), ..Settings::default() };
+        let config = Config::from_settings(settings).unwrap();
+        assert!(config.wallet.descriptors.is_none() && !config.wallet.use_test_keys,
+            "there should be no wallet on mainnet until one is created or restored");
+
+        let with_descriptors = |external: String, internal: &str| Settings {
+            network: Some(Network::Bitcoin),
+            external_descriptor: Some(external),
+            internal_descriptor: Some(internal.to_owned()),
+            ..Settings::default()
+        };
+        let Descriptors { external, internal } = Descriptors::test();
+        assert!(matches!(Config::from_settings(with_descriptors(external, &internal)),
+            Err(ConfigErrorKind::TestKeysOnMainnet)));
+
+        // Nor may the test keys be slipped in as a mainnet xprv or without the checksum.
+        let test_xprv: Xpriv = TEST_EXTERNAL_DESCRIPTOR["tr(".len()..].split('/').next().unwrap().parse().unwrap();
+        let mainnet_xprv = Xpriv { network: NetworkKind::Main, ..test_xprv };
+        let other_xprv = Xpriv::new_master(NetworkKind::Main, &[1; 32]).unwrap();
+        let settings = with_descriptors(format!("tr({mainnet_xprv}/86'/0'/0'/0/*)"), &format!("tr({other_xprv}/86'/0'/0'/1/*)"));
+        assert!(matches!(Config::from_settings(settings), Err(ConfigErrorKind::TestKeysOnMainnet)));
+        let settings = with_descriptors(format!("tr({other_xprv}/86'/0'/0'/0/*)"), TEST_INTERNAL_DESCRIPTOR.split('#').next().unwrap());
+        assert!(matches!(Config::from_settings(settings), Err(ConfigErrorKind::TestKeysOnMainnet)));
+        let settings = with_descriptors(format!("tr({other_xprv}/86'/0'/0'/0/*)"), &format!("tr({other_xprv}/86'/0'/0'/1/*)"));
+        assert!(Config::from_settings(settings).is_ok());
+    }
+}
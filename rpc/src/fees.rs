@@ -0,0 +1,240 @@
+use bdk_bitcoind_rpc::bitcoincore_rpc::{self, Auth, Client, RpcApi as _};
+use bdk_wallet::bitcoin::FeeRate;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::task;
+
+/// The confirmation target of the deposit tx, which is broadcast right after the trade setup and
+/// should confirm soon.
+pub const DEPOSIT_TX_CONFIRMATION_TARGET: u16 = 2;
+/// The confirmation target of the prepared txs, which are broadcast much later, if ever, and can be
+/// bumped through their anchors.
+pub const PREPARED_TX_CONFIRMATION_TARGET: u16 = 144;
+/// The largest confirmation target for which the pricenode's regular fee rate is used, rather than
+/// its minimum fee rate.
+const PRICENODE_PRIORITY_TARGET: u16 = 6;
+const PRICENODE_TIMEOUT_SECS: u64 = 10;
+
+/// Where our fee rates come from, e.g. our own node, a Bisq pricenode or static values.
+pub trait FeeEstimator {
+    /// Estimate the fee rate for a tx to confirm within the given number of blocks.
+    ///
+    /// # Errors
+    /// Will return `Err` if the source cannot be reached or has no estimate
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<FeeRate>;
+}
+
+/// Fee estimation by `estimatesmartfee`, which has no estimates until the node has seen enough txs
+/// confirm (so never on a fresh regtest chain).
+pub struct BitcoindFeeEstimator {
+    rpc_url: String,
+    rpc_auth: Auth,
+}
+
+impl BitcoindFeeEstimator {
+    pub const fn new(rpc_url: String, rpc_auth: Auth) -> Self {
+        Self { rpc_url, rpc_auth }
+    }
+}
+
+impl FeeEstimator for BitcoindFeeEstimator {
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<FeeRate> {
+        // Connect afresh each time, as the cookie file changes whenever bitcoind restarts.
+        let result = task::block_in_place(|| Client::new(&self.rpc_url, self.rpc_auth.clone())?
+            .estimate_smart_fee(conf_target, None))?;
+        let sat_per_kvb = result.fee_rate.ok_or(FeeEstimationErrorKind::NoEstimate)?.to_sat();
+        Ok(FeeRate::from_sat_per_kwu(sat_per_kvb / 4))
+    }
+}
+
+/// Fee estimation by a Bisq pricenode, which gives just a regular and a minimum fee rate, used for
+/// the short and long confirmation targets respectively.
+// TODO: Support pricenodes over Tor and HTTPS.
+pub struct PricenodeFeeEstimator {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PricenodeFees {
+    data_map: PricenodeFeeRates,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PricenodeFeeRates {
+    /// The regular fee rate in sats per vbyte.
+    btc_tx_fee: u64,
+    /// The minimum fee rate in sats per vbyte.
+    btc_min_tx_fee: u64,
+}
+
+impl PricenodeFeeEstimator {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+impl FeeEstimator for PricenodeFeeEstimator {
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<FeeRate> {
+        let url = format!("{}/getFees", self.url.trim_end_matches('/'));
+        let response = task::block_in_place(|| minreq::get(url).with_timeout(PRICENODE_TIMEOUT_SECS).send())?;
+        if response.status_code != 200 {
+            return Err(FeeEstimationErrorKind::PricenodeStatus(response.status_code));
+        }
+        let fees: PricenodeFees = response.json()?;
+        let sat_per_vb = if conf_target <= PRICENODE_PRIORITY_TARGET {
+            fees.data_map.btc_tx_fee
+        } else {
+            fees.data_map.btc_min_tx_fee
+        };
+        FeeRate::from_sat_per_vb(sat_per_vb).ok_or(FeeEstimationErrorKind::NoEstimate)
+    }
+}
+
+/// A static fee rate, regardless of the confirmation target.
+pub struct StaticFeeEstimator {
+    pub fee_rate: FeeRate,
+}
+
+impl FeeEstimator for StaticFeeEstimator {
+    fn estimate_fee_rate(&self, _conf_target: u16) -> Result<FeeRate> {
+        Ok(self.fee_rate)
+    }
+}
+
+/// Fee estimation from the first source which has an estimate, trying each in turn.
+pub struct FallbackFeeEstimator {
+    sources: Vec<Box<dyn FeeEstimator + Send + Sync>>,
+}
+
+impl FallbackFeeEstimator {
+    pub fn new(sources: Vec<FeeSource>, rpc_url: &str, rpc_auth: &Auth) -> Self {
+        let sources = sources.into_iter()
+            .map(|source| -> Box<dyn FeeEstimator + Send + Sync> {
+                match source {
+                    FeeSource::Bitcoind => Box::new(BitcoindFeeEstimator::new(rpc_url.to_owned(), rpc_auth.clone())),
+                    FeeSource::Pricenode(url) => Box::new(PricenodeFeeEstimator::new(url)),
+                    FeeSource::Static(fee_rate) => Box::new(StaticFeeEstimator { fee_rate }),
+                }
+            })
+            .collect();
+        Self { sources }
+    }
+}
+
+impl FeeEstimator for FallbackFeeEstimator {
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<FeeRate> {
+        let mut last_error = FeeEstimationErrorKind::NoEstimate;
+        for source in &self.sources {
+            match source.estimate_fee_rate(conf_target) {
+                Ok(fee_rate) => return Ok(fee_rate),
+                Err(e) => {
+                    eprintln!("Fee estimation failed, trying next source: {e}");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// A configured fee estimation source: `bitcoind`, `pricenode=<URL>` or `static=<sats per vbyte>`.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FeeSource {
+    Bitcoind,
+    Pricenode(String),
+    Static(FeeRate),
+}
+
+impl FromStr for FeeSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "bitcoind" => Ok(Self::Bitcoind),
+            Some(("pricenode", url)) => Ok(Self::Pricenode(url.to_owned())),
+            Some(("static", sat_per_vb)) => sat_per_vb.parse().ok()
+                .and_then(FeeRate::from_sat_per_vb)
+                .map(Self::Static)
+                .ok_or_else(|| format!("invalid static fee rate: {sat_per_vb}")),
+            _ => Err(format!("unknown fee source: {s}")),
+        }
+    }
+}
+
+impl TryFrom<String> for FeeSource {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> { value.parse() }
+}
+
+impl fmt::Display for FeeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bitcoind => write!(f, "bitcoind"),
+            Self::Pricenode(url) => write!(f, "pricenode={url}"),
+            Self::Static(fee_rate) => write!(f, "static={}", fee_rate.to_sat_per_vb_ceil()),
+        }
+    }
+}
+
+type Result<T, E = FeeEstimationErrorKind> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum FeeEstimationErrorKind {
+    BitcoindRpc(#[from] bitcoincore_rpc::Error),
+    Pricenode(#[from] minreq::Error),
+    #[error("pricenode responded with HTTP status {0}")]
+    PricenodeStatus(i32),
+    #[error("no fee estimate available")]
+    NoEstimate,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead as _, BufReader, Write as _};
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    /// Serve a single HTTP request with the given body, returning the URL to request.
+    fn spawn_pricenode_stub(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request_line = BufReader::new(&stream).lines().next().unwrap().unwrap();
+            assert_eq!(request_line, "GET /getFees HTTP/1.1");
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n{body}", body.len()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_pricenode_fee_estimator() {
+        let body = r#"{"bitcoinFeesTs":1700000000,"dataMap":{"btcTxFee":12,"btcMinTxFee":2}}"#;
+        let estimator = PricenodeFeeEstimator::new(spawn_pricenode_stub(body));
+        assert_eq!(estimator.estimate_fee_rate(DEPOSIT_TX_CONFIRMATION_TARGET).unwrap(),
+            FeeRate::from_sat_per_vb_unchecked(12));
+        let estimator = PricenodeFeeEstimator::new(spawn_pricenode_stub(body));
+        assert_eq!(estimator.estimate_fee_rate(PREPARED_TX_CONFIRMATION_TARGET).unwrap(),
+            FeeRate::from_sat_per_vb_unchecked(2));
+    }
+
+    #[test]
+    fn test_fallback_fee_estimator() {
+        let sources = ["pricenode=http://127.0.0.1:1", "static=5"].map(|s| s.parse().unwrap()).into();
+        let estimator = FallbackFeeEstimator::new(sources, "http://127.0.0.1:1", &Auth::None);
+        assert_eq!(estimator.estimate_fee_rate(DEPOSIT_TX_CONFIRMATION_TARGET).unwrap(),
+            FeeRate::from_sat_per_vb_unchecked(5), "unreachable pricenode should fall back to static fee rate");
+
+        assert!("static=x".parse::<FeeSource>().is_err());
+        assert_eq!("bitcoind".parse::<FeeSource>().unwrap().to_string(), "bitcoind");
+    }
+}
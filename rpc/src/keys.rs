@@ -0,0 +1,154 @@
+use bdk_wallet::bitcoin::Network;
+use bdk_wallet::bitcoin::bip32::{self, Xpriv};
+use bdk_wallet::bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bdk_wallet::descriptor::DescriptorError;
+use bdk_wallet::keys::bip39::Mnemonic;
+use bdk_wallet::miniscript::Descriptor;
+use bdk_wallet::miniscript::descriptor::DescriptorSecretKey;
+use bdk_wallet::template::{Bip86, DescriptorTemplate as _};
+use bdk_wallet::KeychainKind;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write as _};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::store::open_private;
+
+//noinspection SpellCheckingInspection
+pub const TEST_EXTERNAL_DESCRIPTOR: &str = "tr(tprv8ZgxMBicQKsPdrjwWCyXqqJ4YqcyG4DmKtjjsRt29v1PtD3r3PuFJAj\
+    WytzcvSTKnZAGAkPSmnrdnuHWxCAwy3i1iPhrtKAfXRH7dVCNGp6/86'/1'/0'/0/*)#g9xn7wf9";
+//noinspection SpellCheckingInspection
+pub const TEST_INTERNAL_DESCRIPTOR: &str = "tr(tprv8ZgxMBicQKsPdrjwWCyXqqJ4YqcyG4DmKtjjsRt29v1PtD3r3PuFJAj\
+    WytzcvSTKnZAGAkPSmnrdnuHWxCAwy3i1iPhrtKAfXRH7dVCNGp6/86'/1'/0'/1/*)#e3rjrmea";
+
+const SEED_FILE_NAME: &str = "wallet-seed.json";
+
+/// The external (receive) and internal (change) descriptors of a wallet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Descriptors {
+    pub external: String,
+    pub internal: String,
+}
+
+impl Descriptors {
+    pub fn test() -> Self {
+        Self { external: TEST_EXTERNAL_DESCRIPTOR.to_owned(), internal: TEST_INTERNAL_DESCRIPTOR.to_owned() }
+    }
+
+    /// Whether either descriptor holds the private key of the test descriptors, regardless of the
+    /// network, derivation path, checksum or spelling of the descriptor.
+    pub fn is_test(&self) -> bool {
+        let test_keys = secret_keys(TEST_EXTERNAL_DESCRIPTOR);
+        [&self.external, &self.internal].into_iter()
+            .flat_map(|descriptor| secret_keys(descriptor))
+            .any(|key| test_keys.contains(&key))
+    }
+}
+
+/// The private keys (or extended private keys' keys) of the given descriptor, if it parses.
+fn secret_keys(descriptor: &str) -> Vec<SecretKey> {
+    let Ok((_, key_map)) = Descriptor::parse_descriptor(&Secp256k1::new(), descriptor) else {
+        return vec![];
+    };
+    key_map.into_values()
+        .map(|key| match key {
+            DescriptorSecretKey::Single(key) => key.key.inner,
+            DescriptorSecretKey::XPrv(key) => key.xkey.private_key,
+            DescriptorSecretKey::MultiXPrv(key) => key.xkey.private_key,
+        })
+        .collect()
+}
+
+/// The BIP-39 mnemonic (and optional passphrase) from which a wallet is derived, together with its
+/// birthday: the chain height when the wallet was created, below which there are no blocks to scan.
+///
+/// Note that the mnemonic is persisted in the clear, so the seed file is only readable by the owner.
+// TODO: Encrypt the seed file, with a password given to the daemon at startup.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WalletSeed {
+    #[serde(with = "mnemonic_as_str")]
+    pub mnemonic: Mnemonic,
+    pub passphrase: String,
+    pub birthday_height: u32,
+}
+
+impl WalletSeed {
+    /// Generate a fresh 12-word mnemonic.
+    pub fn generate(passphrase: String, birthday_height: u32) -> Self {
+        let entropy: [u8; 16] = rand::random();
+        let mnemonic = Mnemonic::from_entropy(&entropy).expect("entropy should have valid length");
+        Self { mnemonic, passphrase, birthday_height }
+    }
+
+    /// Derive the BIP-86 (single-key taproot) descriptors of the first account of the wallet.
+    ///
+    /// # Errors
+    /// Will return `Err` if key derivation fails, which is practically impossible
+    pub fn descriptors(&self, network: Network) -> Result<Descriptors, KeyDerivationError> {
+        let xprv = Xpriv::new_master(network, &self.mnemonic.to_seed(self.passphrase.as_str()))?;
+        let descriptor = |keychain| -> Result<_, KeyDerivationError> {
+            let (descriptor, key_map, _) = Bip86(xprv, keychain).build(network)?;
+            Ok(descriptor.to_string_with_secret(&key_map))
+        };
+        Ok(Descriptors { external: descriptor(KeychainKind::External)?, internal: descriptor(KeychainKind::Internal)? })
+    }
+
+    /// # Errors
+    /// Will return `Err` if the seed file exists but cannot be read or parsed
+    pub fn load(dir: &Path) -> io::Result<Option<Self>> {
+        match File::open(dir.join(SEED_FILE_NAME)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Save the seed to a new file, never overwriting an existing one. The seed is written to a
+    /// temporary file first and then linked into place, so that a crash never leaves a partial seed
+    /// file behind.
+    ///
+    /// # Errors
+    /// Will return `Err` if the seed file already exists or cannot be written
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let (path, tmp_path) = (dir.join(SEED_FILE_NAME), dir.join(SEED_FILE_NAME).with_extension("tmp"));
+        let mut file = open_private(&tmp_path, OpenOptions::new().write(true).truncate(true))?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+        let result = fs::hard_link(&tmp_path, path);
+        fs::remove_file(tmp_path)?;
+        result
+    }
+
+    /// Remove the seed file, to undo a save when the wallet could not be set up after all.
+    ///
+    /// # Errors
+    /// Will return `Err` if the seed file cannot be removed
+    pub fn remove(dir: &Path) -> io::Result<()> {
+        fs::remove_file(dir.join(SEED_FILE_NAME))
+    }
+}
+
+mod mnemonic_as_str {
+    use serde::de::Error as _;
+    use serde::{Deserialize as _, Deserializer, Serializer};
+
+    use super::Mnemonic;
+
+    pub fn serialize<S: Serializer>(mnemonic: &Mnemonic, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(mnemonic)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Mnemonic, D::Error> {
+        Mnemonic::parse(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum KeyDerivationError {
+    Bip32(#[from] bip32::Error),
+    Descriptor(#[from] DescriptorError),
+}
@@ -4,8 +4,12 @@ pub mod pb {
     pub mod walletrpc;
 }
 
+pub mod config;
+pub mod fees;
+pub mod keys;
 mod observable;
 mod protocol;
 pub mod server;
 mod storage;
+pub mod store;
 pub mod wallet;
@@ -3,6 +3,8 @@ package bisq;
 import com.google.common.collect.ImmutableMap;
 import io.grpc.Grpc;
 import io.grpc.InsecureChannelCredentials;
+import io.grpc.Status;
+import io.grpc.StatusRuntimeException;
 import musigrpc.MusigGrpc;
 import musigrpc.Rpc.*;
 
@@ -105,8 +107,8 @@ public class TradeProtocolClient {
 
         var sellerDepositPsbt = stub.signDepositTx(DepositTxSignatureRequest.newBuilder()
                 .setTradeId(sellerTradeId)
-                // REDACT buyer's swapTxInputPartialSignature:
-                .setPeersPartialSignatures(buyerPartialSignatureMessage.toBuilder().clearSwapTxInputPartialSignature())
+                // The buyer's swapTxInputPartialSignature is withheld by the buyer's server until message E:
+                .setPeersPartialSignatures(buyerPartialSignatureMessage)
                 .build());
         System.out.println("Got reply: " + sellerDepositPsbt);
 
@@ -121,26 +123,43 @@ public class TradeProtocolClient {
         // *** BUYER BROADCASTS DEPOSIT TX ***
         var depositTxConfirmationIter = stub.publishDepositTx(PublishDepositTxRequest.newBuilder()
                 .setTradeId(buyerTradeId)
+                .setDepositPsbt(sellerDepositPsbt)
                 .build());
-        depositTxConfirmationIter.forEachRemaining(reply -> System.out.println("Got reply: " + reply));
+        // The stream only ends once the deposit tx is deep enough in the chain, so just take the first
+        // update, for when it enters the mempool:
+        System.out.println("Got reply: " + depositTxConfirmationIter.next());
         // ***********************************
 
+        // *** BUYER STARTS PAYMENT ***
+        var paymentStartedMessage = stub.confirmPaymentStarted(ConfirmPaymentStartedRequest.newBuilder()
+                .setTradeId(buyerTradeId)
+                .build());
+        System.out.println("Got reply: " + paymentStartedMessage);
+        // ****************************
+
         // Buyer sends Message E to seller.
 
         var swapTxSignatureResponse = stub.signSwapTx(SwapTxSignatureRequest.newBuilder()
                 .setTradeId(sellerTradeId)
-                // NOW send the redacted buyer's swapTxInputPartialSignature:
-                .setSwapTxInputPeersPartialSignature(buyerPartialSignatureMessage.getSwapTxInputPartialSignature())
+                // NOW send the withheld buyer's swapTxInputPartialSignature:
+                .setSwapTxInputPeersPartialSignature(paymentStartedMessage.getSwapTxInputPartialSignature())
                 .build());
         System.out.println("Got reply: " + swapTxSignatureResponse);
 
+        // *** SELLER RECEIVES PAYMENT ***
+        var paymentReceivedMessage = stub.confirmPaymentReceived(ConfirmPaymentReceivedRequest.newBuilder()
+                .setTradeId(sellerTradeId)
+                .build());
+        System.out.println("Got reply: " + paymentReceivedMessage);
+        // *******************************
+
         if (closureType == ClosureType.COOPERATIVE) {
             // Seller sends Message F to buyer.
 
             // *** BUYER CLOSES TRADE ***
             var buyersCloseTradeResponse = stub.closeTrade(CloseTradeRequest.newBuilder()
                     .setTradeId(buyerTradeId)
-                    .setMyOutputPeersPrvKeyShare(swapTxSignatureResponse.getPeerOutputPrvKeyShare())
+                    .setMyOutputPeersPrvKeyShare(paymentReceivedMessage.getPeerOutputPrvKeyShare())
                     .build());
             System.out.println("Got reply: " + buyersCloseTradeResponse);
             // **************************
@@ -166,18 +185,37 @@ public class TradeProtocolClient {
             System.out.println("Got reply: " + sellersCloseTradeResponse);
             // *********************************
 
-            // Buyer never got Message F from seller -- picks up Swap Tx from bitcoin network instead.
+            // Buyer never got Message F from seller -- the Rust server picks up Swap Tx from bitcoin network instead.
 
             // *** BUYER CLOSES TRADE ***
-            var buyersCloseTradeResponse = stub.closeTrade(CloseTradeRequest.newBuilder()
+            var buyersCloseTradeResponse = closeTradeOnceSwapTxSeen(stub, CloseTradeRequest.newBuilder()
                     .setTradeId(buyerTradeId)
-                    .setSwapTx(swapTxSignatureResponse.getSwapTx())
                     .build());
             System.out.println("Got reply: " + buyersCloseTradeResponse);
             // **************************
         }
     }
 
+    private static CloseTradeResponse closeTradeOnceSwapTxSeen(MusigGrpc.MusigBlockingStub stub,
+                                                               CloseTradeRequest request) {
+        // The server polls the node for new mempool txs, so the swap tx may not have been seen yet.
+        for (int attempt = 1; ; attempt++) {
+            try {
+                return stub.closeTrade(request);
+            } catch (StatusRuntimeException e) {
+                if (e.getStatus().getCode() != Status.Code.FAILED_PRECONDITION || attempt == 10) {
+                    throw e;
+                }
+            }
+            try {
+                Thread.sleep(1000);
+            } catch (InterruptedException e) {
+                Thread.currentThread().interrupt();
+                throw new RuntimeException(e);
+            }
+        }
+    }
+
     @SuppressWarnings("SpellCheckingInspection")
     private static List<ReceiverAddressAndAmount> mockReceivers() {
         return ImmutableMap.of(
@@ -19,6 +19,10 @@ impl<T> Observable<T> {
         Self { value, senders: Vec::new() }
     }
 
+    pub const fn get(&self) -> &T {
+        &self.value
+    }
+
     fn try_into_unobserved(mut self) -> Result<T, StillObservedError<T>> {
         self.senders.retain(|s| !s.is_closed());
         if self.senders.is_empty() { Ok(self.value) } else { Err(StillObservedError(self)) }
@@ -73,6 +77,13 @@ impl<K, V> ObservableHashMap<K, V>
             Entry::Vacant(entry) => entry.insert(Observable::default())
         }.observe()
     }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+        where K: Borrow<Q>,
+              Q: Eq + Hash + ?Sized
+    {
+        self.map.contains_key(k)
+    }
 }
 
 impl<K, V> ObservableHashMap<K, V>
@@ -1,21 +1,23 @@
 use bdk_wallet::{Balance, LocalOutput};
-use bdk_wallet::bitcoin::{Address, Amount, Txid};
+use bdk_wallet::bitcoin::{consensus, Address, Amount, FeeRate, OutPoint, Psbt, SignedAmount, Transaction, Txid};
 use bdk_wallet::bitcoin::address::NetworkUnchecked;
 use bdk_wallet::bitcoin::consensus::Encodable as _;
 use bdk_wallet::bitcoin::hashes::Hash as _;
-use bdk_wallet::chain::ChainPosition;
+use bdk_wallet::chain::{BlockId, ChainPosition};
+use bdk_wallet::error::CreateTxError;
 use musig2::{LiftedSignature, PubNonce};
 use musig2::secp::{Point, MaybeScalar, Scalar};
 use prost::UnknownEnumValue;
 use tonic::{Result, Status};
 
+use crate::fees::FeeEstimationErrorKind;
 use crate::pb::musigrpc::{self, NonceSharesMessage, PartialSignaturesMessage,
-    ReceiverAddressAndAmount};
-use crate::pb::walletrpc::{ConfEvent, ConfidenceType, ConfirmationBlockTime, TransactionOutput,
-    WalletBalanceResponse};
-use crate::protocol::{ExchangedNonces, ExchangedSigs, ProtocolErrorKind, RedirectionReceiver, Role};
+    ReceiverAddressAndAmount, TxConfirmationStatus};
+use crate::pb::walletrpc::{self, BlockEvent, BlockEventType, BroadcastResponse, ConfEvent, ConfidenceType, ConfirmationBlockTime,
+    GetWalletInfoResponse, SpendEvent, TransactionDetails, TransactionOutput, WalletBalanceResponse};
+use crate::protocol::{ExchangedNonces, ExchangedSigs, ProtocolErrorKind, RedirectionReceiver, Role, TradeTxType};
 use crate::storage::{ByRef, ByVal};
-use crate::wallet::TxConfidence;
+use crate::wallet::{ChainTipUpdate, TxConfidence, TxDetails, WalletErrorKind, WalletInfo, WalletSource};
 
 pub trait TryProtoInto<T> {
     /// # Errors
@@ -45,6 +47,27 @@ impl TryProtoInto<Txid> for &[u8] {
     }
 }
 
+impl TryProtoInto<Psbt> for &[u8] {
+    fn try_proto_into(self) -> Result<Psbt> {
+        Psbt::deserialize(self).map_err(|e| Status::invalid_argument(format!("could not decode PSBT: {e}")))
+    }
+}
+
+impl TryProtoInto<FeeRate> for u64 {
+    fn try_proto_into(self) -> Result<FeeRate> {
+        if self == 0 {
+            return Err(Status::invalid_argument("fee rate must be nonzero"));
+        }
+        Ok(FeeRate::from_sat_per_kwu(self))
+    }
+}
+
+impl TryProtoInto<Transaction> for &[u8] {
+    fn try_proto_into(self) -> Result<Transaction> {
+        consensus::deserialize(self).map_err(|e| Status::invalid_argument(format!("could not decode tx: {e}")))
+    }
+}
+
 impl TryProtoInto<Role> for i32 {
     fn try_proto_into(self) -> Result<Role> {
         TryInto::<musigrpc::Role>::try_into(self)
@@ -69,6 +92,18 @@ impl TryProtoInto<RedirectionReceiver<NetworkUnchecked>> for ReceiverAddressAndA
     }
 }
 
+impl TryProtoInto<(Address<NetworkUnchecked>, Amount)> for walletrpc::Recipient {
+    fn try_proto_into(self) -> Result<(Address<NetworkUnchecked>, Amount)> {
+        Ok((self.address.try_proto_into()?, Amount::from_sat(self.amount)))
+    }
+}
+
+impl TryProtoInto<OutPoint> for walletrpc::OutPoint {
+    fn try_proto_into(self) -> Result<OutPoint> {
+        Ok(OutPoint { txid: self.tx_id.try_proto_into()?, vout: self.vout })
+    }
+}
+
 impl<T> TryProtoInto<T> for Vec<u8> where for<'a> &'a [u8]: TryProtoInto<T> {
     fn try_proto_into(self) -> Result<T> { (&self[..]).try_proto_into() }
 }
@@ -135,7 +170,10 @@ impl From<ExchangedNonces<'_, ByRef>> for NonceSharesMessage {
             // Use default values for proto fields besides the nonce shares. TODO: A little hacky; consider refactoring proto.
             warning_tx_fee_bump_address: String::default(),
             redirect_tx_fee_bump_address: String::default(),
+            swap_tx_payout_address: None,
             half_deposit_psbt: Vec::default(),
+            deposit_tx_fee_rate: 0,
+            prepared_tx_fee_rate: 0,
             // Actual nonce shares...
             swap_tx_input_nonce_share:
             value.swap_tx_input_nonce_share.serialize().into(),
@@ -181,6 +219,12 @@ impl From<Balance> for WalletBalanceResponse {
     }
 }
 
+impl From<Txid> for BroadcastResponse {
+    fn from(value: Txid) -> Self {
+        Self { tx_id: value.as_byte_array().into() }
+    }
+}
+
 impl From<LocalOutput> for TransactionOutput {
     fn from(value: LocalOutput) -> Self {
         Self {
@@ -192,6 +236,39 @@ impl From<LocalOutput> for TransactionOutput {
     }
 }
 
+impl From<Option<WalletSource>> for walletrpc::WalletSource {
+    fn from(value: Option<WalletSource>) -> Self {
+        match value {
+            None => Self::NoWallet,
+            Some(WalletSource::TestKeys) => Self::TestKeys,
+            Some(WalletSource::Descriptors) => Self::Descriptors,
+            Some(WalletSource::Mnemonic) => Self::Mnemonic,
+        }
+    }
+}
+
+impl From<WalletInfo> for GetWalletInfoResponse {
+    fn from(value: WalletInfo) -> Self {
+        let (external_descriptor, internal_descriptor) = value.descriptors
+            .map(|d| (d.external, d.internal))
+            .unwrap_or_default();
+        Self {
+            network: value.network.to_string(),
+            source: walletrpc::WalletSource::from(value.source).into(),
+            birthday_height: value.birthday_height,
+            external_descriptor,
+            internal_descriptor,
+        }
+    }
+}
+
+impl From<(Option<TxConfidence>, BlockId)> for TxConfirmationStatus {
+    fn from((confidence, chain_tip): (Option<TxConfidence>, BlockId)) -> Self {
+        let ConfEvent { raw_tx, confidence_type, num_confirmations, .. } = confidence.map(Into::into).unwrap_or_default();
+        Self { tx: raw_tx.unwrap_or_default(), current_block_height: chain_tip.height, num_confirmations, confidence_type }
+    }
+}
+
 impl From<TxConfidence> for ConfEvent {
     fn from(TxConfidence { wallet_tx, num_confirmations }: TxConfidence) -> Self {
         let mut raw_tx = Vec::new();
@@ -214,9 +291,103 @@ impl From<TxConfidence> for ConfEvent {
     }
 }
 
+impl From<(TxConfidence, OutPoint)> for SpendEvent {
+    fn from((confidence, outpoint): (TxConfidence, OutPoint)) -> Self {
+        let spending_tx_id = confidence.wallet_tx.txid.as_byte_array().to_vec();
+        let (input_index, witness) = confidence.wallet_tx.tx.input.iter().enumerate()
+            .find(|(_, input)| input.previous_output == outpoint)
+            .map(|(i, input)| (i.try_into().unwrap_or(u32::MAX), input.witness.to_vec()))
+            .unwrap_or_default();
+        let ConfEvent { raw_tx, confidence_type, num_confirmations, confirmation_block_time } = confidence.into();
+        Self { raw_tx, input_index, witness, confidence_type, num_confirmations, confirmation_block_time, spending_tx_id }
+    }
+}
+
+impl From<TradeTxType> for walletrpc::TradeTxType {
+    fn from(value: TradeTxType) -> Self {
+        match value {
+            TradeTxType::Deposit => Self::Deposit,
+            TradeTxType::Swap => Self::Swap,
+            TradeTxType::Warning => Self::Warning,
+            TradeTxType::Redirect => Self::Redirect,
+            TradeTxType::Claim => Self::Claim,
+        }
+    }
+}
+
+impl From<(TxDetails, Option<(String, TradeTxType)>)> for TransactionDetails {
+    fn from((TxDetails { confidence, sent, received, fee }, trade_tx): (TxDetails, Option<(String, TradeTxType)>)) -> Self {
+        let tx_id = confidence.wallet_tx.txid.as_byte_array().to_vec();
+        let ConfEvent { confidence_type, num_confirmations, confirmation_block_time, .. } = confidence.into();
+        let (trade_id, trade_tx_type) = trade_tx.map_or_else(
+            || (String::new(), walletrpc::TradeTxType::NotTradeTx),
+            |(trade_id, tx_type)| (trade_id, tx_type.into()));
+        // Neither conversion can fail for amounts below the 21M BTC supply cap, and saturating
+        // instead can't make the subtraction overflow.
+        let net_amount = received.to_signed().unwrap_or(SignedAmount::MAX) - sent.to_signed().unwrap_or(SignedAmount::MAX);
+        Self {
+            tx_id,
+            net_amount: net_amount.to_sat(),
+            fee: fee.map(Amount::to_sat),
+            confidence_type,
+            num_confirmations,
+            confirmation_block_time,
+            trade_tx_type: trade_tx_type.into(),
+            trade_id,
+        }
+    }
+}
+
+impl From<ChainTipUpdate> for Vec<BlockEvent> {
+    fn from(ChainTipUpdate { tip, disconnected }: ChainTipUpdate) -> Self {
+        let reorg_depth = disconnected.len().try_into().unwrap_or(u32::MAX);
+        let block_event = |event_type: BlockEventType, block_id: BlockId| BlockEvent {
+            event_type: event_type.into(),
+            block_hash: block_id.hash.as_byte_array().to_vec(),
+            block_height: block_id.height,
+            reorg_depth,
+        };
+        disconnected.into_iter()
+            .map(|block_id| block_event(BlockEventType::Disconnected, block_id))
+            .chain([block_event(BlockEventType::Connected, tip)])
+            .collect()
+    }
+}
+
 impl From<ProtocolErrorKind> for Status {
     fn from(value: ProtocolErrorKind) -> Self {
-        Self::internal(value.to_string())
+        match value {
+            ProtocolErrorKind::FeeRateOutOfRange(_) => Self::out_of_range(value.to_string()),
+            ProtocolErrorKind::IllegalTradeState(_) => Self::failed_precondition(value.to_string()),
+            ProtocolErrorKind::MismatchedFeeRates |
+            ProtocolErrorKind::InvalidHalfDepositPsbt(_) |
+            ProtocolErrorKind::InvalidDepositPsbt |
+            ProtocolErrorKind::InvalidSwapTx |
+            ProtocolErrorKind::MissingDepositTxSig => Self::invalid_argument(value.to_string()),
+            _ => Self::internal(value.to_string()),
+        }
+    }
+}
+
+impl From<WalletErrorKind> for Status {
+    fn from(value: WalletErrorKind) -> Self {
+        match value {
+            WalletErrorKind::CreateTx(CreateTxError::CoinSelection(_)) |
+            WalletErrorKind::IllegalWalletState(_) => Self::failed_precondition(value.to_string()),
+            WalletErrorKind::CreateTx(CreateTxError::NoRecipients | CreateTxError::OutputBelowDustLimit(_)) |
+            WalletErrorKind::AddUtxo(_) |
+            WalletErrorKind::Address(_) |
+            WalletErrorKind::BirthdayAboveChainTip(..) |
+            WalletErrorKind::ExtractTx(_) |
+            WalletErrorKind::IncompletePsbt => Self::invalid_argument(value.to_string()),
+            _ => Self::internal(value.to_string()),
+        }
+    }
+}
+
+impl From<FeeEstimationErrorKind> for Status {
+    fn from(value: FeeEstimationErrorKind) -> Self {
+        Self::unavailable(value.to_string())
     }
 }
 
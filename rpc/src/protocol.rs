@@ -1,39 +1,28 @@
-use bdk_wallet::bitcoin::{Address, Amount, FeeRate, Network};
+use bdk_wallet::bitcoin::{absolute, psbt, taproot, transaction, Address, Amount, FeeRate, Network, OutPoint, Psbt,
+    ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut, Txid, Witness};
+use bdk_wallet::bitcoin::secp256k1::schnorr;
+use bdk_wallet::bitcoin::transaction::InputWeightPrediction;
+use bdk_wallet::bitcoin::hashes::Hash as _;
 use bdk_wallet::bitcoin::address::{NetworkChecked, NetworkUnchecked, NetworkValidation};
 use musig2::{AggNonce, KeyAggContext, LiftedSignature, NonceSeed, PartialSignature, PubNonce,
     SecNonce, SecNonceBuilder};
 use musig2::adaptor::AdaptorSignature;
 use musig2::secp::{MaybePoint, MaybeScalar, Point, Scalar};
-use std::collections::BTreeMap;
-use std::sync::{Arc, LazyLock, Mutex};
+use musig_core::{key, tx};
+use musig_core::tx::{DEPOSIT_TX_FEE_RATE_BOUNDS, PREPARED_TX_FEE_RATE_BOUNDS};
+use musig_core::musig::TMuSig2;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::storage::{ByRef, ByVal, ByOptVal, Storage, ValStorage};
 
-pub trait TradeModelStore {
-    fn add_trade_model(&self, trade_model: TradeModel);
-    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>>;
-}
-
-type TradeModelMemoryStore = Mutex<BTreeMap<String, Arc<Mutex<TradeModel>>>>;
-
-impl TradeModelStore for TradeModelMemoryStore {
-    fn add_trade_model(&self, trade_model: TradeModel) {
-        // TODO: Maybe use try_insert (or similar), to disallow overwriting a trade model with the same ID.
-        self.lock().unwrap().insert(trade_model.trade_id.clone(), Arc::new(Mutex::new(trade_model)));
-    }
-
-    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>> {
-        self.lock().unwrap().get(trade_id).map(Arc::clone)
-    }
-}
-
-pub static TRADE_MODELS: LazyLock<TradeModelMemoryStore> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
-
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct TradeModel {
     trade_id: String,
     my_role: Role,
+    /// The network of the daemon, which every address of the trade must be for. It is only unset
+    /// for trades persisted before it was recorded, which were all on signet.
+    network: Option<Network>,
     pub trade_amount: Option<Amount>,
     pub buyers_security_deposit: Option<Amount>,
     pub sellers_security_deposit: Option<Amount>,
@@ -41,11 +30,30 @@ pub struct TradeModel {
     pub prepared_tx_fee_rate: Option<FeeRate>,
     buyer_output_key_ctx: KeyCtx,
     seller_output_key_ctx: KeyCtx,
+    #[serde(with = "crate::store::assume_checked")]
     buyers_warning_tx_fee_bump_address: Option<Address>,
+    #[serde(with = "crate::store::assume_checked")]
     sellers_warning_tx_fee_bump_address: Option<Address>,
+    #[serde(with = "crate::store::assume_checked")]
     buyers_redirect_tx_fee_bump_address: Option<Address>,
+    #[serde(with = "crate::store::assume_checked")]
     sellers_redirect_tx_fee_bump_address: Option<Address>,
+    #[serde(with = "crate::store::assume_checked")]
+    swap_tx_payout_address: Option<Address>,
+    #[serde(with = "crate::store::assume_checked")]
     redirection_receivers: Option<Vec<RedirectionReceiver>>,
+    my_half_deposit_psbt: Option<Psbt>,
+    peers_half_deposit_psbt: Option<Psbt>,
+    deposit_psbt: Option<Psbt>,
+    swap_tx: Option<Transaction>,
+    buyers_warning_tx: Option<Transaction>,
+    sellers_warning_tx: Option<Transaction>,
+    buyers_redirect_tx: Option<Transaction>,
+    sellers_redirect_tx: Option<Transaction>,
+    payment_received_confirmed: bool,
+    /// The number of confirmations of the swap tx once we have force-closed the trade by publishing
+    /// it, tracked up to the deposit tx confirmation depth.
+    pub swap_tx_num_confirmations: Option<u32>,
     swap_tx_input_sig_ctx: SigCtx,
     buyers_warning_tx_buyer_input_sig_ctx: SigCtx,
     buyers_warning_tx_seller_input_sig_ctx: SigCtx,
@@ -55,7 +63,16 @@ pub struct TradeModel {
     sellers_redirect_tx_input_sig_ctx: SigCtx,
 }
 
-#[derive(Default, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TradeTxType {
+    Deposit,
+    Swap,
+    Warning,
+    Redirect,
+    Claim,
+}
+
+#[derive(Default, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Role {
     #[default] SellerAsMaker,
     SellerAsTaker,
@@ -84,6 +101,8 @@ pub struct ExchangedSigs<'a, S: Storage> {
     pub swap_tx_input_partial_signature: Option<S::Store<'a, PartialSignature>>,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "", deserialize = "Address<V>: Deserialize<'de>"))]
 pub struct RedirectionReceiver<V: NetworkValidation = NetworkChecked> {
     pub address: Address<V>,
     pub amount: Amount,
@@ -95,30 +114,36 @@ impl RedirectionReceiver<NetworkUnchecked> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "PrvKey::Store<Scalar>: Serialize", deserialize = "PrvKey::Store<Scalar>: Deserialize<'de>"))]
 pub struct KeyPair<PrvKey: ValStorage = ByVal> {
     pub pub_key: Point,
     pub prv_key: PrvKey::Store<Scalar>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct NoncePair {
     pub pub_nonce: PubNonce,
     pub sec_nonce: Option<SecNonce>,
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 struct KeyCtx {
     am_buyer: bool,
     my_key_share: Option<KeyPair>,
     peers_key_share: Option<KeyPair<ByOptVal>>,
     aggregated_key: Option<KeyPair<ByOptVal>>,
     key_agg_ctx: Option<KeyAggContext>,
+    /// The same key, tweaked with the claim leaf of the warning tx paying to it.
+    warning_output_key_agg_ctx: Option<KeyAggContext>,
 }
 
 // TODO: For safety, this should hold a reference to the KeyCtx our nonce & signature share (& final
 //  aggregation) are built from, so that we don't have to pass it repeatedly as a method parameter.
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 struct SigCtx {
     am_buyer: bool,
+    spends_warning_output: bool,
     adaptor_point: MaybePoint,
     my_nonce_share: Option<NoncePair>,
     peers_nonce_share: Option<PubNonce>,
@@ -130,8 +155,8 @@ struct SigCtx {
 }
 
 impl TradeModel {
-    pub fn new(trade_id: String, my_role: Role) -> Self {
-        let mut trade_model = Self { trade_id, my_role, ..Default::default() };
+    pub fn new(trade_id: String, my_role: Role, network: Network) -> Self {
+        let mut trade_model = Self { trade_id, my_role, network: Some(network), ..Default::default() };
         let am_buyer = trade_model.am_buyer();
         trade_model.buyer_output_key_ctx.am_buyer = am_buyer;
         trade_model.seller_output_key_ctx.am_buyer = am_buyer;
@@ -142,10 +167,24 @@ impl TradeModel {
         trade_model.sellers_warning_tx_seller_input_sig_ctx.am_buyer = am_buyer;
         trade_model.buyers_redirect_tx_input_sig_ctx.am_buyer = am_buyer;
         trade_model.sellers_redirect_tx_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.buyers_redirect_tx_input_sig_ctx.spends_warning_output = true;
+        trade_model.sellers_redirect_tx_input_sig_ctx.spends_warning_output = true;
         trade_model
     }
 
-    const fn am_buyer(&self) -> bool {
+    pub fn trade_id(&self) -> &str {
+        &self.trade_id
+    }
+
+    fn network(&self) -> Network {
+        self.network.unwrap_or(Network::Signet)
+    }
+
+    pub const fn am_maker(&self) -> bool {
+        matches!(self.my_role, Role::SellerAsMaker | Role::BuyerAsMaker)
+    }
+
+    pub const fn am_buyer(&self) -> bool {
         matches!(self.my_role, Role::BuyerAsMaker | Role::BuyerAsTaker)
     }
 
@@ -173,28 +212,50 @@ impl TradeModel {
         }
     }
 
+    pub fn set_fee_rates(&mut self, deposit_tx_fee_rate: FeeRate, prepared_tx_fee_rate: FeeRate) -> Result<()> {
+        if !DEPOSIT_TX_FEE_RATE_BOUNDS.contains(&deposit_tx_fee_rate) {
+            return Err(ProtocolErrorKind::FeeRateOutOfRange(deposit_tx_fee_rate));
+        }
+        if !PREPARED_TX_FEE_RATE_BOUNDS.contains(&prepared_tx_fee_rate) {
+            return Err(ProtocolErrorKind::FeeRateOutOfRange(prepared_tx_fee_rate));
+        }
+        self.deposit_tx_fee_rate = Some(deposit_tx_fee_rate);
+        self.prepared_tx_fee_rate = Some(prepared_tx_fee_rate);
+        Ok(())
+    }
+
+    /// Check that the peer has the same fee rates as us, which the maker fixes for both.
+    pub fn check_peer_fee_rates(&self, deposit_tx_fee_rate: FeeRate, prepared_tx_fee_rate: FeeRate) -> Result<()> {
+        if self.deposit_tx_fee_rate != Some(deposit_tx_fee_rate) || self.prepared_tx_fee_rate != Some(prepared_tx_fee_rate) {
+            return Err(ProtocolErrorKind::MismatchedFeeRates);
+        }
+        Ok(())
+    }
+
     pub fn aggregate_key_shares(&mut self) -> Result<()> {
         self.buyer_output_key_ctx.aggregate_key_shares()?;
         self.seller_output_key_ctx.aggregate_key_shares()?;
+        // Each trader's WarningTx output has a claim leaf for the trader's key share for its own payout,
+        // which is never revealed (see 'musig-trade-protocol-messages.txt'):
+        let buyers_claim_key = self.buyer_output_key_ctx.get_pub_key_share(true)
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        let sellers_claim_key = self.seller_output_key_ctx.get_pub_key_share(false)
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        self.seller_output_key_ctx.aggregate_warning_output_key(buyers_claim_key)?;
+        self.buyer_output_key_ctx.aggregate_warning_output_key(sellers_claim_key)?;
         Ok(())
     }
 
-    //noinspection SpellCheckingInspection
-    pub fn init_my_fee_bump_addresses(&mut self) -> Result<()> {
-        // TODO: Replace dummy addresses with real ones provided by a service, say by passing in a
-        //  suitable service or context object. (Need to make the network configurable as well.)
+    /// Set our addresses for the fee bump (anchor) outputs of our warning and redirect txs, which
+    /// must be fresh addresses of our wallet.
+    pub const fn set_my_fee_bump_addresses(&mut self, [warning_tx_fee_bump_address, redirect_tx_fee_bump_address]: [Address; 2]) {
         if self.am_buyer() {
-            self.buyers_warning_tx_fee_bump_address = Some("tb1pkar3gerekw8f9gef9vn9xz0qypytgacp9wa5saelpksdgct33qdqs257jl"
-                .parse::<Address<_>>()?.require_network(Network::Signet)?);
-            self.buyers_redirect_tx_fee_bump_address = Some("tb1pv537m7m6w0gdrcdn3mqqdpgrk3j400yrdrjwf5c9whyl2f8f4p6qg5eh2l"
-                .parse::<Address<_>>()?.require_network(Network::Signet)?);
+            self.buyers_warning_tx_fee_bump_address = Some(warning_tx_fee_bump_address);
+            self.buyers_redirect_tx_fee_bump_address = Some(redirect_tx_fee_bump_address);
         } else {
-            self.sellers_warning_tx_fee_bump_address = Some("tb1pzvynlely05x82u40cts3znctmvyskue74xa5zwy0t5ueuv92726s0cz8g8"
-                .parse::<Address<_>>()?.require_network(Network::Signet)?);
-            self.sellers_redirect_tx_fee_bump_address = Some("tb1pt5xd4aqe9whmvlz78mt39rvdlgpp6hujs5ggwan5285zjnsf73rq8kunpq"
-                .parse::<Address<_>>()?.require_network(Network::Signet)?);
+            self.sellers_warning_tx_fee_bump_address = Some(warning_tx_fee_bump_address);
+            self.sellers_redirect_tx_fee_bump_address = Some(redirect_tx_fee_bump_address);
         }
-        Ok(())
     }
 
     pub fn get_my_fee_bump_addresses(&self) -> Option<[&Address; 2]> {
@@ -206,9 +267,8 @@ impl TradeModel {
     }
 
     pub fn set_peer_fee_bump_addresses(&mut self, fee_bump_addresses: [Address<NetworkUnchecked>; 2]) -> Result<()> {
-        // TODO: Make the required network configurable and enforced to match the one above:
         let (warning_tx_fee_bump_address, redirect_tx_fee_bump_address) = fee_bump_addresses
-            .map(|addr| addr.require_network(Network::Signet)).into();
+            .map(|addr| addr.require_network(self.network())).into();
         if self.am_buyer() {
             self.sellers_warning_tx_fee_bump_address = Some(warning_tx_fee_bump_address?);
             self.sellers_redirect_tx_fee_bump_address = Some(redirect_tx_fee_bump_address?);
@@ -219,14 +279,34 @@ impl TradeModel {
         Ok(())
     }
 
+    /// Set the seller's address for the swap tx payout, which must be a fresh address of our wallet.
+    /// The buyer gets it from the peer instead.
+    pub const fn set_my_swap_tx_payout_address(&mut self, address: Address) {
+        if !self.am_buyer() {
+            self.swap_tx_payout_address = Some(address);
+        }
+    }
+
+    /// The seller's address for the swap tx payout, which the buyer needs to build the same swap tx.
+    pub fn get_my_swap_tx_payout_address(&self) -> Option<&Address> {
+        self.swap_tx_payout_address.as_ref().filter(|_| !self.am_buyer())
+    }
+
+    pub fn set_peer_swap_tx_payout_address(&mut self, address: Option<Address<NetworkUnchecked>>) -> Result<()> {
+        if self.am_buyer() {
+            let address = address.ok_or(ProtocolErrorKind::MissingSwapTxPayoutAddress)?;
+            self.swap_tx_payout_address = Some(address.require_network(self.network())?);
+        }
+        Ok(())
+    }
+
     pub fn set_redirection_receivers<I, E>(&mut self, receivers: I) -> Result<(), E>
         where I: IntoIterator<Item=Result<RedirectionReceiver<NetworkUnchecked>, E>>,
               E: From<ProtocolErrorKind>
     {
-        // TODO: Make the required network configurable and enforced to match the ones above.
         let mut vec = Vec::new();
         for receiver in receivers {
-            vec.push(receiver?.require_network(Network::Signet)?);
+            vec.push(receiver?.require_network(self.network())?);
         }
         vec.shrink_to_fit();
         self.redirection_receivers = Some(vec);
@@ -299,25 +379,280 @@ impl TradeModel {
         Ok(())
     }
 
+    /// The output paying our half of the deposit, which our half-deposit PSBT must fund. It goes
+    /// to our payout output, the two halves being merged into the full payout outputs later.
+    pub fn get_my_half_deposit_output(&self) -> Result<TxOut> {
+        let [buyers_half, sellers_half] = self.get_half_deposit_outputs()?;
+        Ok(if self.am_buyer() { buyers_half } else { sellers_half })
+    }
+
+    fn get_half_deposit_outputs(&self) -> Result<[TxOut; 2]> {
+        let (Some(trade_amount), Some(buyers_security_deposit), Some(sellers_security_deposit)) =
+            (self.trade_amount, self.buyers_security_deposit, self.sellers_security_deposit) else {
+            return Err(ProtocolErrorKind::MissingTradeAmounts);
+        };
+        Ok([
+            TxOut {
+                value: buyers_security_deposit,
+                script_pubkey: self.buyer_output_key_ctx.get_agg_script_pubkey()?,
+            },
+            TxOut {
+                value: trade_amount.checked_add(sellers_security_deposit).ok_or(ProtocolErrorKind::AmountOverflow)?,
+                script_pubkey: self.seller_output_key_ctx.get_agg_script_pubkey()?,
+            },
+        ])
+    }
+
+    pub fn set_my_half_deposit_psbt(&mut self, psbt: Psbt) -> Result<()> {
+        self.check_half_deposit_psbt(&psbt, self.am_buyer())?;
+        self.my_half_deposit_psbt = Some(psbt);
+        Ok(())
+    }
+
+    pub const fn get_my_half_deposit_psbt(&self) -> Option<&Psbt> {
+        self.my_half_deposit_psbt.as_ref()
+    }
+
+    pub fn set_peers_half_deposit_psbt(&mut self, psbt: Psbt) -> Result<()> {
+        self.check_half_deposit_psbt(&psbt, !self.am_buyer())?;
+        self.peers_half_deposit_psbt = Some(psbt);
+        Ok(())
+    }
+
+    fn check_half_deposit_psbt(&self, psbt: &Psbt, is_buyers_half: bool) -> Result<()> {
+        let [buyers_half, sellers_half] = self.get_half_deposit_outputs()?;
+        let half_deposit_output = if is_buyers_half { buyers_half } else { sellers_half };
+        let fee_rate = self.deposit_tx_fee_rate.ok_or(ProtocolErrorKind::MissingFeeRate)?;
+        let tx = &psbt.unsigned_tx;
+        if tx.output.iter().filter(|&o| *o == half_deposit_output).count() != 1 {
+            return Err(ProtocolErrorKind::InvalidHalfDepositPsbt("must have exactly one half-deposit output"));
+        }
+        // All the prevouts are needed to compute the fee, and to sign any taproot input. Only taproot
+        // key spends are allowed, whose witness size is known in advance, so the fee can be checked.
+        let mut input_amount = Amount::ZERO;
+        let mut input_weights = Vec::with_capacity(psbt.inputs.len());
+        for input in &psbt.inputs {
+            let prevout = input.witness_utxo.as_ref()
+                .ok_or(ProtocolErrorKind::InvalidHalfDepositPsbt("missing witness UTXO"))?;
+            if !prevout.script_pubkey.is_p2tr() || !input.tap_scripts.is_empty() {
+                return Err(ProtocolErrorKind::InvalidHalfDepositPsbt("inputs must be taproot key spends"));
+            }
+            input_amount = input_amount.checked_add(prevout.value).ok_or(ProtocolErrorKind::AmountOverflow)?;
+            input_weights.push(if input.sighash_type.is_none_or(|t| t == TapSighashType::Default.into()) {
+                InputWeightPrediction::P2TR_KEY_DEFAULT_SIGHASH
+            } else {
+                InputWeightPrediction::P2TR_KEY_NON_DEFAULT_SIGHASH
+            });
+        }
+        let output_amount = tx.output.iter()
+            .try_fold(Amount::ZERO, |sum, o| sum.checked_add(o.value))
+            .ok_or(ProtocolErrorKind::AmountOverflow)?;
+        let fee = input_amount.checked_sub(output_amount)
+            .ok_or(ProtocolErrorKind::InvalidHalfDepositPsbt("outputs exceed inputs"))?;
+        let weight = transaction::predict_weight(input_weights, tx.output.iter().map(|o| o.script_pubkey.len()));
+        if fee < fee_rate.fee_wu(weight).ok_or(ProtocolErrorKind::AmountOverflow)? {
+            return Err(ProtocolErrorKind::InvalidHalfDepositPsbt("fee too low"));
+        }
+        Ok(())
+    }
+
+    /// Merge both half-deposit PSBTs into the deposit PSBT, with the buyer's inputs and change
+    /// before the seller's, and the two payout outputs first. Both traders thus get the same tx.
+    pub fn build_deposit_tx(&mut self) -> Result<&Transaction> {
+        let (Some(my_half), Some(peers_half)) = (&self.my_half_deposit_psbt, &self.peers_half_deposit_psbt) else {
+            return Err(ProtocolErrorKind::MissingHalfDepositPsbt);
+        };
+        if peers_half.unsigned_tx.input.iter()
+            .any(|p| my_half.unsigned_tx.input.iter().any(|m| m.previous_output == p.previous_output)) {
+            return Err(ProtocolErrorKind::InvalidHalfDepositPsbt("inputs overlap with ours"));
+        }
+        let halves = if self.am_buyer() { [my_half, peers_half] } else { [peers_half, my_half] };
+
+        let mut tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![],
+            output: self.get_deposit_tx_payout_outputs()?.into(),
+        };
+        let mut inputs = vec![];
+        let mut outputs = vec![psbt::Output::default(); tx.output.len()];
+        for (half, half_deposit_output) in halves.into_iter().zip(self.get_half_deposit_outputs()?) {
+            for (txin, input) in half.unsigned_tx.input.iter().zip(&half.inputs) {
+                tx.input.push(TxIn {
+                    previous_output: txin.previous_output,
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                });
+                inputs.push(input.clone());
+            }
+            for (txout, output) in half.unsigned_tx.output.iter().zip(&half.outputs) {
+                if *txout != half_deposit_output {
+                    tx.output.push(txout.clone());
+                    outputs.push(output.clone());
+                }
+            }
+        }
+        let mut deposit_psbt = Psbt::from_unsigned_tx(tx).expect("tx inputs should be unsigned");
+        deposit_psbt.inputs = inputs;
+        deposit_psbt.outputs = outputs;
+        Ok(&self.deposit_psbt.insert(deposit_psbt).unsigned_tx)
+    }
+
+    pub const fn get_deposit_psbt(&self) -> Option<&Psbt> {
+        self.deposit_psbt.as_ref()
+    }
+
+    pub fn set_deposit_psbt(&mut self, psbt: Psbt) -> Result<()> {
+        let deposit_psbt = self.deposit_psbt.as_mut().ok_or(ProtocolErrorKind::MissingDepositTx)?;
+        if psbt.unsigned_tx != deposit_psbt.unsigned_tx {
+            return Err(ProtocolErrorKind::InvalidDepositPsbt);
+        }
+        *deposit_psbt = psbt;
+        Ok(())
+    }
+
+    /// Combine the peer's signatures on the deposit tx with our own, returning the fully signed tx.
+    pub fn combine_deposit_psbt(&mut self, peers_psbt: Psbt) -> Result<Transaction> {
+        let deposit_psbt = self.deposit_psbt.as_mut().ok_or(ProtocolErrorKind::MissingDepositTx)?;
+        if peers_psbt.unsigned_tx != deposit_psbt.unsigned_tx {
+            return Err(ProtocolErrorKind::InvalidDepositPsbt);
+        }
+        deposit_psbt.combine(peers_psbt).map_err(|_| ProtocolErrorKind::InvalidDepositPsbt)?;
+        // All the deposit tx inputs are expected to be taproot key spends, so finalize them directly.
+        for input in &mut deposit_psbt.inputs {
+            if input.final_script_witness.is_none() {
+                let sig = input.tap_key_sig.ok_or(ProtocolErrorKind::MissingDepositTxSig)?;
+                input.final_script_witness = Some(Witness::p2tr_key_spend(&sig));
+            }
+        }
+        Ok(deposit_psbt.clone().extract_tx()?)
+    }
+
+    pub fn get_deposit_txid(&self) -> Option<Txid> {
+        Some(self.deposit_psbt.as_ref()?.unsigned_tx.compute_txid())
+    }
+
+    /// The txids of the deposit tx and prepared txs built so far, each with its tx type.
+    pub fn get_trade_txids(&self) -> Vec<(Txid, TradeTxType)> {
+        [
+            (self.deposit_psbt.as_ref().map(|p| &p.unsigned_tx), TradeTxType::Deposit),
+            (self.swap_tx.as_ref(), TradeTxType::Swap),
+            (self.buyers_warning_tx.as_ref(), TradeTxType::Warning),
+            (self.sellers_warning_tx.as_ref(), TradeTxType::Warning),
+            (self.buyers_redirect_tx.as_ref(), TradeTxType::Redirect),
+            (self.sellers_redirect_tx.as_ref(), TradeTxType::Redirect),
+        ].into_iter()
+            .filter_map(|(tx, tx_type)| Some((tx?.compute_txid(), tx_type)))
+            .collect()
+    }
+
+    /// The outpoints of the warning tx outputs. Any spend of these other than by a redirect tx is a
+    /// claim tx, which is only built once needed, so has no txid known in advance.
+    pub fn get_warning_outpoints(&self) -> Vec<OutPoint> {
+        [&self.buyers_warning_tx, &self.sellers_warning_tx].into_iter()
+            .filter_map(|tx| Some(OutPoint::new(tx.as_ref()?.compute_txid(), 0)))
+            .collect()
+    }
+
+    fn get_deposit_tx_payout_outputs(&self) -> Result<[TxOut; 2]> {
+        let (Some(trade_amount), Some(buyers_security_deposit), Some(sellers_security_deposit)) =
+            (self.trade_amount, self.buyers_security_deposit, self.sellers_security_deposit) else {
+            return Err(ProtocolErrorKind::MissingTradeAmounts);
+        };
+        Ok([
+            TxOut {
+                value: trade_amount.checked_add(buyers_security_deposit).ok_or(ProtocolErrorKind::AmountOverflow)?,
+                script_pubkey: self.buyer_output_key_ctx.get_agg_script_pubkey()?,
+            },
+            TxOut {
+                value: sellers_security_deposit,
+                script_pubkey: self.seller_output_key_ctx.get_agg_script_pubkey()?,
+            },
+        ])
+    }
+
+    fn get_redirect_tx_receivers(&self) -> Option<Vec<(ScriptBuf, Amount)>> {
+        // The receivers share the RedirectTx output in proportion to their given amounts, which
+        // need not add up to the (fee-dependent) amount actually available.
+        Some(self.redirection_receivers.as_ref()?.iter()
+            .map(|r| (r.address.script_pubkey(), r.amount))
+            .collect())
+    }
+
+    pub fn build_prepared_txs(&mut self) -> Result<()> {
+        let deposit_tx = &self.deposit_psbt.as_ref().ok_or(ProtocolErrorKind::MissingDepositTx)?.unsigned_tx;
+        let payout_outputs = self.get_deposit_tx_payout_outputs()?;
+        let seller_payout_amount = payout_outputs[1].value;
+        let deposit_amount = payout_outputs[0].value.checked_add(seller_payout_amount)
+            .ok_or(ProtocolErrorKind::AmountOverflow)?;
+        let deposit_txid = deposit_tx.compute_txid();
+        let [buyer_payout_out, seller_payout_out] = payout_outputs
+            .map(|o| deposit_tx.output.iter().position(|d| *d == o)
+                .map(|vout| OutPoint::new(deposit_txid, vout.try_into().unwrap()))
+                .ok_or(ProtocolErrorKind::MissingPayoutOutput));
+        let deposit_outs = [buyer_payout_out?, seller_payout_out?];
+        let fee_rate = self.prepared_tx_fee_rate.ok_or(ProtocolErrorKind::MissingFeeRate)?;
+        let [Some(buyers_warning_tx_fee_bump), Some(sellers_warning_tx_fee_bump),
+            Some(buyers_redirect_tx_fee_bump), Some(sellers_redirect_tx_fee_bump)] = [
+            &self.buyers_warning_tx_fee_bump_address,
+            &self.sellers_warning_tx_fee_bump_address,
+            &self.buyers_redirect_tx_fee_bump_address,
+            &self.sellers_redirect_tx_fee_bump_address
+        ].map(|a| a.as_ref().map(Address::script_pubkey)) else {
+            return Err(ProtocolErrorKind::MissingFeeBumpAddress);
+        };
+        let receivers = self.get_redirect_tx_receivers()
+            .ok_or(ProtocolErrorKind::MissingRedirectionReceivers)?;
+        let swap_tx_payout = self.swap_tx_payout_address.as_ref()
+            .ok_or(ProtocolErrorKind::MissingSwapTxPayoutAddress)?.script_pubkey();
+
+        // Each trader's WarningTx pays to the multisig key of the peer's payout output, so that the
+        // peer can redirect it with their own RedirectTx, signed in advance along with the WarningTx.
+        // The key is tweaked with the claim leaf, the script spend path of the warning trader.
+        let buyers_warning_tx = tx::warning_tx(deposit_outs, deposit_amount,
+            self.seller_output_key_ctx.get_warning_output_script_pubkey()?, buyers_warning_tx_fee_bump, fee_rate)?;
+        let sellers_warning_tx = tx::warning_tx(deposit_outs, deposit_amount,
+            self.buyer_output_key_ctx.get_warning_output_script_pubkey()?, sellers_warning_tx_fee_bump, fee_rate)?;
+        let buyers_redirect_tx = tx::redirect_tx(OutPoint::new(sellers_warning_tx.compute_txid(), 0),
+            sellers_warning_tx.output[0].value, &receivers, buyers_redirect_tx_fee_bump, fee_rate)?;
+        let sellers_redirect_tx = tx::redirect_tx(OutPoint::new(buyers_warning_tx.compute_txid(), 0),
+            buyers_warning_tx.output[0].value, &receivers, sellers_redirect_tx_fee_bump, fee_rate)?;
+        let swap_tx = tx::swap_tx(deposit_outs[1], seller_payout_amount, swap_tx_payout, fee_rate)?;
+
+        self.swap_tx = Some(swap_tx);
+        self.buyers_warning_tx = Some(buyers_warning_tx);
+        self.sellers_warning_tx = Some(sellers_warning_tx);
+        self.buyers_redirect_tx = Some(buyers_redirect_tx);
+        self.sellers_redirect_tx = Some(sellers_redirect_tx);
+        Ok(())
+    }
+
     pub fn sign_partial(&mut self) -> Result<()> {
-        // TODO: Make these dummy messages (txs-to-sign) non-fixed, for greater realism:
+        let missing_tx = || ProtocolErrorKind::MissingPreparedTx;
+        let deposit_tx = &self.deposit_psbt.as_ref().ok_or(ProtocolErrorKind::MissingDepositTx)?.unsigned_tx;
+        let swap_tx = self.swap_tx.as_ref().ok_or_else(missing_tx)?;
+        let buyers_warning_tx = self.buyers_warning_tx.as_ref().ok_or_else(missing_tx)?;
+        let sellers_warning_tx = self.sellers_warning_tx.as_ref().ok_or_else(missing_tx)?;
+        let buyers_redirect_tx = self.buyers_redirect_tx.as_ref().ok_or_else(missing_tx)?;
+        let sellers_redirect_tx = self.sellers_redirect_tx.as_ref().ok_or_else(missing_tx)?;
         let [buyer_key_ctx, seller_key_ctx] = [&self.buyer_output_key_ctx, &self.seller_output_key_ctx];
 
         self.buyers_warning_tx_buyer_input_sig_ctx
-            .sign_partial(buyer_key_ctx, b"buyer's warning tx buyer input".into())?;
+            .sign_partial(buyer_key_ctx, key_spend_sighash(buyers_warning_tx, 0, deposit_tx)?)?;
         self.sellers_warning_tx_buyer_input_sig_ctx
-            .sign_partial(buyer_key_ctx, b"seller's warning tx buyer input".into())?;
+            .sign_partial(buyer_key_ctx, key_spend_sighash(sellers_warning_tx, 0, deposit_tx)?)?;
         self.buyers_redirect_tx_input_sig_ctx
-            .sign_partial(buyer_key_ctx, b"buyer's redirect tx input".into())?;
+            .sign_partial(buyer_key_ctx, key_spend_sighash(buyers_redirect_tx, 0, sellers_warning_tx)?)?;
 
         self.swap_tx_input_sig_ctx
-            .sign_partial(seller_key_ctx, b"swap tx input".into())?;
+            .sign_partial(seller_key_ctx, key_spend_sighash(swap_tx, 0, deposit_tx)?)?;
         self.buyers_warning_tx_seller_input_sig_ctx
-            .sign_partial(seller_key_ctx, b"buyer's warning tx seller input".into())?;
+            .sign_partial(seller_key_ctx, key_spend_sighash(buyers_warning_tx, 1, deposit_tx)?)?;
         self.sellers_warning_tx_seller_input_sig_ctx
-            .sign_partial(seller_key_ctx, b"seller's warning tx seller input".into())?;
+            .sign_partial(seller_key_ctx, key_spend_sighash(sellers_warning_tx, 1, deposit_tx)?)?;
         self.sellers_redirect_tx_input_sig_ctx
-            .sign_partial(seller_key_ctx, b"seller's redirect tx input".into())?;
+            .sign_partial(seller_key_ctx, key_spend_sighash(sellers_redirect_tx, 0, buyers_warning_tx)?)?;
         Ok(())
     }
 
@@ -327,7 +662,8 @@ impl TradeModel {
                 peers_warning_tx_buyer_input_partial_signature: self.sellers_warning_tx_buyer_input_sig_ctx.my_partial_sig.as_ref()?,
                 peers_warning_tx_seller_input_partial_signature: self.sellers_warning_tx_seller_input_sig_ctx.my_partial_sig.as_ref()?,
                 peers_redirect_tx_input_partial_signature: self.sellers_redirect_tx_input_sig_ctx.my_partial_sig.as_ref()?,
-                swap_tx_input_partial_signature: Some(self.swap_tx_input_sig_ctx.my_partial_sig.as_ref()?),
+                // Withheld until the buyer confirms payment started, to prevent premature trade closure:
+                swap_tx_input_partial_signature: None,
             }
         } else {
             ExchangedSigs {
@@ -349,8 +685,8 @@ impl TradeModel {
             self.sellers_warning_tx_seller_input_sig_ctx.peers_partial_sig = Some(sigs.peers_warning_tx_seller_input_partial_signature);
             self.sellers_redirect_tx_input_sig_ctx.peers_partial_sig = Some(sigs.peers_redirect_tx_input_partial_signature);
         }
-        // NOTE: This passed field would normally be 'None' for the seller, as the buyer should redact the field
-        // at the trade start and reveal it later, after payment is started, to prevent premature trade closure:
+        // NOTE: This passed field is 'None' for the seller, as the buyer withholds it at the trade start and
+        // reveals it later, after payment is started, to prevent premature trade closure:
         self.swap_tx_input_sig_ctx.peers_partial_sig = sigs.swap_tx_input_partial_signature;
     }
 
@@ -372,6 +708,32 @@ impl TradeModel {
         Ok(())
     }
 
+    /// Release our partial signature on the swap tx (message E), once the buyer has started payment.
+    /// It lets the seller close the trade, so may only be released once the deposit tx is published.
+    pub fn confirm_payment_started(&self) -> Result<&PartialSignature> {
+        if !self.am_buyer() {
+            return Err(ProtocolErrorKind::IllegalTradeState("only the buyer can confirm payment started"));
+        }
+        if self.buyers_warning_tx_buyer_input_sig_ctx.aggregated_sig.is_none() {
+            return Err(ProtocolErrorKind::IllegalTradeState("deposit tx not yet signed"));
+        }
+        self.swap_tx_input_sig_ctx.my_partial_sig.as_ref().ok_or(ProtocolErrorKind::MissingPartialSig)
+    }
+
+    /// Release our private key share for the buyer's payout (message F), once the seller has received
+    /// payment. This is only allowed once we can force-close with the swap tx, should the buyer
+    /// never send back their key share for our payout.
+    pub fn confirm_payment_received(&mut self) -> Result<&Scalar> {
+        if self.am_buyer() {
+            return Err(ProtocolErrorKind::IllegalTradeState("only the seller can confirm payment received"));
+        }
+        if self.swap_tx_input_sig_ctx.aggregated_sig.is_none() {
+            return Err(ProtocolErrorKind::IllegalTradeState("swap tx not yet signed"));
+        }
+        self.payment_received_confirmed = true;
+        self.get_my_private_key_share_for_peer_output().ok_or(ProtocolErrorKind::MissingKeyShare)
+    }
+
     pub const fn set_swap_tx_input_peers_partial_signature(&mut self, sig: PartialSignature) {
         self.swap_tx_input_sig_ctx.peers_partial_sig = Some(sig);
     }
@@ -386,13 +748,18 @@ impl TradeModel {
         Ok(())
     }
 
+    /// Our private key share for the peer's payout, or `None` if it isn't yet safe to release the
+    /// funds: the buyer must already hold the private key for the buyer payout, and the seller must
+    /// have confirmed payment received.
     pub fn get_my_private_key_share_for_peer_output(&self) -> Option<&Scalar> {
-        // TODO: Check that it's actually safe to release the funds at this point.
-        let peer_key_ctx = if self.am_buyer() {
-            &self.seller_output_key_ctx
+        let (peer_key_ctx, is_safe) = if self.am_buyer() {
+            (&self.seller_output_key_ctx, self.buyer_output_key_ctx.aggregated_key.as_ref()?.prv_key.is_some())
         } else {
-            &self.buyer_output_key_ctx
+            (&self.buyer_output_key_ctx, self.payment_received_confirmed)
         };
+        if !is_safe {
+            return None;
+        }
         Some(&peer_key_ctx.my_key_share.as_ref()?.prv_key)
     }
 
@@ -423,6 +790,39 @@ impl TradeModel {
         adaptor_sig.adapt(adaptor_secret).ok_or(ProtocolErrorKind::ZeroNonce)
     }
 
+    /// The outpoint of the seller's payout output spent by the swap tx, whose spend the buyer must
+    /// watch for in order to pick up the swap tx from the network.
+    pub fn get_swap_tx_input_outpoint(&self) -> Option<OutPoint> {
+        Some(self.swap_tx.as_ref()?.input.first()?.previous_output)
+    }
+
+    /// Whether the buyer still has to watch for the swap tx, which is from the signing of the deposit
+    /// tx until the seller's private key share for the buyer payout is recovered.
+    pub fn needs_swap_tx_watcher(&self) -> bool {
+        self.am_buyer() && self.deposit_psbt.is_some() && self.swap_tx.is_some()
+            && self.buyer_output_key_ctx.aggregated_key.as_ref().is_none_or(|key| key.prv_key.is_none())
+    }
+
+    pub const fn get_swap_tx(&self) -> Option<&Transaction> {
+        self.swap_tx.as_ref()
+    }
+
+    /// The swap tx with the (final) signature in its witness, ready for the seller to broadcast. As
+    /// this releases the buyer payout, it is only allowed once the seller has confirmed payment received.
+    pub fn get_signed_swap_tx(&self) -> Result<Transaction> {
+        if !self.payment_received_confirmed {
+            return Err(ProtocolErrorKind::IllegalTradeState("payment receipt not yet confirmed"));
+        }
+        let mut swap_tx = self.swap_tx.clone().ok_or(ProtocolErrorKind::MissingPreparedTx)?;
+        let sig = self.compute_swap_tx_input_signature()?;
+        let signature = taproot::Signature {
+            signature: schnorr::Signature::from_slice(&sig.serialize()).expect("signature should be 64 bytes"),
+            sighash_type: TapSighashType::Default,
+        };
+        swap_tx.input[0].witness = Witness::p2tr_key_spend(&signature);
+        Ok(swap_tx)
+    }
+
     pub fn recover_seller_private_key_share_for_buyer_output(&mut self, swap_tx_input_signature: &LiftedSignature) -> Result<()> {
         let adaptor_sig = self.swap_tx_input_sig_ctx.aggregated_sig
             .ok_or(ProtocolErrorKind::MissingAggSig)?;
@@ -430,6 +830,27 @@ impl TradeModel {
             .ok_or(ProtocolErrorKind::MismatchedSigs)?;
         self.buyer_output_key_ctx.set_sellers_prv_key_if_buyer(adaptor_secret.try_into()?)
     }
+
+    /// Recover the seller's private key share from the signature on a published swap tx.
+    pub fn recover_seller_private_key_share_from_swap_tx(&mut self, swap_tx: &Transaction) -> Result<()> {
+        // The witness doesn't affect the txid, so this checks that it's really our swap tx.
+        let my_swap_tx = self.swap_tx.as_ref().ok_or(ProtocolErrorKind::MissingPreparedTx)?;
+        if swap_tx.compute_txid() != my_swap_tx.compute_txid() {
+            return Err(ProtocolErrorKind::InvalidSwapTx);
+        }
+        let sig = TMuSig2::extract_p2tr_key_path_signature(swap_tx, 0)
+            .map_err(|_| ProtocolErrorKind::InvalidSwapTx)?;
+        let sig = LiftedSignature::from_bytes(&sig.signature.serialize())
+            .map_err(|_| ProtocolErrorKind::InvalidSwapTx)?;
+        self.recover_seller_private_key_share_for_buyer_output(&sig)
+    }
+}
+
+/// The BIP-341 sighash (with the default sighash type) of a key spend of the given input, which
+/// must spend an output of `prev_tx`, as do all the other inputs of the tx.
+fn key_spend_sighash(tx: &Transaction, input_index: usize, prev_tx: &Transaction) -> Result<Vec<u8>> {
+    let prevouts = tx::prevouts(prev_tx, &tx.input)?;
+    Ok(TMuSig2::extract_message_from_tx(input_index, &prevouts, tx)?.to_byte_array().into())
 }
 
 impl KeyPair {
@@ -472,37 +893,62 @@ impl KeyCtx {
     }
 
     fn get_key_shares(&self) -> Option<[Point; 2]> {
-        Some(if self.am_buyer {
-            [self.my_key_share.as_ref()?.pub_key, self.peers_key_share.as_ref()?.pub_key]
-        } else {
-            [self.peers_key_share.as_ref()?.pub_key, self.my_key_share.as_ref()?.pub_key]
-        })
+        Some([self.my_key_share.as_ref()?.pub_key, self.peers_key_share.as_ref()?.pub_key])
     }
 
     fn aggregate_key_shares(&mut self) -> Result<()> {
-        let agg_ctx = KeyAggContext::new(self.get_key_shares()
-            .ok_or(ProtocolErrorKind::MissingKeyShare)?)?;
+        // The key share order (and the unspendable taproot tweak) is decided by 'musig_core', so that
+        // we get the same aggregated keys as the 'protocol' crate:
+        let [my_pub_key, peers_pub_key] = self.get_key_shares()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        let agg_ctx = key::key_agg_context(my_pub_key, peers_pub_key, None)?;
         self.aggregated_key = Some(KeyPair::from_public(agg_ctx.aggregated_pubkey()));
         self.key_agg_ctx = Some(agg_ctx);
         Ok(())
     }
 
-    fn get_prv_key_shares(&self) -> Option<[Scalar; 2]> {
-        Some(if self.am_buyer {
-            [self.my_key_share.as_ref()?.prv_key, self.peers_key_share.as_ref()?.prv_key?]
+    fn get_agg_script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(key::agg_script_pubkey(self.key_agg_ctx.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?)?)
+    }
+
+    fn get_pub_key_share(&self, of_buyer: bool) -> Option<Point> {
+        Some(if self.am_buyer == of_buyer {
+            self.my_key_share.as_ref()?.pub_key
         } else {
-            [self.peers_key_share.as_ref()?.prv_key?, self.my_key_share.as_ref()?.prv_key]
+            self.peers_key_share.as_ref()?.pub_key
         })
     }
 
+    fn aggregate_warning_output_key(&mut self, claim_key: Point) -> Result<()> {
+        let [my_pub_key, peers_pub_key] = self.get_key_shares()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        let merkle_root = tx::claim_leaf_merkle_root(&tx::claim_leaf(claim_key)?);
+        self.warning_output_key_agg_ctx = Some(key::key_agg_context(my_pub_key, peers_pub_key, Some(merkle_root))?);
+        Ok(())
+    }
+
+    fn get_warning_output_script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(key::agg_script_pubkey(self.warning_output_key_agg_ctx.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?)?)
+    }
+
+    fn get_key_agg_ctx(&self, for_warning_output: bool) -> Result<&KeyAggContext> {
+        if for_warning_output { &self.warning_output_key_agg_ctx } else { &self.key_agg_ctx }.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)
+    }
+
+    fn get_prv_key_shares(&self) -> Option<[(Point, Scalar); 2]> {
+        let (my_key_share, peers_key_share) = (self.my_key_share.as_ref()?, self.peers_key_share.as_ref()?);
+        Some([(my_key_share.pub_key, my_key_share.prv_key), (peers_key_share.pub_key, peers_key_share.prv_key?)])
+    }
+
     fn aggregate_prv_key_shares(&mut self) -> Result<&Scalar> {
-        let prv_key_shares = self.get_prv_key_shares()
+        let [my_key_share, peers_key_share] = self.get_prv_key_shares()
             .ok_or(ProtocolErrorKind::MissingKeyShare)?;
         let agg_ctx = self.key_agg_ctx.as_ref()
             .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
         let agg_key = self.aggregated_key.as_mut()
             .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
-        agg_key.set_prv_key(agg_ctx.aggregated_seckey(prv_key_shares)?)
+        agg_key.set_prv_key(key::aggregated_seckey(agg_ctx, my_key_share, peers_key_share)?)
     }
 
     fn get_sellers_prv_key(&self) -> Option<Scalar> {
@@ -553,8 +999,7 @@ impl SigCtx {
     }
 
     fn sign_partial(&mut self, key_ctx: &KeyCtx, message: Vec<u8>) -> Result<&PartialSignature> {
-        let key_agg_ctx = key_ctx.key_agg_ctx.as_ref()
-            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        let key_agg_ctx = key_ctx.get_key_agg_ctx(self.spends_warning_output)?;
         let seckey = key_ctx.my_key_share.as_ref()
             .ok_or(ProtocolErrorKind::MissingKeyShare)?.prv_key;
         let secnonce = self.my_nonce_share.as_mut()
@@ -578,8 +1023,7 @@ impl SigCtx {
     }
 
     fn aggregate_partial_signatures(&mut self, key_ctx: &KeyCtx) -> Result<&AdaptorSignature> {
-        let key_agg_ctx = key_ctx.key_agg_ctx.as_ref()
-            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        let key_agg_ctx = key_ctx.get_key_agg_ctx(self.spends_warning_output)?;
         let aggregated_nonce = &self.aggregated_nonce.as_ref()
             .ok_or(ProtocolErrorKind::MissingAggNonce)?;
         let partial_signatures = self.get_partial_signatures()
@@ -618,10 +1062,373 @@ pub enum ProtocolErrorKind {
     MismatchedKeyPair,
     #[error("mismatched adaptor and final signature")]
     MismatchedSigs,
+    #[error("missing trade amounts")]
+    MissingTradeAmounts,
+    #[error("missing fee rate")]
+    MissingFeeRate,
+    #[error("missing fee bump address")]
+    MissingFeeBumpAddress,
+    #[error("missing swap tx payout address")]
+    MissingSwapTxPayoutAddress,
+    #[error("missing redirection receivers")]
+    MissingRedirectionReceivers,
+    #[error("missing half-deposit PSBT")]
+    MissingHalfDepositPsbt,
+    #[error("invalid half-deposit PSBT: {0}")]
+    InvalidHalfDepositPsbt(&'static str),
+    #[error("deposit PSBT doesn't match our deposit tx")]
+    InvalidDepositPsbt,
+    #[error("missing deposit tx")]
+    MissingDepositTx,
+    #[error("missing deposit tx input signature")]
+    MissingDepositTxSig,
+    #[error("missing prepared tx")]
+    MissingPreparedTx,
+    #[error("not a validly signed swap tx for this trade")]
+    InvalidSwapTx,
+    #[error("deposit tx is missing a payout output")]
+    MissingPayoutOutput,
+    #[error("amount overflow")]
+    AmountOverflow,
+    #[error("not allowed in the current trade state: {0}")]
+    IllegalTradeState(&'static str),
+    #[error("fee rate out of range: {0}")]
+    FeeRateOutOfRange(FeeRate),
+    #[error("fee rates don't match those of the peer")]
+    MismatchedFeeRates,
     KeyAgg(#[from] musig2::errors::KeyAggError),
     Signing(#[from] musig2::errors::SigningError),
     Verify(#[from] musig2::errors::VerifyError),
     InvalidSecretKeys(#[from] musig2::errors::InvalidSecretKeysError),
     ZeroScalar(#[from] musig2::secp::errors::ZeroScalarError),
     AddressParse(#[from] bdk_wallet::bitcoin::address::ParseError),
+    Core(#[from] musig_core::Error),
+    // Boxed, as it would make every protocol 'Result' large:
+    ExtractTx(Box<psbt::ExtractTxError>),
+}
+
+impl From<psbt::ExtractTxError> for ProtocolErrorKind {
+    fn from(value: psbt::ExtractTxError) -> Self { Self::ExtractTx(Box::new(value)) }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bdk_wallet::{KeychainKind, Wallet};
+    use super::*;
+    use crate::keys::Descriptors;
+    use crate::store::{TradeModelMemoryStore, TradeModelStore as _};
+
+    /// A wallet with the test keys, which knows its addresses from the start of either keychain.
+    pub(crate) fn test_wallet() -> Wallet {
+        test_wallet_for(Network::Signet)
+    }
+
+    fn test_wallet_for(network: Network) -> Wallet {
+        let Descriptors { external, internal } = Descriptors::test();
+        Wallet::create(external, internal).network(network).create_wallet_no_persist().unwrap()
+    }
+
+    pub(crate) fn next_address(wallet: &mut Wallet) -> Address {
+        wallet.reveal_next_address(KeychainKind::External).address
+    }
+
+    fn new_trade_models() -> [TradeModel; 2] {
+        let mut buyer = TradeModel::new("buyer-trade".to_owned(), Role::BuyerAsTaker, Network::Signet);
+        let mut seller = TradeModel::new("seller-trade".to_owned(), Role::SellerAsMaker, Network::Signet);
+        buyer.init_my_key_shares();
+        seller.init_my_key_shares();
+        let buyer_key_shares = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let seller_key_shares = seller.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        buyer.set_peer_key_shares(seller_key_shares[0], seller_key_shares[1]);
+        seller.set_peer_key_shares(buyer_key_shares[0], buyer_key_shares[1]);
+        let mut wallet = test_wallet();
+        for trade_model in [&mut buyer, &mut seller] {
+            trade_model.aggregate_key_shares().unwrap();
+            trade_model.trade_amount = Some(Amount::from_sat(200_000));
+            trade_model.buyers_security_deposit = Some(Amount::from_sat(30_000));
+            trade_model.sellers_security_deposit = Some(Amount::from_sat(30_000));
+            trade_model.set_fee_rates(FeeRate::from_sat_per_kwu(50_000), FeeRate::from_sat_per_kwu(40_000)).unwrap();
+            trade_model.set_my_fee_bump_addresses([next_address(&mut wallet), next_address(&mut wallet)]);
+            trade_model.set_my_swap_tx_payout_address(next_address(&mut wallet));
+            trade_model.init_my_nonce_shares().unwrap();
+        }
+        let swap_tx_payout_address = seller.get_my_swap_tx_payout_address().unwrap().as_unchecked().clone();
+        buyer.set_peer_swap_tx_payout_address(Some(swap_tx_payout_address)).unwrap();
+        [buyer, seller]
+    }
+
+    fn half_deposit_psbt(trade_model: &TradeModel, funding_txid: [u8; 32], fee: Amount) -> Psbt {
+        let deposit_output = trade_model.get_my_half_deposit_output().unwrap();
+        let change_output = TxOut { value: Amount::from_sat(100_000), ..deposit_output.clone() };
+        let funding_output = TxOut {
+            value: deposit_output.value + change_output.value + fee,
+            ..deposit_output.clone()
+        };
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn { previous_output: OutPoint::new(Txid::from_byte_array(funding_txid), 0), ..TxIn::default() }],
+            output: vec![change_output, deposit_output],
+        }).unwrap();
+        psbt.inputs[0].witness_utxo = Some(funding_output);
+        psbt
+    }
+
+    fn fee_bump_addresses(trade_model: &TradeModel) -> [Address<NetworkUnchecked>; 2] {
+        trade_model.get_my_fee_bump_addresses().unwrap().map(|a| a.as_unchecked().clone())
+    }
+
+    fn nonce_shares(trade_model: &TradeModel) -> ExchangedNonces<'static, ByVal> {
+        let nonces = trade_model.get_my_nonce_shares().unwrap();
+        ExchangedNonces {
+            swap_tx_input_nonce_share: nonces.swap_tx_input_nonce_share.clone(),
+            buyers_warning_tx_buyer_input_nonce_share: nonces.buyers_warning_tx_buyer_input_nonce_share.clone(),
+            buyers_warning_tx_seller_input_nonce_share: nonces.buyers_warning_tx_seller_input_nonce_share.clone(),
+            sellers_warning_tx_buyer_input_nonce_share: nonces.sellers_warning_tx_buyer_input_nonce_share.clone(),
+            sellers_warning_tx_seller_input_nonce_share: nonces.sellers_warning_tx_seller_input_nonce_share.clone(),
+            buyers_redirect_tx_input_nonce_share: nonces.buyers_redirect_tx_input_nonce_share.clone(),
+            sellers_redirect_tx_input_nonce_share: nonces.sellers_redirect_tx_input_nonce_share.clone(),
+        }
+    }
+
+    /// Continues the exchange from the buyer having the seller's half-deposit PSBT, up to both peers
+    /// having built and partially signed the deposit and prepared txs.
+    fn build_and_sign_txs(buyer: &mut TradeModel, seller: &mut TradeModel) {
+        seller.set_peers_half_deposit_psbt(buyer.get_my_half_deposit_psbt().unwrap().clone()).unwrap();
+        buyer.set_peer_fee_bump_addresses(fee_bump_addresses(seller)).unwrap();
+        seller.set_peer_fee_bump_addresses(fee_bump_addresses(buyer)).unwrap();
+        let (buyer_nonces, seller_nonces) = (nonce_shares(buyer), nonce_shares(seller));
+        buyer.set_peer_nonce_shares(seller_nonces);
+        seller.set_peer_nonce_shares(buyer_nonces);
+        for trade_model in [buyer, seller] {
+            let receivers = [("tb1pwxlp4v9v7v03nx0e7vunlc87d4936wnyqegw0fuahudypan64wys5stxh7", 200_000),
+                ("tb1qpg889v22f3gefuvwpe3963t5a00nvfmkhlgqw5", 80_000)]
+                .map(|(address, amount)| Ok::<_, ProtocolErrorKind>(RedirectionReceiver {
+                    address: address.parse().unwrap(),
+                    amount: Amount::from_sat(amount),
+                }));
+            trade_model.set_redirection_receivers(receivers).unwrap();
+            trade_model.aggregate_nonce_shares().unwrap();
+            trade_model.build_deposit_tx().unwrap();
+            trade_model.build_prepared_txs().unwrap();
+            trade_model.sign_partial().unwrap();
+        }
+    }
+
+    /// A buyer and seller trade model, each with the deposit and prepared txs built.
+    pub(crate) fn trade_models_with_prepared_txs() -> [TradeModel; 2] {
+        let [mut buyer, mut seller] = new_trade_models();
+        let fee = Amount::from_sat(50_000);
+        buyer.set_my_half_deposit_psbt(half_deposit_psbt(&buyer, [1; 32], fee)).unwrap();
+        seller.set_my_half_deposit_psbt(half_deposit_psbt(&seller, [2; 32], fee)).unwrap();
+        buyer.set_peers_half_deposit_psbt(seller.get_my_half_deposit_psbt().unwrap().clone()).unwrap();
+        build_and_sign_txs(&mut buyer, &mut seller);
+        [buyer, seller]
+    }
+
+    fn partial_sigs(trade_model: &TradeModel) -> ExchangedSigs<'static, ByVal> {
+        let sigs = trade_model.get_my_partial_signatures_on_peer_txs().unwrap();
+        ExchangedSigs {
+            peers_warning_tx_buyer_input_partial_signature: *sigs.peers_warning_tx_buyer_input_partial_signature,
+            peers_warning_tx_seller_input_partial_signature: *sigs.peers_warning_tx_seller_input_partial_signature,
+            peers_redirect_tx_input_partial_signature: *sigs.peers_redirect_tx_input_partial_signature,
+            swap_tx_input_partial_signature: sigs.swap_tx_input_partial_signature.copied(),
+        }
+    }
+
+    #[test]
+    fn test_aggregated_key() {
+        let buyer_key_share = KeyPair::from_private(Scalar::from_slice(&[0x11; 32]).unwrap());
+        let seller_key_share = KeyPair::from_private(Scalar::from_slice(&[0x22; 32]).unwrap());
+        let [buyer_ctx, seller_ctx] = [(true, &buyer_key_share, &seller_key_share), (false, &seller_key_share, &buyer_key_share)]
+            .map(|(am_buyer, mine, peers)| {
+                let mut key_ctx = KeyCtx {
+                    am_buyer,
+                    my_key_share: Some(KeyPair::from_private(mine.prv_key)),
+                    peers_key_share: Some(KeyPair::from_public(peers.pub_key)),
+                    ..KeyCtx::default()
+                };
+                key_ctx.aggregate_key_shares().unwrap();
+                key_ctx
+            });
+        let agg_pub_key = buyer_ctx.aggregated_key.as_ref().unwrap().pub_key;
+        assert_eq!(agg_pub_key, seller_ctx.aggregated_key.as_ref().unwrap().pub_key);
+        assert_eq!(agg_pub_key.to_string(), "03fad385bffd10cd49e520006cd68f396d909823ba8ee1b3c1c4694b354ea85497");
+        assert_eq!(buyer_ctx.get_agg_script_pubkey().unwrap().as_bytes()[2..], agg_pub_key.serialize_xonly());
+
+        // Before the keys came from 'musig_core', the shares were in buyer-seller order and the
+        // aggregated key was untweaked, so its P2TR output couldn't be spent by a standard signer:
+        let untweaked: Point = KeyAggContext::new([buyer_key_share.pub_key, seller_key_share.pub_key]).unwrap()
+            .aggregated_pubkey();
+        assert_eq!(untweaked.to_string(), "0276eaa6c77a7f2e4b89e733be05288b0f49918e7788084d983f2434048b532ad3");
+    }
+
+    #[test]
+    fn test_peer_address_network() {
+        let regtest_address = next_address(&mut test_wallet_for(Network::Regtest)).into_unchecked();
+        let signet_address = next_address(&mut test_wallet()).into_unchecked();
+        let mut buyer = TradeModel::new("trade".to_owned(), Role::BuyerAsTaker, Network::Regtest);
+        buyer.set_peer_fee_bump_addresses([regtest_address.clone(), regtest_address.clone()]).unwrap();
+        buyer.set_peer_swap_tx_payout_address(Some(regtest_address)).unwrap();
+        assert!(matches!(buyer.set_peer_swap_tx_payout_address(Some(signet_address.clone())),
+            Err(ProtocolErrorKind::AddressParse(_))));
+        let receiver = RedirectionReceiver { address: signet_address, amount: Amount::from_sat(1000) };
+        assert!(matches!(buyer.set_redirection_receivers([Ok::<_, ProtocolErrorKind>(receiver)]),
+            Err(ProtocolErrorKind::AddressParse(_))));
+    }
+
+    #[test]
+    fn test_sign_prepared_txs() {
+        let [mut buyer, mut seller] = new_trade_models();
+        let fee = Amount::from_sat(50_000);
+        let (buyers_half, sellers_half) = (half_deposit_psbt(&buyer, [1; 32], fee), half_deposit_psbt(&seller, [2; 32], fee));
+        buyer.set_my_half_deposit_psbt(buyers_half.clone()).unwrap();
+        seller.set_my_half_deposit_psbt(sellers_half.clone()).unwrap();
+        assert!(matches!(buyer.set_peers_half_deposit_psbt(buyers_half),
+            Err(ProtocolErrorKind::InvalidHalfDepositPsbt("must have exactly one half-deposit output"))));
+        assert!(matches!(buyer.set_peers_half_deposit_psbt(half_deposit_psbt(&seller, [2; 32], Amount::from_sat(100))),
+            Err(ProtocolErrorKind::InvalidHalfDepositPsbt("fee too low"))));
+        let mut non_taproot_half = half_deposit_psbt(&seller, [2; 32], fee);
+        non_taproot_half.inputs[0].witness_utxo.as_mut().unwrap().script_pubkey = ScriptBuf::new();
+        assert!(matches!(buyer.set_peers_half_deposit_psbt(non_taproot_half),
+            Err(ProtocolErrorKind::InvalidHalfDepositPsbt("inputs must be taproot key spends"))));
+        buyer.set_peers_half_deposit_psbt(sellers_half).unwrap();
+        buyer.check_peer_fee_rates(seller.deposit_tx_fee_rate.unwrap(), seller.prepared_tx_fee_rate.unwrap()).unwrap();
+        assert!(matches!(buyer.check_peer_fee_rates(seller.prepared_tx_fee_rate.unwrap(), seller.prepared_tx_fee_rate.unwrap()),
+            Err(ProtocolErrorKind::MismatchedFeeRates)));
+        build_and_sign_txs(&mut buyer, &mut seller);
+
+        // Both peers must have built exactly the same txs.
+        let deposit_tx = &buyer.get_deposit_psbt().unwrap().unsigned_tx;
+        assert_eq!(deposit_tx, &seller.get_deposit_psbt().unwrap().unsigned_tx);
+        assert_eq!((deposit_tx.input.len(), deposit_tx.output.len()), (2, 4));
+        assert_eq!(deposit_tx.output[0].value, Amount::from_sat(230_000));
+        assert_eq!(buyer.swap_tx, seller.swap_tx);
+        assert_eq!(buyer.buyers_warning_tx, seller.buyers_warning_tx);
+        assert_eq!(buyer.sellers_warning_tx, seller.sellers_warning_tx);
+        assert_eq!(buyer.buyers_redirect_tx, seller.buyers_redirect_tx);
+        assert_eq!(buyer.sellers_redirect_tx, seller.sellers_redirect_tx);
+        let redirect_tx = buyer.buyers_redirect_tx.as_ref().unwrap();
+        assert_eq!(redirect_tx.output.len(), 3);
+        assert!(redirect_tx.output[0].value > redirect_tx.output[1].value * 2);
+
+        let (buyer_sigs, seller_sigs) = (partial_sigs(&buyer), partial_sigs(&seller));
+        buyer.set_peer_partial_signatures_on_my_txs(&seller_sigs);
+        assert!(buyer_sigs.swap_tx_input_partial_signature.is_none(), "buyer must withhold swap tx signature");
+        seller.set_peer_partial_signatures_on_my_txs(&buyer_sigs);
+        assert!(matches!(buyer.confirm_payment_started(), Err(ProtocolErrorKind::IllegalTradeState(_))));
+        buyer.aggregate_partial_signatures().unwrap();
+        seller.aggregate_partial_signatures().unwrap();
+
+        // The aggregated signatures must be valid for the real sighashes.
+        let warning_tx_sig: LiftedSignature = buyer.buyers_warning_tx_buyer_input_sig_ctx.aggregated_sig.unwrap()
+            .adapt(MaybeScalar::Zero).unwrap();
+        let message = key_spend_sighash(buyer.buyers_warning_tx.as_ref().unwrap(), 0,
+            &buyer.get_deposit_psbt().unwrap().unsigned_tx).unwrap();
+        musig2::verify_single(buyer.buyer_output_key_ctx.aggregated_key.as_ref().unwrap().pub_key,
+            warning_tx_sig, &message[..]).unwrap();
+
+        // The RedirectTx spends the peer's WarningTx output by its key path, the key being tweaked with
+        // the claim leaf, whereas the SwapTx pays to an address of its own.
+        let redirect_tx_sig: LiftedSignature = buyer.buyers_redirect_tx_input_sig_ctx.aggregated_sig.unwrap()
+            .adapt(MaybeScalar::Zero).unwrap();
+        let message = key_spend_sighash(buyer.buyers_redirect_tx.as_ref().unwrap(), 0,
+            buyer.sellers_warning_tx.as_ref().unwrap()).unwrap();
+        let warning_output_key_agg_ctx = buyer.buyer_output_key_ctx.warning_output_key_agg_ctx.as_ref().unwrap();
+        musig2::verify_single(warning_output_key_agg_ctx.aggregated_pubkey::<Point>(), redirect_tx_sig, &message[..]).unwrap();
+        assert_ne!(buyer.sellers_warning_tx.as_ref().unwrap().output[0].script_pubkey,
+            buyer.get_deposit_psbt().unwrap().unsigned_tx.output[0].script_pubkey);
+        let swap_tx_payout = &buyer.swap_tx.as_ref().unwrap().output[0].script_pubkey;
+        assert_eq!(Some(swap_tx_payout), seller.swap_tx_payout_address.as_ref().map(Address::script_pubkey).as_ref());
+        assert!(test_wallet().is_mine(swap_tx_payout.clone()), "swap tx must pay out to the seller's wallet");
+        assert_ne!(Some(swap_tx_payout), seller.sellers_warning_tx_fee_bump_address.as_ref().map(Address::script_pubkey).as_ref());
+
+        assert!(matches!(seller.confirm_payment_started(), Err(ProtocolErrorKind::IllegalTradeState(_))));
+        assert!(matches!(seller.confirm_payment_received(), Err(ProtocolErrorKind::IllegalTradeState(_))));
+        seller.set_swap_tx_input_peers_partial_signature(*buyer.confirm_payment_started().unwrap());
+        seller.aggregate_swap_tx_partial_signatures().unwrap();
+        assert!(seller.get_my_private_key_share_for_peer_output().is_none());
+        let swap_tx_sig = seller.compute_swap_tx_input_signature().unwrap();
+        let message = key_spend_sighash(seller.swap_tx.as_ref().unwrap(), 0,
+            &seller.get_deposit_psbt().unwrap().unsigned_tx).unwrap();
+        musig2::verify_single(seller.seller_output_key_ctx.aggregated_key.as_ref().unwrap().pub_key,
+            swap_tx_sig, &message[..]).unwrap();
+
+        // The buyer recovers the seller's key share from the published swap tx alone.
+        assert!(matches!(seller.get_signed_swap_tx(), Err(ProtocolErrorKind::IllegalTradeState(_))));
+        seller.confirm_payment_received().unwrap();
+        let signed_swap_tx = seller.get_signed_swap_tx().unwrap();
+        assert_eq!(signed_swap_tx.compute_txid(), seller.swap_tx.as_ref().unwrap().compute_txid());
+        assert_eq!(buyer.get_swap_tx_input_outpoint(), Some(signed_swap_tx.input[0].previous_output));
+        assert!(matches!(buyer.recover_seller_private_key_share_from_swap_tx(seller.swap_tx.as_ref().unwrap()),
+            Err(ProtocolErrorKind::InvalidSwapTx)));
+        assert!(buyer.get_my_private_key_share_for_peer_output().is_none());
+        assert!(buyer.needs_swap_tx_watcher() && !seller.needs_swap_tx_watcher());
+        buyer.recover_seller_private_key_share_from_swap_tx(&signed_swap_tx).unwrap();
+        buyer.aggregate_private_keys_for_my_output().unwrap();
+        assert!(buyer.get_my_private_key_share_for_peer_output().is_some());
+        assert!(!buyer.needs_swap_tx_watcher());
+    }
+
+    #[test]
+    fn test_trade_tx_index() {
+        let [buyer, seller] = trade_models_with_prepared_txs();
+        let store = TradeModelMemoryStore::default();
+        store.add_trade_model(buyer).unwrap();
+        let index = store.get_trade_tx_index();
+        let trade_tx_type = |tx: &Transaction| index.get(tx).map(|(trade_id, tx_type)| {
+            assert_eq!(trade_id, "buyer-trade");
+            tx_type
+        });
+
+        assert_eq!(trade_tx_type(&seller.get_deposit_psbt().unwrap().unsigned_tx), Some(TradeTxType::Deposit));
+        assert_eq!(trade_tx_type(seller.swap_tx.as_ref().unwrap()), Some(TradeTxType::Swap));
+        assert_eq!(trade_tx_type(seller.sellers_warning_tx.as_ref().unwrap()), Some(TradeTxType::Warning));
+
+        // Both the redirect tx and a claim tx spend a warning tx output, but only the former is known.
+        let redirect_tx = seller.sellers_redirect_tx.as_ref().unwrap();
+        let warning_outpoint = redirect_tx.input[0].previous_output;
+        assert!(seller.get_warning_outpoints().contains(&warning_outpoint));
+        assert_eq!(trade_tx_type(redirect_tx), Some(TradeTxType::Redirect));
+        let claim_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn { previous_output: warning_outpoint, ..TxIn::default() }],
+            output: vec![],
+        };
+        assert_eq!(trade_tx_type(&claim_tx), Some(TradeTxType::Claim));
+        let other_tx = Transaction {
+            input: vec![TxIn { previous_output: OutPoint::new(warning_outpoint.txid, 1), ..TxIn::default() }],
+            ..claim_tx
+        };
+        assert_eq!(trade_tx_type(&other_tx), None);
+    }
+
+    #[test]
+    fn test_combine_deposit_psbt() {
+        let [mut buyer, mut seller] = new_trade_models();
+        let fee = Amount::from_sat(50_000);
+        buyer.set_my_half_deposit_psbt(half_deposit_psbt(&buyer, [1; 32], fee)).unwrap();
+        seller.set_my_half_deposit_psbt(half_deposit_psbt(&seller, [2; 32], fee)).unwrap();
+        buyer.set_peers_half_deposit_psbt(seller.get_my_half_deposit_psbt().unwrap().clone()).unwrap();
+        seller.set_peers_half_deposit_psbt(buyer.get_my_half_deposit_psbt().unwrap().clone()).unwrap();
+        buyer.build_deposit_tx().unwrap();
+        seller.build_deposit_tx().unwrap();
+
+        // Pretend that each trader's wallet has signed its own input.
+        let dummy_sig = bdk_wallet::bitcoin::taproot::Signature::from_slice(&[1; 64]).unwrap();
+        let mut buyers_psbt = buyer.get_deposit_psbt().unwrap().clone();
+        buyers_psbt.inputs[0].tap_key_sig = Some(dummy_sig);
+        buyer.set_deposit_psbt(buyers_psbt.clone()).unwrap();
+        let mut sellers_psbt = seller.get_deposit_psbt().unwrap().clone();
+        sellers_psbt.inputs[1].tap_key_sig = Some(dummy_sig);
+
+        assert!(matches!(seller.combine_deposit_psbt(buyer.get_my_half_deposit_psbt().unwrap().clone()),
+            Err(ProtocolErrorKind::InvalidDepositPsbt)));
+        assert!(matches!(seller.combine_deposit_psbt(buyers_psbt),
+            Err(ProtocolErrorKind::MissingDepositTxSig)));
+        let deposit_tx = buyer.combine_deposit_psbt(sellers_psbt).unwrap();
+        assert_eq!(Some(deposit_tx.compute_txid()), seller.get_deposit_txid());
+        assert!(deposit_tx.input.iter().all(|input| input.witness.len() == 1));
+    }
 }
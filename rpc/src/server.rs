@@ -1,58 +1,90 @@
-use bdk_wallet::bitcoin::{Amount, FeeRate};
+use bdk_wallet::bitcoin::{Amount, FeeRate, Network, OutPoint, Transaction};
+use bdk_wallet::bitcoin::hashes::Hash as _;
+use futures::{future, FutureExt as _};
 use futures::stream::{self, BoxStream, StreamExt as _};
-use std::iter;
+use std::collections::HashSet;
 use std::marker::{Send, Sync};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tonic::{Request, Response, Result, Status};
 
+use crate::fees::{self, FeeEstimator};
 use crate::pb::convert::TryProtoInto;
-use crate::pb::musigrpc::{CloseTradeRequest, CloseTradeResponse, DepositPsbt,
-    DepositTxSignatureRequest, NonceSharesMessage, NonceSharesRequest, PartialSignaturesMessage,
-    PartialSignaturesRequest, PubKeySharesRequest, PubKeySharesResponse, PublishDepositTxRequest,
+use crate::pb::musigrpc::{AbortTradeRequest, AbortTradeResponse, CloseTradeRequest, CloseTradeResponse, ConfirmPaymentReceivedRequest,
+    ConfirmPaymentStartedRequest, DepositPsbt, DepositTxSignatureRequest, NonceSharesMessage,
+    NonceSharesRequest, PartialSignaturesMessage, PartialSignaturesRequest, PaymentReceivedMessage,
+    PaymentStartedMessage, PubKeySharesRequest, PubKeySharesResponse, PublishDepositTxRequest,
     SwapTxSignatureRequest, SwapTxSignatureResponse, TxConfirmationStatus};
 use crate::pb::musigrpc::musig_server;
-use crate::pb::walletrpc::{ConfEvent, ConfRequest, ListUnspentRequest, ListUnspentResponse,
-    NewAddressRequest, NewAddressResponse, WalletBalanceRequest, WalletBalanceResponse};
+use crate::pb::walletrpc::{BlockEvent, BlockRequest, BroadcastResponse, ConfEvent, ConfRequest, ConfidenceType,
+    CreateFundedPsbtRequest, CreateFundedPsbtResponse, CreateWalletRequest, CreateWalletResponse,
+    EstimateFeeRequest, EstimateFeeResponse, FeeEstimate, FinalizeAndBroadcastRequest, GetWalletInfoRequest,
+    GetWalletInfoResponse, ListTransactionsRequest, ListTransactionsResponse, ListUnspentRequest, ListUnspentResponse,
+    NewAddressRequest, NewAddressResponse, RestoreWalletRequest, RestoreWalletResponse, SendToAddressRequest,
+    SignPsbtRequest, SignPsbtResponse, SpendEvent, SpendRequest, SweepAllRequest, TransactionDetails, WalletBalanceRequest,
+    WalletBalanceResponse};
 use crate::pb::walletrpc::wallet_server;
-use crate::protocol::{TradeModel, TradeModelStore as _, TRADE_MODELS};
-use crate::wallet::WalletService;
+use crate::protocol::{ProtocolErrorKind, TradeModel};
+use crate::store::{TradeModelStore, TradeTxIndex};
+use crate::keys::WalletSeed;
+use crate::wallet::{TxDetails, WalletService};
 
 pub use musig_server::MusigServer;
 pub use wallet_server::WalletServer;
 
-#[derive(Debug, Default)]
-pub struct MusigImpl {}
-
-// FIXME: At present, the Musig service passes some fields to the Java client that should be kept
-//  secret for a time before passing them to the peer, namely the buyer's partial signature on the
-//  swap tx and the seller's private key share for the buyer payout. Premature revelation of those
-//  secrets would allow the seller to close the trade before the buyer starts payment, or the buyer
-//  to close the trade before the seller had a chance to confirm receipt of payment (but after the
-//  buyer starts payment), respectively. This should probably be changed, as the Java client should
-//  never hold secrets which directly control funds (but doing so makes the RPC interface a little
-//  bigger and less symmetrical.)
+pub struct MusigImpl {
+    pub trade_model_store: Arc<dyn TradeModelStore + Send + Sync>,
+    pub wallet_service: Arc<dyn WalletService + Send + Sync>,
+    /// The source of the trade fee rates, when the trader leaves them to be estimated.
+    pub fee_estimator: Arc<dyn FeeEstimator + Send + Sync>,
+    /// The number of confirmations after which the deposit tx confirmation stream ends.
+    pub deposit_tx_confirmation_depth: u32,
+    /// The network of the daemon, which the addresses of every trade must be for.
+    pub network: Network,
+    /// The IDs of the trades with a running swap tx watcher.
+    swap_tx_watchers: Arc<Mutex<HashSet<String>>>,
+}
+
+// NOTE: The secrets which directly control funds, namely the buyer's partial signature on the swap
+//  tx and the seller's private key share for the buyer payout, are kept by the Musig service until
+//  the trader explicitly releases them to pass to the peer (messages E & F), via ConfirmPaymentStarted
+//  and ConfirmPaymentReceived respectively. Premature revelation of those secrets would allow the
+//  seller to close the trade before the buyer starts payment, or the buyer to close the trade before
+//  the seller had a chance to confirm receipt of payment, respectively.
 #[tonic::async_trait]
 impl musig_server::Musig for MusigImpl {
     async fn init_trade(&self, request: Request<PubKeySharesRequest>) -> Result<Response<PubKeySharesResponse>> {
         println!("Got a request: {request:?}");
 
         let request = request.into_inner();
-        let mut trade_model = TradeModel::new(request.trade_id, request.my_role.try_proto_into()?);
+        let mut trade_model = TradeModel::new(request.trade_id, request.my_role.try_proto_into()?, self.network);
         trade_model.init_my_key_shares();
         let my_key_shares = trade_model.get_my_key_shares()
             .ok_or_else(|| Status::internal("missing key shares"))?;
         let response = PubKeySharesResponse {
             buyer_output_pub_key_share: my_key_shares[0].pub_key.serialize().into(),
             seller_output_pub_key_share: my_key_shares[1].pub_key.serialize().into(),
-            current_block_height: 900_000,
+            current_block_height: self.wallet_service.get_chain_tip().height,
         };
-        TRADE_MODELS.add_trade_model(trade_model);
+        self.trade_model_store.add_trade_model(trade_model)?;
 
         Ok(Response::new(response))
     }
 
     async fn get_nonce_shares(&self, request: Request<NonceSharesRequest>) -> Result<Response<NonceSharesMessage>> {
-        handle_request(request, move |request, trade_model| {
+        // Reject a taker request lacking the maker's fee rates before estimating any in its place. A
+        // missing trade is left for the handler to report.
+        let am_maker = self.trade_model_store.get_trade_model(request.get_ref().trade_id())
+            .is_none_or(|trade_model| trade_model.lock().unwrap().am_maker());
+        let request_ref = request.get_ref();
+        if !am_maker && (request_ref.deposit_tx_fee_rate.is_none() || request_ref.prepared_tx_fee_rate.is_none()) {
+            return Err(Status::invalid_argument("taker must use the fee rates fixed by the maker"));
+        }
+        // Estimate before taking the trade lock, as it may take a while:
+        let deposit_tx_fee_rate = self.fee_rate_or_estimate(
+            request.get_ref().deposit_tx_fee_rate, fees::DEPOSIT_TX_CONFIRMATION_TARGET)?;
+        let prepared_tx_fee_rate = self.fee_rate_or_estimate(
+            request.get_ref().prepared_tx_fee_rate, fees::PREPARED_TX_CONFIRMATION_TARGET)?;
+        self.handle_request(request, move |request, trade_model| {
             trade_model.set_peer_key_shares(
                 request.buyer_output_peers_pub_key_share.try_proto_into()?,
                 request.seller_output_peers_pub_key_share.try_proto_into()?);
@@ -60,36 +92,55 @@ impl musig_server::Musig for MusigImpl {
             trade_model.trade_amount = Some(Amount::from_sat(request.trade_amount));
             trade_model.buyers_security_deposit = Some(Amount::from_sat(request.buyers_security_deposit));
             trade_model.sellers_security_deposit = Some(Amount::from_sat(request.sellers_security_deposit));
-            trade_model.deposit_tx_fee_rate = Some(FeeRate::from_sat_per_kwu(request.deposit_tx_fee_rate));
-            trade_model.prepared_tx_fee_rate = Some(FeeRate::from_sat_per_kwu(request.prepared_tx_fee_rate));
-            trade_model.init_my_fee_bump_addresses()?;
+            trade_model.set_fee_rates(deposit_tx_fee_rate, prepared_tx_fee_rate)?;
+            trade_model.set_my_fee_bump_addresses([
+                self.wallet_service.reveal_next_address()?.address,
+                self.wallet_service.reveal_next_address()?.address
+            ]);
+            if !trade_model.am_buyer() {
+                trade_model.set_my_swap_tx_payout_address(self.wallet_service.reveal_next_address()?.address);
+            }
             trade_model.init_my_nonce_shares()?;
+            let half_deposit_psbt = self.wallet_service.create_half_deposit_psbt(trade_model.trade_id(),
+                trade_model.get_my_half_deposit_output()?, deposit_tx_fee_rate)?;
+            trade_model.set_my_half_deposit_psbt(half_deposit_psbt)?;
 
             let my_fee_bump_addresses = trade_model.get_my_fee_bump_addresses()
                 .ok_or_else(|| Status::internal("missing fee bump addresses"))?;
+            let my_half_deposit_psbt = trade_model.get_my_half_deposit_psbt()
+                .ok_or_else(|| Status::internal("missing half-deposit PSBT"))?;
             let my_nonce_shares = trade_model.get_my_nonce_shares()
                 .ok_or_else(|| Status::internal("missing nonce shares"))?;
 
             Ok(NonceSharesMessage {
                 warning_tx_fee_bump_address: my_fee_bump_addresses[0].to_string(),
                 redirect_tx_fee_bump_address: my_fee_bump_addresses[1].to_string(),
-                half_deposit_psbt: vec![],
+                swap_tx_payout_address: trade_model.get_my_swap_tx_payout_address().map(ToString::to_string),
+                half_deposit_psbt: my_half_deposit_psbt.serialize(),
+                deposit_tx_fee_rate: deposit_tx_fee_rate.to_sat_per_kwu(),
+                prepared_tx_fee_rate: prepared_tx_fee_rate.to_sat_per_kwu(),
                 ..my_nonce_shares.into()
             })
         })
     }
 
     async fn get_partial_signatures(&self, request: Request<PartialSignaturesRequest>) -> Result<Response<PartialSignaturesMessage>> {
-        handle_request(request, move |request, trade_model| {
+        self.handle_request(request, move |request, trade_model| {
             let peer_nonce_shares = request.peers_nonce_shares
                 .ok_or_else(|| Status::not_found("missing request.peers_nonce_shares"))?;
+            trade_model.check_peer_fee_rates(peer_nonce_shares.deposit_tx_fee_rate.try_proto_into()?,
+                peer_nonce_shares.prepared_tx_fee_rate.try_proto_into()?)?;
             trade_model.set_peer_fee_bump_addresses([
                 (&peer_nonce_shares.warning_tx_fee_bump_address).try_proto_into()?,
                 (&peer_nonce_shares.redirect_tx_fee_bump_address).try_proto_into()?
             ])?;
+            trade_model.set_peer_swap_tx_payout_address(peer_nonce_shares.swap_tx_payout_address.as_deref().try_proto_into()?)?;
             trade_model.set_redirection_receivers(request.receivers.into_iter().map(TryProtoInto::try_proto_into))?;
+            trade_model.set_peers_half_deposit_psbt((&peer_nonce_shares.half_deposit_psbt[..]).try_proto_into()?)?;
             trade_model.set_peer_nonce_shares(peer_nonce_shares.try_proto_into()?);
             trade_model.aggregate_nonce_shares()?;
+            trade_model.build_deposit_tx()?;
+            trade_model.build_prepared_txs()?;
             trade_model.sign_partial()?;
             let my_partial_signatures = trade_model.get_my_partial_signatures_on_peer_txs()
                 .ok_or_else(|| Status::internal("missing partial signatures"))?;
@@ -99,77 +150,192 @@ impl musig_server::Musig for MusigImpl {
     }
 
     async fn sign_deposit_tx(&self, request: Request<DepositTxSignatureRequest>) -> Result<Response<DepositPsbt>> {
-        handle_request(request, move |request, trade_model| {
+        self.handle_request(request, move |request, trade_model| {
             let peers_partial_signatures = request.peers_partial_signatures
                 .ok_or_else(|| Status::not_found("missing request.peers_partial_signatures"))?;
             trade_model.set_peer_partial_signatures_on_my_txs(&peers_partial_signatures.try_proto_into()?);
             trade_model.aggregate_partial_signatures()?;
-
-            Ok(DepositPsbt { deposit_psbt: b"deposit_psbt".into() })
+            // Only now that we hold valid signatures on our own warning & redirect txs is it safe to
+            // sign our deposit tx inputs.
+            let mut deposit_psbt = trade_model.get_deposit_psbt()
+                .ok_or_else(|| Status::internal("missing deposit PSBT"))?.clone();
+            self.wallet_service.sign_psbt(&mut deposit_psbt)?;
+            let response = DepositPsbt { deposit_psbt: deposit_psbt.serialize() };
+            trade_model.set_deposit_psbt(deposit_psbt)?;
+
+            Ok(response)
         })
     }
 
     type PublishDepositTxStream = BoxStream<'static, Result<TxConfirmationStatus>>;
 
     async fn publish_deposit_tx(&self, request: Request<PublishDepositTxRequest>) -> Result<Response<Self::PublishDepositTxStream>> {
-        handle_request(request, move |_request, _trade_model| {
-            // TODO: *** BROADCAST DEPOSIT TX ***
-
-            let confirmation_event = TxConfirmationStatus {
-                tx: b"signed_deposit_tx".into(),
-                current_block_height: 900_001,
-                num_confirmations: 1,
+        let (trade_id, deposit_tx, txid) = self.handle_request(request, move |request, trade_model| {
+            // The trader receiving the peer's signed deposit PSBT publishes the deposit tx. Either
+            // trader may just observe it, by leaving out the PSBT.
+            let deposit_tx = match request.deposit_psbt {
+                Some(peers_deposit_psbt) =>
+                    Some(trade_model.combine_deposit_psbt(peers_deposit_psbt.deposit_psbt.try_proto_into()?)?),
+                None => None,
             };
+            let txid = trade_model.get_deposit_txid()
+                .ok_or_else(|| Status::failed_precondition("missing deposit tx"))?;
+            if trade_model.needs_swap_tx_watcher() {
+                let swap_tx_input = trade_model.get_swap_tx_input_outpoint()
+                    .ok_or_else(|| Status::internal("missing swap tx"))?;
+                self.spawn_swap_tx_watcher(trade_model.trade_id().to_owned(), swap_tx_input);
+            }
+            Ok((trade_model.trade_id().to_owned(), deposit_tx, txid))
+        })?.into_inner();
+        // Broadcast only once the trade lock is released, as it may take a while:
+        if let Some(deposit_tx) = deposit_tx {
+            self.wallet_service.broadcast_tx(deposit_tx)?;
+        }
 
-            Ok(stream::iter(iter::once(Ok(confirmation_event))).boxed())
+        // Stream every change in confidence, including reorgs, until the required depth is reached:
+        let depth = self.deposit_tx_confirmation_depth;
+        let mut depth_reached = false;
+        let wallet_service = Arc::clone(&self.wallet_service);
+        // The wallet knows our UTXOs to be spent once it has seen the deposit tx, after which they
+        // needn't be reserved any longer:
+        let reserving_wallet_service = Arc::clone(&self.wallet_service);
+        let confidence_stream = self.wallet_service.get_tx_confidence_stream(txid)
+            .inspect(move |confidence| if confidence.is_some() {
+                reserving_wallet_service.release_reserved_utxos(&trade_id);
+            })
+            .take_while(move |confidence| {
+                let take = !depth_reached;
+                depth_reached = confidence.as_ref().is_some_and(|c| c.num_confirmations >= depth);
+                future::ready(take)
+            })
+            .map(move |confidence| Ok((confidence, wallet_service.get_chain_tip()).into()))
+            .boxed();
+
+        Ok(Response::new(confidence_stream))
+    }
+
+    async fn confirm_payment_started(&self, request: Request<ConfirmPaymentStartedRequest>) -> Result<Response<PaymentStartedMessage>> {
+        self.handle_request(request, move |_, trade_model| {
+            // TODO: Consider requiring the deposit tx to have some confirmations, not just be seen.
+            if trade_model.get_deposit_txid().is_none() {
+                return Err(Status::failed_precondition("missing deposit tx"));
+            }
+            if !self.is_deposit_tx_seen(trade_model) {
+                return Err(Status::failed_precondition("deposit tx has not been published"));
+            }
+            let sig = trade_model.confirm_payment_started()?;
+
+            Ok(PaymentStartedMessage { swap_tx_input_partial_signature: sig.serialize().into() })
         })
     }
 
     async fn sign_swap_tx(&self, request: Request<SwapTxSignatureRequest>) -> Result<Response<SwapTxSignatureResponse>> {
-        handle_request(request, move |request, trade_model| {
+        self.handle_request(request, move |request, trade_model| {
             trade_model.set_swap_tx_input_peers_partial_signature(request.swap_tx_input_peers_partial_signature.try_proto_into()?);
             trade_model.aggregate_swap_tx_partial_signatures()?;
-            let sig = trade_model.compute_swap_tx_input_signature()?;
-            let prv_key_share = trade_model.get_my_private_key_share_for_peer_output()
-                .ok_or_else(|| Status::internal("missing private key share"))?;
-
-            Ok(SwapTxSignatureResponse {
-                // For now, just set 'swap_tx' to be the (final) swap tx signature, rather than the actual signed tx:
-                swap_tx: sig.serialize().into(),
-                peer_output_prv_key_share: prv_key_share.serialize().into(),
-            })
+            let swap_txid = trade_model.get_swap_tx()
+                .ok_or_else(|| Status::internal("missing swap tx"))?.compute_txid();
+
+            Ok(SwapTxSignatureResponse { swap_tx_id: swap_txid.as_byte_array().to_vec() })
+        })
+    }
+
+    async fn confirm_payment_received(&self, request: Request<ConfirmPaymentReceivedRequest>) -> Result<Response<PaymentReceivedMessage>> {
+        self.handle_request(request, move |_, trade_model| {
+            let prv_key_share = trade_model.confirm_payment_received()?;
+
+            Ok(PaymentReceivedMessage { peer_output_prv_key_share: prv_key_share.serialize().into() })
         })
     }
 
     async fn close_trade(&self, request: Request<CloseTradeRequest>) -> Result<Response<CloseTradeResponse>> {
-        handle_request(request, move |request, trade_model| {
+        self.handle_request(request, move |request, trade_model| {
             if let Some(peer_prv_key_share) = request.my_output_peers_prv_key_share.try_proto_into()? {
                 // Trader receives the private key share from a cooperative peer, closing our trade.
                 trade_model.set_peer_private_key_share_for_my_output(peer_prv_key_share)?;
                 trade_model.aggregate_private_keys_for_my_output()?;
-            } else if let Some(swap_tx_input_signature) = request.swap_tx.try_proto_into()? {
+            } else if let Some(swap_tx) = request.swap_tx.try_proto_into()? {
                 // Buyer supplies a signed swap tx to the Rust server, to close our trade. (Mainly for
-                // testing -- normally the tx would be picked up from the bitcoin network by the server.)
-                trade_model.recover_seller_private_key_share_for_buyer_output(&swap_tx_input_signature)?;
+                // testing -- normally the tx is picked up from the bitcoin network by the server.)
+                trade_model.recover_seller_private_key_share_from_swap_tx(&swap_tx)?;
                 trade_model.aggregate_private_keys_for_my_output()?;
+            } else if !trade_model.am_buyer() {
+                // Peer unresponsive -- force-close our trade by publishing the swap tx.
+                let swap_tx = trade_model.get_signed_swap_tx()?;
+                let txid = self.wallet_service.broadcast_tx(swap_tx)?;
+                println!("Broadcast swap tx {txid} for trade: {}", trade_model.trade_id());
+                let swap_tx_input = trade_model.get_swap_tx_input_outpoint()
+                    .ok_or_else(|| Status::internal("missing swap tx"))?;
+                trade_model.swap_tx_num_confirmations = Some(0);
+                self.spawn_swap_tx_confirmation_tracker(trade_model.trade_id().to_owned(), swap_tx_input);
             } else {
-                // Peer unresponsive -- force-close our trade by publishing the swap tx. For seller only.
-                // TODO: *** BROADCAST SWAP TX ***
+                // Buyer closes our trade with the seller's key share recovered from the swap tx, once
+                // the server has picked it up from the bitcoin network.
+                match trade_model.aggregate_private_keys_for_my_output() {
+                    Err(ProtocolErrorKind::MissingKeyShare) =>
+                        return Err(Status::failed_precondition("swap tx has not been seen yet")),
+                    result => result?,
+                };
             }
             let my_prv_key_share = trade_model.get_my_private_key_share_for_peer_output()
-                .ok_or_else(|| Status::internal("missing private key share"))?;
+                .ok_or_else(|| Status::failed_precondition("not yet safe to release private key share"))?;
+            // Normally released as soon as the deposit tx is seen, but the trade is done with anyway.
+            self.wallet_service.release_reserved_utxos(trade_model.trade_id());
 
             Ok(CloseTradeResponse { peer_output_prv_key_share: my_prv_key_share.serialize().into() })
         })
     }
+
+    async fn abort_trade(&self, request: Request<AbortTradeRequest>) -> Result<Response<AbortTradeResponse>> {
+        self.handle_request(request, move |_, trade_model| {
+            if self.is_deposit_tx_seen(trade_model) {
+                return Err(Status::failed_precondition("deposit tx has already been published"));
+            }
+            self.wallet_service.release_reserved_utxos(trade_model.trade_id());
+
+            Ok(AbortTradeResponse {})
+        })
+    }
 }
 
 pub struct WalletImpl {
     pub wallet_service: Arc<dyn WalletService + Send + Sync>,
+    /// The trades, to pick out their txs from the wallet's tx history.
+    pub trade_model_store: Arc<dyn TradeModelStore + Send + Sync>,
+    pub fee_estimator: Arc<dyn FeeEstimator + Send + Sync>,
 }
 
 #[tonic::async_trait]
 impl wallet_server::Wallet for WalletImpl {
+    async fn create_wallet(&self, request: Request<CreateWalletRequest>) -> Result<Response<CreateWalletResponse>> {
+        // Not logged, as the request holds the passphrase.
+        let seed = self.wallet_service.create_wallet(request.into_inner().passphrase)?;
+
+        Ok(Response::new(CreateWalletResponse {
+            mnemonic: seed.mnemonic.to_string(),
+            birthday_height: seed.birthday_height,
+        }))
+    }
+
+    async fn restore_wallet(&self, request: Request<RestoreWalletRequest>) -> Result<Response<RestoreWalletResponse>> {
+        // Not logged, as the request holds the mnemonic.
+        let request = request.into_inner();
+        let seed = WalletSeed {
+            mnemonic: request.mnemonic.parse().map_err(|e| Status::invalid_argument(format!("invalid mnemonic: {e}")))?,
+            passphrase: request.passphrase,
+            birthday_height: request.birthday_height,
+        };
+        self.wallet_service.restore_wallet(seed)?;
+
+        Ok(Response::new(RestoreWalletResponse {}))
+    }
+
+    async fn get_wallet_info(&self, request: Request<GetWalletInfoRequest>) -> Result<Response<GetWalletInfoResponse>> {
+        println!("Got a request: {request:?}");
+
+        Ok(Response::new(self.wallet_service.get_wallet_info().into()))
+    }
+
     async fn wallet_balance(&self, request: Request<WalletBalanceRequest>) -> Result<Response<WalletBalanceResponse>> {
         println!("Got a request: {request:?}");
 
@@ -181,7 +347,7 @@ impl wallet_server::Wallet for WalletImpl {
     async fn new_address(&self, request: Request<NewAddressRequest>) -> Result<Response<NewAddressResponse>> {
         println!("Got a request: {request:?}");
 
-        let address = self.wallet_service.reveal_next_address();
+        let address = self.wallet_service.reveal_next_address()?;
 
         Ok(Response::new(NewAddressResponse {
             address: address.address.to_string(),
@@ -199,6 +365,85 @@ impl wallet_server::Wallet for WalletImpl {
         Ok(Response::new(ListUnspentResponse { utxos }))
     }
 
+    async fn list_transactions(&self, request: Request<ListTransactionsRequest>) -> Result<Response<ListTransactionsResponse>> {
+        println!("Got a request: {request:?}");
+
+        let request = request.into_inner();
+        let confidence_type = request.confidence_type.map(ConfidenceType::try_from).transpose()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let trade_tx_index = self.trade_model_store.get_trade_tx_index();
+        let txs = self.wallet_service.list_transactions();
+
+        Ok(Response::new(list_transactions_page(txs, &trade_tx_index, confidence_type, request.offset, request.limit)))
+    }
+
+    async fn create_funded_psbt(&self, request: Request<CreateFundedPsbtRequest>) -> Result<Response<CreateFundedPsbtResponse>> {
+        println!("Got a request: {request:?}");
+
+        let request = request.into_inner();
+        let recipients = request.recipients.into_iter()
+            .map(TryProtoInto::try_proto_into)
+            .collect::<Result<_>>()?;
+        let utxos = request.utxos.into_iter()
+            .map(TryProtoInto::try_proto_into)
+            .collect::<Result<Vec<_>>>()?;
+        let psbt = self.wallet_service.create_funded_psbt(recipients, request.fee_rate.try_proto_into()?, &utxos)?;
+
+        Ok(Response::new(CreateFundedPsbtResponse { psbt: psbt.serialize() }))
+    }
+
+    async fn sign_psbt(&self, request: Request<SignPsbtRequest>) -> Result<Response<SignPsbtResponse>> {
+        println!("Got a request: {request:?}");
+
+        let mut psbt = request.into_inner().psbt.try_proto_into()?;
+        self.wallet_service.sign_psbt(&mut psbt)?;
+
+        Ok(Response::new(SignPsbtResponse { psbt: psbt.serialize() }))
+    }
+
+    async fn finalize_and_broadcast(&self, request: Request<FinalizeAndBroadcastRequest>) -> Result<Response<BroadcastResponse>> {
+        println!("Got a request: {request:?}");
+
+        let psbt = request.into_inner().psbt.try_proto_into()?;
+        let txid = self.wallet_service.finalize_and_broadcast_psbt(psbt)?;
+
+        Ok(Response::new(txid.into()))
+    }
+
+    async fn send_to_address(&self, request: Request<SendToAddressRequest>) -> Result<Response<BroadcastResponse>> {
+        println!("Got a request: {request:?}");
+
+        let request = request.into_inner();
+        let txid = self.wallet_service.send_to_address(request.address.try_proto_into()?,
+            Amount::from_sat(request.amount), request.fee_rate.try_proto_into()?)?;
+
+        Ok(Response::new(txid.into()))
+    }
+
+    async fn sweep_all(&self, request: Request<SweepAllRequest>) -> Result<Response<BroadcastResponse>> {
+        println!("Got a request: {request:?}");
+
+        let request = request.into_inner();
+        let txid = self.wallet_service.sweep_all(request.address.try_proto_into()?, request.fee_rate.try_proto_into()?)?;
+
+        Ok(Response::new(txid.into()))
+    }
+
+    async fn estimate_fee(&self, request: Request<EstimateFeeRequest>) -> Result<Response<EstimateFeeResponse>> {
+        println!("Got a request: {request:?}");
+
+        let fee_estimates = request.into_inner().conf_targets.into_iter()
+            .map(|conf_target| {
+                let target = u16::try_from(conf_target)
+                    .map_err(|_| Status::invalid_argument(format!("confirmation target out of range: {conf_target}")))?;
+                let fee_rate = self.fee_estimator.estimate_fee_rate(target)?;
+                Ok(FeeEstimate { conf_target, fee_rate: fee_rate.to_sat_per_kwu() })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Response::new(EstimateFeeResponse { fee_estimates }))
+    }
+
     type RegisterConfidenceNtfnStream = BoxStream<'static, Result<ConfEvent>>;
 
     async fn register_confidence_ntfn(&self, request: Request<ConfRequest>) -> Result<Response<Self::RegisterConfidenceNtfnStream>> {
@@ -211,6 +456,32 @@ impl wallet_server::Wallet for WalletImpl {
 
         Ok(Response::new(conf_events))
     }
+
+    type RegisterSpendNtfnStream = BoxStream<'static, Result<SpendEvent>>;
+
+    async fn register_spend_ntfn(&self, request: Request<SpendRequest>) -> Result<Response<Self::RegisterSpendNtfnStream>> {
+        println!("Got a request: {request:?}");
+
+        let request = request.into_inner();
+        let outpoint = OutPoint::new(request.tx_id.try_proto_into()?, request.vout);
+        let spend_events = self.wallet_service.get_spend_confidence_stream(outpoint)
+            .map(move |o| Ok(o.map(|c| (c, outpoint).into()).unwrap_or_default()))
+            .boxed();
+
+        Ok(Response::new(spend_events))
+    }
+
+    type RegisterBlockNtfnStream = BoxStream<'static, Result<BlockEvent>>;
+
+    async fn register_block_ntfn(&self, request: Request<BlockRequest>) -> Result<Response<Self::RegisterBlockNtfnStream>> {
+        println!("Got a request: {request:?}");
+
+        let block_events = self.wallet_service.get_chain_tip_stream()
+            .flat_map(|update| stream::iter(Vec::<BlockEvent>::from(update)).map(Ok))
+            .boxed();
+
+        Ok(Response::new(block_events))
+    }
 }
 
 trait MusigRequest: std::fmt::Debug {
@@ -229,18 +500,287 @@ impl_musig_req!(PartialSignaturesRequest);
 impl_musig_req!(NonceSharesRequest);
 impl_musig_req!(DepositTxSignatureRequest);
 impl_musig_req!(PublishDepositTxRequest);
+impl_musig_req!(ConfirmPaymentStartedRequest);
 impl_musig_req!(SwapTxSignatureRequest);
+impl_musig_req!(ConfirmPaymentReceivedRequest);
 impl_musig_req!(CloseTradeRequest);
+impl_musig_req!(AbortTradeRequest);
+
+impl MusigImpl {
+    pub fn new(trade_model_store: Arc<dyn TradeModelStore + Send + Sync>,
+               wallet_service: Arc<dyn WalletService + Send + Sync>,
+               fee_estimator: Arc<dyn FeeEstimator + Send + Sync>,
+               deposit_tx_confirmation_depth: u32,
+               network: Network) -> Self {
+        Self {
+            trade_model_store, wallet_service, fee_estimator, deposit_tx_confirmation_depth, network,
+            swap_tx_watchers: Arc::default(),
+        }
+    }
+
+    /// Restart the swap tx watchers of the trades left open by the last run of the server.
+    pub fn spawn_open_trade_watchers(&self) {
+        for trade_model in self.trade_model_store.get_trade_models() {
+            let trade_model = trade_model.lock().unwrap();
+            if let (true, Some(swap_tx_input)) = (trade_model.needs_swap_tx_watcher(), trade_model.get_swap_tx_input_outpoint()) {
+                self.spawn_swap_tx_watcher(trade_model.trade_id().to_owned(), swap_tx_input);
+            }
+            let tracking = trade_model.swap_tx_num_confirmations.is_some_and(|n| n < self.deposit_tx_confirmation_depth);
+            if let (true, Some(swap_tx_input)) = (tracking, trade_model.get_swap_tx_input_outpoint()) {
+                self.spawn_swap_tx_confirmation_tracker(trade_model.trade_id().to_owned(), swap_tx_input);
+            }
+        }
+    }
 
-fn handle_request<Req, Res, F>(request: Request<Req>, handler: F) -> Result<Response<Res>>
-    where Req: MusigRequest,
-          F: FnOnce(Req, &mut TradeModel) -> Result<Res> {
-    println!("Got a request: {request:?}");
+    fn fee_rate_or_estimate(&self, sat_per_kwu: Option<u64>, conf_target: u16) -> Result<FeeRate> {
+        match sat_per_kwu {
+            Some(sat_per_kwu) => sat_per_kwu.try_proto_into(),
+            None => Ok(self.fee_estimator.estimate_fee_rate(conf_target)?),
+        }
+    }
+
+    /// Whether the wallet has seen the deposit tx of the trade, in the mempool or a block.
+    fn is_deposit_tx_seen(&self, trade_model: &TradeModel) -> bool {
+        trade_model.get_deposit_txid().is_some_and(|txid| self.wallet_service.get_tx_confidence_stream(txid).next()
+            .now_or_never().flatten().flatten().is_some())
+    }
 
-    let request = request.into_inner();
-    let trade_model = TRADE_MODELS.get_trade_model(request.trade_id())
-        .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id())))?;
-    let response = handler(request, &mut trade_model.lock().unwrap())?;
+    fn handle_request<Req, Res, F>(&self, request: Request<Req>, handler: F) -> Result<Response<Res>>
+        where Req: MusigRequest,
+              F: FnOnce(Req, &mut TradeModel) -> Result<Res> {
+        println!("Got a request: {request:?}");
+
+        let request = request.into_inner();
+        let trade_model = self.trade_model_store.get_trade_model(request.trade_id())
+            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id())))?;
+        let mut trade_model = trade_model.lock().unwrap();
+        let response = handler(request, &mut trade_model);
+        // Persist the trade model even if the handler failed part way, and before responding, so
+        // that a used-up secret nonce can never be restored after a restart and reused.
+        self.trade_model_store.save_trade_model(&trade_model)?;
+        drop(trade_model);
+
+        Ok(Response::new(response?))
+    }
 
-    Ok(Response::new(response))
+    /// Watch for the seller publishing the swap tx, so that the buyer can recover the seller's private
+    /// key share for the buyer payout from its signature, without the seller having to send it. Does
+    /// nothing if the trade already has a watcher, e.g. if the deposit tx is published repeatedly.
+    fn spawn_swap_tx_watcher(&self, trade_id: String, swap_tx_input: OutPoint) {
+        if !self.swap_tx_watchers.lock().unwrap().insert(trade_id.clone()) {
+            return;
+        }
+        let (trade_model_store, swap_tx_watchers) = (Arc::clone(&self.trade_model_store), Arc::clone(&self.swap_tx_watchers));
+        let mut spends = self.wallet_service.get_spend_confidence_stream(swap_tx_input)
+            .filter_map(future::ready);
+        tokio::spawn(async move {
+            while let Some(spend) = spends.next().await {
+                if recover_seller_private_key_share(&*trade_model_store, &trade_id, &spend.wallet_tx.tx) {
+                    break;
+                }
+            }
+            swap_tx_watchers.lock().unwrap().remove(&trade_id);
+        });
+    }
+
+    fn spawn_swap_tx_confirmation_tracker(&self, trade_id: String, swap_tx_input: OutPoint) {
+        let depth = self.deposit_tx_confirmation_depth;
+        let trade_model_store = Arc::clone(&self.trade_model_store);
+        let mut spends = self.wallet_service.get_spend_confidence_stream(swap_tx_input)
+            .filter_map(future::ready);
+        tokio::spawn(async move {
+            while let Some(spend) = spends.next().await {
+                let (txid, num_confirmations) = (spend.wallet_tx.txid, spend.num_confirmations);
+                println!("Swap tx input {swap_tx_input} spent by tx {txid} with {num_confirmations} confirmations.");
+                let Some(trade_model) = trade_model_store.get_trade_model(&trade_id) else {
+                    break;
+                };
+                let mut trade_model = trade_model.lock().unwrap();
+                trade_model.swap_tx_num_confirmations = Some(num_confirmations);
+                if let Err(e) = trade_model_store.save_trade_model(&trade_model) {
+                    eprintln!("Could not save swap tx confirmations for trade {trade_id}: {e}");
+                }
+                drop(trade_model);
+                if num_confirmations >= depth {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Tags each wallet tx with the trade it belongs to (if any), then filters the txs by confidence and
+/// returns the requested page, a limit of zero meaning no limit.
+fn list_transactions_page(txs: Vec<TxDetails>, trade_tx_index: &TradeTxIndex, confidence_type: Option<ConfidenceType>,
+                          offset: u32, limit: u32) -> ListTransactionsResponse {
+    let txs: Vec<_> = txs.into_iter()
+        .map(|tx| {
+            let trade_tx = trade_tx_index.get(&tx.confidence.wallet_tx.tx);
+            TransactionDetails::from((tx, trade_tx))
+        })
+        .filter(|tx| confidence_type.is_none_or(|t| tx.confidence_type() == t))
+        .collect();
+    let total_count = txs.len().try_into().unwrap_or(u32::MAX);
+    let limit = if limit == 0 { usize::MAX } else { limit as usize };
+    let transactions = txs.into_iter().skip(offset as usize).take(limit).collect();
+    ListTransactionsResponse { transactions, total_count }
+}
+
+/// Returns `true` if the trade is done with, either because the key share has been recovered or the
+/// trade is gone, and `false` if the tx isn't the swap tx (or it couldn't be handled).
+fn recover_seller_private_key_share(trade_model_store: &(dyn TradeModelStore + Send + Sync),
+                                    trade_id: &str, swap_tx: &Transaction) -> bool {
+    let Some(trade_model) = trade_model_store.get_trade_model(trade_id) else {
+        return true;
+    };
+    let mut trade_model = trade_model.lock().unwrap();
+    let result = trade_model.recover_seller_private_key_share_from_swap_tx(swap_tx)
+        .and_then(|()| trade_model.aggregate_private_keys_for_my_output().map(|_| ()))
+        .map_err(Status::from)
+        .and_then(|()| Ok(trade_model_store.save_trade_model(&trade_model)?));
+    drop(trade_model);
+    match result {
+        Ok(()) => {
+            println!("Recovered seller's private key share from swap tx for trade: {trade_id}");
+            true
+        }
+        Err(e) => {
+            eprintln!("Could not recover seller's private key share for trade {trade_id}: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk_wallet::bitcoin::{absolute, transaction, BlockHash, TxIn, Txid};
+    use bdk_wallet::bitcoin::hashes::Hash as _;
+    use bdk_wallet::chain::{BlockId, ChainPosition, ConfirmationBlockTime};
+    use bdk_wallet::bitcoin::TxOut;
+    use super::*;
+    use crate::fees::StaticFeeEstimator;
+    use crate::pb::musigrpc::{self, musig_server::Musig as _};
+    use crate::pb::walletrpc::TradeTxType;
+    use crate::protocol::Role;
+    use crate::protocol::tests::trade_models_with_prepared_txs;
+    use crate::store::TradeModelMemoryStore;
+    use crate::wallet::{TxConfidence, WalletServiceImpl, WalletTx};
+
+    fn tx_details(tx: Transaction, confirmation_height: Option<u32>) -> TxDetails {
+        let chain_position = confirmation_height.map_or(ChainPosition::Unconfirmed { last_seen: Some(0) }, |height|
+            ChainPosition::Confirmed {
+                anchor: ConfirmationBlockTime {
+                    block_id: BlockId { height, hash: BlockHash::all_zeros() },
+                    confirmation_time: 0,
+                },
+                transitively: None,
+            });
+        let wallet_tx = WalletTx { txid: tx.compute_txid(), tx: Arc::new(tx), chain_position };
+        TxDetails {
+            confidence: TxConfidence { wallet_tx, num_confirmations: confirmation_height.map_or(0, |h| 11 - h) },
+            sent: Amount::ZERO,
+            received: Amount::from_sat(1000),
+            fee: None,
+        }
+    }
+
+    fn dummy_tx(funding_txid: [u8; 32]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn { previous_output: OutPoint::new(Txid::from_byte_array(funding_txid), 0), ..TxIn::default() }],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_list_transactions_page() {
+        let [buyer, _] = trade_models_with_prepared_txs();
+        let deposit_tx = buyer.get_deposit_psbt().unwrap().unsigned_tx.clone();
+        let store = TradeModelMemoryStore::default();
+        store.add_trade_model(buyer).unwrap();
+        let index = store.get_trade_tx_index();
+        let txs = vec![
+            tx_details(dummy_tx([1; 32]), None),
+            tx_details(deposit_tx.clone(), Some(10)),
+            tx_details(dummy_tx([2; 32]), Some(9)),
+            tx_details(dummy_tx([3; 32]), Some(8)),
+        ];
+        let page = |confidence_type, offset, limit| list_transactions_page(txs.clone(), &index, confidence_type, offset, limit);
+
+        let response = page(None, 0, 0);
+        assert_eq!(response.total_count, 4);
+        let [unconfirmed, deposit, ..] = &response.transactions[..] else { panic!("expected 4 txs") };
+        assert_eq!((unconfirmed.trade_tx_type(), unconfirmed.trade_id.as_str()), (TradeTxType::NotTradeTx, ""));
+        assert_eq!((deposit.trade_tx_type(), deposit.trade_id.as_str()), (TradeTxType::Deposit, "buyer-trade"));
+        assert_eq!(deposit.tx_id, deposit_tx.compute_txid().as_byte_array());
+        assert_eq!(deposit.num_confirmations, 1);
+
+        // The total count is of all the matching txs, not just those in the page.
+        let response = page(Some(ConfidenceType::Confirmed), 1, 1);
+        assert_eq!(response.total_count, 3);
+        assert_eq!(response.transactions, [page(None, 2, 1).transactions[0].clone()]);
+        assert_eq!(page(Some(ConfidenceType::Confirmed), 1, 0).transactions.len(), 2);
+        let response = page(Some(ConfidenceType::Unconfirmed), 0, 10);
+        assert_eq!((response.total_count, response.transactions.len()), (1, 1));
+        assert_eq!(response.transactions[0].confidence_type(), ConfidenceType::Unconfirmed);
+        let response = page(None, 5, 0);
+        assert_eq!((response.total_count, response.transactions.len()), (4, 0));
+    }
+
+    #[tokio::test]
+    async fn test_abort_trade_releases_utxos() {
+        let wallet_service = Arc::new(WalletServiceImpl::new());
+        let address = wallet_service.reveal_next_address().unwrap().address;
+        let funding_tx = Transaction {
+            output: vec![TxOut { value: Amount::from_sat(100_000), script_pubkey: address.script_pubkey() }],
+            ..dummy_tx([1; 32])
+        };
+        wallet_service.apply_unconfirmed_txs(vec![(funding_tx, 100)]);
+        let fee_estimator = Arc::new(StaticFeeEstimator { fee_rate: FeeRate::from_sat_per_vb_unchecked(2) });
+        let musig = MusigImpl::new(Arc::new(TradeModelMemoryStore::default()), wallet_service, fee_estimator, 6,
+            Network::Regtest);
+        let mut peer = TradeModel::new("peer".to_owned(), Role::BuyerAsTaker, Network::Regtest);
+        peer.init_my_key_shares();
+        let peer_key_shares = peer.get_my_key_shares().unwrap().map(|k| k.pub_key.serialize().to_vec());
+        let start_trade = async |trade_id: &str| {
+            let request = PubKeySharesRequest { trade_id: trade_id.to_owned(), my_role: musigrpc::Role::SellerAsMaker.into() };
+            musig.init_trade(Request::new(request)).await.unwrap();
+            musig.get_nonce_shares(Request::new(NonceSharesRequest {
+                trade_id: trade_id.to_owned(),
+                buyer_output_peers_pub_key_share: peer_key_shares[0].clone(),
+                seller_output_peers_pub_key_share: peer_key_shares[1].clone(),
+                trade_amount: 50_000,
+                buyers_security_deposit: 10_000,
+                sellers_security_deposit: 10_000,
+                ..NonceSharesRequest::default()
+            })).await
+        };
+
+        // The only UTXO is reserved for the first trade, until it is aborted.
+        start_trade("trade1").await.unwrap();
+        assert_eq!(start_trade("trade2").await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+        musig.abort_trade(Request::new(AbortTradeRequest { trade_id: "trade1".to_owned() })).await.unwrap();
+        start_trade("trade3").await.unwrap();
+    }
+
+    struct UnreachableFeeEstimator;
+
+    impl FeeEstimator for UnreachableFeeEstimator {
+        fn estimate_fee_rate(&self, _conf_target: u16) -> std::result::Result<FeeRate, fees::FeeEstimationErrorKind> {
+            panic!("fee rates should not be estimated");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_taker_nonce_shares_require_maker_fee_rates() {
+        let musig = MusigImpl::new(Arc::new(TradeModelMemoryStore::default()), Arc::new(WalletServiceImpl::new()),
+            Arc::new(UnreachableFeeEstimator), 6, Network::Regtest);
+        let request = PubKeySharesRequest { trade_id: "trade".to_owned(), my_role: musigrpc::Role::BuyerAsTaker.into() };
+        musig.init_trade(Request::new(request)).await.unwrap();
+
+        let request = NonceSharesRequest { trade_id: "trade".to_owned(), ..NonceSharesRequest::default() };
+        let status = musig.get_nonce_shares(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
 }
@@ -0,0 +1,351 @@
+use bdk_wallet::bitcoin::{Address, OutPoint, Transaction, Txid};
+use bdk_wallet::bitcoin::address::NetworkUnchecked;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead as _, BufReader, BufWriter, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::{RedirectionReceiver, TradeModel, TradeTxType};
+
+pub trait TradeModelStore {
+    /// # Errors
+    /// Will return `Err` if the trade model could not be persisted, or a trade with the same ID
+    /// already exists
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()>;
+
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>>;
+
+    fn get_trade_models(&self) -> Vec<Arc<Mutex<TradeModel>>>;
+
+    /// Persist the current state of a trade model, which must be called after every change, before
+    /// anything derived from the new state (e.g. a partial signature) is handed out to the client.
+    ///
+    /// # Errors
+    /// Will return `Err` if the trade model could not be persisted
+    fn save_trade_model(&self, trade_model: &TradeModel) -> io::Result<()>;
+
+    /// The txs of every trade built so far, to tell the trade ID and tx type of a wallet tx.
+    fn get_trade_tx_index(&self) -> TradeTxIndex;
+}
+
+/// The trade ID and tx type of each trade tx, by its txid, or by the warning tx output it spends in
+/// the case of a claim tx.
+#[derive(Default)]
+pub struct TradeTxIndex {
+    txids: HashMap<Txid, (String, TradeTxType)>,
+    warning_outpoints: HashMap<OutPoint, String>,
+}
+
+impl TradeTxIndex {
+    fn insert(&mut self, trade_model: &TradeModel) {
+        let trade_id = trade_model.trade_id();
+        self.txids.extend(trade_model.get_trade_txids().into_iter()
+            .map(|(txid, tx_type)| (txid, (trade_id.to_owned(), tx_type))));
+        self.warning_outpoints.extend(trade_model.get_warning_outpoints().into_iter()
+            .map(|outpoint| (outpoint, trade_id.to_owned())));
+    }
+
+    pub fn get(&self, tx: &Transaction) -> Option<(String, TradeTxType)> {
+        if let Some(trade_tx) = self.txids.get(&tx.compute_txid()) {
+            return Some(trade_tx.clone());
+        }
+        tx.input.iter()
+            .find_map(|txin| self.warning_outpoints.get(&txin.previous_output))
+            .map(|trade_id| (trade_id.clone(), TradeTxType::Claim))
+    }
+}
+
+#[derive(Default)]
+pub struct TradeModelMemoryStore {
+    trade_models: Mutex<BTreeMap<String, Arc<Mutex<TradeModel>>>>,
+}
+
+impl TradeModelMemoryStore {
+    fn insert(&self, trade_model: TradeModel) {
+        self.trade_models.lock().unwrap().insert(trade_model.trade_id().to_owned(), Arc::new(Mutex::new(trade_model)));
+    }
+
+    fn check_new_trade_id(&self, trade_id: &str) -> io::Result<()> {
+        if self.trade_models.lock().unwrap().contains_key(trade_id) {
+            return Err(already_exists(trade_id));
+        }
+        Ok(())
+    }
+}
+
+impl TradeModelStore for TradeModelMemoryStore {
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()> {
+        match self.trade_models.lock().unwrap().entry(trade_model.trade_id().to_owned()) {
+            Entry::Occupied(entry) => Err(already_exists(entry.key())),
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::new(Mutex::new(trade_model)));
+                Ok(())
+            }
+        }
+    }
+
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>> {
+        self.trade_models.lock().unwrap().get(trade_id).map(Arc::clone)
+    }
+
+    fn get_trade_models(&self) -> Vec<Arc<Mutex<TradeModel>>> {
+        self.trade_models.lock().unwrap().values().map(Arc::clone).collect()
+    }
+
+    fn save_trade_model(&self, _trade_model: &TradeModel) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn get_trade_tx_index(&self) -> TradeTxIndex {
+        let mut index = TradeTxIndex::default();
+        for trade_model in self.get_trade_models() {
+            index.insert(&trade_model.lock().unwrap());
+        }
+        index
+    }
+}
+
+/// A trade model store backed by an append-only file, with one JSON-serialized trade model per line.
+/// Each save appends a snapshot of the whole trade model, the last one for each trade ID winning upon
+/// loading. The file is compacted to just the latest snapshots whenever it is opened, dropping a
+/// partially written last line left behind by a crash.
+///
+/// Note that the file holds private key shares and secret nonces, so it is only readable by the owner.
+pub struct FileTradeModelStore {
+    path: PathBuf,
+    file: Mutex<File>,
+    trade_models: TradeModelMemoryStore,
+}
+
+impl FileTradeModelStore {
+    /// # Errors
+    /// Will return `Err` if the file cannot be read, parsed or rewritten
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let trade_models = TradeModelMemoryStore::default();
+        match File::open(&path) {
+            Ok(file) => {
+                let mut lines = BufReader::new(file).lines().peekable();
+                while let Some(line) = lines.next() {
+                    match serde_json::from_str(&line?) {
+                        Ok(trade_model) => trade_models.insert(trade_model),
+                        // Only the last line can be torn by a crash mid-append. Anything else is corruption.
+                        Err(e) if lines.peek().is_none() => eprintln!("Dropping incomplete last line of {}: {e}", path.display()),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        Self::compact(&path, &trade_models)?;
+        let file = Mutex::new(open_private(&path, OpenOptions::new().append(true))?);
+        Ok(Self { path, file, trade_models })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn compact(path: &Path, trade_models: &TradeModelMemoryStore) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(open_private(&tmp_path, OpenOptions::new().write(true).truncate(true))?);
+        for trade_model in trade_models.trade_models.lock().unwrap().values() {
+            write_line(&mut writer, &trade_model.lock().unwrap())?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    fn append(&self, trade_model: &TradeModel) -> io::Result<()> {
+        append_line(&mut self.file.lock().unwrap(), trade_model)
+    }
+}
+
+impl TradeModelStore for FileTradeModelStore {
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()> {
+        // Holding the file lock throughout keeps a concurrent add of the same trade ID out.
+        let mut file = self.file.lock().unwrap();
+        self.trade_models.check_new_trade_id(trade_model.trade_id())?;
+        append_line(&mut file, &trade_model)?;
+        self.trade_models.insert(trade_model);
+        drop(file);
+        Ok(())
+    }
+
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>> {
+        self.trade_models.get_trade_model(trade_id)
+    }
+
+    fn get_trade_models(&self) -> Vec<Arc<Mutex<TradeModel>>> {
+        self.trade_models.get_trade_models()
+    }
+
+    fn save_trade_model(&self, trade_model: &TradeModel) -> io::Result<()> {
+        self.append(trade_model)
+    }
+
+    fn get_trade_tx_index(&self) -> TradeTxIndex {
+        self.trade_models.get_trade_tx_index()
+    }
+}
+
+fn already_exists(trade_id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("trade {trade_id} already exists"))
+}
+
+fn write_line(writer: &mut impl io::Write, trade_model: &TradeModel) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, trade_model)?;
+    writer.write_all(b"\n")
+}
+
+/// Append the trade model as a line, truncating the file back to its old length if that fails part
+/// way (e.g. on a full disk), as a torn line is only tolerated at the end of the file upon loading.
+fn append_line(file: &mut File, trade_model: &TradeModel) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let mut line = serde_json::to_vec(trade_model)?;
+    line.push(b'\n');
+    file.write_all(&line).and_then(|()| file.sync_data()).inspect_err(|_| {
+        if let Err(e) = file.set_len(len) {
+            eprintln!("Could not truncate torn line from trade store: {e}");
+        }
+    })
+}
+
+pub(crate) fn open_private(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    options.create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(options, 0o600);
+    options.open(path)
+}
+
+/// Serde helper for addresses, which are only ever stored after their network has been checked, so
+/// that the check needn't be repeated when they are loaded back in.
+pub mod assume_checked {
+    use super::{Address, Deserialize, Deserializer, NetworkUnchecked, RedirectionReceiver, Serialize, Serializer};
+
+    pub trait AssumeChecked: Serialize {
+        type Unchecked;
+
+        fn assume_checked(unchecked: Self::Unchecked) -> Self;
+    }
+
+    impl AssumeChecked for Address {
+        type Unchecked = Address<NetworkUnchecked>;
+
+        fn assume_checked(unchecked: Self::Unchecked) -> Self {
+            unchecked.assume_checked()
+        }
+    }
+
+    impl AssumeChecked for RedirectionReceiver {
+        type Unchecked = RedirectionReceiver<NetworkUnchecked>;
+
+        fn assume_checked(unchecked: Self::Unchecked) -> Self {
+            Self { address: unchecked.address.assume_checked(), amount: unchecked.amount }
+        }
+    }
+
+    impl<T: AssumeChecked> AssumeChecked for Vec<T> {
+        type Unchecked = Vec<T::Unchecked>;
+
+        fn assume_checked(unchecked: Self::Unchecked) -> Self {
+            unchecked.into_iter().map(T::assume_checked).collect()
+        }
+    }
+
+    impl<T: AssumeChecked> AssumeChecked for Option<T> {
+        type Unchecked = Option<T::Unchecked>;
+
+        fn assume_checked(unchecked: Self::Unchecked) -> Self {
+            unchecked.map(T::assume_checked)
+        }
+    }
+
+    /// # Errors
+    /// Will return `Err` if the serializer fails
+    pub fn serialize<T: AssumeChecked, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        value.serialize(serializer)
+    }
+
+    /// # Errors
+    /// Will return `Err` if the deserializer fails or the value is malformed
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+        where T: AssumeChecked,
+              T::Unchecked: Deserialize<'de>,
+              D: Deserializer<'de>
+    {
+        Ok(T::assume_checked(T::Unchecked::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk_wallet::bitcoin::{FeeRate, Network};
+    use std::io::Write as _;
+    use super::*;
+    use crate::protocol::Role;
+    use crate::protocol::tests::{next_address, test_wallet};
+
+    fn to_json(trade_model: &TradeModel) -> String {
+        serde_json::to_string(trade_model).unwrap()
+    }
+
+    #[test]
+    fn test_file_store_reload() {
+        let path = std::env::temp_dir().join(format!("musigd-test-{}/trades.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut seller = TradeModel::new("trade1".to_owned(), Role::SellerAsMaker, Network::Signet);
+        let mut buyer = TradeModel::new("trade1".to_owned(), Role::BuyerAsTaker, Network::Signet);
+        seller.init_my_key_shares();
+        buyer.init_my_key_shares();
+        let [buyer_output_key, seller_output_key] = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        seller.set_peer_key_shares(buyer_output_key, seller_output_key);
+
+        let store = FileTradeModelStore::open(&path).unwrap();
+        store.add_trade_model(TradeModel::new("trade2".to_owned(), Role::BuyerAsMaker, Network::Signet)).unwrap();
+        store.add_trade_model(seller).unwrap();
+        let seller = store.get_trade_model("trade1").unwrap();
+        let mut trade_model = seller.lock().unwrap();
+        trade_model.aggregate_key_shares().unwrap();
+        trade_model.set_fee_rates(FeeRate::from_sat_per_vb_unchecked(10), FeeRate::from_sat_per_vb_unchecked(2)).unwrap();
+        let mut wallet = test_wallet();
+        trade_model.set_my_fee_bump_addresses([next_address(&mut wallet), next_address(&mut wallet)]);
+        trade_model.init_my_nonce_shares().unwrap();
+        store.save_trade_model(&trade_model).unwrap();
+        drop(trade_model);
+        drop(store);
+
+        // the latest snapshot of each trade is loaded, including its secret nonces
+        let store = FileTradeModelStore::open(&path).unwrap();
+        let reloaded = store.get_trade_model("trade1").unwrap();
+        assert_eq!(to_json(&reloaded.lock().unwrap()), to_json(&seller.lock().unwrap()));
+        assert!(store.get_trade_model("trade2").is_some());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2, "file should be compacted");
+        let err = store.add_trade_model(TradeModel::new("trade2".to_owned(), Role::SellerAsTaker, Network::Signet)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        drop(store);
+
+        // a crash in the middle of an append leaves a torn last line, which is dropped upon loading
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        let torn_line = to_json(&TradeModel::new("trade3".to_owned(), Role::BuyerAsTaker, Network::Signet));
+        file.write_all(&torn_line.as_bytes()[..torn_line.len() / 2]).unwrap();
+        drop(file);
+        let store = FileTradeModelStore::open(&path).unwrap();
+        assert!(store.get_trade_model("trade1").is_some());
+        assert!(store.get_trade_model("trade3").is_none());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2, "torn line should be truncated");
+
+        // but a bad line anywhere else is an error
+        fs::write(&path, format!("{{}}\n{}\n", to_json(&seller.lock().unwrap()))).unwrap();
+        assert!(FileTradeModelStore::open(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,25 +1,33 @@
-use bdk_bitcoind_rpc::Emitter;
+use bdk_bitcoind_rpc::{BlockEvent, Emitter};
 use bdk_bitcoind_rpc::bitcoincore_rpc::{Auth, Client, RpcApi as _};
-use bdk_wallet::{AddressInfo, Balance, KeychainKind, LocalOutput, Wallet};
-use bdk_wallet::bitcoin::{Network, Transaction, Txid};
-use bdk_wallet::chain::{CheckPoint, ChainPosition, ConfirmationBlockTime};
+use bdk_wallet::{AddUtxoError, AddressInfo, Balance, CreateWithPersistError, KeychainKind, LoadWithPersistError,
+    LocalOutput, PersistedWallet, SignOptions, TxBuilder, Wallet};
+use bdk_wallet::bitcoin::{Address, Amount, Block, FeeRate, Network, OutPoint, Psbt, Sequence, Transaction, TxOut, Txid};
+use bdk_wallet::bitcoin::address::NetworkUnchecked;
+use bdk_wallet::bitcoin::psbt::ExtractTxError;
+use bdk_wallet::coin_selection::DefaultCoinSelectionAlgorithm;
+use bdk_wallet::bitcoin::constants::genesis_block;
+use bdk_wallet::error::CreateTxError;
+use bdk_wallet::rusqlite::{self, Connection};
+use bdk_wallet::signer::SignerError;
+use bdk_wallet::chain::{BlockId, CheckPoint, ChainPosition, ConfirmationBlockTime};
 use drop_stream::DropStream;
 use futures::never::Never;
-use futures::stream::{BoxStream, StreamExt as _};
+use futures::stream::{BoxStream, Stream, StreamExt as _};
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::task::{self, JoinHandle};
 use tokio::time::{self, Duration, MissedTickBehavior};
 
-use crate::observable::ObservableHashMap;
-
-const COOKIE_FILE_PATH: &str = ".localnet/bitcoind/regtest/.cookie";
-//noinspection SpellCheckingInspection
-const EXTERNAL_DESCRIPTOR: &str = "tr(tprv8ZgxMBicQKsPdrjwWCyXqqJ4YqcyG4DmKtjjsRt29v1PtD3r3PuFJAj\
-    WytzcvSTKnZAGAkPSmnrdnuHWxCAwy3i1iPhrtKAfXRH7dVCNGp6/86'/1'/0'/0/*)#g9xn7wf9";
-//noinspection SpellCheckingInspection
-const INTERNAL_DESCRIPTOR: &str = "tr(tprv8ZgxMBicQKsPdrjwWCyXqqJ4YqcyG4DmKtjjsRt29v1PtD3r3PuFJAj\
-    WytzcvSTKnZAGAkPSmnrdnuHWxCAwy3i1iPhrtKAfXRH7dVCNGp6/86'/1'/0'/1/*)#e3rjrmea";
+use crate::keys::{Descriptors, KeyDerivationError, WalletSeed};
+use crate::observable::{Observable, ObservableHashMap};
 
 #[tonic::async_trait]
 pub trait WalletService {
@@ -28,10 +36,97 @@ pub trait WalletService {
     async fn connect(&self) -> Result<Never>;
 
     fn balance(&self) -> Balance;
-    fn reveal_next_address(&self) -> AddressInfo;
+    fn get_chain_tip(&self) -> BlockId;
+
+    /// Stream every change of the wallet's chain tip, starting with the current tip.
+    fn get_chain_tip_stream(&self) -> BoxStream<'static, ChainTipUpdate>;
+
+    /// # Errors
+    /// Will return `Err` if there is no wallet or the revealed address index could not be persisted
+    fn reveal_next_address(&self) -> Result<AddressInfo>;
     fn list_unspent(&self) -> Vec<LocalOutput>;
+
+    /// List the wallet's txs, newest first: unconfirmed txs by when they were last seen, followed by
+    /// confirmed txs by descending height.
+    fn list_transactions(&self) -> Vec<TxDetails>;
     fn get_tx_confidence_stream(&self, txid: Txid) -> BoxStream<'static, Option<TxConfidence>>;
 
+    /// Stream the confidence of whichever tx spends the given outpoint, which needn't belong to the
+    /// wallet, starting with `None` until such a tx is seen in the mempool or a block. Spends made
+    /// before the outpoint was first watched are looked up on the next poll, which requires bitcoind
+    /// to be run with `-txindex` for spends that have already confirmed.
+    fn get_spend_confidence_stream(&self, outpoint: OutPoint) -> BoxStream<'static, Option<TxConfidence>>;
+
+    fn get_wallet_info(&self) -> WalletInfo;
+
+    /// Create a wallet from a freshly generated BIP-39 mnemonic, with the current block height of
+    /// the node as its birthday, replacing the test wallet (if any).
+    ///
+    /// # Errors
+    /// Will return `Err` if a wallet has already been set up, or the wallet cannot be created
+    fn create_wallet(&self, passphrase: String) -> Result<WalletSeed>;
+
+    /// Restore a wallet from a BIP-39 mnemonic, rescanning the chain from the given birthday height,
+    /// replacing the test wallet (if any).
+    ///
+    /// # Errors
+    /// Will return `Err` if a wallet has already been set up, the birthday is above the chain tip,
+    /// or the wallet cannot be restored
+    fn restore_wallet(&self, seed: WalletSeed) -> Result<()>;
+
+    /// Fund a PSBT paying our half of the deposit to the given output, with change back to the
+    /// wallet, to be merged with the peer's half into the deposit tx. The selected UTXOs are
+    /// reserved for the trade, so that no other tx spends them, until they are released again.
+    ///
+    /// # Errors
+    /// Will return `Err` if the wallet has insufficient funds or the PSBT can't otherwise be built
+    fn create_half_deposit_psbt(&self, trade_id: &str, deposit_output: TxOut, fee_rate: FeeRate) -> Result<Psbt>;
+
+    /// Release the UTXOs reserved for the trade, once its deposit tx is published or it is aborted
+    /// or closed.
+    /// (The reservations aren't persisted, so a restart releases them as well.)
+    fn release_reserved_utxos(&self, trade_id: &str);
+
+    /// Fund a PSBT paying the given recipients, with change back to the wallet. If any UTXOs are
+    /// given, only those are spent.
+    ///
+    /// # Errors
+    /// Will return `Err` if an address is for the wrong network, a UTXO is unknown, the wallet has
+    /// insufficient funds or the PSBT can't otherwise be built
+    fn create_funded_psbt(&self, recipients: Vec<(Address<NetworkUnchecked>, Amount)>, fee_rate: FeeRate,
+                          utxos: &[OutPoint]) -> Result<Psbt>;
+
+    /// # Errors
+    /// Will return `Err` if the PSBT isn't fully signed, or the tx is rejected or the node cannot
+    /// be reached
+    fn finalize_and_broadcast_psbt(&self, psbt: Psbt) -> Result<Txid>;
+
+    /// # Errors
+    /// Will return `Err` if the address is for the wrong network, or the tx cannot be funded or
+    /// broadcast
+    fn send_to_address(&self, address: Address<NetworkUnchecked>, amount: Amount, fee_rate: FeeRate) -> Result<Txid>;
+
+    /// Send every spendable UTXO of the wallet to the given address, leaving no change.
+    ///
+    /// # Errors
+    /// Will return `Err` if the address is for the wrong network, or the tx cannot be funded or
+    /// broadcast
+    fn sweep_all(&self, address: Address<NetworkUnchecked>, fee_rate: FeeRate) -> Result<Txid>;
+
+    /// Broadcast the tx via the chain connection and add it to the wallet as unconfirmed, so that
+    /// it can be observed straight away with [`Self::get_tx_confidence_stream`] (or with
+    /// [`Self::get_spend_confidence_stream`], if it isn't a wallet tx).
+    ///
+    /// # Errors
+    /// Will return `Err` if the tx is rejected or the node cannot be reached
+    fn broadcast_tx(&self, tx: Transaction) -> Result<Txid>;
+
+    /// Sign all the inputs of the PSBT which belong to the wallet, leaving the others untouched.
+    ///
+    /// # Errors
+    /// Will return `Err` if there is no wallet or it fails to sign any of its inputs
+    fn sign_psbt(&self, psbt: &mut Psbt) -> Result<()>;
+
     /// # Panics
     /// Will panic if called outside the context of a Tokio runtime
     fn spawn_connection(self: Arc<Self>) -> JoinHandle<Result<Never>> where Self: Send + Sync + 'static {
@@ -43,31 +138,356 @@ pub trait WalletService {
     }
 }
 
+/// The wallet setup and the bitcoind connection details.
+#[derive(Debug)]
+pub struct WalletConfig {
+    pub network: Network,
+    /// The descriptors of the wallet, if given explicitly, which rules out creating or restoring a
+    /// wallet from a mnemonic.
+    pub descriptors: Option<Descriptors>,
+    /// Whether to use the test keys until a wallet is created or restored from a mnemonic, rather
+    /// than having no wallet until then.
+    pub use_test_keys: bool,
+    /// The directory to persist the wallet and its seed to, or `None` to keep them in memory only.
+    pub data_dir: Option<PathBuf>,
+    pub rpc_url: String,
+    pub rpc_auth: Auth,
+}
+
+impl Default for WalletConfig {
+    /// The regtest setup with the test keys, expecting bitcoind to run locally with its default
+    /// RPC port and its data-dir set to `.localnet/bitcoind`.
+    fn default() -> Self {
+        Self {
+            network: Network::Regtest,
+            descriptors: None,
+            use_test_keys: true,
+            data_dir: None,
+            rpc_url: "http://127.0.0.1:18443".to_owned(),
+            rpc_auth: Auth::CookieFile(".localnet/bitcoind/regtest/.cookie".into()),
+        }
+    }
+}
+
+/// Where the keys of the wallet came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WalletSource {
+    TestKeys,
+    Descriptors,
+    Mnemonic,
+}
+
+impl WalletSource {
+    /// The database of the wallet, kept apart for each source, so that a wallet created or restored
+    /// later never picks up the state of the test wallet or a configured wallet, and vice versa.
+    const fn db_file_name(self) -> &'static str {
+        match self {
+            Self::TestKeys => "test-wallet.sqlite",
+            Self::Descriptors => "descriptor-wallet.sqlite",
+            Self::Mnemonic => "mnemonic-wallet.sqlite",
+        }
+    }
+}
+
+pub struct WalletInfo {
+    pub network: Network,
+    /// The source of the wallet keys, or `None` if no wallet has been created or restored yet.
+    pub source: Option<WalletSource>,
+    pub birthday_height: Option<u32>,
+    /// The public descriptors of the wallet, if any.
+    pub descriptors: Option<Descriptors>,
+}
+
+/// A wallet together with the database it is persisted to.
+struct LoadedWallet {
+    wallet: PersistedWallet<Connection>,
+    db: Mutex<Connection>,
+    source: WalletSource,
+    birthday_height: Option<u32>,
+}
+
+impl LoadedWallet {
+    /// Load the wallet from its database, or create it afresh if the database is new.
+    fn open(network: Network, data_dir: Option<&Path>, descriptors: Descriptors, source: WalletSource,
+            birthday_height: Option<u32>) -> Result<Self> {
+        let mut db = if let Some(data_dir) = data_dir {
+            fs::create_dir_all(data_dir)?;
+            Connection::open(data_dir.join(source.db_file_name()))?
+        } else {
+            Connection::open_in_memory()?
+        };
+        let wallet = Wallet::load()
+            .descriptor(KeychainKind::External, Some(descriptors.external.clone()))
+            .descriptor(KeychainKind::Internal, Some(descriptors.internal.clone()))
+            .extract_keys()
+            .check_network(network)
+            .load_wallet(&mut db)?;
+        let wallet = if let Some(wallet) = wallet {
+            wallet
+        } else {
+            Wallet::create(descriptors.external, descriptors.internal)
+                .network(network)
+                .create_wallet(&mut db)?
+        };
+        Ok(Self { wallet, db: Mutex::new(db), source, birthday_height })
+    }
+
+    fn persist(&mut self) -> Result<()> {
+        self.wallet.persist(self.db.get_mut().unwrap())?;
+        Ok(())
+    }
+
+    /// The wallet tip and the height to start syncing from, which is above the tip if it hasn't yet
+    /// reached the birthday of the wallet.
+    fn sync_start(&self) -> (CheckPoint, u32) {
+        let wallet_tip = self.wallet.latest_checkpoint();
+        let start_height = wallet_tip.height().max(self.birthday_height.unwrap_or(0));
+        (wallet_tip, start_height)
+    }
+}
+
 pub struct WalletServiceImpl {
     // NOTE: To avoid deadlocks, must be careful to acquire these locks in consistent order. At
-    //  present, the lock on 'wallet' is acquired first, then the lock on 'tx_confidence_map'.
+    //  present, the lock on 'wallet' is acquired first, then the lock on 'reserved_utxos', then the
+    //  lock on 'tx_confidence_map', then the lock on 'spend_map', then the lock on 'chain_tip'.
     // TODO: Consider using async locks here, as wallet operations have nontrivial cost:
-    wallet: RwLock<Wallet>,
+    network: Network,
+    data_dir: Option<PathBuf>,
+    rpc_url: String,
+    rpc_auth: Auth,
+    wallet: RwLock<Option<LoadedWallet>>,
+    /// The number of times the wallet has been replaced, so that syncing can restart with the new one.
+    wallet_generation: AtomicU64,
+    /// The UTXOs selected for the half-deposit PSBT of each trade, which no other tx may spend.
+    reserved_utxos: Mutex<HashMap<String, Vec<OutPoint>>>,
     tx_confidence_map: Mutex<ObservableHashMap<Txid, TxConfidence>>,
+    spend_map: Mutex<SpendMap>,
+    chain_tip: Mutex<Observable<ChainTipUpdate>>,
 }
 
 impl WalletServiceImpl {
-    // TODO: Make wallet setup configurable.
     pub fn new() -> Self {
-        let wallet = Wallet::create(EXTERNAL_DESCRIPTOR, INTERNAL_DESCRIPTOR)
-            .network(Network::Regtest)
-            .create_wallet_no_persist()
-            .expect("hardcoded descriptors should be valid");
+        Self::with_config(WalletConfig::default()).expect("hardcoded descriptors should be valid")
+    }
+
+    /// Load the wallet from its database, resuming from the last persisted checkpoint, or create it
+    /// afresh if the database is new. The keys are taken from the configured descriptors, else the
+    /// persisted seed of a wallet created or restored earlier, else the test keys (if enabled).
+    ///
+    /// # Errors
+    /// Will return `Err` if the database or seed cannot be read, the descriptors are invalid, or they
+    /// or the network don't match those of the persisted wallet
+    pub fn with_config(config: WalletConfig) -> Result<Self> {
+        let seed = config.data_dir.as_deref().map(WalletSeed::load).transpose()?.flatten();
+        let data_dir = config.data_dir.as_deref();
+        let wallet = match (config.descriptors, seed) {
+            (Some(_), Some(_)) =>
+                return Err(WalletErrorKind::IllegalWalletState("wallet descriptors given despite existing wallet seed")),
+            (Some(descriptors), None) =>
+                Some(LoadedWallet::open(config.network, data_dir, descriptors, WalletSource::Descriptors, None)?),
+            (None, Some(seed)) => Some(LoadedWallet::open(config.network, data_dir, seed.descriptors(config.network)?,
+                WalletSource::Mnemonic, Some(seed.birthday_height))?),
+            (None, None) if config.use_test_keys =>
+                Some(LoadedWallet::open(config.network, data_dir, Descriptors::test(), WalletSource::TestKeys, None)?),
+            (None, None) => None,
+        };
 
         let mut tx_confidence_map = ObservableHashMap::new();
-        tx_confidence_map.sync(tx_confidence_entries(&wallet));
+        tx_confidence_map.sync(wallet.iter().flat_map(|w| tx_confidence_entries(&w.wallet)));
+
+        let tip = wallet.as_ref().map_or_else(
+            || BlockId { height: 0, hash: genesis_block(config.network).block_hash() },
+            |w| w.wallet.latest_checkpoint().block_id());
+
+        Ok(Self {
+            network: config.network,
+            data_dir: config.data_dir,
+            rpc_url: config.rpc_url,
+            rpc_auth: config.rpc_auth,
+            wallet: RwLock::new(wallet),
+            wallet_generation: AtomicU64::new(0),
+            reserved_utxos: Mutex::default(),
+            tx_confidence_map: Mutex::new(tx_confidence_map),
+            spend_map: Mutex::default(),
+            chain_tip: Mutex::new(Observable::new(ChainTipUpdate { tip, disconnected: Vec::new() })),
+        })
+    }
 
-        Self { wallet: RwLock::new(wallet), tx_confidence_map: Mutex::new(tx_confidence_map) }
+    fn new_rpc_client(&self) -> Result<Client> {
+        Ok(Client::new(&self.rpc_url, self.rpc_auth.clone())?)
+    }
+
+    /// The wallet tip and height to start syncing from, together with the current wallet generation,
+    /// or `None` if there is no wallet yet.
+    fn sync_start(&self) -> Option<(CheckPoint, u32, u64)> {
+        let wallet = self.wallet.read().unwrap();
+        let (wallet_tip, start_height) = wallet.as_ref()?.sync_start();
+        let generation = self.wallet_generation.load(Ordering::Relaxed);
+        drop(wallet);
+        Some((wallet_tip, start_height, generation))
+    }
+
+    /// Apply the block to the wallet, unless the wallet has been replaced since the given generation
+    /// (returning `false` in that case), in which case syncing must start afresh.
+    fn apply_block(&self, block: &BlockEvent<Block>, generation: u64) -> Result<bool> {
+        let mut wallet = self.wallet.write().unwrap();
+        let Some(loaded) = wallet.as_mut().filter(|_| self.wallet_generation.load(Ordering::Relaxed) == generation) else {
+            return Ok(false);
+        };
+        let old_tip = loaded.wallet.latest_checkpoint();
+        loaded.wallet.apply_block_connected_to(&block.block, block.block_height(), block.connected_to())?;
+        let new_tip = loaded.wallet.latest_checkpoint();
+        self.spend_map.lock().unwrap().apply_block(&block.block, block.block_height());
+        let mut chain_tip = self.chain_tip.lock().unwrap();
+        // While a newly created or restored wallet catches up, its tip lags the known chain tip.
+        if old_tip.block_id() == chain_tip.get().tip || new_tip.height() > chain_tip.get().tip.height {
+            chain_tip.replace(ChainTipUpdate::new(&old_tip, &new_tip));
+        }
+        drop((chain_tip, wallet));
+        Ok(true)
+    }
+
+    pub(crate) fn apply_unconfirmed_txs(&self, unconfirmed_txs: Vec<(Transaction, u64)>) {
+        let mut wallet = self.wallet.write().unwrap();
+        self.spend_map.lock().unwrap().apply_unconfirmed_txs(&unconfirmed_txs);
+        if let Some(loaded) = wallet.as_mut() {
+            loaded.wallet.apply_unconfirmed_txs(unconfirmed_txs);
+        }
+    }
+
+    /// Persist the wallet changes staged by syncing, which needn't be done after every block.
+    fn persist_sync(&self) -> Result<()> {
+        self.wallet.write().unwrap().as_mut().map_or(Ok(()), LoadedWallet::persist)
+    }
+
+    /// Look up any spends of the newly watched outpoints made before they were watched, which the
+    /// emitter won't emit again: in the mempool, else in the blocks from that of the funding tx. A
+    /// failed lookup needn't stop syncing, so is just logged and retried upon the next sync.
+    fn find_earlier_spends(&self, rpc_client: &Client) {
+        let newly_watched = std::mem::take(&mut self.spend_map.lock().unwrap().newly_watched);
+        let failed: Vec<_> = newly_watched.into_iter()
+            .filter(|&outpoint| match self.find_earlier_spend(rpc_client, outpoint) {
+                Ok(()) => false,
+                Err(e) => {
+                    eprintln!("Could not look up earlier spends of outpoint {outpoint}: {e}");
+                    true
+                }
+            })
+            .collect();
+        self.spend_map.lock().unwrap().newly_watched.extend(failed);
+    }
+
+    fn find_earlier_spend(&self, rpc_client: &Client, outpoint: OutPoint) -> Result<()> {
+        let args = [serde_json::json!([{ "txid": outpoint.txid, "vout": outpoint.vout }])];
+        let [mempool_spend]: [MempoolSpend; 1] = rpc_client.call("gettxspendingprevout", &args)?;
+        if let Some(spending_txid) = mempool_spend.spendingtxid {
+            let tx = rpc_client.get_raw_transaction(&spending_txid, None)?;
+            let last_seen = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            self.spend_map.lock().unwrap().apply_unconfirmed_txs(&[(tx, last_seen)]);
+            return Ok(());
+        }
+        if rpc_client.get_tx_out(&outpoint.txid, outpoint.vout, Some(false))?.is_some() {
+            // Still unspent, so any spend will be emitted in due course.
+            return Ok(());
+        }
+        let Some(funding_block_hash) = rpc_client.get_raw_transaction_info(&outpoint.txid, None)?.blockhash else {
+            return Ok(());
+        };
+        let funding_height = rpc_client.get_block_header_info(&funding_block_hash)?.height as u64;
+        for height in funding_height..=rpc_client.get_block_count()? {
+            let block = rpc_client.get_block(&rpc_client.get_block_hash(height)?)?;
+            let mut spend_map = self.spend_map.lock().unwrap();
+            spend_map.insert_block_spends(&block, height.try_into().unwrap_or(u32::MAX));
+            if spend_map.spending_txs.contains_key(&outpoint) {
+                break;
+            }
+        }
+        Ok(())
     }
 
     fn sync_tx_confidence_map(&self) {
         let wallet = self.wallet.read().unwrap();
-        self.tx_confidence_map.lock().unwrap().sync(tx_confidence_entries(&wallet));
+        let next_height = wallet.as_ref().map_or_else(
+            || self.get_chain_tip().height, |w| w.wallet.latest_checkpoint().height()) + 1;
+        self.tx_confidence_map.lock().unwrap().sync(wallet.iter().flat_map(|w| tx_confidence_entries(&w.wallet)));
+        drop(wallet);
+        self.spend_map.lock().unwrap().sync(next_height);
+    }
+
+    /// Build a PSBT with the wallet's tx builder, persisting any change address revealed for it. The
+    /// UTXOs reserved for trades aren't spent, and if a trade ID is given, the selected UTXOs are
+    /// reserved for that trade in turn.
+    fn build_psbt(&self, reserve_for_trade_id: Option<&str>,
+                  build: impl FnOnce(&mut TxBuilder<'_, DefaultCoinSelectionAlgorithm>) -> Result<()>) -> Result<Psbt> {
+        let mut wallet = self.wallet.write().unwrap();
+        let loaded = wallet.as_mut().ok_or(WalletErrorKind::IllegalWalletState(NO_WALLET))?;
+        let mut reserved_utxos = self.reserved_utxos.lock().unwrap();
+        if let Some(trade_id) = reserve_for_trade_id {
+            // Any earlier half-deposit PSBT of the trade is superseded.
+            reserved_utxos.remove(trade_id);
+        }
+        let reserved: Vec<OutPoint> = reserved_utxos.values().flatten().copied().collect();
+        let mut builder = loaded.wallet.build_tx();
+        builder.unspendable(reserved.clone());
+        build(&mut builder)?;
+        let psbt = builder.finish()?;
+        let inputs = psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output);
+        // Manually selected UTXOs take precedence over unspendable ones, so must be checked here.
+        if inputs.clone().any(|outpoint| reserved.contains(&outpoint)) {
+            return Err(WalletErrorKind::IllegalWalletState("UTXO is reserved for a trade"));
+        }
+        if let Some(trade_id) = reserve_for_trade_id {
+            reserved_utxos.insert(trade_id.to_owned(), inputs.collect());
+        }
+        drop(reserved_utxos);
+        loaded.persist()?;
+        drop(wallet);
+        Ok(psbt)
+    }
+
+    fn sign_and_broadcast_psbt(&self, mut psbt: Psbt) -> Result<Txid> {
+        let wallet = self.wallet.read().unwrap();
+        let loaded = wallet.as_ref().ok_or(WalletErrorKind::IllegalWalletState(NO_WALLET))?;
+        loaded.wallet.sign(&mut psbt, SignOptions::default())?;
+        drop(wallet);
+        self.finalize_and_broadcast_psbt(psbt)
+    }
+
+    fn fetch_chain_height(&self) -> Result<u32> {
+        let block_count = task::block_in_place(|| Ok::<_, WalletErrorKind>(self.new_rpc_client()?.get_block_count()?))?;
+        Ok(u32::try_from(block_count).expect("block height should fit in u32"))
+    }
+
+    /// Replace the test wallet (if any) with one derived from the given seed, whose birthday mustn't
+    /// be above the given chain height. The seed is persisted before the wallet database is created,
+    /// so that there is never a database without the seed to recover its funds.
+    fn replace_wallet(&self, seed: &WalletSeed, chain_height: u32) -> Result<()> {
+        let mut wallet = self.wallet.write().unwrap();
+        if wallet.as_ref().is_some_and(|w| w.source != WalletSource::TestKeys) {
+            return Err(WalletErrorKind::IllegalWalletState("wallet has already been set up"));
+        }
+        if seed.birthday_height > chain_height {
+            return Err(WalletErrorKind::BirthdayAboveChainTip(seed.birthday_height, chain_height));
+        }
+        let descriptors = seed.descriptors(self.network)?;
+        let data_dir = self.data_dir.as_deref();
+        if let Some(data_dir) = data_dir {
+            seed.save(data_dir)?;
+        }
+        let new_wallet = LoadedWallet::open(self.network, data_dir, descriptors, WalletSource::Mnemonic,
+            Some(seed.birthday_height));
+        let new_wallet = match (new_wallet, data_dir) {
+            (Err(e), Some(data_dir)) => {
+                WalletSeed::remove(data_dir)?;
+                return Err(e);
+            }
+            (new_wallet, _) => new_wallet?,
+        };
+        self.tx_confidence_map.lock().unwrap().sync(tx_confidence_entries(&new_wallet.wallet));
+        *wallet = Some(new_wallet);
+        self.wallet_generation.fetch_add(1, Ordering::Relaxed);
+        drop(wallet);
+        Ok(())
     }
 }
 
@@ -79,77 +499,186 @@ fn tx_confidence_entries(wallet: &Wallet) -> impl Iterator<Item=(Txid, TxConfide
     let next_height = wallet.latest_checkpoint().height() + 1;
     wallet.transactions()
         .map(move |wallet_tx| {
-            let wallet_tx: WalletTx = wallet_tx.into();
-            let conf_height = wallet_tx.chain_position.confirmation_height_upper_bound().unwrap_or(next_height);
-            let num_confirmations = next_height - conf_height;
-            (wallet_tx.txid, TxConfidence { wallet_tx, num_confirmations })
+            let confidence = TxConfidence::new(wallet_tx.into(), next_height);
+            (confidence.wallet_tx.txid, confidence)
         })
 }
 
-#[tonic::async_trait]
-impl WalletService for WalletServiceImpl {
-    async fn connect(&self) -> Result<Never> {
-        let rpc_client: Client = task::block_in_place(|| Client::new(
-            "https://127.0.0.1:18443",
-            Auth::CookieFile(COOKIE_FILE_PATH.into()),
-        ))?;
+/// The txs seen spending the watched outpoints. As these needn't belong to the wallet, they are
+/// picked out of each block and mempool emission directly, rather than out of the wallet.
+#[derive(Default)]
+struct SpendMap {
+    spending_txs: HashMap<OutPoint, WalletTx>,
+    observable_map: ObservableHashMap<OutPoint, TxConfidence>,
+    /// The outpoints watched since the last poll, whose earlier spends (if any) are yet to be looked up.
+    newly_watched: Vec<OutPoint>,
+}
 
-        let blockchain_info = task::block_in_place(|| rpc_client.get_blockchain_info())?;
-        println!("Connected to Bitcoin Core RPC.\n  Chain: {}\n  Latest block: {} at height {}",
-            blockchain_info.chain, blockchain_info.best_block_hash, blockchain_info.blocks);
+impl SpendMap {
+    fn apply_block(&mut self, block: &Block, height: u32) {
+        // Blocks are emitted in height order, with the blocks of any reorg emitted afresh from the
+        // fork point, so spends confirmed at this height or above may no longer be in the chain.
+        for wallet_tx in self.spending_txs.values_mut() {
+            if wallet_tx.chain_position.confirmation_height_upper_bound().is_some_and(|h| h >= height) {
+                wallet_tx.chain_position = ChainPosition::Unconfirmed { last_seen: None };
+            }
+        }
+        self.insert_block_spends(block, height);
+    }
 
-        let wallet_tip: CheckPoint = self.wallet.read().unwrap().latest_checkpoint();
-        let start_height = wallet_tip.height();
-        println!("Current wallet tip is: {} at height {}", wallet_tip.hash(), start_height);
+    fn insert_block_spends(&mut self, block: &Block, height: u32) {
+        let anchor = ConfirmationBlockTime {
+            block_id: BlockId { height, hash: block.block_hash() },
+            confirmation_time: block.header.time.into(),
+        };
+        for tx in &block.txdata {
+            self.insert_spends(tx, ChainPosition::Confirmed { anchor, transitively: None });
+        }
+    }
 
-        let mut emitter = Emitter::new(&rpc_client, wallet_tip, start_height);
-        while let Some(block) = task::block_in_place(|| emitter.next_block())? {
-            print!(" {}", block.block_height());
-            self.wallet.write().unwrap()
-                .apply_block_connected_to(&block.block, block.block_height(), block.connected_to())?;
+    fn apply_unconfirmed_txs(&mut self, unconfirmed_txs: &[(Transaction, u64)]) {
+        for (tx, last_seen) in unconfirmed_txs {
+            self.insert_spends(tx, ChainPosition::Unconfirmed { last_seen: Some(*last_seen) });
         }
-        println!();
+    }
 
-        println!("Syncing mempool...");
-        let mempool_emissions = task::block_in_place(|| emitter.mempool())?;
-        self.wallet.write().unwrap().apply_unconfirmed_txs(mempool_emissions);
+    fn insert_spends(&mut self, tx: &Transaction, chain_position: ChainPosition<ConfirmationBlockTime>) {
+        let mut txid = None;
+        for input in &tx.input {
+            let outpoint = input.previous_output;
+            if !self.observable_map.contains_key(&outpoint) {
+                continue;
+            }
+            let txid = *txid.get_or_insert_with(|| tx.compute_txid());
+            // Only a block may replace a confirmed spend, and a mempool tx already seen is unchanged.
+            if !chain_position.is_confirmed() && self.spending_txs.get(&outpoint)
+                .is_some_and(|t| t.chain_position.is_confirmed() || t.txid == txid) {
+                continue;
+            }
+            let tx = Arc::new(tx.clone());
+            self.spending_txs.insert(outpoint, WalletTx { txid, tx, chain_position });
+        }
+    }
 
-        println!("Syncing tx confidence map with wallet.");
-        self.sync_tx_confidence_map();
+    fn observe(&mut self, outpoint: OutPoint) -> impl Stream<Item=Option<TxConfidence>> {
+        if !self.observable_map.contains_key(&outpoint) {
+            self.newly_watched.push(outpoint);
+        }
+        self.observable_map.observe(outpoint)
+    }
 
-        println!("Wallet balance after syncing: {}", self.balance().total());
+    fn sync(&mut self, next_height: u32) {
+        self.observable_map.sync(self.spending_txs.iter()
+            .map(|(outpoint, wallet_tx)| (*outpoint, TxConfidence::new(wallet_tx.clone(), next_height))));
+    }
+}
+
+#[tonic::async_trait]
+impl WalletService for WalletServiceImpl {
+    async fn connect(&self) -> Result<Never> {
+        let rpc_client = task::block_in_place(|| self.new_rpc_client())?;
+
+        let blockchain_info = task::block_in_place(|| rpc_client.get_blockchain_info())?;
+        println!("Connected to Bitcoin Core RPC.\n  Chain: {}\n  Latest block: {} at height {}",
+            blockchain_info.chain, blockchain_info.best_block_hash, blockchain_info.blocks);
 
-        println!("Polling for further blocks and mempool txs...");
         let mut interval = time::interval(Duration::from_secs(1));
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-        interval.tick().await;
         loop {
-            interval.tick().await;
+            let Some((wallet_tip, start_height, generation)) = self.sync_start() else {
+                // There is nothing to sync until a wallet is created or restored.
+                interval.tick().await;
+                continue;
+            };
+            println!("Current wallet tip is: {} at height {}", wallet_tip.hash(), wallet_tip.height());
 
+            let mut emitter = Emitter::new(&rpc_client, wallet_tip, start_height);
             while let Some(block) = task::block_in_place(|| emitter.next_block())? {
-                println!("New block {} at height {}.", block.block_hash(), block.block_height());
-                self.wallet.write().unwrap()
-                    .apply_block_connected_to(&block.block, block.block_height(), block.connected_to())?;
+                print!(" {}", block.block_height());
+                if !self.apply_block(&block, generation)? {
+                    break;
+                }
             }
+            println!();
 
+            println!("Syncing mempool...");
             let mempool_emissions = task::block_in_place(|| emitter.mempool())?;
-            self.wallet.write().unwrap().apply_unconfirmed_txs(mempool_emissions);
+            self.apply_unconfirmed_txs(mempool_emissions);
+            self.persist_sync()?;
+            task::block_in_place(|| self.find_earlier_spends(&rpc_client));
 
-            // TODO: Skip needless cache/map updates if the wallet hasn't actually changed:
+            println!("Syncing tx confidence map with wallet.");
             self.sync_tx_confidence_map();
+
+            println!("Wallet balance after syncing: {}", self.balance().total());
+
+            println!("Polling for further blocks and mempool txs...");
+            interval.reset();
+            'poll: loop {
+                interval.tick().await;
+
+                while let Some(block) = task::block_in_place(|| emitter.next_block())? {
+                    println!("New block {} at height {}.", block.block_hash(), block.block_height());
+                    if !self.apply_block(&block, generation)? {
+                        println!("Wallet has been replaced -- syncing afresh.");
+                        break 'poll;
+                    }
+                }
+
+                let mempool_emissions = task::block_in_place(|| emitter.mempool())?;
+                self.apply_unconfirmed_txs(mempool_emissions);
+                self.persist_sync()?;
+                task::block_in_place(|| self.find_earlier_spends(&rpc_client));
+
+                // TODO: Skip needless cache/map updates if the wallet hasn't actually changed:
+                self.sync_tx_confidence_map();
+
+                if self.wallet_generation.load(Ordering::Relaxed) != generation {
+                    println!("Wallet has been replaced -- syncing afresh.");
+                    break;
+                }
+            }
         }
     }
 
     fn balance(&self) -> Balance {
-        self.wallet.read().unwrap().balance()
+        self.wallet.read().unwrap().as_ref().map(|w| w.wallet.balance()).unwrap_or_default()
+    }
+
+    fn get_chain_tip(&self) -> BlockId {
+        self.chain_tip.lock().unwrap().get().tip
+    }
+
+    fn get_chain_tip_stream(&self) -> BoxStream<'static, ChainTipUpdate> {
+        DropStream::new(self.chain_tip.lock().unwrap().observe(), || {
+            println!("Chain tip stream has been dropped.");
+        }).boxed()
     }
 
-    fn reveal_next_address(&self) -> AddressInfo {
-        self.wallet.write().unwrap().reveal_next_address(KeychainKind::External)
+    fn reveal_next_address(&self) -> Result<AddressInfo> {
+        let mut wallet = self.wallet.write().unwrap();
+        let loaded = wallet.as_mut().ok_or(WalletErrorKind::IllegalWalletState(NO_WALLET))?;
+        let address = loaded.wallet.reveal_next_address(KeychainKind::External);
+        // The address mustn't be handed out before its index is persisted, lest it be reused.
+        loaded.persist()?;
+        drop(wallet);
+        Ok(address)
     }
 
     fn list_unspent(&self) -> Vec<LocalOutput> {
-        self.wallet.read().unwrap().list_unspent().collect()
+        self.wallet.read().unwrap().iter().flat_map(|w| w.wallet.list_unspent()).collect()
+    }
+
+    fn list_transactions(&self) -> Vec<TxDetails> {
+        let mut txs: Vec<_> = self.wallet.read().unwrap().iter()
+            .flat_map(|w| tx_confidence_entries(&w.wallet).map(|(_, confidence)| {
+                let (sent, received) = w.wallet.sent_and_received(&confidence.wallet_tx.tx);
+                let fee = w.wallet.calculate_fee(&confidence.wallet_tx.tx).ok();
+                TxDetails { confidence, sent, received, fee }
+            }))
+            .collect();
+        txs.sort_by_key(|tx| Reverse(tx.confidence.wallet_tx.chain_position));
+        txs
     }
 
     fn get_tx_confidence_stream(&self, txid: Txid) -> BoxStream<'static, Option<TxConfidence>> {
@@ -157,6 +686,144 @@ impl WalletService for WalletServiceImpl {
             println!("Confidence stream has been dropped for txid: {txid}");
         }).boxed()
     }
+
+    fn get_spend_confidence_stream(&self, outpoint: OutPoint) -> BoxStream<'static, Option<TxConfidence>> {
+        DropStream::new(self.spend_map.lock().unwrap().observe(outpoint), move || {
+            println!("Spend confidence stream has been dropped for outpoint: {outpoint}");
+        }).boxed()
+    }
+
+    fn get_wallet_info(&self) -> WalletInfo {
+        let wallet = self.wallet.read().unwrap();
+        let loaded = wallet.as_ref();
+        let info = WalletInfo {
+            network: self.network,
+            source: loaded.map(|w| w.source),
+            birthday_height: loaded.and_then(|w| w.birthday_height),
+            descriptors: loaded.map(|w| Descriptors {
+                external: w.wallet.public_descriptor(KeychainKind::External).to_string(),
+                internal: w.wallet.public_descriptor(KeychainKind::Internal).to_string(),
+            }),
+        };
+        drop(wallet);
+        info
+    }
+
+    fn create_wallet(&self, passphrase: String) -> Result<WalletSeed> {
+        let chain_height = self.fetch_chain_height()?;
+        let seed = WalletSeed::generate(passphrase, chain_height);
+        self.replace_wallet(&seed, chain_height)?;
+        Ok(seed)
+    }
+
+    fn restore_wallet(&self, seed: WalletSeed) -> Result<()> {
+        // A birthday at genesis (rescanning the whole chain) is always valid, so the node needn't be asked.
+        let chain_height = if seed.birthday_height == 0 { 0 } else { self.fetch_chain_height()? };
+        self.replace_wallet(&seed, chain_height)
+    }
+
+    fn create_half_deposit_psbt(&self, trade_id: &str, deposit_output: TxOut, fee_rate: FeeRate) -> Result<Psbt> {
+        self.build_psbt(Some(trade_id), |builder| {
+            builder.add_recipient(deposit_output.script_pubkey, deposit_output.value)
+                .fee_rate(fee_rate)
+                // The deposit tx mustn't be replaceable, as the prepared txs commit to its txid:
+                .set_exact_sequence(Sequence::MAX);
+            Ok(())
+        })
+    }
+
+    fn release_reserved_utxos(&self, trade_id: &str) {
+        self.reserved_utxos.lock().unwrap().remove(trade_id);
+    }
+
+    fn create_funded_psbt(&self, recipients: Vec<(Address<NetworkUnchecked>, Amount)>, fee_rate: FeeRate,
+                          utxos: &[OutPoint]) -> Result<Psbt> {
+        let recipients = recipients.into_iter()
+            .map(|(address, amount)| Ok((address.require_network(self.network)?.script_pubkey(), amount)))
+            .collect::<Result<_>>()?;
+        self.build_psbt(None, |builder| {
+            builder.set_recipients(recipients).fee_rate(fee_rate);
+            if !utxos.is_empty() {
+                builder.add_utxos(utxos)?.manually_selected_only();
+            }
+            Ok(())
+        })
+    }
+
+    fn finalize_and_broadcast_psbt(&self, mut psbt: Psbt) -> Result<Txid> {
+        let wallet = self.wallet.read().unwrap();
+        let loaded = wallet.as_ref().ok_or(WalletErrorKind::IllegalWalletState(NO_WALLET))?;
+        let finalized = loaded.wallet.finalize_psbt(&mut psbt, SignOptions::default())?;
+        drop(wallet);
+        if !finalized {
+            return Err(WalletErrorKind::IncompletePsbt);
+        }
+        self.broadcast_tx(psbt.extract_tx()?)
+    }
+
+    fn send_to_address(&self, address: Address<NetworkUnchecked>, amount: Amount, fee_rate: FeeRate) -> Result<Txid> {
+        let script_pubkey = address.require_network(self.network)?.script_pubkey();
+        let psbt = self.build_psbt(None, |builder| {
+            builder.add_recipient(script_pubkey, amount).fee_rate(fee_rate);
+            Ok(())
+        })?;
+        self.sign_and_broadcast_psbt(psbt)
+    }
+
+    fn sweep_all(&self, address: Address<NetworkUnchecked>, fee_rate: FeeRate) -> Result<Txid> {
+        let script_pubkey = address.require_network(self.network)?.script_pubkey();
+        let psbt = self.build_psbt(None, |builder| {
+            builder.drain_wallet().drain_to(script_pubkey).fee_rate(fee_rate);
+            Ok(())
+        })?;
+        self.sign_and_broadcast_psbt(psbt)
+    }
+
+    fn broadcast_tx(&self, tx: Transaction) -> Result<Txid> {
+        let txid = task::block_in_place(|| Ok::<_, WalletErrorKind>(self.new_rpc_client()?.send_raw_transaction(&tx)?))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.apply_unconfirmed_txs(vec![(tx, now)]);
+        self.sync_tx_confidence_map();
+        Ok(txid)
+    }
+
+    fn sign_psbt(&self, psbt: &mut Psbt) -> Result<()> {
+        let sign_options = SignOptions {
+            // The peer's inputs are included, which are not ours to finalize.
+            try_finalize: false,
+            // Only taproot inputs are expected, for which the witness UTXOs suffice.
+            trust_witness_utxo: true,
+            ..SignOptions::default()
+        };
+        let wallet = self.wallet.read().unwrap();
+        wallet.as_ref().ok_or(WalletErrorKind::IllegalWalletState(NO_WALLET))?.wallet.sign(psbt, sign_options)?;
+        drop(wallet);
+        Ok(())
+    }
+}
+
+/// The part of a `gettxspendingprevout` result we need.
+#[derive(Deserialize)]
+struct MempoolSpend {
+    spendingtxid: Option<Txid>,
+}
+
+/// A new chain tip, after disconnecting any blocks of the old chain that were reorged out.
+#[derive(Clone, Eq, PartialEq)]
+pub struct ChainTipUpdate {
+    pub tip: BlockId,
+    /// The disconnected blocks, from the old tip downwards, which is empty unless there was a reorg.
+    pub disconnected: Vec<BlockId>,
+}
+
+impl ChainTipUpdate {
+    fn new(old_tip: &CheckPoint, new_tip: &CheckPoint) -> Self {
+        let disconnected = old_tip.iter()
+            .take_while(|cp| new_tip.get(cp.height()).is_none_or(|new_cp| new_cp.hash() != cp.hash()))
+            .map(|cp| cp.block_id())
+            .collect();
+        Self { tip: new_tip.block_id(), disconnected }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq)]
@@ -165,6 +832,27 @@ pub struct TxConfidence {
     pub num_confirmations: u32,
 }
 
+impl TxConfidence {
+    fn new(wallet_tx: WalletTx, next_height: u32) -> Self {
+        let conf_height = wallet_tx.chain_position.confirmation_height_upper_bound().unwrap_or(next_height);
+        // A spend found by scanning the node's blocks may be confirmed above the wallet tip, in which
+        // case it counts as unconfirmed until the wallet has caught up.
+        Self { wallet_tx, num_confirmations: next_height.saturating_sub(conf_height) }
+    }
+}
+
+/// A wallet tx together with the amounts it moves in and out of the wallet.
+#[derive(Clone, Eq, PartialEq)]
+pub struct TxDetails {
+    pub confidence: TxConfidence,
+    /// The total value of the wallet's outputs spent by the tx.
+    pub sent: Amount,
+    /// The total value of the tx outputs paying the wallet.
+    pub received: Amount,
+    /// The fee, which is unknown if the tx spends any foreign outputs the wallet hasn't seen.
+    pub fee: Option<Amount>,
+}
+
 #[derive(Clone, Eq, PartialEq)]
 pub struct WalletTx {
     pub txid: Txid,
@@ -178,6 +866,8 @@ impl From<bdk_wallet::WalletTx<'_>> for WalletTx {
     }
 }
 
+const NO_WALLET: &str = "no wallet has been created or restored";
+
 pub type Result<T, E = WalletErrorKind> = std::result::Result<T, E>;
 
 #[derive(Error, Debug)]
@@ -185,4 +875,225 @@ pub type Result<T, E = WalletErrorKind> = std::result::Result<T, E>;
 pub enum WalletErrorKind {
     BitcoindRpc(#[from] bdk_bitcoind_rpc::bitcoincore_rpc::Error),
     ApplyHeader(#[from] bdk_wallet::chain::local_chain::ApplyHeaderError),
+    Io(#[from] std::io::Error),
+    Persist(#[from] rusqlite::Error),
+    // Boxed (like 'ExtractTx'), as they would make every wallet 'Result' hundreds of bytes large:
+    LoadWallet(Box<LoadWithPersistError<rusqlite::Error>>),
+    CreateWallet(Box<CreateWithPersistError<rusqlite::Error>>),
+    KeyDerivation(#[from] KeyDerivationError),
+    #[error("illegal wallet state: {0}")]
+    IllegalWalletState(&'static str),
+    #[error("wallet birthday height {0} is above the chain tip height {1}")]
+    BirthdayAboveChainTip(u32, u32),
+    CreateTx(#[from] CreateTxError),
+    AddUtxo(#[from] AddUtxoError),
+    Address(#[from] bdk_wallet::bitcoin::address::ParseError),
+    ExtractTx(Box<ExtractTxError>),
+    #[error("PSBT is not fully signed")]
+    IncompletePsbt,
+    Signer(#[from] SignerError),
+}
+
+impl From<LoadWithPersistError<rusqlite::Error>> for WalletErrorKind {
+    fn from(value: LoadWithPersistError<rusqlite::Error>) -> Self { Self::LoadWallet(Box::new(value)) }
+}
+
+impl From<CreateWithPersistError<rusqlite::Error>> for WalletErrorKind {
+    fn from(value: CreateWithPersistError<rusqlite::Error>) -> Self { Self::CreateWallet(Box::new(value)) }
+}
+
+impl From<ExtractTxError> for WalletErrorKind {
+    fn from(value: ExtractTxError) -> Self { Self::ExtractTx(Box::new(value)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk_wallet::bitcoin::BlockHash;
+    use bdk_wallet::bitcoin::hashes::Hash as _;
+    use super::*;
+
+    fn chain(fork: u32, heights: std::ops::RangeInclusive<u32>) -> CheckPoint {
+        let block_hash = |height: u32| BlockHash::hash(&[if height < 3 { 0 } else { fork }, height].map(u32::to_le_bytes).concat());
+        let block_id = |height| BlockId { height, hash: block_hash(height) };
+        CheckPoint::from_block_ids(heights.map(block_id)).unwrap()
+    }
+
+    #[test]
+    fn test_chain_tip_update() {
+        let old_tip = chain(1, 0..=4);
+
+        let update = ChainTipUpdate::new(&old_tip, &chain(1, 0..=5));
+        assert_eq!(update.tip, chain(1, 0..=5).block_id());
+        assert!(update.disconnected.is_empty());
+
+        // Reorg replacing blocks 3 & 4, tracked as soon as the first block of the new fork connects.
+        let update = ChainTipUpdate::new(&old_tip, &chain(2, 0..=3));
+        assert_eq!(update.tip, chain(2, 0..=3).block_id());
+        assert_eq!(update.disconnected, [4, 3].map(|h| old_tip.get(h).unwrap().block_id()));
+    }
+
+    #[test]
+    fn test_wallet_persistence() {
+        let dir = std::env::temp_dir().join(format!("musigd-wallet-test-{}", std::process::id()));
+        let config = || WalletConfig { data_dir: Some(dir.clone()), ..WalletConfig::default() };
+
+        let wallet_service = WalletServiceImpl::with_config(config()).unwrap();
+        assert_eq!(wallet_service.get_wallet_info().source, Some(WalletSource::TestKeys));
+        assert_eq!(wallet_service.reveal_next_address().unwrap().index, 0);
+        assert_eq!(wallet_service.reveal_next_address().unwrap().index, 1);
+        drop(wallet_service);
+
+        // revealed addresses are not handed out again after reloading
+        let wallet_service = WalletServiceImpl::with_config(config()).unwrap();
+        assert_eq!(wallet_service.reveal_next_address().unwrap().index, 2);
+
+        // a restored wallet replaces the test wallet, and is loaded from its seed from then on
+        let seed = WalletSeed::generate(String::new(), 100);
+        assert!(matches!(wallet_service.replace_wallet(&seed, 99), Err(WalletErrorKind::BirthdayAboveChainTip(100, 99))));
+        assert!(WalletSeed::load(&dir).unwrap().is_none(), "seed of rejected wallet should not be saved");
+        wallet_service.replace_wallet(&seed, 100).unwrap();
+        assert!(matches!(wallet_service.replace_wallet(&seed, 100), Err(WalletErrorKind::IllegalWalletState(_))));
+        assert!(dir.join(WalletSource::TestKeys.db_file_name()).exists() && dir.join(WalletSource::Mnemonic.db_file_name()).exists());
+        assert_eq!(wallet_service.reveal_next_address().unwrap().index, 0);
+        let info = wallet_service.get_wallet_info();
+        drop(wallet_service);
+
+        let wallet_service = WalletServiceImpl::with_config(config()).unwrap();
+        let reloaded_info = wallet_service.get_wallet_info();
+        assert_eq!(reloaded_info.source, Some(WalletSource::Mnemonic));
+        assert_eq!(reloaded_info.birthday_height, Some(100));
+        assert_eq!(reloaded_info.descriptors, info.descriptors);
+        assert!(!info.descriptors.unwrap().external.contains("tprv"), "only public descriptors should be given out");
+        assert_eq!(wallet_service.reveal_next_address().unwrap().index, 1);
+        drop(wallet_service);
+
+        let other_network = WalletConfig { network: Network::Signet, ..config() };
+        assert!(matches!(WalletServiceImpl::with_config(other_network), Err(WalletErrorKind::LoadWallet(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_transactions() {
+        let wallet_service = WalletServiceImpl::new();
+        assert!(wallet_service.list_transactions().is_empty());
+
+        let address = wallet_service.reveal_next_address().unwrap().address;
+        let tx = |n: u8| Transaction {
+            version: bdk_wallet::bitcoin::transaction::Version::TWO,
+            lock_time: bdk_wallet::bitcoin::absolute::LockTime::ZERO,
+            input: vec![bdk_wallet::bitcoin::TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([n; 32]), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut { value: Amount::from_sat(u64::from(n) * 1000), script_pubkey: address.script_pubkey() }],
+        };
+        wallet_service.apply_unconfirmed_txs(vec![(tx(1), 100), (tx(2), 200)]);
+
+        let txs = wallet_service.list_transactions();
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].confidence.wallet_tx.txid, tx(2).compute_txid(), "newest tx should be listed first");
+        assert_eq!((txs[0].sent, txs[0].received), (Amount::ZERO, Amount::from_sat(2000)));
+        assert_eq!(txs[0].fee, None, "fee of tx spending unknown foreign output should be unknown");
+        assert_eq!(txs[1].confidence.wallet_tx.txid, tx(1).compute_txid());
+    }
+
+    #[test]
+    fn test_reserved_utxos() {
+        let wallet_service = WalletServiceImpl::new();
+        let address = wallet_service.reveal_next_address().unwrap().address;
+        let tx = |n: u8| Transaction {
+            version: bdk_wallet::bitcoin::transaction::Version::TWO,
+            lock_time: bdk_wallet::bitcoin::absolute::LockTime::ZERO,
+            input: vec![bdk_wallet::bitcoin::TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([n; 32]), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut { value: Amount::from_sat(50_000), script_pubkey: address.script_pubkey() }],
+        };
+        wallet_service.apply_unconfirmed_txs(vec![(tx(1), 100), (tx(2), 200)]);
+        let deposit_output = TxOut { value: Amount::from_sat(30_000), script_pubkey: address.script_pubkey() };
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(2);
+        let inputs = |psbt: &Psbt| psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect::<Vec<_>>();
+
+        let psbt1 = wallet_service.create_half_deposit_psbt("trade1", deposit_output.clone(), fee_rate).unwrap();
+        let sweep_psbt = wallet_service.build_psbt(None, |builder| {
+            builder.drain_wallet().drain_to(address.script_pubkey()).fee_rate(fee_rate);
+            Ok(())
+        }).unwrap();
+        assert_eq!(inputs(&sweep_psbt).len(), 1, "sweeping the wallet should leave the reserved UTXO alone");
+        assert!(!inputs(&psbt1).contains(&inputs(&sweep_psbt)[0]));
+        let psbt2 = wallet_service.create_half_deposit_psbt("trade2", deposit_output.clone(), fee_rate).unwrap();
+        assert!(inputs(&psbt1).iter().all(|outpoint| !inputs(&psbt2).contains(outpoint)), "trades must not share UTXOs");
+        assert!(matches!(wallet_service.create_half_deposit_psbt("trade3", deposit_output.clone(), fee_rate),
+            Err(WalletErrorKind::CreateTx(_))));
+        assert!(matches!(wallet_service.sweep_all(address.as_unchecked().clone(), fee_rate),
+            Err(WalletErrorKind::CreateTx(_))));
+        let recipients = vec![(address.as_unchecked().clone(), Amount::from_sat(10_000))];
+        assert!(matches!(wallet_service.create_funded_psbt(recipients, fee_rate, &inputs(&psbt1)),
+            Err(WalletErrorKind::IllegalWalletState(_))));
+
+        // a new half-deposit PSBT for the same trade replaces the old reservation
+        let psbt1 = wallet_service.create_half_deposit_psbt("trade1", deposit_output.clone(), fee_rate).unwrap();
+        wallet_service.release_reserved_utxos("trade1");
+        let psbt3 = wallet_service.create_half_deposit_psbt("trade3", deposit_output, fee_rate).unwrap();
+        assert_eq!(inputs(&psbt3), inputs(&psbt1));
+    }
+
+    #[test]
+    fn test_spend_map() {
+        let outpoint = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let spending_tx = Transaction {
+            version: bdk_wallet::bitcoin::transaction::Version::TWO,
+            lock_time: bdk_wallet::bitcoin::absolute::LockTime::ZERO,
+            input: vec![bdk_wallet::bitcoin::TxIn { previous_output: outpoint, ..Default::default() }],
+            output: vec![],
+        };
+        let mut spend_map = SpendMap::default();
+        let _spends = spend_map.observe(outpoint);
+        let _more_spends = spend_map.observe(outpoint);
+        assert_eq!(spend_map.newly_watched, [outpoint], "outpoint should be queued for lookup only once");
+
+        spend_map.apply_unconfirmed_txs(&[(spending_tx.clone(), 100)]);
+        assert!(!spend_map.spending_txs[&outpoint].chain_position.is_confirmed());
+
+        let block = Block { header: genesis_block(Network::Regtest).header, txdata: vec![spending_tx] };
+        spend_map.insert_block_spends(&block, 7);
+        let spending_tx = &spend_map.spending_txs[&outpoint];
+        assert_eq!(spending_tx.txid, block.txdata[0].compute_txid());
+        assert_eq!(spending_tx.chain_position.confirmation_height_upper_bound(), Some(7));
+        assert_eq!(TxConfidence::new(spending_tx.clone(), 10).num_confirmations, 3);
+        assert_eq!(TxConfidence::new(spending_tx.clone(), 5).num_confirmations, 0);
+    }
+
+    #[test]
+    fn test_failed_spend_lookup_is_retried() {
+        let wallet_service = WalletServiceImpl::with_config(WalletConfig {
+            use_test_keys: false,
+            ..WalletConfig::default()
+        }).unwrap();
+        let outpoint = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let _spends = wallet_service.spend_map.lock().unwrap().observe(outpoint);
+
+        // Nothing listens on port 1, so the lookup fails and the outpoint must stay queued.
+        let rpc_client = Client::new("http://127.0.0.1:1", Auth::None).unwrap();
+        wallet_service.find_earlier_spends(&rpc_client);
+        wallet_service.find_earlier_spends(&rpc_client);
+        assert_eq!(wallet_service.spend_map.lock().unwrap().newly_watched, [outpoint]);
+    }
+
+    #[test]
+    fn test_no_wallet_without_test_keys() {
+        let wallet_service = WalletServiceImpl::with_config(WalletConfig {
+            network: Network::Bitcoin,
+            use_test_keys: false,
+            ..WalletConfig::default()
+        }).unwrap();
+        assert_eq!(wallet_service.get_wallet_info().source, None);
+        assert_eq!(wallet_service.get_chain_tip().hash, genesis_block(Network::Bitcoin).block_hash());
+        assert!(matches!(wallet_service.reveal_next_address(), Err(WalletErrorKind::IllegalWalletState(_))));
+
+        wallet_service.replace_wallet(&WalletSeed::generate(String::new(), 0), 0).unwrap();
+        assert_eq!(wallet_service.get_wallet_info().source, Some(WalletSource::Mnemonic));
+        assert!(wallet_service.reveal_next_address().unwrap().address.to_string().starts_with("bc1p"));
+    }
 }
@@ -1,4 +1,7 @@
+use bdk_wallet::bitcoin::FeeRate;
+use rpc::fees::StaticFeeEstimator;
 use rpc::server::{WalletImpl, WalletServer};
+use rpc::store::TradeModelMemoryStore;
 use rpc::wallet::WalletServiceImpl;
 use std::process::{Command, Output};
 use std::sync::Arc;
@@ -58,6 +61,71 @@ async fn test_cli_new_address() {
     assert!(output.stderr.is_empty());
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cli_restore_wallet() {
+    let mut port = 50052;
+    let _guard = AbortOnDropHandle::new(spawn_wallet_grpc_service(&mut port));
+
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["wallet-info"]))
+        .await.unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.contains_subslice(b"source: TestKeys"));
+
+    let mnemonic = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["restore-wallet", mnemonic,
+        "--birthday-height", "0"])).await.unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["wallet-info"]))
+        .await.unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.contains_subslice(b"source: Mnemonic"));
+    assert!(output.stdout.contains_subslice(b"external_descriptor: \"tr([73c5da0a/86'/1'/0']tpub"));
+
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["restore-wallet", mnemonic,
+        "--birthday-height", "0"])).await.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stderr.contains_subslice(b"FailedPrecondition"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cli_send_to_address_rejected() {
+    let mut port = 50052;
+    let _guard = AbortOnDropHandle::new(spawn_wallet_grpc_service(&mut port));
+
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["send-to-address",
+        "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", "10000", "--fee-rate", "2"])).await.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stderr.contains_subslice(b"InvalidArgument"), "mainnet address should be rejected on regtest");
+
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["send-to-address",
+        "bcrt1pkar3gerekw8f9gef9vn9xz0qypytgacp9wa5saelpksdgct33qdqan7c89", "10000", "--fee-rate", "2"])).await.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stderr.contains_subslice(b"FailedPrecondition"), "empty wallet should have insufficient funds");
+
+    for fee_rate in ["0", "18446744073709551615"] {
+        let output = task::spawn_blocking(move || exec_cli_with_port(port, ["send-to-address",
+            "bcrt1pkar3gerekw8f9gef9vn9xz0qypytgacp9wa5saelpksdgct33qdqan7c89", "10000", "--fee-rate", fee_rate])).await.unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert!(output.stderr.contains_subslice(b"fee rate out of range"));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cli_estimate_fee() {
+    let mut port = 50052;
+    let _guard = AbortOnDropHandle::new(spawn_wallet_grpc_service(&mut port));
+
+    let output = task::spawn_blocking(move || exec_cli_with_port(port, ["estimate-fee", "--conf-target", "6"]))
+        .await.unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.contains_subslice(b"EstimateFeeResponse"));
+    assert!(output.stdout.contains_subslice(b"conf_target: 6"));
+    assert!(output.stdout.contains_subslice(b"fee_rate: 750"));
+    assert!(output.stderr.is_empty());
+}
+
 fn exec_cli<'a>(args: impl IntoIterator<Item=&'a str>) -> Output {
     Command::new(env!("CARGO_BIN_EXE_musig-cli"))
         .args(args)
@@ -74,7 +142,11 @@ fn exec_cli_with_port<'a>(port: u16, args: impl IntoIterator<Item=&'a str>) -> O
 }
 
 fn spawn_wallet_grpc_service(port: &mut u16) -> JoinHandle<Result<(), transport::Error>> {
-    let wallet = WalletImpl { wallet_service: Arc::new(WalletServiceImpl::new()) };
+    let wallet = WalletImpl {
+        wallet_service: Arc::new(WalletServiceImpl::new()),
+        trade_model_store: Arc::new(TradeModelMemoryStore::default()),
+        fee_estimator: Arc::new(StaticFeeEstimator { fee_rate: FeeRate::from_sat_per_vb_unchecked(3) }),
+    };
     let incoming = loop {
         let addr = format!("127.0.0.1:{port}").parse().unwrap();
         match TcpIncoming::bind(addr) {